@@ -177,10 +177,10 @@
             } ''
             mkdir -p $out/bin
 
-            # Clean PATH to only contain what we need to do objcopy. Also
+            # Clean PATH to only contain what we need to do objcopy and to sign. Also
             # tell lanzatool where to find our UEFI binaries.
             makeWrapper ${tool}/bin/lzbt-systemd $out/bin/lzbt \
-              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]} \
+              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool pkgs.tpm2-tools pkgs.openssl ]} \
               --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
           '';
         in
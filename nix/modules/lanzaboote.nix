@@ -15,6 +15,18 @@ let
   loaderConfigFile = loaderSettingsFormat.generate "loader.conf" cfg.settings;
 
   configurationLimit = if cfg.configurationLimit == null then 0 else cfg.configurationLimit;
+
+  # toString prevents copying the keys to the Nix store if they are paths.
+  installConfig = pkgs.writeText "lanzaboote-install.json" (builtins.toJSON {
+    # Use the system from the kernel's hostPlatform because this should
+    # always, even in the cross compilation case, be the right system.
+    system = config.boot.kernelPackages.stdenv.hostPlatform.system;
+    systemd = "${config.systemd.package}";
+    systemdBootLoaderConfig = "${loaderConfigFile}";
+    publicKey = toString cfg.publicKeyFile;
+    privateKey = toString cfg.privateKeyFile;
+    inherit configurationLimit;
+  });
 in
 {
   options.boot.lanzaboote = {
@@ -110,6 +122,30 @@ in
         https://uapi-group.org/specifications/specs/boot_loader_specification/#sorting
       '';
     };
+
+    entryTitle = mkOption {
+      default = null;
+      type = lib.types.nullOr lib.types.str;
+      example = "{label} (Generation {version}, {date})";
+      description = ''
+        Template for the title of the NixOS bootloader entries. `{label}`,
+        `{version}` and `{date}` are replaced by the system label, the
+        generation (including the specialisation) and its build date. The
+        title should be unique for every generation, otherwise systemd-boot
+        shows the version next to it. By default, the title is
+        `<label> (Generation <version>, <date>)`.
+      '';
+    };
+
+    machineId = mkOption {
+      default = null;
+      type = lib.types.nullOr lib.types.str;
+      example = "0123456789abcdef0123456789abcdef";
+      description = ''
+        The machine ID written into the Boot Loader Specification Type #1
+        entries, if they are written. See `machine-id(5)`.
+      '';
+    };
   };
 
   config = mkIf cfg.enable {
@@ -117,6 +153,8 @@ in
       enable = true;
       extensions."org.nix-community.lanzaboote" = {
         sort_key = config.boot.lanzaboote.sortKey;
+        title = config.boot.lanzaboote.entryTitle;
+        machine_id = config.boot.lanzaboote.machineId;
       };
     };
     boot.loader.supportsInitrdSecrets = true;
@@ -129,15 +167,8 @@ in
           ${lib.getExe sbctlWithPki} enroll-keys --yes-this-might-brick-my-machine
         ''}
 
-        # Use the system from the kernel's hostPlatform because this should
-        # always, even in the cross compilation case, be the right system.
         ${lib.getExe cfg.package} install \
-          --system ${config.boot.kernelPackages.stdenv.hostPlatform.system} \
-          --systemd ${config.systemd.package} \
-          --systemd-boot-loader-config ${loaderConfigFile} \
-          --public-key ${cfg.publicKeyFile} \
-          --private-key ${cfg.privateKeyFile} \
-          --configuration-limit ${toString configurationLimit} \
+          --config ${installConfig} \
           ${config.boot.loader.efi.efiSysMountPoint} \
           /nix/var/nix/profiles/system-*-link
       '';
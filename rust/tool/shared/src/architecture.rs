@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 use anyhow::{bail, Result};
+use goblin::pe::header::{COFF_MACHINE_ARM64, COFF_MACHINE_X86_64};
 
 /// Supported system
 #[non_exhaustive]
@@ -21,6 +22,14 @@ impl Architecture {
     pub fn efi_fallback_filename(&self) -> PathBuf {
         format!("BOOT{}.EFI", self.efi_representation().to_ascii_uppercase()).into()
     }
+
+    /// The machine type in the COFF header of PE binaries for this architecture.
+    pub fn pe_machine(&self) -> u16 {
+        match self {
+            Self::X86 => COFF_MACHINE_X86_64,
+            Self::AArch64 => COFF_MACHINE_ARM64,
+        }
+    }
 }
 
 impl Architecture {
@@ -32,4 +41,34 @@ impl Architecture {
             _ => bail!(format!("Unsupported NixOS system: {}.", system_double)),
         })
     }
+
+    /// Converts from the machine type in the COFF header of a PE binary to a supported system
+    pub fn from_pe_machine(machine: u16) -> Result<Self> {
+        Ok(match machine {
+            COFF_MACHINE_X86_64 => Self::X86,
+            COFF_MACHINE_ARM64 => Self::AArch64,
+            _ => bail!(format!("Unsupported PE machine type: {:#06x}.", machine)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nixos_system() -> Result<()> {
+        assert_eq!(
+            Architecture::from_nixos_system("x86_64-linux")?,
+            Architecture::X86
+        );
+        assert_eq!(
+            Architecture::from_nixos_system("aarch64-linux")?,
+            Architecture::AArch64
+        );
+        // Unknown systems are never mistaken for x64.
+        let err = Architecture::from_nixos_system("riscv64-linux").unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported NixOS system: riscv64-linux.");
+        Ok(())
+    }
 }
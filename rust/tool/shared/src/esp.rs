@@ -1,14 +1,16 @@
 use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Result};
+
 use crate::architecture::Architecture;
 
 /// Generic ESP paths which can be specific to a bootloader
-pub trait EspPaths<const N: usize> {
+pub trait EspPaths {
     /// Build an ESP path structure out of the ESP root directory
     fn new(esp: impl AsRef<Path>, arch: Architecture) -> Self;
 
     /// Return the used file paths to store as garbage collection roots.
-    fn iter(&self) -> std::array::IntoIter<&PathBuf, N>;
+    fn iter(&self) -> impl Iterator<Item = &PathBuf>;
 
     /// Returns the path containing NixOS EFI binaries
     fn nixos_path(&self) -> &Path;
@@ -16,3 +18,46 @@ pub trait EspPaths<const N: usize> {
     /// Returns the path containing Linux EFI binaries
     fn linux_path(&self) -> &Path;
 }
+
+/// Characters that FAT does not allow in file names.
+const FAT_ILLEGAL_CHARACTERS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// Ensure that a file name derived from e.g. a specialisation name can be created on a FAT ESP.
+///
+/// Besides the characters FAT does not allow, control characters (which also break loader
+/// entries) and trailing dots and spaces (which FAT silently strips, so that the file cannot be
+/// found under its name) are rejected.
+pub fn validate_file_name(name: &str) -> Result<()> {
+    if let Some(illegal) = name
+        .chars()
+        .find(|c| FAT_ILLEGAL_CHARACTERS.contains(c) || c.is_control())
+    {
+        bail!("The file name {name:?} contains {illegal:?}, which is not allowed on the ESP");
+    }
+    if name.is_empty() || name.ends_with(['.', ' ']) {
+        bail!("The file name {name:?} is empty or ends with a dot or space, which FAT strips");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_invalid_fat_file_names() {
+        assert!(validate_file_name("nixos-generation-1-specialisation-with space.efi").is_ok());
+        assert!(validate_file_name("kernel-6.1.1+custom~1-abc.efi").is_ok());
+        for name in [
+            "",
+            "a/b.efi",
+            "a:b.efi",
+            "what?.efi",
+            "new\nline.efi",
+            "dot.",
+            "space ",
+        ] {
+            assert!(validate_file_name(name).is_err(), "accepted {name:?}");
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! The state of the boot files Lanzaboote installed on an ESP, for tools that inspect it.
+//!
+//! [`EspState`] is serialized to JSON with a [`SCHEMA_VERSION`]. Fields are only added within a
+//! version; removing or changing one increments it.
+
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use x509_cert::Certificate;
+
+use crate::os_release::OsRelease;
+use crate::pe::{self, StubMetadata};
+use crate::signature::authenticode;
+
+/// The version of the JSON representation of [`EspState`].
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The generations and the boot loader installed on an ESP.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EspState {
+    pub schema_version: u32,
+    /// Sorted by version, with the generation itself before its specialisations.
+    pub generations: Vec<InstalledGeneration>,
+    /// `None` if no boot loader is installed.
+    pub systemd_boot: Option<BootloaderInfo>,
+}
+
+/// A stub of a generation or one of its specialisations.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledGeneration {
+    pub version: u64,
+    pub specialisation: Option<String>,
+    /// The path from the root of the ESP, e.g. `/EFI/Linux/nixos-generation-1-abc.efi`.
+    pub path: String,
+    pub size: u64,
+    pub signature: SignatureStatus,
+    /// The toplevel the stub was built from, if it embeds metadata.
+    pub toplevel: Option<PathBuf>,
+    /// The SHA-256 hashes of the kernel and initrd the stub boots, as embedded in it.
+    pub kernel_sha256: Option<String>,
+    pub initrd_sha256: Option<String>,
+    /// The SHA-256 hash of the stub recorded in the signed manifest, if there is one.
+    pub manifest_sha256: Option<String>,
+}
+
+/// The systemd-boot binary.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootloaderInfo {
+    /// The path from the root of the ESP, e.g. `/EFI/systemd/systemd-bootx64.efi`.
+    pub path: String,
+    pub size: u64,
+    pub signature: SignatureStatus,
+    /// The `VERSION` from the os-release embedded in the binary.
+    pub version: Option<String>,
+}
+
+/// Whether a binary is signed and, if a certificate is given, whether the signature is valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SignatureStatus {
+    Unsigned,
+    /// Signed, but not verified because no certificate was given.
+    Signed,
+    Valid,
+    Invalid,
+}
+
+impl SignatureStatus {
+    fn of(pe_binary: &[u8], certificate: Option<&Certificate>) -> Self {
+        if pe::read_pkcs7_signature(pe_binary).is_err() {
+            return Self::Unsigned;
+        }
+        match certificate {
+            None => Self::Signed,
+            Some(certificate) => match authenticode::verify(pe_binary, None, certificate) {
+                Ok(()) => Self::Valid,
+                Err(_) => Self::Invalid,
+            },
+        }
+    }
+}
+
+/// The files of a signed manifest written by `lzbt install --signed-manifest`.
+#[derive(Deserialize)]
+struct SignedManifest {
+    signed: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+struct ManifestEntry {
+    path: String,
+    sha256: String,
+}
+
+/// Scan the ESP mounted at `esp` for the stubs Lanzaboote installed and the boot loader.
+///
+/// With a `certificate`, the signatures are verified against it. With a `manifest`, the hashes
+/// recorded in it are reported alongside the stubs.
+pub fn scan(
+    esp: &Path,
+    bootloader: Option<&Path>,
+    certificate: Option<&Certificate>,
+    manifest: Option<&Path>,
+) -> Result<EspState> {
+    let manifest = manifest
+        .map(|path| {
+            let contents = fs::read(path)
+                .with_context(|| format!("Failed to read the signed manifest {path:?}"))?;
+            serde_json::from_slice::<SignedManifest>(&contents)
+                .with_context(|| format!("Failed to parse the signed manifest {path:?}"))
+        })
+        .transpose()?;
+
+    let mut generations = Vec::new();
+    for stub in installed_stubs(esp)? {
+        let Some((version, specialisation)) = stub
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_stub_name)
+        else {
+            log::warn!("Skipping {stub:?}, which is not named like a stub of a generation.");
+            continue;
+        };
+        let data = fs::read(&stub).with_context(|| format!("Failed to read {stub:?}"))?;
+        let path = esp_relative_path(esp, &stub)?;
+        let hash = |section| pe::read_section_data(&data, section).map(hex);
+        generations.push(InstalledGeneration {
+            version,
+            specialisation,
+            size: data.len() as u64,
+            signature: SignatureStatus::of(&data, certificate),
+            toplevel: StubMetadata::read(&data)
+                .ok()
+                .flatten()
+                .map(|metadata| metadata.toplevel),
+            kernel_sha256: hash(".linuxh"),
+            initrd_sha256: hash(".initrdh"),
+            manifest_sha256: manifest.as_ref().and_then(|manifest| {
+                manifest
+                    .signed
+                    .iter()
+                    .find(|entry| entry.path == path)
+                    .map(|entry| entry.sha256.clone())
+            }),
+            path,
+        });
+    }
+    generations.sort_by(|a, b| {
+        (a.version, &a.specialisation, &a.path).cmp(&(b.version, &b.specialisation, &b.path))
+    });
+
+    let systemd_boot = bootloader
+        .filter(|bootloader| bootloader.is_file())
+        .map(|bootloader| -> Result<_> {
+            let data =
+                fs::read(bootloader).with_context(|| format!("Failed to read {bootloader:?}"))?;
+            Ok(BootloaderInfo {
+                path: esp_relative_path(esp, bootloader)?,
+                size: data.len() as u64,
+                signature: SignatureStatus::of(&data, certificate),
+                version: embedded_version(&data),
+            })
+        })
+        .transpose()?;
+
+    Ok(EspState {
+        schema_version: SCHEMA_VERSION,
+        generations,
+        systemd_boot,
+    })
+}
+
+/// The stubs installed by Lanzaboote in `EFI/Linux` and, if they are booted via boot loader
+/// entries, in `EFI/nixos`, sorted by file name.
+///
+/// An ESP without these directories has no stubs.
+pub fn installed_stubs(esp: &Path) -> Result<Vec<PathBuf>> {
+    let mut stubs = Vec::new();
+    for directory in ["EFI/Linux", "EFI/nixos"] {
+        let directory = esp.join(directory);
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).with_context(|| format!("Failed to read {directory:?}")),
+        };
+        for entry in entries {
+            stubs.push(entry?.path());
+        }
+    }
+    stubs.retain(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("nixos-") && name.ends_with(".efi"))
+    });
+    stubs.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(stubs)
+}
+
+/// Parse the version and specialisation from the file name of a stub, e.g.
+/// `nixos-generation-1-specialisation-debug-abc.efi`.
+///
+/// The hash after the last `-` is ignored. Specialisation names may contain dashes themselves.
+fn parse_stub_name(name: &str) -> Option<(u64, Option<String>)> {
+    let name = name
+        .strip_prefix("nixos-generation-")?
+        .strip_suffix(".efi")?;
+    let (name, _hash) = name.rsplit_once('-')?;
+    match name.split_once("-specialisation-") {
+        Some((version, specialisation)) => {
+            Some((version.parse().ok()?, Some(specialisation.to_owned())))
+        }
+        None => Some((name.parse().ok()?, None)),
+    }
+}
+
+/// The `VERSION` of the NUL terminated os-release in the `.osrel` section of a binary.
+fn embedded_version(pe_binary: &[u8]) -> Option<String> {
+    let section = pe::read_section_data(pe_binary, ".osrel")?;
+    let contents = CStr::from_bytes_until_nul(section).ok()?.to_str().ok()?;
+    OsRelease::from_str(contents).ok()?.0.remove("VERSION")
+}
+
+fn esp_relative_path(esp: &Path, path: &Path) -> Result<String> {
+    let relative_path = path
+        .strip_prefix(esp)
+        .with_context(|| format!("{path:?} is not on the ESP {esp:?}"))?;
+    let relative_path = relative_path
+        .to_str()
+        .with_context(|| format!("{relative_path:?} is not valid UTF-8"))?;
+    Ok(format!("/{relative_path}"))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stub_names() {
+        assert_eq!(
+            parse_stub_name("nixos-generation-42-abc.efi"),
+            Some((42, None))
+        );
+        assert_eq!(
+            parse_stub_name("nixos-generation-1-specialisation-no-gui-abc.efi"),
+            Some((1, Some("no-gui".to_owned())))
+        );
+        assert_eq!(parse_stub_name("nixos-generation-x-abc.efi"), None);
+        assert_eq!(parse_stub_name("nixos-generation-1.efi"), None);
+        assert_eq!(parse_stub_name("ubuntu-1-abc.efi"), None);
+    }
+
+    #[test]
+    fn scan_stubs_and_manifest() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let linux = esp.path().join("EFI/Linux");
+        fs::create_dir_all(&linux)?;
+        let unsigned = include_bytes!("../../systemd/tests/fixtures/authenticode/unsigned.efi");
+        let signed = include_bytes!("../../systemd/tests/fixtures/authenticode/signed.efi");
+        fs::write(linux.join("nixos-generation-2-abc.efi"), signed)?;
+        fs::write(
+            linux.join("nixos-generation-1-specialisation-debug-def.efi"),
+            unsigned,
+        )?;
+        fs::write(linux.join("other-os.efi"), unsigned)?;
+        // Stubs booted via boot loader entries are in EFI/nixos next to the kernels.
+        let nixos = esp.path().join("EFI/nixos");
+        fs::create_dir_all(&nixos)?;
+        fs::write(nixos.join("nixos-generation-3-ghi.efi"), unsigned)?;
+        fs::write(nixos.join("kernel-6.1.1-jkl.efi"), unsigned)?;
+        let manifest = esp.path().join("manifest.json");
+        fs::write(
+            &manifest,
+            r#"{"signed": [{"path": "/EFI/Linux/nixos-generation-2-abc.efi", "sha256": "00"}], "unsigned": []}"#,
+        )?;
+        let certificate = authenticode::read_certificate(include_bytes!(
+            "../../systemd/tests/fixtures/uefi-keys/db.pem"
+        ))?;
+
+        let state = scan(esp.path(), None, Some(&certificate), Some(&manifest))?;
+        assert_eq!(state.schema_version, SCHEMA_VERSION);
+        assert!(state.systemd_boot.is_none());
+        let generations = state
+            .generations
+            .iter()
+            .map(|generation| {
+                (
+                    generation.version,
+                    generation.specialisation.as_deref(),
+                    generation.signature,
+                    generation.manifest_sha256.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            generations,
+            [
+                (1, Some("debug"), SignatureStatus::Unsigned, None),
+                (2, None, SignatureStatus::Valid, Some("00")),
+                (3, None, SignatureStatus::Unsigned, None),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,20 +1,35 @@
 use std::collections::HashSet;
 use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{Context, Result};
 use walkdir::{DirEntry, WalkDir};
 
+/// The maximum number of threads that remove garbage at once.
+const MAX_REMOVAL_THREADS: usize = 8;
+
 /// Keeps track of the garbage collection roots.
 ///
 /// The internal HashSet contains all the paths still in use. These paths
 /// are used to find all **unused** paths and delete them.
 #[derive(Debug)]
-pub struct Roots(HashSet<PathBuf>);
+pub struct Roots {
+    paths: HashSet<PathBuf>,
+    /// Files modified within this duration before the scan started are in use as well.
+    keep_newer_than: Option<Duration>,
+}
 
 impl Roots {
     pub fn new() -> Self {
-        Self(HashSet::new())
+        Self {
+            paths: HashSet::new(),
+            keep_newer_than: None,
+        }
     }
 
     /// Extend the garbage collection roots.
@@ -24,17 +39,35 @@ impl Roots {
     /// have a path: `rootdir/example/file.txt`, the three paths: `rootdir`, `rootdir/example`, and
     /// `rootdir/example/file.txt` need to be added for the right files to be garbage collected.
     pub fn extend<'a>(&mut self, other: impl IntoIterator<Item = &'a PathBuf>) {
-        self.0.extend(other.into_iter().cloned());
+        self.paths.extend(other.into_iter().cloned());
+    }
+
+    /// Keep all files that were modified within `age` before garbage collection starts, even if
+    /// they are not among the roots.
+    ///
+    /// This retains everything installed in the last days regardless of how many generations
+    /// that is. An unused directory that contains such a file is not removed as a whole, only
+    /// its other contents are.
+    pub fn keep_newer_than(&mut self, age: Duration) {
+        self.keep_newer_than = Some(age);
     }
 
-    fn in_use(&self, entry: Option<&DirEntry>) -> bool {
-        match entry {
-            Some(e) => self.0.contains(e.path()),
+    /// Whether the entry is a root or, with [`Roots::keep_newer_than`], a file modified after
+    /// `cutoff`.
+    fn in_use(&self, entry: &DirEntry, cutoff: Option<SystemTime>) -> bool {
+        if self.paths.contains(entry.path()) {
+            return true;
+        }
+        match cutoff {
+            Some(cutoff) if entry.file_type().is_dir() => {
+                contains_modified_since(entry.path(), cutoff)
+            }
+            Some(cutoff) => modified_since(entry.path(), cutoff),
             None => false,
         }
     }
 
-    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<()> {
+    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<usize> {
         self.collect_garbage_with_filter(directory, |_| true)
     }
 
@@ -43,43 +76,210 @@ impl Roots {
     /// The filter function takes a &Path and returns a bool. The paths for which the filter
     /// function returns true are considered for garbage collection. This means that _only_ files
     /// that are unused AND for which the filter function returns true are deleted.
+    ///
+    /// Files that were modified after the scan started are never deleted. They might have just
+    /// been written by a concurrent install and are collected by the next run instead.
+    ///
+    /// Entries that cannot be read are skipped so that a single problematic entry does not stop
+    /// the cleanup of everything else. The number of skipped entries is reported at the end.
+    ///
+    /// Entries that disappear during the scan are ignored. Thus, a tree that was only partially
+    /// removed, e.g. because a previous run was interrupted, is removed completely by the next
+    /// run.
+    ///
+    /// The unused entries are found first and then removed by several threads at once, because
+    /// removing many files one by one is slow on ESPs on USB sticks.
+    ///
+    /// Returns the number of removed entries, counting a removed directory once.
     pub fn collect_garbage_with_filter<P>(
+        &self,
+        directory: impl AsRef<Path>,
+        predicate: P,
+    ) -> Result<usize>
+    where
+        P: FnMut(&Path) -> bool,
+    {
+        let scan_start = SystemTime::now();
+        let mut garbage = Vec::new();
+        self.walk_garbage(directory, predicate, |path, is_dir| {
+            garbage.push((path.to_path_buf(), is_dir));
+            Ok(())
+        })?;
+
+        let next_entry = AtomicUsize::new(0);
+        let removed_entries = AtomicUsize::new(0);
+        let workers = thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_REMOVAL_THREADS)
+            .min(garbage.len());
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        while let Some((path, is_dir)) =
+                            garbage.get(next_entry.fetch_add(1, Ordering::Relaxed))
+                        {
+                            if modified_since(path, scan_start) {
+                                log::debug!("Not garbage collecting {path:?} because it was modified after the scan started.");
+                                continue;
+                            }
+                            if remove_entry(path, *is_dir)? {
+                                removed_entries.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("A garbage collection thread panicked"))?
+            })
+        })?;
+        Ok(removed_entries.into_inner())
+    }
+
+    /// List the entries that [`Roots::collect_garbage`] would remove, without removing anything.
+    pub fn collect_garbage_dry_run(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        self.collect_garbage_dry_run_with_filter(directory, |_| true)
+    }
+
+    /// List the entries that [`Roots::collect_garbage_with_filter`] would remove, without removing
+    /// anything.
+    ///
+    /// The contents of an unused directory are not listed because it is removed as a whole.
+    pub fn collect_garbage_dry_run_with_filter<P>(
+        &self,
+        directory: impl AsRef<Path>,
+        predicate: P,
+    ) -> Result<Vec<PathBuf>>
+    where
+        P: FnMut(&Path) -> bool,
+    {
+        let mut garbage = Vec::new();
+        self.walk_garbage(directory, predicate, |path, _| {
+            garbage.push(path.to_path_buf());
+            Ok(())
+        })?;
+        Ok(garbage)
+    }
+
+    /// Call `visit` with every unused entry in `directory` for which `predicate` returns true and
+    /// whether it is a directory.
+    ///
+    /// The contents of an unused directory are not visited.
+    fn walk_garbage<P, V>(
         &self,
         directory: impl AsRef<Path>,
         mut predicate: P,
+        mut visit: V,
     ) -> Result<()>
     where
         P: FnMut(&Path) -> bool,
+        V: FnMut(&Path, bool) -> Result<()>,
     {
-        // Find all the paths not used anymore.
-        let entries_not_in_use = WalkDir::new(directory.as_ref())
-            .into_iter()
-            .filter(|e| !self.in_use(e.as_ref().ok()))
-            .filter(|e| match e.as_ref().ok() {
-                Some(e) => predicate(e.path()),
-                None => false,
-            });
-
-        // Remove all entries not in use.
-        for e in entries_not_in_use {
-            let entry = e?;
+        // FAT only stores modification times with a resolution of two seconds. Thus, this cannot
+        // protect files that were created in the same two seconds the scan started in.
+        let scan_start = SystemTime::now();
+        let cutoff = self.keep_newer_than.map(|age| {
+            scan_start
+                .checked_sub(age)
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        });
+
+        let mut unreadable_entries = 0;
+        let mut entries = WalkDir::new(directory.as_ref()).into_iter();
+
+        while let Some(e) = entries.next() {
+            let entry = match e {
+                Ok(entry) => entry,
+                Err(err) if is_not_found(err.io_error()) => {
+                    log::debug!("Ignoring entry that was removed during garbage collection: {err}");
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!("Skipping unreadable entry during garbage collection: {err}");
+                    unreadable_entries += 1;
+                    continue;
+                }
+            };
+            if self.in_use(&entry, cutoff) || !predicate(entry.path()) {
+                continue;
+            }
+
             let path = entry.path();
+            if modified_since(path, scan_start) {
+                log::debug!("Not garbage collecting {path:?} because it was modified after the scan started.");
+                continue;
+            }
             log::debug!("Garbage collecting {path:?}...");
 
-            if path.is_dir() {
-                // If a directory is marked as unused all its children can be deleted too.
-                fs::remove_dir_all(path)
-                    .with_context(|| format!("Failed to remove directory: {:?}", path))?;
-            } else {
-                // Ignore failing to remove path because the parent directory might have been removed before.
-                fs::remove_file(path).ok();
-            };
+            let is_dir = entry.file_type().is_dir();
+            visit(path, is_dir)?;
+            if is_dir {
+                // Do not descend into the unused directory.
+                entries.skip_current_dir();
+            }
+        }
+
+        if unreadable_entries > 0 {
+            log::warn!(
+                "Skipped {unreadable_entries} unreadable entries while collecting garbage in {:?}.",
+                directory.as_ref()
+            );
         }
 
         Ok(())
     }
 }
 
+/// Remove an unused entry and return whether it was removed.
+///
+/// If a directory is unused, all its children can be deleted too.
+fn remove_entry(path: &Path, is_dir: bool) -> Result<bool> {
+    if is_dir {
+        match fs::remove_dir_all(path) {
+            Err(err) if is_not_found(Some(&err)) => Ok(false),
+            result => {
+                result.with_context(|| format!("Failed to remove directory: {:?}", path))?;
+                Ok(true)
+            }
+        }
+    } else {
+        // Ignore failing to remove path because the parent directory might have been removed before.
+        Ok(fs::remove_file(path).is_ok())
+    }
+}
+
+/// Whether the error is caused by a file that does not exist (anymore).
+fn is_not_found(err: Option<&io::Error>) -> bool {
+    err.is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+}
+
+/// Whether the file at `path` was modified at or after `time`.
+///
+/// If the modification time cannot be determined, the file is assumed to be modified, so that it
+/// is not deleted.
+fn modified_since(path: &Path, time: SystemTime) -> bool {
+    fs::symlink_metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_or(true, |modified| modified >= time)
+}
+
+/// Whether any file below the directory `path` was modified at or after `time`.
+///
+/// Unreadable entries are assumed to be modified, so that the directory is not deleted.
+fn contains_modified_since(path: &Path, time: SystemTime) -> bool {
+    WalkDir::new(path)
+        .min_depth(1)
+        .into_iter()
+        .any(|entry| match entry {
+            Ok(entry) => !entry.file_type().is_dir() && modified_since(entry.path(), time),
+            Err(err) => !is_not_found(err.io_error()),
+        })
+}
+
 impl Default for Roots {
     fn default() -> Self {
         Self::new()
@@ -90,6 +290,8 @@ impl Default for Roots {
 mod tests {
     use super::*;
     use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
 
     #[test]
     fn keep_used_file() -> Result<()> {
@@ -187,7 +389,7 @@ mod tests {
         roots.collect_garbage_with_filter(&rootdir, |p| {
             p.file_name()
                 .and_then(|n| n.to_str())
-                .map_or(false, |n| n.starts_with("prefix_"))
+                .is_some_and(|n| n.starts_with("prefix_"))
         })?;
 
         assert!(unused_file.exists());
@@ -195,6 +397,233 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn find_garbage_without_removing_it() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let used_file = create_file(rootdir.join("used_file"))?;
+        let unused_file = create_file(rootdir.join("unused_file"))?;
+        let unused_directory = create_dir(rootdir.join("unused_directory"))?;
+        let unused_file_in_directory =
+            create_file(unused_directory.join("unused_file_in_directory"))?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &used_file]);
+        let mut garbage = roots.collect_garbage_dry_run(&rootdir)?;
+        garbage.sort();
+
+        assert_eq!(garbage, [unused_directory, unused_file.clone()]);
+        assert!(unused_file.exists());
+        assert!(unused_file_in_directory.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_reports_what_is_collected() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let used_file = create_file(rootdir.join("used_file"))?;
+        let unused_file = create_file(rootdir.join("unused_file"))?;
+        let unused_directory = create_dir(rootdir.join("unused_directory"))?;
+        create_file(unused_directory.join("unused_file_in_directory"))?;
+        let new_file = create_file(rootdir.join("new_file"))?;
+        fs::File::options()
+            .write(true)
+            .open(&new_file)?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &used_file]);
+        let garbage = roots.collect_garbage_dry_run(&rootdir)?;
+        assert!(garbage.iter().all(|path| path.exists()));
+
+        let removed = roots.collect_garbage(&rootdir)?;
+        assert_eq!(removed, garbage.len());
+        assert!(garbage.iter().all(|path| !path.exists()));
+        assert!(used_file.exists());
+        assert!(new_file.exists());
+        assert!(!unused_file.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_file_modified_after_scan_start() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        // Simulate a file that is written by a concurrent install during garbage collection.
+        let new_file = create_file(rootdir.join("new_file"))?;
+        fs::File::options()
+            .write(true)
+            .open(&new_file)?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+        let old_file = create_file(rootdir.join("old_file"))?;
+        fs::File::options()
+            .write(true)
+            .open(&old_file)?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir]);
+        roots.collect_garbage(&rootdir)?;
+
+        assert!(new_file.exists());
+        assert!(!old_file.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_many_unused_files_in_parallel() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let used_directory = create_dir(rootdir.join("used_directory"))?;
+        let used_file = create_file(used_directory.join("used_file"))?;
+        let mut unused_files = Vec::new();
+        for i in 0..300 {
+            unused_files.push(create_file(
+                used_directory.join(format!("unused_file_{i}")),
+            )?);
+        }
+        let mut unused_directories = Vec::new();
+        for i in 0..20 {
+            let unused_directory = create_dir(rootdir.join(format!("unused_directory_{i}")))?;
+            for j in 0..10 {
+                create_file(unused_directory.join(format!("unused_file_{j}")))?;
+            }
+            unused_directories.push(unused_directory);
+        }
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &used_directory, &used_file]);
+        let removed = roots.collect_garbage(&rootdir)?;
+
+        assert_eq!(removed, unused_files.len() + unused_directories.len());
+        assert!(used_file.exists());
+        assert!(unused_files.iter().all(|path| !path.exists()));
+        assert!(unused_directories.iter().all(|path| !path.exists()));
+        assert_eq!(fs::read_dir(&rootdir)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_recently_modified_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let recent_file = create_file(rootdir.join("recent_file"))?;
+        set_age(&recent_file, Duration::from_secs(60 * 60))?;
+        let old_file = create_file(rootdir.join("old_file"))?;
+        set_age(&old_file, Duration::from_secs(3 * 24 * 60 * 60))?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir]);
+        roots.keep_newer_than(Duration::from_secs(24 * 60 * 60));
+        roots.collect_garbage(&rootdir)?;
+
+        assert!(recent_file.exists());
+        assert!(!old_file.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_unused_directory_with_recently_modified_file() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let unused_directory = create_dir(rootdir.join("unused_directory"))?;
+        let recent_file = create_file(unused_directory.join("recent_file"))?;
+        set_age(&recent_file, Duration::from_secs(60 * 60))?;
+        let old_file = create_file(unused_directory.join("old_file"))?;
+        set_age(&old_file, Duration::from_secs(3 * 24 * 60 * 60))?;
+        let old_directory = create_dir(rootdir.join("old_directory"))?;
+        let old_file_in_directory = create_file(old_directory.join("old_file"))?;
+        set_age(
+            &old_file_in_directory,
+            Duration::from_secs(3 * 24 * 60 * 60),
+        )?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir]);
+        roots.keep_newer_than(Duration::from_secs(24 * 60 * 60));
+        roots.collect_garbage(&rootdir)?;
+
+        assert!(recent_file.exists());
+        assert!(!old_file.exists());
+        assert!(!old_directory.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_unreadable_directory() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let used_directory = create_dir(rootdir.join("used_directory"))?;
+        let unreadable_directory = create_dir(used_directory.join("unreadable_directory"))?;
+        let unused_file = create_file(rootdir.join("unused_file"))?;
+        fs::set_permissions(&unreadable_directory, fs::Permissions::from_mode(0o000))?;
+
+        // root can read any directory regardless of its permissions.
+        if fs::read_dir(&unreadable_directory).is_ok() {
+            return Ok(());
+        }
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &used_directory, &unreadable_directory]);
+        let result = roots.collect_garbage(&rootdir);
+        fs::set_permissions(&unreadable_directory, fs::Permissions::from_mode(0o755))?;
+
+        result?;
+        assert!(!unused_file.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn finish_partially_deleted_directory() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        // Simulate a previous run that was interrupted while removing the directory: some of the
+        // children are already gone.
+        let unused_directory = create_dir(rootdir.join("unused_directory"))?;
+        let remaining_directory = create_dir(unused_directory.join("remaining_directory"))?;
+        let remaining_file = create_file(remaining_directory.join("remaining_file"))?;
+        let removed_directory = create_dir(unused_directory.join("removed_directory"))?;
+        create_file(removed_directory.join("removed_file"))?;
+        fs::remove_dir_all(&removed_directory)?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir]);
+        roots.collect_garbage(&rootdir)?;
+        assert!(!remaining_file.exists());
+        assert!(!unused_directory.exists());
+
+        // Running again is a no-op.
+        roots.collect_garbage(&rootdir)?;
+        assert!(rootdir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_removed_directory() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let removed_directory = tmpdir.path().join("removed_directory");
+
+        Roots::new().collect_garbage(removed_directory)?;
+        Ok(())
+    }
+
+    fn set_age(path: &Path, age: Duration) -> Result<()> {
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now() - age)?;
+        Ok(())
+    }
+
     fn create_file(path: PathBuf) -> Result<PathBuf> {
         fs::File::create(&path)?;
         Ok(path)
@@ -1,34 +1,95 @@
+use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
-use std::os::unix::fs::MetadataExt;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{Context, Result};
 use bootspec::BootJson;
 use bootspec::BootSpec;
 use bootspec::SpecialisationName;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use time::Date;
 
 /// (Possibly) extended Bootspec.
 ///
 /// This struct currently does not have any extensions. We keep it around so that extension becomes
 /// easy if/when we have to do it.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ExtendedBootJson {
     pub bootspec: BootSpec,
     pub lanzaboote_extension: LanzabooteExtension,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl ExtendedBootJson {
+    /// Path to the kernel of the generation.
+    pub fn kernel_path(&self) -> &Path {
+        &self.bootspec.bootspec.kernel
+    }
+
+    /// Path to the initrd of the generation, if there is one.
+    pub fn initrd_path(&self) -> Option<&Path> {
+        self.bootspec.bootspec.initrd.as_deref()
+    }
+
+    /// Path to the init of the generation.
+    pub fn init_path(&self) -> &Path {
+        &self.bootspec.bootspec.init
+    }
+
+    /// The kernel parameters of the generation, without `init=`.
+    pub fn kernel_params(&self) -> &[String] {
+        &self.bootspec.bootspec.kernel_params
+    }
+
+    /// The complete kernel command line, i.e. `init=` followed by the kernel parameters.
+    pub fn kernel_cmdline(&self) -> Result<Vec<String>> {
+        let init = self.init_path().to_str().with_context(|| {
+            format!(
+                "Failed to convert init path to string: {:?}",
+                self.init_path()
+            )
+        })?;
+        let mut kernel_cmdline = vec![format!("init={init}")];
+        kernel_cmdline.extend_from_slice(self.kernel_params());
+        Ok(kernel_cmdline)
+    }
+
+    /// The version of the kernel.
+    ///
+    /// The kernel is a file in /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-<version>/.
+    /// (On x86, that file is called bzImage, but other architectures may differ.) Thus, the
+    /// version is extracted from the name of the directory containing the kernel.
+    pub fn kernel_version(&self) -> Result<&str> {
+        let kernel_dirname = self
+            .kernel_path()
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(OsStr::to_str)
+            .context("Failed to extract the kernel directory name.")?;
+        kernel_dirname
+            .rsplit('-')
+            .next()
+            .context("Failed to extract the kernel version.")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LanzabooteExtension {
     pub sort_key: String,
+    /// Template for the title of the boot entries, see [`Generation::title`].
+    pub title: Option<String>,
+    /// The machine ID written into Boot Loader Specification entries.
+    pub machine_id: Option<String>,
 }
 
 impl Default for LanzabooteExtension {
     fn default() -> Self {
         Self {
             sort_key: String::from("lanzaboote"),
+            title: None,
+            machine_id: None,
         }
     }
 }
@@ -49,6 +110,9 @@ pub struct Generation {
     pub build_time: Option<Date>,
     /// Top-level specialisation name
     pub specialisation_name: Option<SpecialisationName>,
+    /// The profile of the generation link, e.g. /nix/var/nix/profiles/system. This is `None` if
+    /// the generation was not read from a link.
+    pub profile: Option<PathBuf>,
     /// Top-level extended boot specification
     pub spec: ExtendedBootJson,
 }
@@ -62,17 +126,47 @@ impl Generation {
             .or_else(|_err| BootJson::synthesize_latest(&link.path)
                     .context("Failed to read a bootspec (missing bootspec?) and failed to synthesize a valid replacement bootspec."))?;
 
+        Ok(Self {
+            profile: link.profile(),
+            ..Self::from_boot_json(link.version, link.build_date(), boot_json)?
+        })
+    }
+
+    /// Build a generation from a bootspec document read from e.g. a file or stdin.
+    ///
+    /// Unlike [`Generation::from_link`], no bootspec is synthesized if it cannot be read.
+    pub fn from_bootspec_reader(version: u64, reader: impl Read) -> Result<Self> {
+        let boot_json: BootJson =
+            serde_json::from_reader(reader).context("Failed to read bootspec JSON")?;
+        Self::from_boot_json(version, None, boot_json)
+    }
+
+    /// Build a generation from an already parsed bootspec document.
+    ///
+    /// This does not need a generation link, e.g. to install a hand-written bootspec.
+    pub fn from_boot_json(
+        version: u64,
+        build_time: Option<Date>,
+        boot_json: BootJson,
+    ) -> Result<Self> {
         let bootspec: BootSpec = boot_json.generation.try_into()?;
         let lanzaboote_extension = boot_json
             .extensions
             .get("org.nix-community.lanzaboote")
-            .and_then(|v| serde_json::from_value::<LanzabooteExtension>(v.clone()).ok())
+            .and_then(|v| {
+                serde_json::from_value::<LanzabooteExtension>(v.clone())
+                    .inspect_err(|err| {
+                        log::warn!("Ignoring the malformed Lanzaboote bootspec extension: {err}")
+                    })
+                    .ok()
+            })
             .unwrap_or_default();
 
         Ok(Self {
-            version: link.version,
-            build_time: link.build_time,
+            version,
+            build_time,
             specialisation_name: None,
+            profile: None,
             spec: ExtendedBootJson {
                 bootspec,
                 lanzaboote_extension,
@@ -121,6 +215,41 @@ impl Generation {
         )
     }
 
+    /// The title of the boot entries of the generation, if a title template is configured.
+    ///
+    /// In the template, `{label}` is replaced by the label from the bootspec, `{version}` by the
+    /// version including the specialisation (i.e. the version tag), and `{date}` by the build
+    /// date.
+    pub fn title(&self) -> Option<String> {
+        let template = self.spec.lanzaboote_extension.title.as_ref()?;
+        let build_time = self
+            .build_time
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| String::from("Unknown"));
+        Some(
+            template
+                .replace("{label}", &self.spec.bootspec.bootspec.label)
+                .replace("{version}", &self.version_tag())
+                .replace("{date}", &build_time),
+        )
+    }
+
+    /// The sort key of the boot entries of the generation, rendered from a template.
+    ///
+    /// In the template, `{sort_key}` is replaced by the sort key from the bootspec and
+    /// `{specialisation}` by the name of the specialisation, which is empty for the generation
+    /// itself.
+    pub fn sort_key(&self, template: &str) -> String {
+        let specialisation = self
+            .specialisation_name
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        template
+            .replace("{sort_key}", &self.spec.lanzaboote_extension.sort_key)
+            .replace("{specialisation}", &specialisation)
+    }
+
     /// A unique short identifier.
     pub fn version_tag(&self) -> String {
         format!("{}{}", self.version, self.describe_specialisation(),)
@@ -133,10 +262,8 @@ impl fmt::Display for Generation {
     }
 }
 
-fn read_build_time(path: &Path) -> Result<Date> {
-    let build_time =
-        time::OffsetDateTime::from_unix_timestamp(fs::symlink_metadata(path)?.mtime())?.date();
-    Ok(build_time)
+fn read_build_time(path: &Path) -> Result<SystemTime> {
+    Ok(fs::symlink_metadata(path)?.modified()?)
 }
 
 /// A link pointing to a generation.
@@ -147,7 +274,8 @@ fn read_build_time(path: &Path) -> Result<Date> {
 pub struct GenerationLink {
     pub version: u64,
     pub path: PathBuf,
-    pub build_time: Option<Date>,
+    /// When the generation was built, i.e. the modification time of the link.
+    pub build_time: Option<SystemTime>,
 }
 
 impl GenerationLink {
@@ -158,19 +286,77 @@ impl GenerationLink {
             build_time: read_build_time(path.as_ref()).ok(),
         })
     }
+
+    /// The day the generation was built, in UTC.
+    pub fn build_date(&self) -> Option<Date> {
+        self.build_time
+            .map(|build_time| time::OffsetDateTime::from(build_time).date())
+    }
+
+    /// Whether the generation was built within `age` before `now`.
+    ///
+    /// Generations whose build time cannot be read are never considered recent.
+    pub fn built_within(&self, age: Duration, now: SystemTime) -> bool {
+        self.build_time
+            .is_some_and(|built| now.duration_since(built).map_or(true, |since| since <= age))
+    }
+
+    /// The profile the link belongs to, e.g. /nix/var/nix/profiles/system for
+    /// /nix/var/nix/profiles/system-1-link.
+    pub fn profile(&self) -> Option<PathBuf> {
+        let name = self.path.file_name()?.to_str()?;
+        let (profile, _version) = name.strip_suffix("-link")?.rsplit_once('-')?;
+        Some(self.path.with_file_name(profile))
+    }
+}
+
+/// Whether the path is named like a generation link of a NixOS system profile.
+///
+/// These are "system-{version}-link" and, for the profiles created with `nixos-rebuild
+/// --profile-name`, "system-profiles/{profile}-{version}-link". A profile directory also
+/// contains e.g. the "system" link to the current generation and the generations of other
+/// profiles like "default-{version}-link".
+pub fn is_generation_link(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    let Some((profile, _version)) = split_generation_link(path) else {
+        return false;
+    };
+    let in_system_profiles = path
+        .parent()
+        .and_then(|parent| parent.file_name())
+        .is_some_and(|name| name == "system-profiles");
+    profile == "system" || in_system_profiles
+}
+
+/// Split the name of a generation link into the profile and the version, e.g. "system" and "7"
+/// for "system-7-link".
+///
+/// The profile name may contain dashes itself, so the version is the last component before
+/// "-link". Returns `None` if the path is not named like "{profile}-{version}-link".
+fn split_generation_link(path: &Path) -> Option<(&str, &str)> {
+    let (profile, version) = path
+        .file_name()?
+        .to_str()?
+        .strip_suffix("-link")?
+        .rsplit_once('-')?;
+    // `u64::from_str` also accepts a leading `+`, which is not a valid version.
+    let is_version = !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit());
+    (!profile.is_empty() && is_version).then_some((profile, version))
 }
 
 /// Parse version number from a path.
 ///
-/// Expects a path in the format of "system-{version}-link".
+/// Expects a path in the format of "{profile}-{version}-link", e.g. "system-7-link".
+///
+/// The version may be zero-padded (e.g. "system-007-link"). The parsed number is the canonical
+/// form of the version: all files on the ESP are named after it without padding (e.g.
+/// "nixos-generation-7-....efi"), so "system-007-link" and "system-7-link" refer to the same
+/// generation.
 fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
-    let generation_version = path
-        .as_ref()
-        .file_name()
-        .and_then(|x| x.to_str())
-        .and_then(|x| x.split('-').nth(1))
-        .and_then(|x| x.parse::<u64>().ok())
-        .with_context(|| format!("Failed to extract version from: {:?}", path.as_ref()))?;
+    let path = path.as_ref();
+    let generation_version = split_generation_link(path)
+        .and_then(|(_profile, version)| version.parse::<u64>().ok())
+        .with_context(|| format!("Failed to extract version from: {:?}", path))?;
 
     Ok(generation_version)
 }
@@ -178,6 +364,7 @@ fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn parse_version_correctly() {
@@ -185,4 +372,255 @@ mod tests {
         let parsed_version = parse_version(path).unwrap();
         assert_eq!(parsed_version, 2,);
     }
+
+    #[test]
+    fn parse_zero_padded_version() -> Result<()> {
+        assert_eq!(parse_version(Path::new("system-007-link"))?, 7);
+        assert_eq!(parse_version(Path::new("system-0-link"))?, 0);
+
+        // The canonical form used for file names on the ESP is not padded.
+        let link = GenerationLink::from_path("/nix/var/nix/profiles/system-007-link")?;
+        assert_eq!(link.version, 7);
+        assert_eq!(link.version.to_string(), "7");
+        assert_eq!(
+            link.profile(),
+            Some(PathBuf::from("/nix/var/nix/profiles/system"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_version() {
+        assert!(parse_version(Path::new("system-+7-link")).is_err());
+        assert!(parse_version(Path::new("system--link")).is_err());
+        assert!(parse_version(Path::new("system-7a-link")).is_err());
+        assert!(parse_version(Path::new("-7-link")).is_err());
+        assert!(parse_version(Path::new("custom-profile-link")).is_err());
+    }
+
+    #[test]
+    fn parse_version_of_profile_with_dashes() -> Result<()> {
+        assert_eq!(parse_version(Path::new("system-42-link"))?, 42);
+        assert_eq!(parse_version(Path::new("custom-profile-7-link"))?, 7);
+        assert_eq!(parse_version(Path::new("system-profile-42-link"))?, 42);
+
+        let link = GenerationLink::from_path(
+            "/nix/var/nix/profiles/system-profiles/custom-profile-7-link",
+        )?;
+        assert_eq!(
+            link.profile(),
+            Some(PathBuf::from(
+                "/nix/var/nix/profiles/system-profiles/custom-profile"
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn derive_build_date_from_build_time() -> Result<()> {
+        let link = GenerationLink {
+            version: 1,
+            path: PathBuf::from("system-1-link"),
+            build_time: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_294_400)),
+        };
+        assert_eq!(
+            link.build_date(),
+            Some(Date::from_calendar_date(2024, time::Month::March, 1)?)
+        );
+        let link = GenerationLink {
+            build_time: None,
+            ..link
+        };
+        assert_eq!(link.build_date(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_non_utf8_generation_link() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let link = Path::new(std::ffi::OsStr::from_bytes(b"system-7\xff-link"));
+        assert!(parse_version(link).is_err());
+        assert!(GenerationLink::from_path(link).is_err());
+    }
+
+    #[test]
+    fn recognize_generation_links() {
+        assert!(is_generation_link("/nix/var/nix/profiles/system-7-link"));
+        assert!(!is_generation_link("/nix/var/nix/profiles/system"));
+        assert!(!is_generation_link(
+            "/nix/var/nix/profiles/system-7-link.bak"
+        ));
+        assert!(!is_generation_link("/nix/var/nix/profiles/system-7-foo"));
+        assert!(!is_generation_link("/nix/var/nix/profiles/default-7-link"));
+        assert!(is_generation_link(
+            "/nix/var/nix/profiles/system-profiles/custom-profile-7-link"
+        ));
+    }
+
+    #[test]
+    fn access_bootspec_fields() -> Result<()> {
+        let spec = extended_boot_json(json!({
+          "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+          "initrd": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-initrd-linux-6.1.1/initrd",
+          "kernel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage",
+          "kernelParams": ["quiet", "loglevel=4"],
+          "label": "LanzaOS",
+          "toplevel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system",
+          "system": "x86_64-linux",
+        }))?;
+
+        assert_eq!(
+            spec.kernel_path(),
+            Path::new("/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage")
+        );
+        assert_eq!(
+            spec.initrd_path(),
+            Some(Path::new(
+                "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-initrd-linux-6.1.1/initrd"
+            ))
+        );
+        assert_eq!(spec.kernel_params(), ["quiet", "loglevel=4"]);
+        assert_eq!(
+            spec.kernel_cmdline()?,
+            [
+                "init=/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+                "quiet",
+                "loglevel=4"
+            ]
+        );
+        assert_eq!(spec.kernel_version()?, "6.1.1");
+        Ok(())
+    }
+
+    #[test]
+    fn access_missing_initrd() -> Result<()> {
+        let spec = extended_boot_json(json!({
+          "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+          "kernel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage",
+          "kernelParams": [],
+          "label": "LanzaOS",
+          "toplevel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system",
+          "system": "x86_64-linux",
+        }))?;
+
+        assert_eq!(spec.initrd_path(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn render_title_template() -> Result<()> {
+        let mut spec = extended_boot_json(json!({
+          "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+          "kernel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage",
+          "kernelParams": [],
+          "label": "LanzaOS",
+          "toplevel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system",
+          "system": "x86_64-linux",
+        }))?;
+        let generation = Generation {
+            version: 7,
+            build_time: Some(Date::from_calendar_date(2024, time::Month::March, 1)?),
+            specialisation_name: None,
+            profile: None,
+            spec: spec.clone(),
+        };
+        assert_eq!(generation.title(), None);
+
+        spec.lanzaboote_extension.title = Some("{label} #{version} built {date}".into());
+        let generation = Generation { spec, ..generation };
+        assert_eq!(
+            generation.title().as_deref(),
+            Some("LanzaOS #7 built 2024-03-01")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_hand_written_bootspec() -> Result<()> {
+        let bootspec = json!({
+            "org.nixos.bootspec.v1": {
+              "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+              "kernel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage",
+              "kernelParams": [],
+              "label": "LanzaOS",
+              "toplevel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system",
+              "system": "x86_64-linux",
+            },
+            "org.nix-community.lanzaboote": {
+                "sort_key": "custom",
+            }
+        });
+
+        let generation =
+            Generation::from_bootspec_reader(3, serde_json::to_vec(&bootspec)?.as_slice())?;
+        assert_eq!(generation.version, 3);
+        assert_eq!(generation.build_time, None);
+        assert_eq!(generation.spec.lanzaboote_extension.sort_key, "custom");
+        assert_eq!(generation.spec.kernel_version()?, "6.1.1");
+
+        assert!(Generation::from_bootspec_reader(3, &b"{}"[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sort_by_sort_key_then_newest_version() -> Result<()> {
+        let spec = extended_boot_json(json!({
+          "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+          "kernel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage",
+          "kernelParams": [],
+          "label": "LanzaOS",
+          "toplevel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system",
+          "system": "x86_64-linux",
+        }))?;
+        let generation = |version| Generation {
+            version,
+            build_time: None,
+            specialisation_name: None,
+            profile: None,
+            spec: spec.clone(),
+        };
+        let specialisation = |version| Generation {
+            specialisation_name: Some(SpecialisationName("debug".into())),
+            ..generation(version)
+        };
+        let mut generations = [
+            generation(9),
+            specialisation(10),
+            generation(10),
+            specialisation(9),
+        ];
+
+        // systemd-boot sorts entries by their sort key and then by their version, newest first.
+        let template = "{sort_key}-{specialisation}";
+        generations.sort_by(|a, b| {
+            a.sort_key(template)
+                .cmp(&b.sort_key(template))
+                .then(b.version.cmp(&a.version))
+        });
+        let order = generations
+            .iter()
+            .map(|generation| (generation.sort_key(template), generation.version_tag()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                ("lanzaboote-".into(), "10".into()),
+                ("lanzaboote-".into(), "9".into()),
+                ("lanzaboote-debug".into(), "10-debug".into()),
+                ("lanzaboote-debug".into(), "9-debug".into()),
+            ]
+        );
+        Ok(())
+    }
+
+    fn extended_boot_json(bootspec: serde_json::Value) -> Result<ExtendedBootJson> {
+        let boot_json: BootJson =
+            serde_json::from_value(json!({ "org.nixos.bootspec.v1": bootspec }))?;
+        Ok(ExtendedBootJson {
+            bootspec: boot_json.generation.try_into()?,
+            lanzaboote_extension: LanzabooteExtension::default(),
+        })
+    }
 }
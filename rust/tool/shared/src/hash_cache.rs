@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{file_hash, tmpname, Hash};
+
+/// The digest of a file and the metadata of the file it was computed for.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    sha256: String,
+}
+
+impl Entry {
+    fn new(metadata: &Metadata, hash: &Hash) -> Self {
+        Self {
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            sha256: format!("{hash:x}"),
+        }
+    }
+
+    /// Whether the digest was computed for a file with this metadata.
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.len()
+            && self.mtime == metadata.mtime()
+            && self.mtime_nsec == metadata.mtime_nsec()
+    }
+
+    fn hash(&self) -> Option<Hash> {
+        let bytes = (0..self.sha256.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(self.sha256.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Hash::from_exact_iter(bytes)
+    }
+}
+
+/// A cache of the SHA-256 digests of files, e.g. of the kernels and initrds of all generations.
+///
+/// A digest is reused as long as the size and the modification time of its file are unchanged.
+/// If the cache has a path, it is persisted there, so that subsequent installations do not have
+/// to read large initrds again.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    path: Option<PathBuf>,
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+impl HashCache {
+    /// A cache that is only kept in memory, i.e. for a single installation.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Load the cache persisted at `path`.
+    ///
+    /// A missing or invalid cache is not an error. The digests are computed again instead.
+    pub fn load(path: &Path) -> Self {
+        let entries = match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                log::warn!("Ignoring the invalid hash cache {path:?}: {err}");
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                log::warn!("Ignoring the unreadable hash cache {path:?}: {err}");
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path: Some(path.to_path_buf()),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Compute the SHA-256 digest of a file or reuse the cached digest.
+    pub fn file_hash(&self, file: &Path) -> Result<Hash> {
+        let metadata =
+            fs::metadata(file).with_context(|| format!("Failed to read file to hash: {file:?}"))?;
+        if let Some(hash) = self
+            .entries()
+            .get(file)
+            .filter(|entry| entry.matches(&metadata))
+            .and_then(Entry::hash)
+        {
+            return Ok(hash);
+        }
+
+        let hash = file_hash(file)?;
+        // Only cache the digest if the file did not change while it was read.
+        let entry = Entry::new(&metadata, &hash);
+        if fs::metadata(file).is_ok_and(|metadata| entry.matches(&metadata)) {
+            self.entries().insert(file.to_path_buf(), entry);
+        }
+        Ok(hash)
+    }
+
+    /// Persist the cache if it has a path.
+    ///
+    /// The digests of files that no longer exist, e.g. garbage collected store paths, are dropped.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut entries = self.entries();
+        entries.retain(|file, _| file.exists());
+        let contents =
+            serde_json::to_vec(&*entries).context("Failed to serialize the hash cache")?;
+
+        // Write to a temporary file first, so that an interrupted installation never leaves a
+        // truncated cache behind.
+        let directory = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create the directory {directory:?}"))?;
+        let tmp = directory.join(tmpname());
+        fs::write(&tmp, contents).with_context(|| format!("Failed to write {tmp:?}"))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to move {tmp:?} to {path:?}"))
+    }
+
+    fn entries(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Entry>> {
+        // Every entry is inserted as a whole, so the entries are valid even if another thread
+        // panicked while holding the lock.
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    fn set_mtime(path: &Path, mtime: SystemTime) -> Result<()> {
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(mtime)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_digest_until_metadata_changes() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let file = tmpdir.path().join("initrd");
+        fs::write(&file, "aaaa")?;
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        set_mtime(&file, mtime)?;
+
+        let cache = HashCache::in_memory();
+        let hash = cache.file_hash(&file)?;
+        assert_eq!(hash, file_hash(&file)?);
+
+        // Store paths never change, so a file with the same size and mtime is not read again.
+        fs::write(&file, "bbbb")?;
+        set_mtime(&file, mtime)?;
+        assert_eq!(cache.file_hash(&file)?, hash);
+
+        set_mtime(&file, mtime + Duration::from_secs(1))?;
+        assert_eq!(cache.file_hash(&file)?, file_hash(&file)?);
+        Ok(())
+    }
+
+    #[test]
+    fn persist_digests_of_existing_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let cache_path = tmpdir.path().join("cache/hashes.json");
+        let kept = tmpdir.path().join("kernel");
+        let removed = tmpdir.path().join("initrd");
+        fs::write(&kept, "kernel")?;
+        fs::write(&removed, "initrd")?;
+
+        let cache = HashCache::load(&cache_path);
+        let hash = cache.file_hash(&kept)?;
+        cache.file_hash(&removed)?;
+        fs::remove_file(&removed)?;
+        cache.save()?;
+
+        let cache = HashCache::load(&cache_path);
+        assert_eq!(cache.entries().len(), 1);
+        assert_eq!(cache.entries().get(&kept).and_then(Entry::hash), Some(hash));
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_invalid_cache() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let cache_path = tmpdir.path().join("hashes.json");
+        fs::write(&cache_path, "not json")?;
+        assert!(HashCache::load(&cache_path).entries().is_empty());
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
 pub mod architecture;
 pub mod esp;
+pub mod esp_state;
 pub mod gc;
 pub mod generation;
+pub mod hash_cache;
 pub mod os_release;
 pub mod pe;
+pub mod sbat;
 pub mod signature;
 pub mod utils;
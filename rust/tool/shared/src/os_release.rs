@@ -1,7 +1,7 @@
 use std::fmt;
 use std::{collections::BTreeMap, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 use crate::generation::Generation;
 
@@ -13,6 +13,7 @@ use crate::generation::Generation;
 /// The BTreeMap is used over a HashMap, so that the keys are ordered. This is irrelevant for
 /// systemd-boot (which does not care about order when reading the os-release file) but is useful
 /// for testing. Ordered keys allow using snapshot tests.
+#[derive(Clone)]
 pub struct OsRelease(pub BTreeMap<String, String>);
 
 impl OsRelease {
@@ -35,30 +36,68 @@ impl OsRelease {
         // user experience.
         //
         // See #220.
+        //
+        // If a title template is configured, the user is responsible for making it unique.
         map.insert(
             "PRETTY_NAME".into(),
-            format!(
-                "{} ({})",
-                generation.spec.bootspec.bootspec.label,
-                generation.describe()
-            ),
+            generation.title().unwrap_or_else(|| {
+                format!(
+                    "{} ({})",
+                    generation.spec.bootspec.bootspec.label,
+                    generation.describe()
+                )
+            }),
         );
 
         map.insert("VERSION_ID".into(), generation.describe());
 
         Ok(Self(map))
     }
-}
 
-impl FromStr for OsRelease {
-    type Err = anyhow::Error;
-    /// Parse the string representation of a os-release file.
+    /// Parse an os-release file and reject anything that isn't valid os-release syntax.
     ///
-    /// **Beware before reusing this function!**
+    /// In contrast to [`OsRelease::from_str`], lines without an assignment, unterminated quotes
+    /// and invalid keys are errors instead of being silently ignored.
+    pub fn from_str_strict(value: &str) -> Result<Self> {
+        let os_release = Self::parse(value, true)?;
+        os_release.validate()?;
+        Ok(os_release)
+    }
+
+    /// Validate that this os-release can be embedded into a stub.
     ///
-    /// This parser might not parse all valid os-release files correctly. It is only designed to
-    /// read the `VERSION` key from the os-release of a systemd-boot binary.
-    fn from_str(value: &str) -> Result<Self> {
+    /// Keys must be valid environment variable names and the `ID` key must be present.
+    pub fn validate(&self) -> Result<()> {
+        for key in self.0.keys() {
+            if !is_valid_key(key) {
+                bail!("Invalid os-release key: {key:?}");
+            }
+        }
+        if !self.0.contains_key("ID") {
+            bail!("The os-release does not contain the required ID key");
+        }
+        Ok(())
+    }
+
+    /// Serialize the os-release into its normalized form for embedding into a stub.
+    ///
+    /// The serialized form is parsed again to make sure that it round-trips, i.e. that systemd
+    /// will read back exactly the keys and values that were written.
+    pub fn to_normalized_string(&self) -> Result<String> {
+        self.validate()?;
+        let serialized = self.to_string();
+        let parsed = Self::from_str_strict(&serialized)
+            .with_context(|| format!("Failed to parse serialized os-release: {serialized:?}"))?;
+        if parsed.0 != self.0 {
+            bail!("The os-release does not round-trip: {serialized:?}");
+        }
+        Ok(serialized)
+    }
+
+    /// Parse the string representation of a os-release file.
+    ///
+    /// If `strict` is set, malformed input is an error instead of being skipped.
+    fn parse(value: &str, strict: bool) -> Result<Self> {
         let mut map = BTreeMap::new();
 
         enum State {
@@ -97,6 +136,9 @@ impl FromStr for OsRelease {
                 }
                 Key => {
                     if NEWLINE.contains(c) {
+                        if strict {
+                            bail!("Line without assignment in os-release: {current_key:?}");
+                        }
                         // keys without any '=' are simply ignored
                         state = PreKey;
                         current_key.clear();
@@ -199,6 +241,15 @@ impl FromStr for OsRelease {
             }
         }
 
+        if strict
+            && matches!(
+                state,
+                Key | SingleQuoteValue | DoubleQuoteValue | DoubleQuoteValueEscape
+            )
+        {
+            bail!("Unexpected end of os-release in key {current_key:?}");
+        }
+
         if matches!(
             state,
             PreValue
@@ -223,11 +274,51 @@ impl FromStr for OsRelease {
     }
 }
 
+impl FromStr for OsRelease {
+    type Err = anyhow::Error;
+    /// Parse the string representation of a os-release file.
+    ///
+    /// **Beware before reusing this function!**
+    ///
+    /// This parser might not parse all valid os-release files correctly. It is only designed to
+    /// read the `VERSION` key from the os-release of a systemd-boot binary.
+    fn from_str(value: &str) -> Result<Self> {
+        Self::parse(value, false)
+    }
+}
+
+/// Whether `key` is a valid os-release key, i.e. a valid environment variable name.
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 /// Display OsRelease in the format of an os-release file.
+///
+/// Values that contain anything but a conservative set of characters are double-quoted and
+/// escaped like in a shell.
 impl fmt::Display for OsRelease {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (key, value) in &self.0 {
-            writeln!(f, "{}={}", key, value)?
+            let needs_quoting = value.is_empty()
+                || !value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-+:/".contains(c));
+            if needs_quoting {
+                write!(f, "{}=\"", key)?;
+                for c in value.chars() {
+                    if "\"\\`$".contains(c) {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{}", c)?;
+                }
+                writeln!(f, "\"")?
+            } else {
+                writeln!(f, "{}={}", key, value)?
+            }
         }
         Ok(())
     }
@@ -236,11 +327,10 @@ impl fmt::Display for OsRelease {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::ffi::CStr;
 
     #[test]
     fn parses_correctly_from_str() -> Result<()> {
-        let os_release_cstr = CStr::from_bytes_with_nul(b"ID=systemd-boot\nVERSION=\"252.1\"\n\0")?;
+        let os_release_cstr = c"ID=systemd-boot\nVERSION=\"252.1\"\n";
         let os_release_str = os_release_cstr.to_str()?;
         let os_release = OsRelease::from_str(os_release_str)?;
 
@@ -275,6 +365,53 @@ mod tests {
         assert!(os_release.0["UNESCAPED_QUOTE"] == "1.2\"");
         assert!(os_release.0["ESCAPED_QUOTE"] == "\"1.2");
 
+        Ok(())
+    }
+    #[test]
+    fn validates_valid_os_release() -> Result<()> {
+        let teststring = r#"
+            # A comment
+            ID=nixos
+            PRETTY_NAME="NixOS 24.11 (Vicuna)"
+            VERSION_ID='24.11'
+        "#;
+        let os_release = OsRelease::from_str_strict(teststring)?;
+
+        assert_eq!(os_release.0["ID"], "nixos");
+        assert_eq!(os_release.0["PRETTY_NAME"], "NixOS 24.11 (Vicuna)");
+        assert_eq!(os_release.0["VERSION_ID"], "24.11");
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_os_release() {
+        // Line without an assignment.
+        assert!(OsRelease::from_str_strict("ID=nixos\nthis is not os-release\n").is_err());
+        // Unterminated quote.
+        assert!(OsRelease::from_str_strict("ID=nixos\nPRETTY_NAME=\"NixOS\n").is_err());
+        // Invalid key.
+        assert!(OsRelease::from_str_strict("ID=nixos\nPRETTY-NAME=NixOS\n").is_err());
+        // Missing ID.
+        assert!(OsRelease::from_str_strict("PRETTY_NAME=NixOS\n").is_err());
+    }
+
+    #[test]
+    fn round_trips() -> Result<()> {
+        let os_release = OsRelease(BTreeMap::from([
+            ("ID".into(), "lanzaboote".into()),
+            (
+                "PRETTY_NAME".into(),
+                r#"Label with "quotes", $dollars and \backslashes"#.into(),
+            ),
+            ("VERSION_ID".into(), "".into()),
+        ]));
+
+        let serialized = os_release.to_normalized_string()?;
+        let parsed = OsRelease::from_str_strict(&serialized)?;
+
+        assert_eq!(parsed.0, os_release.0);
+
         Ok(())
     }
 }
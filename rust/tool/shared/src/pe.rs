@@ -1,39 +1,152 @@
+use std::collections::BTreeSet;
 use std::ffi::OsString;
 use std::fs;
+use std::io::Read;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use goblin::pe::certificate_table::AttributeCertificateType;
+use goblin::pe::header::{Header, SIZEOF_COFF_HEADER, SIZEOF_PE_MAGIC};
+use goblin::pe::section_table::SIZEOF_SECTION_TABLE;
 use goblin::pe::PE;
 use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 
+use crate::architecture::Architecture;
 use crate::utils::{file_hash, tmpname, SecureTempDirExt};
 
+/// The sections Lanzaboote embeds into stubs itself.
+const MANAGED_SECTIONS: [&str; 9] = [
+    ".osrel",
+    ".cmdline",
+    ".initrd",
+    ".linux",
+    ".initrdh",
+    ".linuxh",
+    ".uname",
+    ".sbat",
+    METADATA_SECTION,
+];
+
+/// The number of bytes read from the start of a PE binary to parse its headers. This covers the
+/// headers of most binaries, so that the rest only needs to be read if they are larger.
+const HEADERS_PREFIX: u64 = 4096;
+
+/// The section with the [`StubMetadata`] of a stub.
+pub const METADATA_SECTION: &str = ".lzmeta";
+
+/// The maximum length of a section name in a PE image.
+const MAX_SECTION_NAME_LENGTH: usize = 8;
+
+/// A section that is embedded into the stub verbatim, e.g. for a feature of a newer stub that
+/// Lanzaboote does not know about yet.
+///
+/// The contents are passed through untouched and are covered by the signature like every other
+/// section.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdditionalSection {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+impl AdditionalSection {
+    /// Create a section and ensure that its name is valid and not managed by Lanzaboote.
+    pub fn new(name: &str, contents: Vec<u8>) -> Result<Self> {
+        if !name.starts_with('.')
+            || name.len() < 2
+            || name.len() > MAX_SECTION_NAME_LENGTH
+            || !name.bytes().all(|b| b.is_ascii_graphic() && b != b'=')
+        {
+            bail!(
+                "Invalid section name {name:?}. It has to start with a dot and be at most \
+                 {MAX_SECTION_NAME_LENGTH} printable ASCII characters without `=`."
+            );
+        }
+        if MANAGED_SECTIONS.contains(&name) {
+            bail!("The section {name} is managed by Lanzaboote and cannot be added.");
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            contents,
+        })
+    }
+
+    /// Ensure that no section name is given more than once. Only one of the sections would be
+    /// found by the stub.
+    pub fn ensure_unique_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        for name in names {
+            if !seen.insert(name) {
+                bail!("The section {name} is added more than once.");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The provenance of a stub, embedded as JSON so that tooling can tell which generation a stub
+/// belongs to without the profile links, e.g. on a recovered ESP.
+///
+/// The stub itself ignores it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StubMetadata {
+    pub generation: u64,
+    pub profile: Option<PathBuf>,
+    pub specialisation: Option<String>,
+    pub toplevel: PathBuf,
+}
+
+impl StubMetadata {
+    /// Read the metadata from a PE binary. Stubs without metadata return `None`.
+    pub fn read(file_data: &[u8]) -> Result<Option<Self>> {
+        read_section_data(file_data, METADATA_SECTION)
+            .map(|data| {
+                serde_json::from_slice(data)
+                    .with_context(|| format!("Failed to parse the {METADATA_SECTION} section"))
+            })
+            .transpose()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StubParameters {
     pub lanzaboote_store_path: PathBuf,
     pub kernel_cmdline: Vec<String>,
     pub os_release_contents: Vec<u8>,
     pub kernel_store_path: PathBuf,
-    pub initrd_store_path: PathBuf,
     /// Kernel path rooted at the ESP
     /// i.e. if you refer to /boot/efi/EFI/NixOS/kernel.efi
     /// this gets turned into \\EFI\\NixOS\\kernel.efi as a UTF-16 string
     /// at assembling time.
     pub kernel_path_at_esp: String,
-    /// Same as kernel.
-    pub initrd_path_at_esp: String,
+    /// The store path of the initrd and its path rooted at the ESP, same as for the kernel.
+    /// `None` if the generation has no initrd.
+    pub initrd: Option<(PathBuf, String)>,
+    /// Kernel release (as in `uname -r`) for the `.uname` section that e.g. `bootctl` reads.
+    pub kernel_uname: Option<String>,
+    /// SBAT metadata for the `.sbat` section, embedded verbatim.
+    pub sbat: Option<Vec<u8>>,
+    /// SHA-256 digests of the kernel and the initrd if they are already known. Otherwise, they
+    /// are computed when the image is assembled.
+    pub kernel_hash: Option<Vec<u8>>,
+    pub initrd_hash: Option<Vec<u8>>,
+    /// Provenance for the `.lzmeta` section.
+    pub metadata: Option<StubMetadata>,
+    /// Sections that are embedded after the ones Lanzaboote manages.
+    pub additional_sections: Vec<AdditionalSection>,
 }
 
 impl StubParameters {
+    /// `initrd` is the initrd and its target on the ESP. Without it, the stub boots the kernel
+    /// without an initrd, e.g. for embedded systems that do not need one.
     pub fn new(
         lanzaboote_stub: &Path,
         kernel_path: &Path,
-        initrd_path: &Path,
         kernel_target: &Path,
-        initrd_target: &Path,
+        initrd: Option<(&Path, &Path)>,
         esp: &Path,
     ) -> Result<Self> {
         // Resolve maximally those paths
@@ -43,11 +156,21 @@ impl StubParameters {
         Ok(Self {
             lanzaboote_store_path: lanzaboote_stub.to_path_buf(),
             kernel_store_path: kernel_path.to_path_buf(),
-            initrd_store_path: initrd_path.to_path_buf(),
             kernel_path_at_esp: esp_relative_uefi_path(esp, kernel_target)?,
-            initrd_path_at_esp: esp_relative_uefi_path(esp, initrd_target)?,
+            initrd: initrd
+                .map(|(initrd_path, initrd_target)| {
+                    esp_relative_uefi_path(esp, initrd_target)
+                        .map(|initrd_path_at_esp| (initrd_path.to_path_buf(), initrd_path_at_esp))
+                })
+                .transpose()?,
             kernel_cmdline: Vec::new(),
             os_release_contents: Vec::new(),
+            kernel_uname: None,
+            sbat: None,
+            kernel_hash: None,
+            initrd_hash: None,
+            metadata: None,
+            additional_sections: Vec::new(),
         })
     }
 
@@ -60,6 +183,32 @@ impl StubParameters {
         self.kernel_cmdline = cmdline.to_vec();
         self
     }
+
+    pub fn with_uname(mut self, kernel_uname: &str) -> Self {
+        self.kernel_uname = Some(kernel_uname.to_owned());
+        self
+    }
+
+    pub fn with_sbat(mut self, sbat: &[u8]) -> Self {
+        self.sbat = Some(sbat.to_vec());
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: StubMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn with_additional_sections(mut self, additional_sections: &[AdditionalSection]) -> Self {
+        self.additional_sections = additional_sections.to_vec();
+        self
+    }
+
+    pub fn with_input_hashes(mut self, kernel_hash: &[u8], initrd_hash: Option<&[u8]>) -> Self {
+        self.kernel_hash = Some(kernel_hash.to_vec());
+        self.initrd_hash = initrd_hash.map(<[u8]>::to_vec);
+        self
+    }
 }
 
 /// Performs the evil operation
@@ -85,6 +234,15 @@ pub fn append_initrd_secrets(
     Ok(())
 }
 
+/// The contents of the `.cmdline` section for the kernel parameters.
+///
+/// The section is covered by the signature, so the command line cannot be changed from the boot
+/// menu. It is always embedded, even without parameters, because the stub refuses to boot without
+/// it.
+pub fn cmdline_section(kernel_cmdline: &[String]) -> Vec<u8> {
+    kernel_cmdline.join(" ").into_bytes()
+}
+
 /// Assemble a lanzaboote image.
 pub fn lanzaboote_image(
     // Because the returned path of this function is inside the tempdir as well, the tempdir must
@@ -94,33 +252,97 @@ pub fn lanzaboote_image(
 ) -> Result<PathBuf> {
     // objcopy can only copy files into the PE binary. That's why we
     // have to write the contents of some bootspec properties to disk.
-    let kernel_cmdline_file =
-        tempdir.write_secure_file(stub_parameters.kernel_cmdline.join(" "))?;
+    let kernel_cmdline = cmdline_section(&stub_parameters.kernel_cmdline);
+    // objcopy drops sections without contents, so an empty command line is embedded as a
+    // placeholder byte that is cut off afterwards.
+    let empty_cmdline = kernel_cmdline.is_empty();
+    let kernel_cmdline_file = tempdir.write_secure_file(if empty_cmdline {
+        b"\0".as_slice()
+    } else {
+        &kernel_cmdline
+    })?;
 
     let kernel_path_file = tempdir.write_secure_file(&stub_parameters.kernel_path_at_esp)?;
-    let kernel_hash_file =
-        tempdir.write_secure_file(file_hash(&stub_parameters.kernel_store_path)?.as_slice())?;
-
-    let initrd_path_file = tempdir.write_secure_file(&stub_parameters.initrd_path_at_esp)?;
-    let initrd_hash_file =
-        tempdir.write_secure_file(file_hash(&stub_parameters.initrd_store_path)?.as_slice())?;
+    let kernel_hash_file = tempdir.write_secure_file(input_hash(
+        stub_parameters.kernel_hash.as_deref(),
+        &stub_parameters.kernel_store_path,
+    )?)?;
+
+    // Without an initrd, both of its sections are left out. The stub then boots without one.
+    let initrd_files = match &stub_parameters.initrd {
+        Some((initrd_store_path, initrd_path_at_esp)) => Some((
+            tempdir.write_secure_file(initrd_path_at_esp)?,
+            tempdir.write_secure_file(input_hash(
+                stub_parameters.initrd_hash.as_deref(),
+                initrd_store_path,
+            )?)?,
+        )),
+        None => None,
+    };
 
     let os_release = tempdir.write_secure_file(&stub_parameters.os_release_contents)?;
-    let os_release_offs = stub_offset(&stub_parameters.lanzaboote_store_path)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(&os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrd", initrd_path_file, initrd_path_offs),
-        s(".linux", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".linuxh", kernel_hash_file, kernel_hash_offs),
-    ];
+    let mut fixed_sections = vec![(".osrel", os_release), (".cmdline", kernel_cmdline_file)];
+    match initrd_files {
+        Some((initrd_path_file, initrd_hash_file)) => fixed_sections.extend([
+            (".initrd", initrd_path_file),
+            (".linux", kernel_path_file),
+            (".initrdh", initrd_hash_file),
+            (".linuxh", kernel_hash_file),
+        ]),
+        None => {
+            fixed_sections.extend([(".linux", kernel_path_file), (".linuxh", kernel_hash_file)])
+        }
+    }
+
+    let mut next_offs = stub_offset(&stub_parameters.lanzaboote_store_path)?;
+    let mut sections = Vec::new();
+    for (name, file) in fixed_sections {
+        let offs = next_offs;
+        next_offs += file_size(&file)?;
+        sections.push(s(name, file, offs));
+    }
+
+    // The stub itself does not need the kernel release. It is only embedded so that Boot Loader
+    // Specification Type #2 tooling (e.g. `bootctl list`) can display it.
+    if let Some(kernel_uname) = &stub_parameters.kernel_uname {
+        let uname_file = tempdir.write_secure_file(kernel_uname)?;
+        let uname_offs = next_offs;
+        next_offs += file_size(&uname_file)?;
+        sections.push(s(".uname", uname_file, uname_offs));
+    }
+
+    if let Some(sbat) = &stub_parameters.sbat {
+        let sbat_file = tempdir.write_secure_file(sbat)?;
+        let sbat_offs = next_offs;
+        next_offs += file_size(&sbat_file)?;
+        sections.push(s(".sbat", sbat_file, sbat_offs));
+    }
+
+    if let Some(metadata) = &stub_parameters.metadata {
+        let metadata = serde_json::to_vec(metadata).context("Failed to serialize the metadata")?;
+        let metadata_file = tempdir.write_secure_file(metadata)?;
+        let metadata_offs = next_offs;
+        next_offs += file_size(&metadata_file)?;
+        sections.push(s(METADATA_SECTION, metadata_file, metadata_offs));
+    }
+
+    if !stub_parameters.additional_sections.is_empty() {
+        let stub = fs::read(&stub_parameters.lanzaboote_store_path)
+            .context("Failed to read the Lanzaboote stub")?;
+        let stub_sections = read_sections(&stub)?;
+        for section in &stub_parameters.additional_sections {
+            if stub_sections.iter().any(|other| other.name == section.name) {
+                bail!(
+                    "The Lanzaboote stub already contains a section {}.",
+                    section.name
+                );
+            }
+            let file = tempdir.write_secure_file(&section.contents)?;
+            let offs = next_offs;
+            next_offs += file_size(&file)?;
+            sections.push(s(&section.name, file, offs));
+        }
+    }
 
     let image_path = tempdir.path().join(tmpname());
     wrap_in_pe(
@@ -128,9 +350,36 @@ pub fn lanzaboote_image(
         sections,
         &image_path,
     )?;
+    if empty_cmdline {
+        clear_section(&image_path, ".cmdline")?;
+    }
     Ok(image_path)
 }
 
+/// Set the size in memory of a section of the PE binary at `path` to zero, so that readers of the
+/// section see it as present but empty.
+///
+/// Its contents in the file are still covered by the signature but not part of the section.
+fn clear_section(path: &Path, section_name: &str) -> Result<()> {
+    let mut data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let pe = PE::parse(&data).context("Failed to parse PE binary file")?;
+    let index = pe
+        .sections
+        .iter()
+        .position(|section| section.name().is_ok_and(|name| name == section_name))
+        .with_context(|| format!("The PE binary file has no section {section_name}"))?;
+
+    // The size in memory (VirtualSize) is the first field after the 8 bytes of the name.
+    let offset = pe.header.dos_header.pe_pointer as usize
+        + SIZEOF_PE_MAGIC
+        + SIZEOF_COFF_HEADER
+        + usize::from(pe.header.coff_header.size_of_optional_header)
+        + index * SIZEOF_SECTION_TABLE
+        + 8;
+    data[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
+    fs::write(path, data).with_context(|| format!("Failed to write {path:?}"))
+}
+
 /// Take a PE binary stub and attach sections to it.
 ///
 /// The resulting binary is then written to a newly created file at the provided output path.
@@ -156,7 +405,7 @@ fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()>
 }
 
 struct Section {
-    name: &'static str,
+    name: String,
     file_path: PathBuf,
     offset: u64,
 }
@@ -179,9 +428,9 @@ impl Section {
     }
 }
 
-fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
+fn s(name: &str, file_path: impl AsRef<Path>, offset: u64) -> Section {
     Section {
-        name,
+        name: name.to_owned(),
         file_path: file_path.as_ref().into(),
         offset,
     }
@@ -208,27 +457,64 @@ fn uefi_path(path: &Path) -> Result<String> {
 }
 
 fn stub_offset(binary: &Path) -> Result<u64> {
-    let pe_binary = fs::read(binary).context("Failed to read PE binary file")?;
-    let pe = PE::parse(&pe_binary).context("Failed to parse PE binary file")?;
+    let headers = read_headers(binary)?;
+    let header = Header::parse(&headers).context("Failed to parse PE binary file")?;
 
-    let image_base = image_base(&pe);
+    let image_base = header
+        .optional_header
+        .context("Failed to find optional header")?
+        .windows_fields
+        .image_base;
+
+    // Only the virtual address and size of the last section are needed, so the section table is
+    // read directly instead of parsing the section names, which may point past the headers.
+    let last_section = header
+        .coff_header
+        .number_of_sections
+        .checked_sub(1)
+        .context("The PE binary file has no sections")?;
+    let offset = header.dos_header.pe_pointer as usize
+        + SIZEOF_PE_MAGIC
+        + SIZEOF_COFF_HEADER
+        + usize::from(header.coff_header.size_of_optional_header)
+        + usize::from(last_section) * SIZEOF_SECTION_TABLE;
+    let field = |at: usize| -> Result<u32> {
+        headers
+            .get(offset + at..offset + at + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u32::from_le_bytes)
+            .context("The section table of the PE binary file is truncated")
+    };
+    let virtual_size = field(8)?;
+    let virtual_address = field(12)?;
 
     // The Virtual Memory Address (VMA) is relative to the image base, aka the image base
     // needs to be added to the virtual address to get the actual (but still virtual address)
-    Ok(u64::from(
-        pe.sections
-            .last()
-            .map(|s| s.virtual_size + s.virtual_address)
-            .expect("Failed to calculate offset"),
-    ) + image_base)
+    Ok(u64::from(virtual_size + virtual_address) + image_base)
 }
 
-fn image_base(pe: &PE) -> u64 {
-    pe.header
+/// Read only the headers of a PE binary, including the section table, instead of the whole file.
+fn read_headers(binary: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(binary).context("Failed to open PE binary file")?;
+    let mut headers = Vec::new();
+    file.by_ref()
+        .take(HEADERS_PREFIX)
+        .read_to_end(&mut headers)
+        .context("Failed to read PE binary file")?;
+
+    // The section table ends within `SizeOfHeaders`, which may exceed the prefix.
+    let size_of_headers = Header::parse(&headers)
+        .context("Failed to parse PE binary file")?
         .optional_header
-        .expect("Failed to find optional header, you're fucked")
-        .windows_fields
-        .image_base
+        .map_or(0, |optional_header| {
+            u64::from(optional_header.windows_fields.size_of_headers)
+        });
+    if size_of_headers > HEADERS_PREFIX {
+        file.take(size_of_headers - HEADERS_PREFIX)
+            .read_to_end(&mut headers)
+            .context("Failed to read PE binary file")?;
+    }
+    Ok(headers)
 }
 
 fn file_size(path: impl AsRef<Path>) -> Result<u64> {
@@ -242,6 +528,14 @@ fn file_size(path: impl AsRef<Path>) -> Result<u64> {
         .size())
 }
 
+/// The digest of an input of the stub, computed unless it is already known.
+fn input_hash(known: Option<&[u8]>, path: &Path) -> Result<Vec<u8>> {
+    match known {
+        Some(hash) => Ok(hash.to_vec()),
+        None => Ok(file_hash(path)?.to_vec()),
+    }
+}
+
 /// Read the data from a section of a PE binary.
 ///
 /// The binary is supplied as a `u8` slice.
@@ -260,6 +554,94 @@ pub fn read_section_data<'a>(file_data: &'a [u8], section_name: &str) -> Option<
         })
 }
 
+/// The name, location and size of a section of a PE binary.
+pub struct SectionInfo {
+    pub name: String,
+    pub virtual_address: u32,
+    /// The size of the section in memory.
+    pub size: u32,
+    pub file_offset: u32,
+    /// The size of the section in the file. This is aligned and can be smaller than `size`, e.g.
+    /// for uninitialized data.
+    pub file_size: u32,
+}
+
+impl SectionInfo {
+    /// The data of the section in the binary it was read from, without the alignment padding.
+    pub fn data<'a>(&self, file_data: &'a [u8]) -> Option<&'a [u8]> {
+        let start = usize::try_from(self.file_offset).ok()?;
+        let len = usize::try_from(self.size.min(self.file_size)).ok()?;
+        file_data.get(start..start.checked_add(len)?)
+    }
+}
+
+/// List the sections of a PE binary.
+pub fn read_sections(file_data: &[u8]) -> Result<Vec<SectionInfo>> {
+    let pe_binary = PE::parse(file_data).context("Failed to parse PE binary")?;
+    pe_binary
+        .sections
+        .iter()
+        .map(|s| {
+            Ok(SectionInfo {
+                name: s.name().context("Invalid section name")?.to_owned(),
+                virtual_address: s.virtual_address,
+                size: s.virtual_size,
+                file_offset: s.pointer_to_raw_data,
+                file_size: s.size_of_raw_data,
+            })
+        })
+        .collect()
+}
+
+/// Read the architecture a PE binary is built for from its COFF header.
+pub fn read_architecture(file_data: &[u8]) -> Result<Architecture> {
+    let header =
+        goblin::pe::header::Header::parse(file_data).context("Failed to parse PE header")?;
+    Architecture::from_pe_machine(header.coff_header.machine)
+}
+
+/// Read the Authenticode PKCS#7 signature of a signed PE binary.
+///
+/// The signature is returned as a DER-encoded PKCS#7 `ContentInfo`, i.e. exactly what is stored
+/// in a detached `.p7s` file. If the binary carries multiple signatures, only the first is
+/// returned.
+pub fn read_pkcs7_signature(file_data: &[u8]) -> Result<Vec<u8>> {
+    let pe_binary = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let certificate = pe_binary
+        .certificates
+        .iter()
+        .find(|c| matches!(c.certificate_type, AttributeCertificateType::PkcsSignedData))
+        .context("PE binary does not contain a PKCS#7 signature")?;
+
+    // The certificate table entries are padded to 8 bytes and some signers include the padding
+    // in the length of the entry. Only keep the actual DER structure.
+    let length = der_length(certificate.certificate)?;
+    Ok(certificate.certificate[..length].to_vec())
+}
+
+/// Compute the total length of the DER structure at the start of `data`.
+fn der_length(data: &[u8]) -> Result<usize> {
+    let (header_length, content_length) = match data {
+        [_, length, ..] if length & 0x80 == 0 => (2, usize::from(*length)),
+        [_, length, rest @ ..] => {
+            let length_bytes = usize::from(length & 0x7f);
+            if length_bytes > std::mem::size_of::<usize>() || rest.len() < length_bytes {
+                bail!("Invalid DER length");
+            }
+            let content_length = rest[..length_bytes]
+                .iter()
+                .fold(0, |acc, b| (acc << 8) | usize::from(*b));
+            (2 + length_bytes, content_length)
+        }
+        _ => bail!("DER structure is truncated"),
+    };
+    let length = header_length + content_length;
+    if length > data.len() {
+        bail!("DER structure is truncated");
+    }
+    Ok(length)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -273,6 +655,30 @@ mod tests {
         assert_eq!(converted_path, expected_path);
     }
 
+    #[test]
+    fn convert_uefi_path_relative_to_esp_with_spaces() {
+        let esp = Path::new("/mnt/my esp (backup)");
+        let path = Path::new("/mnt/my esp (backup)/EFI/nixos/kernel-6.1.1-abc.efi");
+        let converted_path = esp_relative_uefi_path(esp, path).unwrap();
+        assert_eq!(converted_path, "\\EFI\\nixos\\kernel-6.1.1-abc.efi");
+    }
+
+    #[test]
+    fn convert_uefi_path_relative_to_non_utf8_esp() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Only the path on the ESP has to be valid UTF-8, not its mountpoint.
+        let esp = Path::new(OsStr::from_bytes(b"/mnt/esp\xff"));
+        let path = esp.join("EFI/nixos/kernel.efi");
+        assert_eq!(
+            esp_relative_uefi_path(esp, &path).unwrap(),
+            "\\EFI\\nixos\\kernel.efi"
+        );
+        let path = esp.join(OsStr::from_bytes(b"EFI/nixos/kernel\xff.efi"));
+        assert!(esp_relative_uefi_path(esp, &path).is_err());
+    }
+
     #[test]
     fn convert_to_valid_uefi_path() {
         let path = Path::new("lanzaboote/is/great.txt");
@@ -280,4 +686,90 @@ mod tests {
         let expected_path = String::from("lanzaboote\\is\\great.txt");
         assert_eq!(converted_path, expected_path);
     }
+
+    #[test]
+    fn strip_padding_from_der_structure() {
+        // Short form length.
+        assert_eq!(
+            der_length(&[0x30, 0x02, 0x05, 0x00, 0x00, 0x00]).unwrap(),
+            4
+        );
+        // Long form length.
+        let mut data = vec![0x30, 0x82, 0x01, 0x00];
+        data.resize(4 + 0x100 + 4, 0);
+        assert_eq!(der_length(&data).unwrap(), 4 + 0x100);
+        // Truncated.
+        assert!(der_length(&[0x30, 0x03, 0x05, 0x00]).is_err());
+    }
+
+    #[test]
+    fn embed_empty_cmdline_section() -> Result<()> {
+        let binary = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures/authenticode/unsigned.efi");
+        let esp = Path::new("/boot");
+        let parameters = StubParameters::new(
+            &binary,
+            &binary,
+            &esp.join("EFI/nixos/kernel.efi"),
+            None,
+            esp,
+        )?;
+
+        let tempdir = TempDir::new()?;
+        let image = fs::read(lanzaboote_image(&tempdir, &parameters)?)?;
+        assert_eq!(read_section_data(&image, ".cmdline"), Some(&b""[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn validate_additional_section_names() {
+        assert!(AdditionalSection::new(".dtb", Vec::new()).is_ok());
+        assert!(AdditionalSection::new(".1234567", Vec::new()).is_ok());
+        for name in [".", "dtb", ".12345678", ".a=b", ".a b", ".cmdline", ".sbat"] {
+            assert!(
+                AdditionalSection::new(name, Vec::new()).is_err(),
+                "accepted {name:?}"
+            );
+        }
+        assert!(AdditionalSection::ensure_unique_names([".dtb", ".splash"]).is_ok());
+        assert!(AdditionalSection::ensure_unique_names([".dtb", ".splash", ".dtb"]).is_err());
+    }
+
+    /// Build the smallest header goblin accepts: a DOS header pointing to a COFF header without
+    /// an optional header.
+    fn pe_header(machine: u16) -> Vec<u8> {
+        let mut data = vec![0; 0x40];
+        data[..2].copy_from_slice(b"MZ");
+        data[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        data.extend_from_slice(b"PE\0\0");
+        data.extend_from_slice(&machine.to_le_bytes());
+        data.resize(0x40 + 4 + 20, 0);
+        data
+    }
+
+    #[test]
+    fn read_architecture_from_pe_header() -> Result<()> {
+        for arch in [Architecture::X86, Architecture::AArch64] {
+            assert_eq!(read_architecture(&pe_header(arch.pe_machine()))?, arch);
+        }
+        // i386
+        assert!(read_architecture(&pe_header(0x14c)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compute_stub_offset_from_headers() -> Result<()> {
+        let binary = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures/authenticode/unsigned.efi");
+        let data = fs::read(&binary)?;
+        let pe = PE::parse(&data)?;
+        let last = pe.sections.last().expect("The fixture has sections");
+        let image_base = pe.header.optional_header.unwrap().windows_fields.image_base;
+        assert_eq!(
+            stub_offset(&binary)?,
+            u64::from(last.virtual_size + last.virtual_address) + image_base
+        );
+        assert!(read_headers(&binary)?.len() < data.len());
+        Ok(())
+    }
 }
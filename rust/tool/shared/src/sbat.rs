@@ -0,0 +1,135 @@
+use anyhow::{bail, Context, Result};
+
+/// The number of columns of every SBAT entry.
+const COLUMNS: usize = 6;
+
+/// The entry that declares the version of the SBAT format.
+const SBAT_VERSION: &str =
+    "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md";
+
+/// The SBAT metadata of a stub that shim checks against its revocations.
+///
+/// This is the CSV that is embedded verbatim into the `.sbat` section. Every line is an entry
+/// `component_name,component_generation,vendor_name,vendor_package_name,vendor_version,vendor_url`
+/// and the first entry declares the version of the format itself, e.g.
+/// `sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md`.
+///
+/// See <https://github.com/rhboot/shim/blob/main/SBAT.md>.
+#[derive(Clone, Debug)]
+pub struct Sbat(String);
+
+impl Sbat {
+    /// Parse SBAT metadata and reject anything that shim would not accept.
+    ///
+    /// SBAT does not support quoting, so every line has to have exactly six non-empty columns
+    /// without quotes and the generation has to be a positive integer.
+    pub fn from_str_strict(value: &str) -> Result<Self> {
+        if !value.is_ascii() {
+            bail!("The SBAT metadata must only contain ASCII characters");
+        }
+
+        let mut entries = value
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty());
+        let (_, first) = entries.next().context("The SBAT metadata is empty")?;
+        let (component, _) = parse_entry(first).context("Invalid SBAT entry on line 1")?;
+        if component != "sbat" {
+            bail!(
+                "The first SBAT entry must declare the SBAT version, but it is for {component:?}"
+            );
+        }
+        for (index, line) in entries {
+            parse_entry(line)
+                .with_context(|| format!("Invalid SBAT entry on line {}", index + 1))?;
+        }
+
+        Ok(Self(value.to_owned()))
+    }
+
+    /// The SBAT metadata of Lanzaboote with the given generation.
+    ///
+    /// Raising the generation lets shim revoke all stubs with a lower one, e.g. after a
+    /// vulnerability in the stub was fixed.
+    pub fn lanzaboote(generation: u32) -> Result<Self> {
+        if generation == 0 {
+            bail!("The SBAT generation must be a positive integer");
+        }
+        Ok(Self(format!(
+            "{SBAT_VERSION}\nlanzaboote,{generation},Lanzaboote,lanzaboote,{},https://github.com/nix-community/lanzaboote\n",
+            env!("CARGO_PKG_VERSION")
+        )))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Parse an SBAT entry into its component name and generation.
+fn parse_entry(line: &str) -> Result<(&str, u32)> {
+    let columns: Vec<&str> = line.split(',').collect();
+    if columns.len() != COLUMNS {
+        bail!(
+            "Expected {COLUMNS} columns, but the entry has {}: {line:?}",
+            columns.len()
+        );
+    }
+    if let Some(column) = columns.iter().find(|column| column.trim().is_empty()) {
+        bail!("Empty column {column:?} in {line:?}");
+    }
+    if columns.iter().any(|column| column.contains('"')) {
+        bail!("SBAT does not support quoted columns: {line:?}");
+    }
+    let generation = columns[1]
+        .parse()
+        .ok()
+        .filter(|generation| *generation > 0)
+        .with_context(|| {
+            format!(
+                "The generation must be a positive integer, but it is {:?}",
+                columns[1]
+            )
+        })?;
+    Ok((columns[0], generation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_sbat() -> Result<()> {
+        let sbat = format!(
+            "{SBAT_VERSION}\nlanzaboote,1,Lanzaboote,lanzaboote,0.4.1,https://github.com/nix-community/lanzaboote\n"
+        );
+        assert_eq!(Sbat::from_str_strict(&sbat)?.as_bytes(), sbat.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn generate_lanzaboote_sbat() -> Result<()> {
+        let sbat = Sbat::lanzaboote(2)?;
+        let contents = std::str::from_utf8(sbat.as_bytes())?;
+        Sbat::from_str_strict(contents)?;
+        assert!(contents.starts_with(SBAT_VERSION));
+        assert!(contents.contains("\nlanzaboote,2,Lanzaboote,lanzaboote,"));
+        assert!(Sbat::lanzaboote(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_sbat() {
+        for sbat in [
+            "",
+            "lanzaboote,1,Lanzaboote,lanzaboote,0.4.1,https://github.com/nix-community/lanzaboote",
+            "sbat,1,SBAT Version,sbat,1",
+            "sbat,0,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md",
+            "sbat,one,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md",
+            "sbat,1,\"SBAT, Version\",sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md",
+        ] {
+            assert!(Sbat::from_str_strict(sbat).is_err(), "accepted {sbat:?}");
+        }
+        assert!(Sbat::from_str_strict(&format!("{SBAT_VERSION}\nlanzaboote,1,,,,")).is_err());
+    }
+}
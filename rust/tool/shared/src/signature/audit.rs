@@ -0,0 +1,249 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use der::Encode;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use x509_cert::Certificate;
+
+use super::Signer;
+use crate::pe::StubParameters;
+use crate::utils::file_hash;
+
+/// A record of a single signing operation.
+///
+/// It identifies the key by the fingerprint of its certificate and never contains secret
+/// material.
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    operation: &'static str,
+    key_source: &'static str,
+    certificate_sha256: &'a str,
+    /// The signed file. A stub is assembled from its parameters instead.
+    input: Option<&'a Path>,
+    input_sha256: Option<String>,
+    output: Option<&'a Path>,
+    output_sha256: Option<String>,
+    result: &'static str,
+    error: Option<String>,
+}
+
+/// A signer that appends an audit record to a log for every signing operation of the signer it
+/// wraps, including the failed ones.
+///
+/// The log contains one JSON object per line. Every record is synced to disk before the
+/// operation returns, so that nothing is signed without a record.
+pub struct AuditedSigner<S> {
+    signer: S,
+    key_source: &'static str,
+    certificate_sha256: String,
+    log: Mutex<File>,
+}
+
+impl<S: Signer> AuditedSigner<S> {
+    /// Wrap `signer` and append its records to `log`.
+    ///
+    /// `key_source` describes where the key comes from, e.g. "file", "tpm" or "pkcs11".
+    pub fn new(signer: S, key_source: &'static str, log: &Path) -> Result<Self> {
+        let certificate_sha256 = certificate_sha256(&signer.get_public_key()?)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(log)
+            .with_context(|| format!("Failed to open the audit log {log:?}"))?;
+        Ok(Self {
+            signer,
+            key_source,
+            certificate_sha256,
+            log: Mutex::new(log),
+        })
+    }
+
+    fn record<T>(
+        &self,
+        operation: &'static str,
+        input: (Option<&Path>, Option<String>),
+        output: Option<&Path>,
+        output_sha256: impl FnOnce(&T) -> Option<String>,
+        result: Result<T>,
+    ) -> Result<T> {
+        let record = AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            operation,
+            key_source: self.key_source,
+            certificate_sha256: &self.certificate_sha256,
+            input: input.0,
+            input_sha256: input.1,
+            output,
+            output_sha256: result.as_ref().ok().and_then(output_sha256),
+            result: if result.is_ok() { "success" } else { "failure" },
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+        };
+        let written = self.write(&record);
+        match (result, written) {
+            (Err(err), Err(audit_err)) => {
+                log::error!("Failed to write the audit record: {audit_err:?}");
+                Err(err)
+            }
+            (result, written) => {
+                written?;
+                result
+            }
+        }
+    }
+
+    fn write(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record).context("Failed to serialize the record")?;
+        line.push('\n');
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        log.write_all(line.as_bytes())
+            .context("Failed to write the audit log")?;
+        log.sync_data().context("Failed to sync the audit log")
+    }
+}
+
+impl<S: Signer> Signer for AuditedSigner<S> {
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        let input = (Some(store_path), file_sha256(store_path));
+        let result = self.signer.sign_store_path(store_path);
+        self.record("sign_store_path", input, None, bytes_sha256, result)
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        let parameters = serde_json::to_vec(stub).ok();
+        let input = (None, parameters.as_ref().and_then(bytes_sha256));
+        let result = self.signer.build_and_sign_stub(stub);
+        self.record("build_and_sign_stub", input, None, bytes_sha256, result)
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        self.signer.get_public_key()
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let input = (Some(from), file_sha256(from));
+        let result = self.signer.sign_and_copy(from, to);
+        self.record(
+            "sign_and_copy",
+            input,
+            Some(to),
+            |_| file_sha256(to),
+            result,
+        )
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        self.signer.verify(pe_binary)
+    }
+
+    fn verify_path(&self, from: &Path) -> Result<bool> {
+        self.signer.verify_path(from)
+    }
+}
+
+/// The SHA-256 fingerprint of the certificate, as printed by `openssl x509 -fingerprint`.
+///
+/// The public key of a signer may be followed by the chain of intermediate certificates.
+fn certificate_sha256(public_key: &[u8]) -> Result<String> {
+    let certificate = Certificate::load_pem_chain(public_key)
+        .context("Failed to parse the certificate of the signer")?
+        .into_iter()
+        .next()
+        .context("The signer has no certificate")?;
+    Ok(format!("{:x}", Sha256::digest(certificate.to_der()?)))
+}
+
+fn file_sha256(path: &Path) -> Option<String> {
+    file_hash(path).ok().map(|hash| format!("{hash:x}"))
+}
+
+fn bytes_sha256(bytes: &Vec<u8>) -> Option<String> {
+    Some(format!("{:x}", Sha256::digest(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::fs;
+
+    const CERTIFICATE: &str = include_str!("../../../systemd/tests/fixtures/uefi-keys/db.pem");
+
+    /// Signs by copying and fails for inputs named `reject`.
+    struct CopySigner;
+
+    impl Signer for CopySigner {
+        fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+            Ok(fs::read(store_path)?)
+        }
+
+        fn build_and_sign_stub(&self, _stub: &StubParameters) -> Result<Vec<u8>> {
+            bail!("Not implemented")
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            Ok(CERTIFICATE.as_bytes().to_vec())
+        }
+
+        fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+            if from.ends_with("reject") {
+                bail!("Failed to sign {to:?}.");
+            }
+            fs::copy(from, to)?;
+            Ok(())
+        }
+
+        fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn record_successful_and_failed_signatures() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let log = tmpdir.path().join("audit.log");
+        let input = tmpdir.path().join("input");
+        let rejected = tmpdir.path().join("reject");
+        let output = tmpdir.path().join("output");
+        fs::write(&input, "unsigned")?;
+        fs::write(&rejected, "unsigned")?;
+
+        let signer = AuditedSigner::new(CopySigner, "file", &log)?;
+        signer.sign_and_copy(&input, &output)?;
+        assert!(signer.sign_and_copy(&rejected, &output).is_err());
+
+        let records = fs::read_to_string(&log)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(records.len(), 2);
+
+        let unsigned_sha256 = format!("{:x}", Sha256::digest("unsigned"));
+        assert_eq!(records[0]["operation"], "sign_and_copy");
+        assert_eq!(records[0]["key_source"], "file");
+        assert_eq!(
+            records[0]["certificate_sha256"],
+            "27e28df79edded2d771175a8a8525d496e0281f5952e8268c4fbf6982c22bf86"
+        );
+        assert_eq!(records[0]["input_sha256"], unsigned_sha256.as_str());
+        assert_eq!(records[0]["output_sha256"], unsigned_sha256.as_str());
+        assert_eq!(records[0]["result"], "success");
+
+        assert_eq!(records[1]["result"], "failure");
+        assert!(records[1]["output_sha256"].is_null());
+        assert!(records[1]["error"]
+            .as_str()
+            .is_some_and(|error| error.contains("Failed to sign")));
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Verification of Authenticode signatures without external tools.
+//!
+//! `sbverify` and `openssl` are often not available in a rescue environment. This only
+//! implements what is needed to verify the signatures of `sbsign`: a PKCS#7 `SignedData` with a
+//! single RSA signer over a SHA-256 digest, whose certificate is either the trusted certificate
+//! or chains up to it via the certificates embedded in the signature. ECDSA signatures are
+//! recognized but rejected with an error, because verifying them needs `sbverify`.
+
+use anyhow::{bail, Context, Result};
+use cms::cert::CertificateChoices;
+use cms::content_info::ContentInfo;
+use cms::signed_data::{SignedData, SignerIdentifier, SignerInfo};
+use der::asn1::{ObjectIdentifier, OctetString};
+use der::{Any, Decode, DecodePem, Encode, Sequence};
+use goblin::pe::PE;
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::signature::Verifier;
+use rsa::RsaPublicKey;
+use sha2::{Digest, Sha256};
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+use x509_cert::Certificate;
+
+use super::key::KeyType;
+use crate::pe;
+
+const SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const SPC_INDIRECT_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.4");
+const MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+const SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const SHA256_WITH_RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const ECDSA_UNSUPPORTED: &str =
+    "ECDSA signatures cannot be verified offline. Verify them with sbverify instead";
+
+/// The content of an Authenticode signature.
+///
+/// Only the digest of the image is needed. `data` describes the signed image, which carries no
+/// information that has to be checked.
+#[derive(Sequence)]
+struct SpcIndirectDataContent {
+    data: Any,
+    message_digest: DigestInfo,
+}
+
+#[derive(Sequence)]
+struct DigestInfo {
+    digest_algorithm: AlgorithmIdentifierOwned,
+    digest: OctetString,
+}
+
+/// Read a PEM-encoded certificate, e.g. the `db.pem` that is enrolled in the db.
+pub fn read_certificate(pem: &[u8]) -> Result<Certificate> {
+    Certificate::from_pem(pem).context("Failed to parse the PEM certificate")
+}
+
+/// Compute the SHA-256 Authenticode digest of a PE binary.
+///
+/// The checksum, the certificate table entry of the data directory and the certificate table
+/// itself are not covered. Like `sbsign`, an unsigned binary is hashed as if it was padded to 8
+/// bytes, which `sbsign` does before appending the certificate table.
+pub fn authenticode_digest(pe_binary: &[u8]) -> Result<Vec<u8>> {
+    let pe = PE::parse(pe_binary).context("Failed to parse PE binary")?;
+    let mut hasher = Sha256::new();
+    for range in pe.authenticode_ranges() {
+        hasher.update(range);
+    }
+    if pe.certificates.is_empty() {
+        let padding = pe_binary.len().next_multiple_of(8) - pe_binary.len();
+        hasher.update(&[0; 8][..padding]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Read the certificate that created a DER-encoded PKCS#7 signature.
+///
+/// This does not verify anything, e.g. to report who signed a binary that fails verification.
+pub fn signer(signature: &[u8]) -> Result<Certificate> {
+    let signed_data = signed_data(signature)?;
+    let signer_info = signer_info(&signed_data)?;
+    signer_certificate(&signed_data, signer_info).cloned()
+}
+
+/// Verify that `signature` is a valid Authenticode signature of the PE binary for the trusted
+/// certificate.
+///
+/// If `signature` is `None`, the signature embedded in the binary is verified. Otherwise, e.g. a
+/// detached signature is verified against the binary regardless of the embedded one, which is
+/// not covered by the digest.
+pub fn verify(pe_binary: &[u8], signature: Option<&[u8]>, trusted: &Certificate) -> Result<()> {
+    let embedded;
+    let signature = match signature {
+        Some(signature) => signature,
+        None => {
+            embedded = pe::read_pkcs7_signature(pe_binary)?;
+            &embedded
+        }
+    };
+
+    let signed_data = signed_data(signature)?;
+    let content_info = &signed_data.encap_content_info;
+    if content_info.econtent_type != SPC_INDIRECT_DATA {
+        bail!("The signature is not an Authenticode signature");
+    }
+    let content = content_info
+        .econtent
+        .as_ref()
+        .context("The signature does not contain the signed content")?;
+    let indirect_data = content
+        .decode_as::<SpcIndirectDataContent>()
+        .context("Failed to parse the signed content")?;
+
+    let digest_info = &indirect_data.message_digest;
+    ensure_sha256(&digest_info.digest_algorithm)?;
+    if digest_info.digest.as_bytes() != authenticode_digest(pe_binary)? {
+        bail!("The signature was created for a different binary");
+    }
+
+    let signer_info = signer_info(&signed_data)?;
+    ensure_sha256(&signer_info.digest_alg)?;
+    let signed_attributes = signer_info
+        .signed_attrs
+        .as_ref()
+        .context("The signature does not contain signed attributes")?;
+    let message_digest = signed_attributes
+        .iter()
+        .find(|attribute| attribute.oid == MESSAGE_DIGEST)
+        .and_then(|attribute| attribute.values.get(0))
+        .context("The signature does not contain a message digest")?
+        .decode_as::<OctetString>()
+        .context("Failed to parse the message digest")?;
+    // Authenticode hashes the content without its tag and length.
+    if message_digest.as_bytes() != Sha256::digest(content.value()).as_slice() {
+        bail!("The message digest does not match the signed content");
+    }
+
+    let signature_algorithm = signer_info.signature_algorithm.oid;
+    if signature_algorithm == ECDSA_WITH_SHA256 {
+        bail!(ECDSA_UNSUPPORTED);
+    }
+    if signature_algorithm != RSA_ENCRYPTION && signature_algorithm != SHA256_WITH_RSA_ENCRYPTION {
+        bail!("Unsupported signature algorithm {signature_algorithm}");
+    }
+    let signer = signer_certificate(&signed_data, signer_info)?;
+    verify_rsa(
+        &signer.tbs_certificate.subject_public_key_info,
+        &signed_attributes.to_der()?,
+        signer_info.signature.as_bytes(),
+    )
+    .context("The signature does not match the signing certificate")?;
+
+    ensure_trusted(signer, &certificates(&signed_data), trusted)
+}
+
+fn signed_data(signature: &[u8]) -> Result<SignedData> {
+    let content_info =
+        ContentInfo::from_der(signature).context("Failed to parse the PKCS#7 signature")?;
+    if content_info.content_type != SIGNED_DATA {
+        bail!("The PKCS#7 signature does not contain signed data");
+    }
+    content_info
+        .content
+        .decode_as::<SignedData>()
+        .context("Failed to parse the signed data")
+}
+
+fn signer_info(signed_data: &SignedData) -> Result<&SignerInfo> {
+    let signer_infos = &signed_data.signer_infos.0;
+    if signer_infos.len() != 1 {
+        bail!(
+            "Expected exactly one signer, but the signature has {}",
+            signer_infos.len()
+        );
+    }
+    signer_infos.get(0).context("The signature has no signer")
+}
+
+fn certificates(signed_data: &SignedData) -> Vec<&Certificate> {
+    signed_data
+        .certificates
+        .iter()
+        .flat_map(|certificates| certificates.0.iter())
+        .filter_map(|choice| match choice {
+            CertificateChoices::Certificate(certificate) => Some(certificate),
+            CertificateChoices::Other(_) => None,
+        })
+        .collect()
+}
+
+fn signer_certificate<'a>(
+    signed_data: &'a SignedData,
+    signer_info: &SignerInfo,
+) -> Result<&'a Certificate> {
+    let SignerIdentifier::IssuerAndSerialNumber(id) = &signer_info.sid else {
+        bail!("Signers identified by their subject key identifier are not supported");
+    };
+    certificates(signed_data)
+        .into_iter()
+        .find(|certificate| {
+            let tbs = &certificate.tbs_certificate;
+            tbs.issuer == id.issuer && tbs.serial_number == id.serial_number
+        })
+        .context("The signature does not contain the signing certificate")
+}
+
+/// Ensure that the certificate is the trusted certificate or is issued by it, possibly via the
+/// intermediate certificates.
+///
+/// The signature of every certificate in the chain is verified, not only the names.
+pub fn ensure_trusted(
+    certificate: &Certificate,
+    intermediates: &[&Certificate],
+    trusted: &Certificate,
+) -> Result<()> {
+    let mut certificate = certificate;
+    // Every certificate can appear at most once in a chain.
+    for _ in 0..=intermediates.len() {
+        if certificate == trusted {
+            return Ok(());
+        }
+        let issuer_name = &certificate.tbs_certificate.issuer;
+        if *issuer_name == trusted.tbs_certificate.subject {
+            return verify_certificate(certificate, trusted);
+        }
+        let issuer = intermediates
+            .iter()
+            .find(|issuer| {
+                issuer.tbs_certificate.subject == *issuer_name && **issuer != certificate
+            })
+            .with_context(|| {
+                format!(
+                    "The signing certificate is not issued by the trusted certificate {}",
+                    trusted.tbs_certificate.subject
+                )
+            })?;
+        verify_certificate(certificate, issuer)?;
+        certificate = issuer;
+    }
+    bail!("The certificate chain of the signature is circular")
+}
+
+fn verify_certificate(certificate: &Certificate, issuer: &Certificate) -> Result<()> {
+    let algorithm = certificate.signature_algorithm.oid;
+    if algorithm == ECDSA_WITH_SHA256 {
+        bail!(ECDSA_UNSUPPORTED);
+    }
+    if algorithm != SHA256_WITH_RSA_ENCRYPTION {
+        bail!("Unsupported certificate signature algorithm {algorithm}");
+    }
+    let signature = certificate
+        .signature
+        .as_bytes()
+        .context("The certificate signature is not byte-aligned")?;
+    verify_rsa(
+        &issuer.tbs_certificate.subject_public_key_info,
+        &certificate.tbs_certificate.to_der()?,
+        signature,
+    )
+    .with_context(|| {
+        format!(
+            "The certificate {} is not signed by {}",
+            certificate.tbs_certificate.subject, issuer.tbs_certificate.subject
+        )
+    })
+}
+
+fn verify_rsa(
+    public_key: &SubjectPublicKeyInfoOwned,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    if KeyType::of(public_key).is_ok_and(|key_type| key_type != KeyType::Rsa) {
+        bail!(ECDSA_UNSUPPORTED);
+    }
+    let public_key = RsaPublicKey::from_public_key_der(&public_key.to_der()?)
+        .context("Only RSA keys are supported")?;
+    let signature = Signature::try_from(signature).context("Invalid RSA signature")?;
+    VerifyingKey::<Sha256>::new(public_key)
+        .verify(message, &signature)
+        .context("Invalid RSA signature")
+}
+
+fn ensure_sha256(algorithm: &AlgorithmIdentifierOwned) -> Result<()> {
+    if algorithm.oid != SHA256 {
+        bail!("Unsupported digest algorithm {}", algorithm.oid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_malformed_signature() {
+        assert!(signer(b"not a signature").is_err());
+        assert!(signer(&[0x30, 0x03, 0x06, 0x01, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Checks of the signing key that run before anything is signed.
+//!
+//! A private key that does not belong to the certificate still produces a signature, but one
+//! the firmware rejects. This is only noticed at boot, so it is better caught upfront.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use der::asn1::ObjectIdentifier;
+use der::Encode;
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+use x509_cert::Certificate;
+
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const SECP256R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+const SECP384R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.34");
+
+/// The type of a key that can sign PE binaries for Secure Boot.
+///
+/// `sbsign` signs with whatever key OpenSSL can read. Only RSA keys are widely supported by
+/// firmware, but some firmware also accepts ECDSA keys on the NIST curves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Rsa,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl KeyType {
+    /// Determine the type of a public key, e.g. of a certificate.
+    pub fn of(public_key: &SubjectPublicKeyInfoOwned) -> Result<Self> {
+        let algorithm = &public_key.algorithm;
+        match algorithm.oid {
+            RSA_ENCRYPTION => Ok(Self::Rsa),
+            EC_PUBLIC_KEY => {
+                let curve = algorithm
+                    .parameters
+                    .as_ref()
+                    .and_then(|parameters| parameters.decode_as::<ObjectIdentifier>().ok())
+                    .context("The ECDSA key does not use a named curve")?;
+                match curve {
+                    SECP256R1 => Ok(Self::EcdsaP256),
+                    SECP384R1 => Ok(Self::EcdsaP384),
+                    curve => bail!(
+                        "Unsupported elliptic curve {curve}. Only ECDSA keys on the P-256 or P-384 curve are supported."
+                    ),
+                }
+            }
+            oid => bail!("Unsupported key type {oid}. Only RSA and ECDSA keys are supported."),
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Rsa => "RSA",
+            Self::EcdsaP256 => "ECDSA P-256",
+            Self::EcdsaP384 => "ECDSA P-384",
+        })
+    }
+}
+
+/// Assemble the arguments for `openssl pkey` to derive the DER encoded public key from the
+/// private key. Without a path, the private key is read from stdin.
+pub(super) fn openssl_pubout_args(private_key: Option<&Path>) -> Vec<OsString> {
+    let mut args = vec![OsString::from("pkey")];
+    if let Some(private_key) = private_key {
+        args.extend([OsString::from("-in"), private_key.into()]);
+    }
+    args.extend(["-pubout", "-outform", "DER"].map(OsString::from));
+    args
+}
+
+/// Ensure that the public key derived from the private key is the public key of the certificate
+/// and return its type.
+///
+/// The certificate may be followed by a chain of intermediate certificates.
+pub(super) fn ensure_matching_public_key(certificate: &Path, derived: &[u8]) -> Result<KeyType> {
+    let pem = std::fs::read(certificate)
+        .with_context(|| format!("Failed to read the certificate {certificate:?}"))?;
+    let public_key = Certificate::load_pem_chain(&pem)
+        .with_context(|| format!("Failed to parse the certificate {certificate:?}"))?
+        .into_iter()
+        .next()
+        .with_context(|| format!("{certificate:?} does not contain a certificate"))?
+        .tbs_certificate
+        .subject_public_key_info;
+
+    let key_type = KeyType::of(&public_key)
+        .with_context(|| format!("Cannot sign with the key of {certificate:?}"))?;
+    if public_key.to_der()? != derived {
+        bail!("The private key does not belong to the certificate {certificate:?}.");
+    }
+    Ok(key_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use der::asn1::BitString;
+    use der::DecodePem;
+    use x509_cert::spki::AlgorithmIdentifierOwned;
+
+    fn public_key(pem: &str) -> Result<SubjectPublicKeyInfoOwned> {
+        Ok(Certificate::from_pem(pem)?
+            .tbs_certificate
+            .subject_public_key_info)
+    }
+
+    #[test]
+    fn determine_key_type() -> Result<()> {
+        let rsa = public_key(include_str!(
+            "../../../systemd/tests/fixtures/uefi-keys/db.pem"
+        ))?;
+        assert_eq!(KeyType::of(&rsa)?, KeyType::Rsa);
+
+        let ecdsa = public_key(include_str!(
+            "../../../systemd/tests/fixtures/uefi-keys-ecdsa/db.pem"
+        ))?;
+        assert_eq!(KeyType::of(&ecdsa)?, KeyType::EcdsaP256);
+
+        let ed25519 = SubjectPublicKeyInfoOwned {
+            algorithm: AlgorithmIdentifierOwned {
+                oid: ObjectIdentifier::new_unwrap("1.3.101.112"),
+                parameters: None,
+            },
+            subject_public_key: BitString::from_bytes(&[0; 32])?,
+        };
+        assert!(KeyType::of(&ed25519).is_err());
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::pe::lanzaboote_image;
+use crate::pe::{self, lanzaboote_image};
 use crate::utils::SecureTempDirExt;
 use std::ffi::OsString;
 use std::io::Write;
@@ -8,6 +8,8 @@ use std::process::Command;
 use anyhow::{Context, Result};
 use tempfile::tempdir;
 
+use super::authenticode;
+use super::key::{ensure_matching_public_key, openssl_pubout_args, KeyType};
 use super::Signer;
 
 /// A local keypair is a signer that reuses private key material
@@ -24,6 +26,9 @@ use super::Signer;
 pub struct LocalKeyPair {
     pub private_key: PathBuf,
     pub public_key: PathBuf,
+    /// Intermediate certificates that are embedded into the signature so that the chain up to
+    /// the certificate enrolled in the db can be validated by the firmware.
+    pub cert_chain: Option<PathBuf>,
 }
 
 impl LocalKeyPair {
@@ -31,25 +36,70 @@ impl LocalKeyPair {
         Self {
             public_key: public_key.into(),
             private_key: private_key.into(),
+            cert_chain: None,
         }
     }
-}
 
-impl Signer for LocalKeyPair {
-    fn get_public_key(&self) -> Result<Vec<u8>> {
-        Ok(std::fs::read(&self.public_key)?)
+    pub fn with_cert_chain(mut self, cert_chain: Option<&Path>) -> Self {
+        self.cert_chain = cert_chain.map(Path::to_path_buf);
+        self
     }
 
-    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
-        let args: Vec<OsString> = vec![
+    /// Ensure that the private key belongs to the certificate and return the type of the key.
+    ///
+    /// This reads the private key with `openssl`, i.e. it supports the same keys as `sbsign`.
+    pub fn ensure_matching_keys(&self) -> Result<KeyType> {
+        let args = openssl_pubout_args(Some(&self.private_key));
+        let output = Command::new("openssl")
+            .args(&args)
+            .output()
+            .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+
+        if !output.status.success() {
+            log::debug!("openssl failed with args: `{args:?}`.");
+            return Err(anyhow::anyhow!(
+                "Failed to read the private key {:?}.",
+                self.private_key
+            ));
+        }
+
+        ensure_matching_public_key(&self.public_key, &output.stdout)
+    }
+
+    /// Assemble the arguments for `sbsign` to sign `from` and write the result to `to`.
+    pub(super) fn sbsign_args(&self, from: &Path, to: &Path) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
             OsString::from("--key"),
             self.private_key.clone().into(),
             OsString::from("--cert"),
             self.public_key.clone().into(),
+        ];
+        if let Some(cert_chain) = &self.cert_chain {
+            args.push(OsString::from("--addcert"));
+            args.push(cert_chain.clone().into());
+        }
+        args.extend([
             from.as_os_str().to_owned(),
             OsString::from("--output"),
             to.as_os_str().to_owned(),
-        ];
+        ]);
+        args
+    }
+}
+
+impl Signer for LocalKeyPair {
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        let mut public_key = std::fs::read(&self.public_key)?;
+        // The embedded chain is part of the signature. Thus, changing it needs to change the
+        // identity of the signer as well.
+        if let Some(cert_chain) = &self.cert_chain {
+            public_key.extend(std::fs::read(cert_chain)?);
+        }
+        Ok(public_key)
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let args = self.sbsign_args(from, to);
 
         let output = Command::new("sbsign")
             .args(&args)
@@ -95,24 +145,121 @@ impl Signer for LocalKeyPair {
     }
 
     fn verify_path(&self, path: &Path) -> Result<bool> {
-        let args: Vec<OsString> = vec![
-            OsString::from("--cert"),
-            self.public_key.clone().into(),
-            path.as_os_str().to_owned(),
-        ];
-
-        let output = Command::new("sbverify")
-            .args(&args)
-            .output()
-            .context("Failed to run sbverify. Most likely, the binary is not on PATH.")?;
+        verify_with_certificate(path, &self.public_key)
+    }
+}
 
-        if !output.status.success() {
-            if std::io::stderr().write_all(&output.stderr).is_err() {
-                return Ok(false);
-            };
-            log::debug!("sbverify failed with args: `{args:?}`.");
+/// Whether the PE binary at `path` carries a valid signature for the certificate.
+///
+/// Only the certificate is needed, e.g. to check a binary without access to the private key.
+/// Unsigned binaries and valid RSA signatures are recognized in-process. Only the others, e.g.
+/// ECDSA signatures, are verified with `sbverify`.
+pub fn verify_with_certificate(path: &Path, certificate: &Path) -> Result<bool> {
+    if let Ok(pe_binary) = std::fs::read(path) {
+        if pe::read_pkcs7_signature(&pe_binary).is_err() {
+            log::debug!("{path:?} is not signed.");
             return Ok(false);
         }
-        Ok(true)
+        if verify_offline(&pe_binary, certificate) {
+            return Ok(true);
+        }
+    }
+
+    let args: Vec<OsString> = vec![
+        OsString::from("--cert"),
+        certificate.as_os_str().to_owned(),
+        path.as_os_str().to_owned(),
+    ];
+
+    let output = Command::new("sbverify")
+        .args(&args)
+        .output()
+        .context("Failed to run sbverify. Most likely, the binary is not on PATH.")?;
+
+    if !output.status.success() {
+        if std::io::stderr().write_all(&output.stderr).is_err() {
+            return Ok(false);
+        };
+        log::debug!("sbverify failed with args: `{args:?}`.");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Whether the embedded signature of the PE binary is valid for the certificate, verified
+/// in-process.
+///
+/// This fails for signatures that can only be verified with `sbverify`, e.g. ECDSA ones.
+fn verify_offline(pe_binary: &[u8], certificate: &Path) -> bool {
+    let result = std::fs::read(certificate)
+        .context("Failed to read the certificate")
+        .and_then(|pem| authenticode::read_certificate(&pem))
+        .and_then(|certificate| authenticode::verify(pe_binary, None, &certificate));
+    if let Err(err) = &result {
+        log::debug!("Falling back to sbverify: {err:#}");
+    }
+    result.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(path: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures")
+            .join(path)
+    }
+
+    #[test]
+    fn match_ecdsa_keys() -> Result<()> {
+        let keypair = LocalKeyPair::new(
+            &fixture("uefi-keys-ecdsa/db.pem"),
+            &fixture("uefi-keys-ecdsa/db.key"),
+        );
+        assert_eq!(keypair.ensure_matching_keys()?, KeyType::EcdsaP256);
+
+        let mismatched = LocalKeyPair::new(
+            &fixture("uefi-keys/db.pem"),
+            &fixture("uefi-keys-ecdsa/db.key"),
+        );
+        assert!(mismatched.ensure_matching_keys().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_signed_and_unsigned_binaries() -> Result<()> {
+        let certificate = fixture("uefi-keys/db.pem");
+        assert!(verify_with_certificate(
+            &fixture("authenticode/signed.efi"),
+            &certificate
+        )?);
+        assert!(!verify_with_certificate(
+            &fixture("authenticode/unsigned.efi"),
+            &certificate
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn embed_cert_chain() {
+        let keypair = LocalKeyPair::new(Path::new("db.pem"), Path::new("db.key"))
+            .with_cert_chain(Some(Path::new("intermediate.pem")));
+        let args = keypair.sbsign_args(Path::new("in.efi"), Path::new("out.efi"));
+
+        assert_eq!(
+            args,
+            [
+                "--key",
+                "db.key",
+                "--cert",
+                "db.pem",
+                "--addcert",
+                "intermediate.pem",
+                "in.efi",
+                "--output",
+                "out.efi"
+            ]
+        );
     }
 }
@@ -55,4 +55,38 @@ pub trait Signer {
     }
 }
 
+/// A shared signer, e.g. one that several installations sign with.
+impl<S: Signer + ?Sized> Signer for &S {
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        (**self).sign_store_path(store_path)
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        (**self).build_and_sign_stub(stub)
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        (**self).get_public_key()
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        (**self).sign_and_copy(from, to)
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        (**self).verify(pe_binary)
+    }
+
+    fn verify_path(&self, from: &Path) -> Result<bool> {
+        (**self).verify_path(from)
+    }
+}
+
+pub mod audit;
+pub mod authenticode;
+pub mod key;
 pub mod local;
+pub mod pkcs11;
+mod secret;
+pub mod serialized;
+pub mod tpm;
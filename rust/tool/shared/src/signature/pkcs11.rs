@@ -0,0 +1,319 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use tempfile::tempdir;
+
+use super::key::{ensure_matching_public_key, KeyType};
+use super::local::LocalKeyPair;
+use super::Signer;
+use crate::pe::lanzaboote_image;
+
+/// The scheme of the URIs of objects on PKCS#11 tokens (RFC 7512).
+const PKCS11_SCHEME: &str = "pkcs11:";
+
+/// The attribute of a PKCS#11 URI that contains the PIN of the token in plain text.
+const PIN_VALUE: &str = "pin-value=";
+
+/// The environment variable the PKCS#11 engine of OpenSSL (libp11) reads the module from.
+const PKCS11_MODULE_PATH: &str = "PKCS11_MODULE_PATH";
+
+/// Whether the private key is a PKCS#11 URI, e.g. `pkcs11:token=YubiKey;object=db`, instead of a
+/// path.
+pub fn is_pkcs11_uri(private_key: &Path) -> bool {
+    private_key
+        .to_str()
+        .is_some_and(|private_key| private_key.starts_with(PKCS11_SCHEME))
+}
+
+/// A signer whose private key is stored on a hardware token, e.g. a YubiKey or an HSM.
+///
+/// The key never leaves the token. `sbsign` and `openssl` access it with the PKCS#11 engine of
+/// OpenSSL (libp11), which has to be installed and configured with the module of the token.
+///
+/// The URI is passed to `sbsign` and `openssl` on the command line, so it must not contain the
+/// PIN of the token. A `pin-source` file can be used instead.
+#[derive(Clone)]
+pub struct Pkcs11KeyPair {
+    /// The PKCS#11 URI of the private key, e.g. `pkcs11:token=YubiKey;object=db`.
+    uri: String,
+    /// The PKCS#11 module of the token, e.g. `libykcs11.so`. Without it, the engine uses the
+    /// module it is configured with.
+    pub module: Option<PathBuf>,
+    /// The keypair used to call `sbsign`. Its private key is the URI.
+    keypair: LocalKeyPair,
+}
+
+impl Pkcs11KeyPair {
+    /// Sign with the key at `uri`, which belongs to the certificate at `public_key`.
+    ///
+    /// This fails if the URI contains a `pin-value`, which would be visible in the process list.
+    pub fn new(public_key: &Path, uri: &str) -> Result<Self> {
+        if attributes(uri).any(|attribute| attribute.starts_with(PIN_VALUE)) {
+            bail!(
+                "The PKCS#11 URI {} contains the PIN of the token, which would be visible to other processes. Read it from a file with pin-source instead.",
+                redact_pin(uri)
+            );
+        }
+        Ok(Self {
+            uri: uri.into(),
+            module: None,
+            keypair: LocalKeyPair::new(public_key, Path::new(uri)),
+        })
+    }
+
+    pub fn with_cert_chain(mut self, cert_chain: Option<&Path>) -> Self {
+        self.keypair = self.keypair.with_cert_chain(cert_chain);
+        self
+    }
+
+    pub fn with_module(mut self, module: Option<&Path>) -> Self {
+        self.module = module.map(Path::to_path_buf);
+        self
+    }
+
+    /// The URI of the private key without the PIN, e.g. for messages.
+    pub fn redacted_uri(&self) -> String {
+        redact_pin(&self.uri)
+    }
+
+    /// Prepare a command that loads the PKCS#11 engine with the module of the token.
+    fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        if let Some(module) = &self.module {
+            command.env(PKCS11_MODULE_PATH, module);
+        }
+        command
+    }
+
+    /// Assemble the arguments for `sbsign` to sign `from` with the key on the token and write the
+    /// result to `to`.
+    fn sbsign_args(&self, from: &Path, to: &Path) -> Vec<OsString> {
+        let mut args = vec![OsString::from("--engine"), OsString::from("pkcs11")];
+        args.extend(self.keypair.sbsign_args(from, to));
+        args
+    }
+
+    /// Assemble the arguments for `openssl pkey` to derive the DER encoded public key from the key
+    /// on the token.
+    fn openssl_pubout_args(&self) -> Vec<OsString> {
+        ["pkey", "-engine", "pkcs11", "-inform", "engine", "-in"]
+            .map(OsString::from)
+            .into_iter()
+            .chain([OsString::from(&self.uri)])
+            .chain(["-pubout", "-outform", "DER"].map(OsString::from))
+            .collect()
+    }
+
+    /// Ensure that the key on the token belongs to the certificate and return the type of the key.
+    ///
+    /// This also fails if the PKCS#11 engine cannot be loaded or the token does not hold the
+    /// object. The token might ask for its PIN.
+    pub fn ensure_matching_keys(&self) -> Result<KeyType> {
+        let args = self.openssl_pubout_args();
+        let output = self
+            .command("openssl")
+            .args(&args)
+            .output()
+            .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+
+        if !output.status.success() {
+            log::debug!("openssl failed with args: `{:?}`.", self.redact_args(&args));
+            return Err(anyhow::anyhow!(
+                "Failed to load the private key {} from the PKCS#11 token. Check that the PKCS#11 engine of OpenSSL (libp11) is installed and that the token holds the object: {}",
+                self.redacted_uri(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        ensure_matching_public_key(&self.keypair.public_key, &output.stdout)
+    }
+
+    /// Replace the URI in the arguments of a command with the redacted URI.
+    fn redact_args(&self, args: &[OsString]) -> Vec<OsString> {
+        args.iter()
+            .map(|arg| match arg.to_str() {
+                Some(arg) if arg == self.uri => self.redacted_uri().into(),
+                _ => arg.clone(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for Pkcs11KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11KeyPair")
+            .field("uri", &self.redacted_uri())
+            .field("module", &self.module)
+            .field("public_key", &self.keypair.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The attributes of a PKCS#11 URI, e.g. `token=YubiKey` and `pin-source=file:/run/pin`.
+fn attributes(uri: &str) -> impl Iterator<Item = &str> {
+    uri.strip_prefix(PKCS11_SCHEME)
+        .unwrap_or(uri)
+        .split([';', '?', '&'])
+}
+
+/// Replace the value of the `pin-value` attribute of a PKCS#11 URI.
+fn redact_pin(uri: &str) -> String {
+    let (scheme, attributes) = match uri.strip_prefix(PKCS11_SCHEME) {
+        Some(attributes) => (PKCS11_SCHEME, attributes),
+        None => ("", uri),
+    };
+    let mut redacted = scheme.to_string();
+    for attribute in attributes.split_inclusive([';', '?', '&']) {
+        if attribute.starts_with(PIN_VALUE) {
+            redacted.push_str(PIN_VALUE);
+            redacted.push_str("***");
+            redacted.extend(
+                attribute
+                    .chars()
+                    .last()
+                    .filter(|c| [';', '?', '&'].contains(c)),
+            );
+        } else {
+            redacted.push_str(attribute);
+        }
+    }
+    redacted
+}
+
+impl Signer for Pkcs11KeyPair {
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        self.keypair.get_public_key()
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let args = self.sbsign_args(from, to);
+
+        let output = self
+            .command("sbsign")
+            .args(&args)
+            .output()
+            .context("Failed to run sbsign. Most likely, the binary is not on PATH.")?;
+
+        if !output.status.success() {
+            std::io::stderr()
+                .write_all(&output.stderr)
+                .context("Failed to write output of sbsign to stderr.")?;
+            log::debug!("sbsign failed with args: `{:?}`.", self.redact_args(&args));
+            return Err(anyhow::anyhow!(
+                "Failed to sign {to:?} with the PKCS#11 key {}.",
+                self.redacted_uri()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        let working_tree = tempdir()?;
+        let to = &working_tree.path().join("signed.efi");
+        self.sign_and_copy(store_path, to)?;
+
+        Ok(std::fs::read(to)?)
+    }
+
+    fn build_and_sign_stub(&self, stub: &crate::pe::StubParameters) -> Result<Vec<u8>> {
+        let working_tree = tempdir()?;
+        let lzbt_image_path =
+            lanzaboote_image(&working_tree, stub).context("Failed to build a lanzaboote image")?;
+        let to = working_tree.path().join("signed-stub.efi");
+        self.sign_and_copy(&lzbt_image_path, &to)?;
+
+        std::fs::read(&to).context("Failed to read a lanzaboote image")
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        self.keypair.verify(pe_binary)
+    }
+
+    fn verify_path(&self, path: &Path) -> Result<bool> {
+        self.keypair.verify_path(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "pkcs11:token=YubiKey;object=db;type=private";
+
+    #[test]
+    fn detect_pkcs11_uri() {
+        assert!(is_pkcs11_uri(Path::new(URI)));
+        assert!(!is_pkcs11_uri(Path::new("/var/lib/sbctl/keys/db/db.key")));
+        assert!(!is_pkcs11_uri(Path::new("./pkcs11:db.key")));
+    }
+
+    #[test]
+    fn sign_with_pkcs11_engine() {
+        let keypair = Pkcs11KeyPair::new(Path::new("db.pem"), URI).unwrap();
+
+        assert_eq!(
+            keypair.sbsign_args(Path::new("in.efi"), Path::new("out.efi")),
+            [
+                "--engine", "pkcs11", "--key", URI, "--cert", "db.pem", "in.efi", "--output",
+                "out.efi"
+            ]
+        );
+        assert_eq!(
+            keypair.openssl_pubout_args(),
+            [
+                "pkey", "-engine", "pkcs11", "-inform", "engine", "-in", URI, "-pubout",
+                "-outform", "DER"
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_pin_value() {
+        let uri = "pkcs11:token=YubiKey;object=db?pin-value=123456&module-name=ykcs11";
+        let err = Pkcs11KeyPair::new(Path::new("db.pem"), uri).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("pin-value=***&module-name"), "{message}");
+        assert!(!message.contains("123456"), "{message}");
+
+        assert_eq!(
+            redact_pin("pkcs11:pin-value=123456;object=db"),
+            "pkcs11:pin-value=***;object=db"
+        );
+        let uri = "pkcs11:token=YubiKey;object=db?pin-source=file:/run/keys/pin";
+        assert_eq!(redact_pin(uri), uri);
+        assert!(Pkcs11KeyPair::new(Path::new("db.pem"), uri).is_ok());
+    }
+
+    #[test]
+    fn redact_debug_output() {
+        let keypair = Pkcs11KeyPair {
+            uri: "pkcs11:object=db?pin-value=123456".into(),
+            module: None,
+            keypair: LocalKeyPair::new(Path::new("db.pem"), Path::new("pkcs11:object=db")),
+        };
+        let debug = format!("{keypair:?}");
+        assert!(!debug.contains("123456"), "{debug}");
+        assert!(debug.contains("pin-value=***"), "{debug}");
+    }
+
+    #[test]
+    fn load_pkcs11_module() {
+        let keypair = Pkcs11KeyPair::new(Path::new("db.pem"), URI).unwrap();
+        assert_eq!(keypair.command("sbsign").get_envs().count(), 0);
+
+        let module = Path::new("/run/current-system/sw/lib/libykcs11.so");
+        let keypair = keypair.with_module(Some(module));
+        let command = keypair.command("sbsign");
+
+        assert_eq!(command.get_program(), "sbsign");
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            [(PKCS11_MODULE_PATH.as_ref(), Some(module.as_os_str()))]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+use zeroize::Zeroizing;
+
+/// Secret material, e.g. a passphrase or a private key.
+///
+/// The bytes are wiped from memory when they are dropped. They are never printed: `Debug` is
+/// redacted and there is no `Display`. Thus, they cannot end up in logs or error messages by
+/// accident.
+pub struct SecretBytes(Zeroizing<Vec<u8>>);
+
+impl SecretBytes {
+    /// Access the secret, e.g. to pass it to a command.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(<redacted>)")
+    }
+}
+
+/// The maximum size of a secret read from a command.
+///
+/// This comfortably fits a PEM encoded RSA 4096 private key.
+const MAX_SECRET_SIZE: usize = 64 * 1024;
+
+/// The result of a command that writes a secret to stdout.
+#[derive(Debug)]
+pub struct SecretOutput {
+    pub status: ExitStatus,
+    pub stdout: SecretBytes,
+}
+
+/// Run `command`, write `stdin` to its stdin and read the secret it writes to stdout.
+///
+/// Unlike [`Command::output`], stdout is read into a buffer that is never reallocated. Growing a
+/// buffer leaves a copy of its previous contents in freed memory that is not wiped. stderr is
+/// inherited.
+pub fn secret_output(
+    command: &mut Command,
+    stdin: Option<&SecretBytes>,
+) -> io::Result<SecretOutput> {
+    let mut child = command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    if let Some(input) = stdin {
+        // Dropping stdin closes it so that the command stops reading.
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(input.expose())?;
+    }
+    let stdout = read_secret(child.stdout.take().expect("stdout is piped"));
+    // Always wait for the command so that it does not become a zombie.
+    let status = child.wait()?;
+
+    Ok(SecretOutput {
+        status,
+        stdout: stdout?,
+    })
+}
+
+/// Run `command` and write `secret` to its stdin.
+pub fn run_with_secret_stdin(command: &mut Command, secret: &SecretBytes) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Dropping stdin closes it so that the command stops reading.
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(secret.expose())?;
+
+    child.wait_with_output()
+}
+
+/// Read a secret of at most [`MAX_SECRET_SIZE`] bytes.
+fn read_secret(mut reader: impl Read) -> io::Result<SecretBytes> {
+    // Allocate the whole buffer upfront because it must never grow.
+    let mut secret = Zeroizing::new(vec![0; MAX_SECRET_SIZE]);
+    let mut len = 0;
+    loop {
+        if len == secret.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The secret is larger than {MAX_SECRET_SIZE} bytes."),
+            ));
+        }
+        match reader.read(&mut secret[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    // Truncating never reallocates.
+    secret.truncate(len);
+    Ok(SecretBytes(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, Result};
+    use zeroize::Zeroize;
+
+    #[test]
+    fn read_secret_from_stdout() -> io::Result<()> {
+        let passphrase = SecretBytes::from(b"passphrase".to_vec());
+        let output = secret_output(Command::new("cat").arg("-"), Some(&passphrase))?;
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout.expose(), b"passphrase");
+        Ok(())
+    }
+
+    #[test]
+    fn redact_secret() {
+        let secret = SecretBytes::from(b"hunter2".to_vec());
+
+        let debug = format!("{secret:?}");
+        assert!(!debug.contains("hunter2"));
+        // The bytes are not printed as numbers either.
+        assert!(!debug.contains("104"));
+        assert_eq!(debug, "SecretBytes(<redacted>)");
+    }
+
+    #[test]
+    fn keep_secret_out_of_errors() -> Result<()> {
+        let passphrase = SecretBytes::from(b"hunter2".to_vec());
+        let error = secret_output(&mut Command::new("/nonexistent/command"), Some(&passphrase))
+            .with_context(|| format!("Failed to run the command with {passphrase:?}"))
+            .unwrap_err();
+
+        assert!(!format!("{error:?}").contains("hunter2"));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_too_large_secret() {
+        let secret = vec![b'a'; MAX_SECRET_SIZE + 1];
+        assert!(read_secret(&secret[..]).is_err());
+    }
+
+    #[test]
+    fn clear_whole_secret_buffer() -> io::Result<()> {
+        let mut secret = read_secret(&b"passphrase"[..])?.0;
+        let (pointer, capacity) = (secret.as_ptr(), secret.capacity());
+
+        // This is what happens when the secret is dropped.
+        secret.zeroize();
+
+        assert_eq!(secret.capacity(), capacity);
+        // SAFETY: The allocation is still alive and zeroize wrote to all of it.
+        let buffer = unsafe { std::slice::from_raw_parts(pointer, capacity) };
+        assert!(buffer.iter().all(|&byte| byte == 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::Result;
+
+use super::Signer;
+use crate::pe::StubParameters;
+
+/// A signer that runs at most one signing operation of the signer it wraps at a time.
+///
+/// It can be shared by installations that run concurrently, e.g. to several ESPs, even if the
+/// wrapped signer supports only one operation at a time, like a key sealed in the TPM.
+/// Verifying does not use the key and is not serialized.
+pub struct SerializedSigner<S> {
+    signer: S,
+    lock: Mutex<()>,
+}
+
+impl<S: Signer> SerializedSigner<S> {
+    pub fn new(signer: S) -> Self {
+        Self {
+            signer,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn serialized<T>(&self, operation: impl FnOnce(&S) -> Result<T>) -> Result<T> {
+        // The lock guards no data, so it is fine to continue if another thread panicked.
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        operation(&self.signer)
+    }
+}
+
+impl<S: Signer> Signer for SerializedSigner<S> {
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        self.serialized(|signer| signer.sign_store_path(store_path))
+    }
+
+    fn build_and_sign_stub(&self, stub: &StubParameters) -> Result<Vec<u8>> {
+        self.serialized(|signer| signer.build_and_sign_stub(stub))
+    }
+
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        self.serialized(|signer| signer.get_public_key())
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.serialized(|signer| signer.sign_and_copy(from, to))
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        self.signer.verify(pe_binary)
+    }
+
+    fn verify_path(&self, from: &Path) -> Result<bool> {
+        self.signer.verify_path(from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Fails if it is asked to sign while it is already signing.
+    #[derive(Default)]
+    struct ExclusiveSigner {
+        signing: AtomicBool,
+    }
+
+    impl Signer for ExclusiveSigner {
+        fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+            if self.signing.swap(true, Ordering::SeqCst) {
+                bail!("Already signing");
+            }
+            thread::sleep(Duration::from_millis(10));
+            self.signing.store(false, Ordering::SeqCst);
+            Ok(store_path.as_os_str().as_encoded_bytes().to_vec())
+        }
+
+        fn build_and_sign_stub(&self, _stub: &StubParameters) -> Result<Vec<u8>> {
+            bail!("Not implemented")
+        }
+
+        fn get_public_key(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn verify(&self, _pe_binary: &[u8]) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn never_sign_concurrently() -> Result<()> {
+        let signer = SerializedSigner::new(ExclusiveSigner::default());
+        thread::scope(|scope| {
+            let handles = (0..4)
+                .map(|_| scope.spawn(|| signer.sign_store_path(Path::new("/nix/store/stub"))))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("Signing panicked").map(|_| ()))
+        })
+    }
+}
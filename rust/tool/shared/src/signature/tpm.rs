@@ -0,0 +1,243 @@
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+use super::key::{ensure_matching_public_key, openssl_pubout_args, KeyType};
+use super::local::LocalKeyPair;
+use super::secret::{run_with_secret_stdin, secret_output, SecretBytes};
+use super::Signer;
+use crate::pe::lanzaboote_image;
+
+/// A signer whose private key is encrypted with a passphrase that is sealed to the TPM.
+///
+/// Sealed objects can only hold 128 bytes, which is not enough for a private key. Thus, the TPM
+/// seals the passphrase of an encrypted private key instead. For every signature, the passphrase
+/// is unsealed with `tpm2_unseal` from tpm2-tools, which only succeeds if the PCRs match the
+/// policy it was sealed with. `openssl pkey` decrypts the private key with it. The passphrase and
+/// the decrypted key are only kept in memory and passed via stdin, so the key never touches the
+/// disk in cleartext.
+#[derive(Debug, Clone)]
+pub struct TpmSealedKeyPair {
+    /// The encrypted private key (PEM).
+    pub private_key: PathBuf,
+    /// The sealed passphrase, e.g. a persistent handle like `0x81000001` or a context file. This
+    /// is passed to `tpm2_unseal --object-context`.
+    pub sealed_passphrase: String,
+    /// The PCR policy the key was sealed with, e.g. `sha256:0,7`.
+    pub pcr_policy: String,
+    /// The keypair used to call `sbsign`. Its private key is stdin.
+    keypair: LocalKeyPair,
+}
+
+impl TpmSealedKeyPair {
+    pub fn new(
+        public_key: &Path,
+        private_key: &Path,
+        sealed_passphrase: &str,
+        pcr_policy: &str,
+    ) -> Self {
+        Self {
+            private_key: private_key.into(),
+            sealed_passphrase: sealed_passphrase.into(),
+            pcr_policy: pcr_policy.into(),
+            keypair: LocalKeyPair::new(public_key, Path::new("/dev/stdin")),
+        }
+    }
+
+    pub fn with_cert_chain(mut self, cert_chain: Option<&Path>) -> Self {
+        self.keypair = self.keypair.with_cert_chain(cert_chain);
+        self
+    }
+
+    /// Assemble the arguments for `tpm2_unseal` to write the unsealed passphrase to stdout.
+    fn tpm2_unseal_args(&self) -> Vec<OsString> {
+        vec![
+            OsString::from("--object-context"),
+            OsString::from(&self.sealed_passphrase),
+            OsString::from("--auth"),
+            OsString::from(format!("pcr:{}", self.pcr_policy)),
+        ]
+    }
+
+    /// Assemble the arguments for `openssl pkey` to decrypt the private key with the passphrase
+    /// from stdin and write it to stdout.
+    fn openssl_pkey_args(&self) -> Vec<OsString> {
+        vec![
+            OsString::from("pkey"),
+            OsString::from("-in"),
+            self.private_key.clone().into(),
+            OsString::from("-passin"),
+            OsString::from("stdin"),
+        ]
+    }
+
+    /// Ensure that the sealed private key belongs to the certificate and return the type of the
+    /// key.
+    ///
+    /// This unseals the passphrase like signing does, so it also fails if the PCRs do not match.
+    pub fn ensure_matching_keys(&self) -> Result<KeyType> {
+        let key = self.unseal()?;
+        let args = openssl_pubout_args(None);
+        let output = run_with_secret_stdin(Command::new("openssl").args(&args), &key)
+            .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+        drop(key);
+
+        if !output.status.success() {
+            log::debug!("openssl failed with args: `{args:?}`.");
+            return Err(anyhow::anyhow!(
+                "Failed to read the private key {:?}.",
+                self.private_key
+            ));
+        }
+
+        ensure_matching_public_key(&self.keypair.public_key, &output.stdout)
+    }
+
+    /// Unseal the passphrase from the TPM and decrypt the private key with it.
+    fn unseal(&self) -> Result<SecretBytes> {
+        let args = self.tpm2_unseal_args();
+
+        let output = secret_output(Command::new("tpm2_unseal").args(&args), None)
+            .context("Failed to run tpm2_unseal. Most likely, the binary is not on PATH.")?;
+        let passphrase = output.stdout;
+
+        if !output.status.success() {
+            log::debug!("tpm2_unseal failed with args: `{args:?}`.");
+            return Err(anyhow::anyhow!(
+                "Failed to unseal the passphrase from the TPM. The PCRs might not match the policy."
+            ));
+        }
+
+        let args = self.openssl_pkey_args();
+        let output = secret_output(Command::new("openssl").args(&args), Some(&passphrase))
+            .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+        drop(passphrase);
+        let key = output.stdout;
+
+        if !output.status.success() {
+            log::debug!("openssl failed with args: `{args:?}`.");
+            return Err(anyhow::anyhow!(
+                "Failed to decrypt the private key {:?}.",
+                self.private_key
+            ));
+        }
+
+        Ok(key)
+    }
+}
+
+impl Signer for TpmSealedKeyPair {
+    fn get_public_key(&self) -> Result<Vec<u8>> {
+        self.keypair.get_public_key()
+    }
+
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let key = self.unseal()?;
+        let args = self.keypair.sbsign_args(from, to);
+
+        let output = run_with_secret_stdin(Command::new("sbsign").args(&args), &key)
+            .context("Failed to run sbsign. Most likely, the binary is not on PATH.")?;
+        drop(key);
+
+        if !output.status.success() {
+            std::io::stderr()
+                .write_all(&output.stderr)
+                .context("Failed to write output of sbsign to stderr.")?;
+            log::debug!("sbsign failed with args: `{args:?}`.");
+            return Err(anyhow::anyhow!("Failed to sign {to:?}."));
+        }
+
+        Ok(())
+    }
+
+    fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
+        let working_tree = tempdir()?;
+        let to = &working_tree.path().join("signed.efi");
+        self.sign_and_copy(store_path, to)?;
+
+        Ok(std::fs::read(to)?)
+    }
+
+    fn build_and_sign_stub(&self, stub: &crate::pe::StubParameters) -> Result<Vec<u8>> {
+        let working_tree = tempdir()?;
+        let lzbt_image_path =
+            lanzaboote_image(&working_tree, stub).context("Failed to build a lanzaboote image")?;
+        let to = working_tree.path().join("signed-stub.efi");
+        self.sign_and_copy(&lzbt_image_path, &to)?;
+
+        std::fs::read(&to).context("Failed to read a lanzaboote image")
+    }
+
+    fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
+        self.keypair.verify(pe_binary)
+    }
+
+    fn verify_path(&self, path: &Path) -> Result<bool> {
+        self.keypair.verify_path(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unseal_with_pcr_policy() {
+        let keypair = TpmSealedKeyPair::new(
+            Path::new("db.pem"),
+            Path::new("db.key"),
+            "0x81000001",
+            "sha256:0,7",
+        );
+
+        assert_eq!(
+            keypair.tpm2_unseal_args(),
+            ["--object-context", "0x81000001", "--auth", "pcr:sha256:0,7"]
+        );
+    }
+
+    #[test]
+    fn decrypt_private_key_with_passphrase_from_stdin() {
+        let keypair = TpmSealedKeyPair::new(
+            Path::new("db.pem"),
+            Path::new("db.key"),
+            "0x81000001",
+            "sha256:0,7",
+        );
+
+        assert_eq!(
+            keypair.openssl_pkey_args(),
+            ["pkey", "-in", "db.key", "-passin", "stdin"]
+        );
+    }
+
+    #[test]
+    fn pass_private_key_via_stdin() {
+        let keypair = TpmSealedKeyPair::new(
+            Path::new("db.pem"),
+            Path::new("db.key"),
+            "0x81000001",
+            "sha256:0,7",
+        );
+        let args = keypair
+            .keypair
+            .sbsign_args(Path::new("in.efi"), Path::new("out.efi"));
+
+        assert_eq!(
+            args,
+            [
+                "--key",
+                "/dev/stdin",
+                "--cert",
+                "db.pem",
+                "in.efi",
+                "--output",
+                "out.efi"
+            ]
+        );
+    }
+}
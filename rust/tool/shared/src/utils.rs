@@ -1,6 +1,6 @@
 use std::ffi::OsString;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::iter::repeat_with;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
@@ -16,6 +16,7 @@ const TEMPFILE_RANDOM_LENGTH: usize = 32;
 pub trait SecureTempDirExt {
     fn create_secure_file(&self, path: &Path) -> Result<fs::File>;
     fn write_secure_file(&self, contents: impl AsRef<[u8]>) -> Result<PathBuf>;
+    fn copy_secure_file(&self, from: &Path) -> Result<PathBuf>;
 }
 
 /// This implementation has three useful properties:
@@ -52,6 +53,20 @@ impl SecureTempDirExt for TempDir {
 
         Ok(path)
     }
+
+    /// Create a temporary file and copy the contents of a file to it.
+    ///
+    /// The file is streamed, so that large files, e.g. an initrd, are never fully in memory.
+    fn copy_secure_file(&self, from: &Path) -> Result<PathBuf> {
+        let path = self.path().join(tmpname());
+        let mut tmpfile = self.create_secure_file(&path)?;
+        let mut file = fs::File::open(from).with_context(|| format!("Failed to open {from:?}"))?;
+
+        io::copy(&mut file, &mut tmpfile)
+            .with_context(|| format!("Failed to copy {from:?} to tempfile {path:?}"))?;
+
+        Ok(path)
+    }
 }
 
 /// Generate a random (but not cryptographically secure) name for a temporary file.
@@ -67,11 +82,35 @@ pub fn tmpname() -> OsString {
     buf
 }
 
-type Hash = sha2::digest::Output<Sha256>;
+pub type Hash = sha2::digest::Output<Sha256>;
 
 /// Compute the SHA 256 hash of a file.
+///
+/// The file is streamed through a fixed buffer instead of being read into memory, because kernels,
+/// initrds and UKIs can be hundreds of MiB large.
 pub fn file_hash(file: &Path) -> Result<Hash> {
-    Ok(Sha256::digest(fs::read(file).with_context(|| {
-        format!("Failed to read file to hash: {file:?}")
-    })?))
+    let mut hasher = Sha256::new();
+    fs::File::open(file)
+        .and_then(|mut file| io::copy(&mut file, &mut hasher))
+        .with_context(|| format!("Failed to read file to hash: {file:?}"))?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_file_larger_than_buffer() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let contents = (0..100_000u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        let file = tempdir.write_secure_file(&contents)?;
+        assert_eq!(file_hash(&file)?, Sha256::digest(&contents));
+
+        let copy = tempdir.copy_secure_file(&file)?;
+        assert_eq!(fs::read(copy)?, contents);
+        Ok(())
+    }
 }
@@ -0,0 +1,106 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use nix::sys::resource::{getrusage, UsageWho};
+use tempfile::TempDir;
+
+use lanzaboote_tool::pe::{self, lanzaboote_image};
+use lanzaboote_tool::signature::Signer;
+use lanzaboote_tool::utils::{file_hash, tmpname, SecureTempDirExt};
+
+/// The phases of building a stub that are timed separately.
+const PHASES: [&str; 4] = ["read", "checksum", "assemble", "sign"];
+
+/// Time building and signing a stub from synthetic inputs of the given sizes.
+///
+/// Every phase is run `iterations` times and the fastest and the mean duration of each phase is
+/// printed, followed by the peak memory use of the whole run.
+pub fn bench(
+    lanzaboote_stub: &Path,
+    signer: &impl Signer,
+    kernel_size: usize,
+    initrd_size: usize,
+    iterations: u32,
+) -> Result<()> {
+    let inputs = TempDir::new().context("Failed to create temporary directory.")?;
+    // The contents do not matter, only their size does.
+    let kernel = synthetic_input(&inputs, 0xa5, kernel_size)?;
+    let initrd = synthetic_input(&inputs, 0x5a, initrd_size)?;
+
+    // The stub only references the kernel and initrd on the ESP, so a fake ESP is enough.
+    let esp = inputs.path().join("esp");
+    let kernel_target = esp.join("EFI/nixos/kernel.efi");
+    let initrd_target = esp.join("EFI/nixos/initrd.efi");
+
+    let mut timings = vec![Vec::new(); PHASES.len()];
+    for _ in 0..iterations {
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+
+        let start = Instant::now();
+        read(&kernel).context("Failed to read the kernel.")?;
+        read(&initrd).context("Failed to read the initrd.")?;
+        timings[0].push(start.elapsed());
+
+        let start = Instant::now();
+        file_hash(&kernel)?;
+        file_hash(&initrd)?;
+        timings[1].push(start.elapsed());
+
+        let start = Instant::now();
+        let parameters = pe::StubParameters::new(
+            lanzaboote_stub,
+            &kernel,
+            &kernel_target,
+            Some((&initrd, &initrd_target)),
+            &esp,
+        )?
+        .with_cmdline(&[String::from("init=/init")])
+        .with_os_release_contents(b"ID=lanzaboote\n");
+        let image = lanzaboote_image(&tempdir, &parameters)
+            .context("Failed to build lanzaboote stub image.")?;
+        timings[2].push(start.elapsed());
+
+        let start = Instant::now();
+        signer
+            .sign_and_copy(&image, &tempdir.path().join("signed.efi"))
+            .context("Failed to sign the Lanzaboote stub.")?;
+        timings[3].push(start.elapsed());
+    }
+
+    println!("kernel: {kernel_size} bytes, initrd: {initrd_size} bytes, iterations: {iterations}");
+    println!("{:<10} {:>12} {:>12}", "phase", "min (ms)", "mean (ms)");
+    for (phase, durations) in PHASES.iter().zip(&timings) {
+        let min = durations.iter().min().copied().unwrap_or_default();
+        let mean = durations.iter().sum::<Duration>() / iterations;
+        println!(
+            "{:<10} {:>12.3} {:>12.3}",
+            phase,
+            min.as_secs_f64() * 1000.0,
+            mean.as_secs_f64() * 1000.0
+        );
+    }
+
+    // On Linux, the maximum resident set size is reported in KiB.
+    let usage = getrusage(UsageWho::RUSAGE_SELF).context("Failed to get the resource usage.")?;
+    println!("peak RSS: {:.1} MiB", usage.max_rss() as f64 / 1024.0);
+
+    Ok(())
+}
+
+/// Write a file of `size` bytes that all have the value `byte`, without holding it in memory.
+fn synthetic_input(tempdir: &TempDir, byte: u8, size: usize) -> Result<PathBuf> {
+    let path = tempdir.path().join(tmpname());
+    let mut file = tempdir.create_secure_file(&path)?;
+    io::copy(&mut io::repeat(byte).take(size as u64), &mut file)
+        .with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(path)
+}
+
+/// Read a file through a fixed buffer, like the hashing does.
+fn read(path: &Path) -> Result<()> {
+    io::copy(&mut fs::File::open(path)?, &mut io::sink())?;
+    Ok(())
+}
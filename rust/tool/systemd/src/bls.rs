@@ -0,0 +1,236 @@
+use std::fmt;
+use std::iter;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/// A Boot Loader Specification Type #1 entry.
+///
+/// Lanzaboote only boots signed images. Thus, the entry either uses the `efi` key to chainload
+/// the signed Lanzaboote stub of the generation or references a kernel that is signed on its own.
+///
+/// See https://uapi-group.org/specifications/specs/boot_loader_specification/#type-1-boot-loader-entry-keys
+pub struct BlsEntry {
+    pub title: String,
+    pub version: String,
+    pub sort_key: String,
+    /// The machine ID of the installation, i.e. 32 lowercase hexadecimal characters.
+    pub machine_id: Option<String>,
+    pub boot: BlsBoot,
+    pub options: Vec<String>,
+}
+
+/// What a `BlsEntry` boots. All paths are relative to the root of the ESP, using `/` as
+/// separator.
+pub enum BlsBoot {
+    /// Chainload an EFI program, i.e. the signed Lanzaboote stub.
+    Efi(String),
+    /// Boot a signed kernel via its EFI stub with an initrd, which is not verified, if the
+    /// generation has one.
+    Linux {
+        linux: String,
+        initrd: Option<String>,
+    },
+}
+
+impl BlsBoot {
+    fn values(&self) -> Vec<&String> {
+        match self {
+            BlsBoot::Efi(efi) => vec![efi],
+            BlsBoot::Linux { linux, initrd } => iter::once(linux).chain(initrd).collect(),
+        }
+    }
+}
+
+impl BlsEntry {
+    /// Ensure that every value fits on its line of the entry.
+    ///
+    /// A value is the rest of the line after the key, so spaces need no escaping, but a line break
+    /// would end the value and start a new key.
+    pub fn validate(&self) -> Result<()> {
+        let values = [&self.title, &self.version, &self.sort_key]
+            .into_iter()
+            .chain(self.boot.values())
+            .chain(self.machine_id.as_ref())
+            .chain(&self.options);
+        for value in values {
+            if value.contains(['\n', '\r']) {
+                bail!("The boot loader entry value {value:?} contains a line break");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Display a BlsEntry in the format of a `loader/entries/*.conf` file.
+impl fmt::Display for BlsEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "title {}", self.title)?;
+        writeln!(f, "version {}", self.version)?;
+        writeln!(f, "sort-key {}", self.sort_key)?;
+        if let Some(machine_id) = &self.machine_id {
+            writeln!(f, "machine-id {}", machine_id)?;
+        }
+        match &self.boot {
+            // The stub ignores the options when Secure Boot is active and boots with the command
+            // line embedded in its signed `.cmdline` section instead. They are written anyway so
+            // that BLS-aware tools can display them.
+            BlsBoot::Efi(efi) => writeln!(f, "efi {}", efi)?,
+            BlsBoot::Linux { linux, initrd } => {
+                writeln!(f, "linux {}", linux)?;
+                if let Some(initrd) = initrd {
+                    writeln!(f, "initrd {}", initrd)?;
+                }
+            }
+        }
+        writeln!(f, "options {}", self.options.join(" "))?;
+        Ok(())
+    }
+}
+
+/// Check that a machine ID is formatted as in `machine-id(5)`.
+pub fn validate_machine_id(machine_id: &str) -> Result<()> {
+    if machine_id.len() != 32
+        || !machine_id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        bail!("Invalid machine ID {machine_id:?}: expected 32 lowercase hexadecimal characters");
+    }
+    Ok(())
+}
+
+/// Convert a path to a BLS path relative to the specified ESP.
+///
+/// BLS paths are absolute paths from the root of the partition and use `/` as separator.
+pub fn esp_relative_path(esp: &Path, path: &Path) -> Result<String> {
+    let relative_path = path
+        .strip_prefix(esp)
+        .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
+    let relative_path = relative_path
+        .to_str()
+        .with_context(|| format!("Failed to convert {:?} to a BLS path", relative_path))?;
+    Ok(format!("/{relative_path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_entry() {
+        let entry = BlsEntry {
+            title: String::from("LanzaOS"),
+            version: String::from("Generation 1, 1970-01-01"),
+            sort_key: String::from("lanza"),
+            machine_id: None,
+            boot: BlsBoot::Efi(String::from("/EFI/Linux/nixos-generation-1-abc.efi")),
+            options: vec![String::from("init=/init"), String::from("quiet")],
+        };
+
+        assert_eq!(
+            entry.to_string(),
+            "title LanzaOS\n\
+             version Generation 1, 1970-01-01\n\
+             sort-key lanza\n\
+             efi /EFI/Linux/nixos-generation-1-abc.efi\n\
+             options init=/init quiet\n"
+        );
+    }
+
+    #[test]
+    fn render_entry_booting_signed_kernel() {
+        let entry = BlsEntry {
+            title: String::from("LanzaOS"),
+            version: String::from("Generation 1, 1970-01-01"),
+            sort_key: String::from("lanza"),
+            machine_id: None,
+            boot: BlsBoot::Linux {
+                linux: String::from("/EFI/nixos/kernel-6.1.1-abc.efi"),
+                initrd: Some(String::from("/EFI/nixos/initrd-6.1.1-def.efi")),
+            },
+            options: vec![String::from("init=/init")],
+        };
+
+        assert!(entry.to_string().ends_with(
+            "sort-key lanza\n\
+             linux /EFI/nixos/kernel-6.1.1-abc.efi\n\
+             initrd /EFI/nixos/initrd-6.1.1-def.efi\n\
+             options init=/init\n"
+        ));
+    }
+
+    #[test]
+    fn render_entry_without_initrd() {
+        let entry = BlsEntry {
+            title: String::from("LanzaOS"),
+            version: String::from("Generation 1, 1970-01-01"),
+            sort_key: String::from("lanza"),
+            machine_id: None,
+            boot: BlsBoot::Linux {
+                linux: String::from("/EFI/nixos/kernel-6.1.1-abc.efi"),
+                initrd: None,
+            },
+            options: vec![String::from("init=/init")],
+        };
+
+        assert!(entry.to_string().ends_with(
+            "sort-key lanza\n\
+             linux /EFI/nixos/kernel-6.1.1-abc.efi\n\
+             options init=/init\n"
+        ));
+    }
+
+    #[test]
+    fn render_entry_with_machine_id() {
+        let entry = BlsEntry {
+            title: String::from("LanzaOS"),
+            version: String::from("Generation 1, 1970-01-01"),
+            sort_key: String::from("lanza"),
+            machine_id: Some(String::from("0123456789abcdef0123456789abcdef")),
+            boot: BlsBoot::Efi(String::from("/EFI/Linux/nixos-generation-1-abc.efi")),
+            options: vec![],
+        };
+
+        assert!(entry
+            .to_string()
+            .contains("sort-key lanza\nmachine-id 0123456789abcdef0123456789abcdef\n"));
+    }
+
+    #[test]
+    fn reject_line_breaks_in_entry() {
+        let entry = BlsEntry {
+            title: String::from("LanzaOS\nefi /EFI/other.efi"),
+            version: String::from("Generation 1, 1970-01-01"),
+            sort_key: String::from("lanza"),
+            machine_id: None,
+            boot: BlsBoot::Efi(String::from("/EFI/Linux/nixos-generation-1-abc.efi")),
+            options: vec![],
+        };
+        assert!(entry.validate().is_err());
+    }
+
+    #[test]
+    fn check_machine_id() {
+        assert!(validate_machine_id("0123456789abcdef0123456789abcdef").is_ok());
+        assert!(validate_machine_id("0123456789ABCDEF0123456789ABCDEF").is_err());
+        assert!(validate_machine_id("0123456789abcdef").is_err());
+    }
+
+    #[test]
+    fn convert_to_esp_relative_path() {
+        let esp = Path::new("esp");
+        let path = Path::new("esp/EFI/Linux/nixos-generation-1.efi");
+        let converted_path = esp_relative_path(esp, path).unwrap();
+        assert_eq!(converted_path, "/EFI/Linux/nixos-generation-1.efi");
+    }
+
+    #[test]
+    fn convert_esp_relative_path_with_spaces() {
+        // The mount point of the ESP is not part of the path.
+        let esp = Path::new("/mnt/my esp (backup)");
+        let path = Path::new("/mnt/my esp (backup)/EFI/Linux/nixos-generation-1.efi");
+        let converted_path = esp_relative_path(esp, path).unwrap();
+        assert_eq!(converted_path, "/EFI/Linux/nixos-generation-1.efi");
+    }
+}
@@ -1,10 +1,38 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
 
-use crate::install;
-use lanzaboote_tool::{architecture::Architecture, signature::local::LocalKeyPair};
+use crate::cmdline_map::CmdlineMap;
+use crate::config::Config;
+use crate::esp::SystemdEspPaths;
+use crate::esp_device::MountedEsp;
+use crate::kernel_install::KernelInstallEntries;
+#[cfg(feature = "test-boot")]
+use crate::test_boot::TestBoot;
+use crate::{
+    bench, diff, drift, dump_bootspec, enrolled, inspect, install, keys, sizes, snapshot,
+    store_refs, uki, verify,
+};
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::esp_state;
+use lanzaboote_tool::hash_cache::HashCache;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::pe::AdditionalSection;
+use lanzaboote_tool::sbat::Sbat;
+use lanzaboote_tool::signature::{
+    audit::AuditedSigner,
+    authenticode,
+    local::LocalKeyPair,
+    pkcs11::{self, Pkcs11KeyPair},
+    serialized::SerializedSigner,
+    tpm::TpmSealedKeyPair,
+    Signer,
+};
 
 /// The default log level.
 ///
@@ -25,42 +53,595 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Install(InstallCommand),
+    Install(Box<InstallCommand>),
+    /// List the sections of a stub or extract one of them
+    Inspect(InspectCommand),
+    /// Verify the embedded or a detached signature of PE binaries
+    Verify(VerifyCommand),
+    /// Build, sign and install a stub from an explicit kernel and initrd without a bootspec
+    BuildUki(BuildUkiCommand),
+    /// Generate a self-signed key and certificate (db.key and db.pem) for Secure Boot
+    GenerateKeys(GenerateKeysCommand),
+    /// Print the estimated ESP usage of the generations that would be installed
+    Sizes(SizesCommand),
+    /// Print the store paths only referenced by generations beyond the configuration limit
+    PruneStoreRefs(PruneStoreRefsCommand),
+    /// Check that the installed stubs of a system match its kernel and initrd
+    CheckDrift(CheckDriftCommand),
+    /// Report whether the signing certificate is enrolled in the db of the firmware and list the
+    /// enrolled certificates
+    Enrolled(EnrolledCommand),
+    /// Print the bootspec of a generation as parsed by Lanzaboote as JSON
+    DumpBootspec(DumpBootspecCommand),
+    /// Print what changed between the boot artifacts of two generations
+    Diff(DiffCommand),
+    /// Put the files of a snapshot taken by `install --snapshot` back on the ESP
+    RestoreSnapshot(RestoreSnapshotCommand),
+    /// Print the generations and the boot loader installed on the ESP as JSON
+    Status(StatusCommand),
+    /// Time assembling and signing a stub from synthetic inputs
+    #[command(hide = true)]
+    Bench(BenchCommand),
 }
 
 #[derive(Parser)]
 struct InstallCommand {
+    /// JSON file with settings, e.g. written by the NixOS module. Flags take precedence over it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// System for lanzaboote binaries, e.g. defines the EFI fallback path
     #[arg(long)]
-    system: String,
+    system: Option<String>,
 
     /// Systemd path
     #[arg(long)]
-    systemd: PathBuf,
+    systemd: Option<PathBuf>,
 
     /// Systemd-boot loader config
     #[arg(long)]
-    systemd_boot_loader_config: PathBuf,
+    systemd_boot_loader_config: Option<PathBuf>,
 
     /// sbsign Public Key
     #[arg(long)]
     public_key: Option<PathBuf>,
 
-    /// sbsign Private Key
+    /// sbsign Private Key, or the PKCS#11 URI of a key on a hardware token, e.g.
+    /// pkcs11:token=YubiKey;object=db
     #[arg(long)]
     private_key: Option<PathBuf>,
 
-    /// Configuration limit
-    #[arg(long, default_value_t = 1)]
-    configuration_limit: usize,
+    /// PKCS#11 module of the token that holds the --private-key, e.g. libykcs11.so. Defaults to
+    /// the module the PKCS#11 engine of OpenSSL is configured with
+    #[arg(long)]
+    pkcs11_module: Option<PathBuf>,
+
+    /// TPM object (e.g. a persistent handle) the passphrase of the encrypted --private-key is
+    /// sealed in
+    #[arg(long, requires = "tpm_pcr_policy")]
+    tpm_sealed_passphrase: Option<String>,
+
+    /// PCR policy the passphrase is sealed with, e.g. sha256:0,7
+    #[arg(long, requires = "tpm_sealed_passphrase")]
+    tpm_pcr_policy: Option<String>,
+
+    /// Abort unless the --public-key or an issuer of it, possibly via the --cert-chain, is enrolled
+    /// in the db of the firmware. This only works on the machine that boots the ESP
+    #[arg(long)]
+    require_enrolled: bool,
+
+    /// Directory with the EFI variables to read the db from for --require-enrolled
+    #[arg(long, hide = true, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+
+    /// Intermediate certificates to embed into the signatures (PEM)
+    #[arg(long)]
+    cert_chain: Option<PathBuf>,
+
+    /// Configuration limit [default: 1]
+    #[arg(long)]
+    configuration_limit: Option<usize>,
+
+    /// Also keep the generations beyond the configuration limit that were built within this
+    /// duration, e.g. 30d. Units are s, m, h, d and w
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    keep_since: Option<Duration>,
+
+    /// Do not garbage collect files on the ESP that were modified within this duration, e.g. 7d,
+    /// even if no installed generation uses them. Units are s, m, h, d and w
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    keep_modified_since: Option<Duration>,
+
+    /// Order in which the generations are installed. Installation stops at the first failing
+    /// generation, so newest is the safest order for production
+    #[arg(long, value_enum, default_value_t = install::InstallOrder::Newest)]
+    install_order: install::InstallOrder,
+
+    /// How the generations are booted: via the Lanzaboote stub or directly via the EFI stub of
+    /// the signed kernel with a boot loader entry, in which the initrd and the kernel command line
+    /// are not verified
+    #[arg(long, value_enum, default_value_t = install::BootMode::Stub)]
+    boot_mode: install::BootMode,
+
+    /// What to do if the booted generation is beyond the configuration limit: abort, install it
+    /// anyway or remove it from the ESP
+    #[arg(long, value_enum, default_value_t = install::RunningGenerationPolicy::Keep)]
+    running_generation_policy: install::RunningGenerationPolicy,
+
+    /// Only install the newest of consecutive generations that point to the same toplevel, e.g.
+    /// after rebuilding without changes
+    #[arg(long)]
+    collapse_identical: bool,
+
+    /// Maximum size in MiB the generations may use on the ESP. The oldest generations are
+    /// dropped until the estimated size fits. The newest generation is always installed
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    esp_budget: Option<u64>,
+
+    /// Drop the oldest generations until the estimated size fits into the free space of the ESP
+    /// and the space already used by Lanzaboote. The newest and the booted generation are always
+    /// installed
+    #[arg(long)]
+    fit_esp: bool,
+
+    /// Free space in MiB to keep on the ESP, e.g. for the random seed of systemd-boot and
+    /// future updates. The installation is refused if it would leave less. With --fit-esp, the
+    /// oldest generations are dropped instead
+    #[arg(long)]
+    esp_reserve: Option<u64>,
+
+    /// The running system, whose generation is never dropped to fit into the ESP
+    #[arg(long, hide = true, default_value = "/run/booted-system")]
+    booted_system: PathBuf,
+
+    /// Boot the stubs via Boot Loader Specification Type #1 entries in loader/entries. The stubs
+    /// are installed to EFI/nixos instead of EFI/Linux, so that systemd-boot lists them only once
+    #[arg(long)]
+    bls_entries: bool,
+
+    /// Sign the next generation while the previous one is copied to the ESP. Signing itself is
+    /// never run concurrently, so this also works with signers that support only one operation at
+    /// a time
+    #[arg(long)]
+    parallel_copy: bool,
+
+    /// Also write the PKCS#7 signature of every signed image to <name>.p7s in this directory
+    #[arg(long)]
+    detached_signatures: Option<PathBuf>,
+
+    /// Append a JSON record of every signing operation to this file, including failed ones. A
+    /// record contains the fingerprint of the certificate and the digests of the input and the
+    /// output, but no secret material
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// JSON file mapping generation numbers to kernel command line overrides
+    #[arg(long)]
+    cmdline_map: Option<PathBuf>,
+
+    /// os-release to embed into all stubs instead of generating one from the bootspec
+    #[arg(long)]
+    os_release: Option<PathBuf>,
+
+    /// SBAT CSV to embed verbatim into the .sbat section of all stubs, e.g. to test revocation
+    #[arg(long)]
+    sbat: Option<PathBuf>,
+
+    /// Embed SBAT metadata of Lanzaboote with this generation into the .sbat section of all
+    /// stubs, so that shim can revoke stubs with a lower generation
+    #[arg(long, value_name = "GENERATION", conflicts_with = "sbat")]
+    sbat_level: Option<u32>,
+
+    /// Embed the file at PATH verbatim as section NAME into all stubs, e.g. `.dtb=./board.dtb`
+    /// for a section Lanzaboote does not support yet. It is covered by the signature. Can be
+    /// repeated
+    #[arg(long = "add-section", value_name = "NAME=PATH", value_parser = parse_section)]
+    additional_sections: Vec<(String, PathBuf)>,
+
+    /// Embed the generation, profile and specialisation as JSON into a .lzmeta section of every
+    /// stub, so that `inspect` can tell where a stub came from without the profile links
+    #[arg(long)]
+    embed_metadata: bool,
+
+    /// Never garbage collect this file on the ESP, e.g. EFI/nixos/custom.efi. Relative paths are
+    /// relative to the ESP. Only files in the directories Lanzaboote collects garbage in can be
+    /// affected. Can be repeated
+    #[arg(long = "gc-root", value_name = "PATH")]
+    gc_roots: Vec<PathBuf>,
+
+    /// File with garbage collection roots like --gc-root, one per line. Empty lines and lines
+    /// starting with # are ignored
+    #[arg(long)]
+    gc_roots_file: Option<PathBuf>,
+
+    /// Write a manifest of the installed files to this path on the ESP, e.g.
+    /// EFI/nixos-signed/manifest.json. It lists the signed and the unsigned files with their
+    /// digests, so that an auditor can check every signed file. The files themselves stay at the
+    /// paths the firmware expects, as FAT has no symlinks
+    #[arg(long)]
+    signed_manifest: Option<PathBuf>,
+
+    /// File to cache the digests of kernels and initrds in, e.g.
+    /// /var/cache/lanzaboote/hashes.json. A digest is reused until the size or the modification
+    /// time of its file changes
+    #[arg(long)]
+    hash_cache: Option<PathBuf>,
+
+    /// Only print the changes the installation would make to the ESP and exit with code 4 if
+    /// there are any. Nothing is written or signed, e.g. to check in CI that the ESP is up to date
+    #[arg(long)]
+    compare_with_installed: bool,
+
+    /// Boot this generation or specialisation by default instead of the newest generation, e.g.
+    /// 42 or 42:debug. It is written as the `default` key to loader.conf and has to be among the
+    /// installed generations
+    #[arg(long = "default", value_name = "GENERATION[:SPECIALISATION]", value_parser = parse_default_entry)]
+    default_entry: Option<install::DefaultEntry>,
+
+    /// Before installing, copy the files Lanzaboote manages on the ESP into a new snapshot in this
+    /// directory, e.g. /var/lib/lanzaboote/snapshots. `restore-snapshot` puts them back, e.g. from
+    /// a rescue system if the installation left the machine unbootable. Snapshots are never
+    /// removed automatically
+    #[arg(long, value_name = "DIR")]
+    snapshot: Option<PathBuf>,
+
+    /// Check that the files of installed generations are intact and correctly signed and repair
+    /// those that are not. Intact generations are still skipped, so this is cheap enough to run
+    /// periodically, e.g. from a timer. The installed files are always read again, even with
+    /// --hash-cache
+    #[arg(long)]
+    reconcile: bool,
+
+    /// Sign all generations and systemd-boot again, even if they are already installed and
+    /// validly signed
+    #[arg(long)]
+    force: bool,
+
+    /// Print the outcome as JSON, e.g. `{"changed": false}` when the ESP was already up to date
+    #[arg(long)]
+    json: bool,
+
+    /// Template for the sort key of the boot entries, e.g. "{sort_key}-{specialisation}". It is
+    /// embedded as IMAGE_ID with the generation as IMAGE_VERSION, so that systemd-boot sorts by
+    /// it and then shows the newest generation first
+    #[arg(long)]
+    sort_key_template: Option<String>,
+
+    /// Kernel command line for all generations instead of the one from the bootspec. It has to
+    /// contain init=
+    #[arg(long)]
+    cmdline: Option<String>,
+
+    /// File name of the removable media fallback in EFI/BOOT instead of the default for the
+    /// architecture (e.g. BOOTX64.EFI)
+    #[arg(long, value_parser = parse_file_name)]
+    efi_fallback_filename: Option<String>,
+
+    /// Do not install systemd-boot as the removable media fallback, e.g. to keep the fallback of
+    /// another OS on a shared ESP
+    #[arg(long, conflicts_with = "efi_fallback_filename")]
+    no_efi_fallback: bool,
+
+    /// Also garbage collect files of kernel-install that use the same names as Lanzaboote's
+    /// files, i.e. when its entry token in /etc/kernel/entry-token starts with nixos, and all
+    /// unused files in EFI/nixos, not only the kernels and initrds Lanzaboote creates there
+    #[arg(long)]
+    take_over: bool,
+
+    /// Boot every newly assembled stub in a QEMU VM and only install it if the marker appears on
+    /// the serial console. The kernel command line has to contain e.g. console=ttyS0
+    #[cfg(feature = "test-boot")]
+    #[arg(long, requires_all = ["test_boot_firmware", "test_boot_firmware_vars"])]
+    test_boot: bool,
+
+    /// UEFI firmware code for --test-boot, e.g. OVMF_CODE.fd
+    #[cfg(feature = "test-boot")]
+    #[arg(long)]
+    test_boot_firmware: Option<PathBuf>,
+
+    /// UEFI variable store for --test-boot, e.g. OVMF_VARS.fd. It needs Secure Boot enabled and
+    /// the signing certificate enrolled for Secure Boot to be tested
+    #[cfg(feature = "test-boot")]
+    #[arg(long)]
+    test_boot_firmware_vars: Option<PathBuf>,
+
+    /// Seconds to wait for the marker during --test-boot
+    #[cfg(feature = "test-boot")]
+    #[arg(long, default_value_t = 120)]
+    test_boot_timeout: u64,
+
+    /// Text on the serial console that signals a successful --test-boot
+    #[cfg(feature = "test-boot")]
+    #[arg(long, default_value = "<<< NixOS Stage 1 >>>")]
+    test_boot_marker: String,
+
+    /// Also install to the ESP mounted at this path, e.g. a mirrored ESP on a second disk. All
+    /// ESPs are installed to concurrently and independently of each other. Can be repeated
+    #[arg(long = "mirror-esp", value_name = "PATH")]
+    mirror_esps: Vec<PathBuf>,
+
+    /// Fail if the installation to any ESP fails. By default, it is enough if the installation to
+    /// one of the ESPs succeeds
+    #[arg(long, requires = "mirror_esps")]
+    require_all_esps: bool,
+
+    /// Permission bits (octal) of files created on the ESP. Only meaningful if the ESP is not FAT
+    #[arg(long, default_value = "755", value_parser = parse_mode)]
+    esp_file_mode: u32,
+
+    /// Permission bits (octal) of directories created on the ESP. Only meaningful if the ESP is
+    /// not FAT
+    #[arg(long, default_value = "755", value_parser = parse_mode)]
+    esp_dir_mode: u32,
+
+    /// Device of an ESP that is not mounted, e.g. /dev/sda1. It is mounted at a temporary
+    /// mountpoint for the installation and unmounted afterwards. Refused if the device does not
+    /// contain a FAT file system. Replaces the ESP mountpoint argument
+    #[arg(long, value_name = "DEVICE")]
+    esp_device: Option<PathBuf>,
 
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(required_unless_present = "esp_device")]
+    esp: Option<PathBuf>,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct InspectCommand {
+    /// Write the contents of this section (e.g. .cmdline) instead of listing all sections
+    #[arg(long)]
+    extract: Option<String>,
+
+    /// File to write the extracted section to instead of stdout
+    #[arg(long, requires = "extract")]
+    output: Option<PathBuf>,
+
+    /// Certificate to verify the signature with
+    #[arg(long, conflicts_with = "extract")]
+    public_key: Option<PathBuf>,
+
+    /// Output format of the section list
+    #[arg(long, value_enum, default_value_t = inspect::Format::Text, conflicts_with = "extract")]
+    format: inspect::Format,
+
+    /// PE binary to inspect, e.g. an installed stub
+    file: PathBuf,
+}
+
+#[derive(Parser)]
+struct VerifyCommand {
+    /// Certificate to verify the signature with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Detached PKCS#7 signature (e.g. a .p7s written by --detached-signatures) to verify instead
+    /// of the embedded one
+    #[arg(long)]
+    detached: Option<PathBuf>,
+
+    /// Print the result for every file as JSON, e.g. for monitoring
+    #[arg(long)]
+    json: bool,
+
+    /// Verify in-process without sbverify, sbattach and openssl, e.g. from a rescue shell. Only
+    /// RSA signatures over SHA-256 digests are supported, not ECDSA signatures
+    #[arg(long)]
+    offline: bool,
+
+    /// Verify the stubs, the kernels booted by loader entries, systemd-boot and the EFI fallback
+    /// installed on this ESP instead of explicit files
+    #[arg(long, requires = "system", conflicts_with_all = ["files", "detached"])]
+    esp: Option<PathBuf>,
+
+    /// System for lanzaboote binaries, e.g. defines the path of systemd-boot on the ESP
+    #[arg(long)]
+    system: Option<String>,
+
+    /// PE binaries to verify, e.g. the installed stubs
+    #[arg(required_unless_present = "esp")]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct BuildUkiCommand {
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key, or the PKCS#11 URI of a key on a hardware token
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// PKCS#11 module of the token that holds the --private-key
+    #[arg(long)]
+    pkcs11_module: Option<PathBuf>,
+
+    /// Lanzaboote stub to assemble the image from
+    #[arg(long)]
+    stub: PathBuf,
+
+    /// Kernel image
+    #[arg(long)]
+    kernel: PathBuf,
+
+    /// Initrd. Without it, the image boots the kernel without an initrd
+    #[arg(long)]
+    initrd: Option<PathBuf>,
+
+    /// Kernel command line
+    #[arg(long, default_value = "")]
+    cmdline: String,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long)]
     esp: PathBuf,
 
+    /// Path of the signed image on the ESP. The kernel and initrd are installed next to it
+    #[arg(long)]
+    output: PathBuf,
+
+    /// Append a JSON record of the signing operation to this file
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// SBAT CSV to embed verbatim into the .sbat section of the image
+    #[arg(long)]
+    sbat: Option<PathBuf>,
+
+    /// Embed SBAT metadata of Lanzaboote with this generation into the .sbat section of the image
+    #[arg(long, value_name = "GENERATION", conflicts_with = "sbat")]
+    sbat_level: Option<u32>,
+
+    /// Assemble the unsigned image twice and fail with the first differing offset and section
+    /// if the builds are not identical, e.g. to find a section with nondeterministic data
+    #[arg(long)]
+    check_reproducible: bool,
+}
+
+#[derive(Parser)]
+struct GenerateKeysCommand {
+    /// Directory to write db.key and db.pem to
+    #[arg(long)]
+    out_dir: PathBuf,
+
+    /// Common name (CN) of the certificate
+    #[arg(long, default_value = "Lanzaboote Secure Boot Signing Key")]
+    common_name: String,
+
+    /// Number of days the certificate is valid
+    #[arg(long, default_value_t = 3650, value_parser = clap::value_parser!(u32).range(1..))]
+    validity_days: u32,
+
+    /// Overwrite existing keys
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Parser)]
+struct SizesCommand {
+    /// Configuration limit of the installation, 0 means no limit
+    #[arg(long, default_value_t = 1)]
+    configuration_limit: usize,
+
+    /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
+    generations: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+struct PruneStoreRefsCommand {
+    /// Configuration limit of the installation
+    #[arg(long, default_value_t = 1)]
+    configuration_limit: usize,
+
     /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
     generations: Vec<PathBuf>,
 }
 
+#[derive(Parser)]
+struct CheckDriftCommand {
+    /// EFI system partition mountpoint (e.g. /boot)
+    esp: PathBuf,
+
+    /// Toplevel of the system to check
+    #[arg(long, default_value = "/run/current-system")]
+    system: PathBuf,
+}
+
+#[derive(Parser)]
+struct EnrolledCommand {
+    /// Certificate the files are signed with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Directory with the EFI variables to read the db and the dbx from
+    #[arg(long, hide = true, default_value = "/sys/firmware/efi/efivars")]
+    efivars: PathBuf,
+}
+
+#[derive(Parser)]
+struct DumpBootspecCommand {
+    /// Version of the generation
+    #[arg(long)]
+    generation: u64,
+
+    /// Directory with the generation links of the system profile
+    #[arg(long, default_value = "/nix/var/nix/profiles")]
+    profile_dir: PathBuf,
+}
+
+#[derive(Parser)]
+struct DiffCommand {
+    /// Version of the old generation, e.g. the last one that booted
+    old: u64,
+
+    /// Version of the new generation
+    new: u64,
+
+    /// Directory with the generation links of the system profile
+    #[arg(long, default_value = "/nix/var/nix/profiles")]
+    profile_dir: PathBuf,
+
+    /// Also compare the sections of the stubs installed on this ESP
+    #[arg(long)]
+    esp: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct RestoreSnapshotCommand {
+    /// Directory the snapshot was saved in, i.e. the argument of `install --snapshot`
+    #[arg(long)]
+    snapshots: PathBuf,
+
+    /// ID of the snapshot, as printed by `install --snapshot`
+    id: String,
+
+    /// EFI system partition mountpoint (e.g. /boot)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct StatusCommand {
+    /// System for lanzaboote binaries, e.g. defines the path of systemd-boot
+    #[arg(long)]
+    system: String,
+
+    /// Certificate to verify the signatures against. Without it, they are only reported as signed
+    #[arg(long)]
+    public_key: Option<PathBuf>,
+
+    /// Path of the signed manifest on the ESP, as passed to `install --signed-manifest`
+    #[arg(long)]
+    signed_manifest: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. /boot)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct BenchCommand {
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// Size of the synthetic kernel in bytes
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    kernel_size: usize,
+
+    /// Size of the synthetic initrd in bytes
+    #[arg(long, default_value_t = 32 * 1024 * 1024)]
+    initrd_size: usize,
+
+    /// Number of times every phase is run
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+}
+
 impl Cli {
     pub fn call(self, module: &str) {
         stderrlog::new()
@@ -73,6 +654,12 @@ impl Cli {
 
         if let Err(e) = self.commands.call() {
             log::error!("{e:#}");
+            if e.is::<install::NoGenerations>() {
+                std::process::exit(install::NO_GENERATIONS_EXIT_CODE);
+            }
+            if e.is::<install::NotUpToDate>() {
+                std::process::exit(install::NOT_UP_TO_DATE_EXIT_CODE);
+            }
             std::process::exit(1);
         };
     }
@@ -81,29 +668,608 @@ impl Cli {
 impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
-            Commands::Install(args) => install(args),
+            Commands::Install(args) => install(*args),
+            Commands::Inspect(args) => inspect(args),
+            Commands::Verify(args) => verify(args),
+            Commands::BuildUki(args) => build_uki(args),
+            Commands::GenerateKeys(args) => generate_keys(args),
+            Commands::Sizes(args) => sizes(args),
+            Commands::PruneStoreRefs(args) => prune_store_refs(args),
+            Commands::CheckDrift(args) => drift::check_drift(&args.esp, &args.system),
+            Commands::Enrolled(args) => enrolled::report_enrolled(&args.public_key, &args.efivars),
+            Commands::DumpBootspec(args) => {
+                dump_bootspec::dump_bootspec(&args.profile_dir, args.generation)
+            }
+            Commands::Diff(args) => {
+                diff::diff(&args.profile_dir, args.esp.as_deref(), args.old, args.new)
+            }
+            Commands::RestoreSnapshot(args) => {
+                snapshot::restore(&args.snapshots, &args.id, &args.esp)
+            }
+            Commands::Status(args) => status(args),
+            Commands::Bench(args) => bench(args),
+        }
+    }
+}
+
+impl InstallCommand {
+    /// Fill in the settings from the config file that are not set on the command line.
+    fn apply_config(&mut self, config: Config) {
+        self.system = self.system.take().or(config.system);
+        self.systemd = self.systemd.take().or(config.systemd);
+        self.systemd_boot_loader_config = self
+            .systemd_boot_loader_config
+            .take()
+            .or(config.systemd_boot_loader_config);
+        self.public_key = self.public_key.take().or(config.public_key);
+        self.private_key = self.private_key.take().or(config.private_key);
+        self.configuration_limit = self.configuration_limit.or(config.configuration_limit);
+    }
+
+    /// Without the ESP mountpoint, the first positional argument is a generation link.
+    fn shift_positional_args(&mut self) {
+        if self.esp_device.is_some() {
+            if let Some(generation) = self.esp.take() {
+                self.generations.insert(0, generation);
+            }
         }
     }
 }
 
-fn install(args: InstallCommand) -> Result<()> {
+fn install(mut args: InstallCommand) -> Result<()> {
     let lanzaboote_stub =
         std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
 
-    let local_signer = LocalKeyPair::new(
-        &args.public_key.expect("Failed to obtain public key"),
-        &args.private_key.expect("Failed to obtain private key"),
+    if let Some(config) = &args.config {
+        let config = Config::from_path(config)?;
+        args.apply_config(config);
+    }
+    args.shift_positional_args();
+
+    let public_key = required(args.public_key.clone(), "public-key")?;
+    let private_key = required(args.private_key.clone(), "private-key")?;
+
+    if args.require_enrolled {
+        enrolled::ensure_enrolled(&public_key, args.cert_chain.as_deref(), &args.efivars)?;
+    }
+
+    if pkcs11::is_pkcs11_uri(&private_key) {
+        if args.tpm_sealed_passphrase.is_some() {
+            bail!(
+                "A key on a PKCS#11 token cannot be combined with a passphrase sealed to the TPM."
+            );
+        }
+        let pkcs11_signer = Pkcs11KeyPair::new(&public_key, &private_key.to_string_lossy())?
+            .with_module(args.pkcs11_module.as_deref())
+            .with_cert_chain(args.cert_chain.as_deref());
+        let key_type = pkcs11_signer.ensure_matching_keys()?;
+        log::debug!(
+            "Signing with the {key_type} key {} of {public_key:?}.",
+            pkcs11_signer.redacted_uri()
+        );
+        return install_with_audit_log(args, lanzaboote_stub, pkcs11_signer, "pkcs11");
+    }
+    ensure_no_pkcs11_module(args.pkcs11_module.as_deref())?;
+
+    match (&args.tpm_sealed_passphrase, &args.tpm_pcr_policy) {
+        (Some(sealed_passphrase), Some(pcr_policy)) => {
+            let tpm_signer =
+                TpmSealedKeyPair::new(&public_key, &private_key, sealed_passphrase, pcr_policy)
+                    .with_cert_chain(args.cert_chain.as_deref());
+            let key_type = tpm_signer.ensure_matching_keys()?;
+            log::debug!("Signing with the {key_type} key of {public_key:?}.");
+            install_with_audit_log(args, lanzaboote_stub, tpm_signer, "tpm")
+        }
+        _ => {
+            let local_signer = LocalKeyPair::new(&public_key, &private_key)
+                .with_cert_chain(args.cert_chain.as_deref());
+            let key_type = local_signer.ensure_matching_keys()?;
+            log::debug!("Signing with the {key_type} key of {public_key:?}.");
+            install_with_audit_log(args, lanzaboote_stub, local_signer, "file")
+        }
+    }
+}
+
+/// Read the SBAT metadata for the `.sbat` section from a CSV file or generate the metadata of
+/// Lanzaboote with the given generation.
+fn read_sbat(sbat: Option<&Path>, sbat_level: Option<u32>) -> Result<Option<Sbat>> {
+    sbat.map(|path| {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the SBAT metadata: {path:?}"))?;
+        Sbat::from_str_strict(&contents)
+            .with_context(|| format!("Failed to parse the SBAT metadata: {path:?}"))
+    })
+    .or_else(|| sbat_level.map(Sbat::lanzaboote))
+    .transpose()
+}
+
+/// A PKCS#11 module is only used to sign with a key on a token. Fail instead of silently signing
+/// with a key file.
+fn ensure_no_pkcs11_module(pkcs11_module: Option<&Path>) -> Result<()> {
+    if let Some(module) = pkcs11_module {
+        bail!("--pkcs11-module {module:?} requires a PKCS#11 URI as --private-key.");
+    }
+    Ok(())
+}
+
+/// Record every signing operation of the signer if there is an audit log.
+fn install_with_audit_log<S: Signer + Sync>(
+    args: InstallCommand,
+    lanzaboote_stub: String,
+    signer: S,
+    key_source: &'static str,
+) -> Result<()> {
+    match args.audit_log.clone() {
+        Some(audit_log) => {
+            let signer = AuditedSigner::new(signer, key_source, &audit_log)?;
+            install_with_signer(args, lanzaboote_stub, signer)
+        }
+        None => install_with_signer(args, lanzaboote_stub, signer),
+    }
+}
+
+fn install_with_signer<S: Signer + Sync>(
+    args: InstallCommand,
+    lanzaboote_stub: String,
+    signer: S,
+) -> Result<()> {
+    let cmdline_map = args
+        .cmdline_map
+        .as_deref()
+        .map(CmdlineMap::from_path)
+        .transpose()?;
+
+    let os_release = args
+        .os_release
+        .as_deref()
+        .map(|path| {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the os-release: {path:?}"))?;
+            OsRelease::from_str_strict(&contents)
+                .with_context(|| format!("Failed to parse the os-release: {path:?}"))
+        })
+        .transpose()?;
+
+    let sbat = read_sbat(args.sbat.as_deref(), args.sbat_level)?;
+
+    AdditionalSection::ensure_unique_names(
+        args.additional_sections
+            .iter()
+            .map(|(name, _)| name.as_str()),
+    )?;
+    let additional_sections = args
+        .additional_sections
+        .iter()
+        .map(|(name, path)| {
+            let contents = fs::read(path)
+                .with_context(|| format!("Failed to read the section {name}: {path:?}"))?;
+            AdditionalSection::new(name, contents)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut pinned_gc_roots = args.gc_roots;
+    if let Some(gc_roots_file) = &args.gc_roots_file {
+        pinned_gc_roots.extend(read_gc_roots(gc_roots_file)?);
+    }
+
+    let kernel_cmdline = args
+        .cmdline
+        .map(|cmdline| cmdline.split_whitespace().map(String::from).collect());
+
+    let kernel_install_entries = if args.take_over {
+        None
+    } else {
+        KernelInstallEntries::detect(Path::new("/etc"))?
+    };
+
+    let arch = Architecture::from_nixos_system(&required(args.system, "system")?)?;
+
+    #[cfg(feature = "test-boot")]
+    if args.test_boot && args.boot_mode == install::BootMode::Kernel {
+        anyhow::bail!("--test-boot only supports booting via the Lanzaboote stub.");
+    }
+    #[cfg(feature = "test-boot")]
+    let test_boot = args.test_boot.then(|| TestBoot {
+        arch,
+        firmware: args.test_boot_firmware.clone().unwrap_or_default(),
+        firmware_vars: args.test_boot_firmware_vars.clone().unwrap_or_default(),
+        timeout: Duration::from_secs(args.test_boot_timeout),
+        marker: args.test_boot_marker.clone(),
+    });
+
+    let systemd = required(args.systemd.clone(), "systemd")?;
+    let systemd_boot_loader_config = required(
+        args.systemd_boot_loader_config.clone(),
+        "systemd-boot-loader-config",
+    )?;
+    // Signing is never run concurrently, even if several ESPs are installed to at the same time.
+    let signer = SerializedSigner::new(signer);
+
+    // The ESP stays mounted until the installation is finished.
+    let mounted_esp = args
+        .esp_device
+        .as_deref()
+        .map(MountedEsp::mount)
+        .transpose()?;
+    let esp = match &mounted_esp {
+        Some(mounted_esp) => mounted_esp.path().to_path_buf(),
+        None => args.esp.clone().context("Missing the ESP mountpoint.")?,
+    };
+
+    // The installers of all ESPs share the hash cache, so that it is loaded and saved only once.
+    let hash_cache = Arc::new(
+        args.hash_cache
+            .as_deref()
+            .map_or_else(HashCache::in_memory, HashCache::load),
     );
+    let installer = |esp: PathBuf| {
+        let installer = install::Installer::new(
+            PathBuf::from(&lanzaboote_stub),
+            arch,
+            systemd.clone(),
+            systemd_boot_loader_config.clone(),
+            &signer,
+            args.configuration_limit.unwrap_or(1),
+            esp,
+            args.generations.clone(),
+        )
+        .with_install_order(args.install_order)
+        .with_keep_since(args.keep_since)
+        .with_keep_modified_since(args.keep_modified_since)
+        .with_boot_mode(args.boot_mode)
+        .with_collapse_identical(args.collapse_identical)
+        .with_compare_with_installed(args.compare_with_installed)
+        .with_reconcile(args.reconcile)
+        .with_force(args.force)
+        .with_snapshot(args.snapshot.clone())
+        .with_default_entry(args.default_entry.clone())
+        .with_running_generation_policy(args.running_generation_policy)
+        .with_esp_budget(
+            args.esp_budget
+                .map(|budget| budget.saturating_mul(install::MIB)),
+        )
+        .with_fit_esp(args.fit_esp)
+        .with_esp_reserve(
+            args.esp_reserve
+                .map(|reserve| reserve.saturating_mul(install::MIB)),
+        )
+        .with_booted_system(fs::canonicalize(&args.booted_system).ok())
+        .with_bls_entries(args.bls_entries)
+        .with_parallel_copy(args.parallel_copy)
+        .with_detached_signatures(args.detached_signatures.as_deref())
+        .with_cmdline_map(cmdline_map.clone())
+        .with_os_release(os_release.clone())
+        .with_sort_key_template(args.sort_key_template.clone())
+        .with_sbat(sbat.clone())
+        .with_additional_sections(additional_sections.clone())
+        .with_embed_metadata(args.embed_metadata)
+        .with_pinned_gc_roots(pinned_gc_roots.clone())
+        .with_signed_manifest(args.signed_manifest.clone())
+        .with_hash_cache(Arc::clone(&hash_cache))
+        .with_kernel_cmdline(kernel_cmdline.clone())
+        .with_efi_fallback_filename(args.efi_fallback_filename.as_deref())
+        .with_efi_fallback(!args.no_efi_fallback)
+        .with_kernel_install_entries(kernel_install_entries.clone())
+        .with_take_over(args.take_over)
+        .with_esp_permissions(install::EspPermissions {
+            file_mode: args.esp_file_mode,
+            dir_mode: args.esp_dir_mode,
+        });
+
+        #[cfg(feature = "test-boot")]
+        let installer = installer.with_test_boot(test_boot.clone());
+        installer
+    };
+
+    if args.mirror_esps.is_empty() {
+        let changed = installer(esp).install();
+        save_hash_cache(&hash_cache);
+        let changed = changed?;
+        if args.json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({ "changed": changed }))
+                    .context("Failed to serialize to JSON")?
+            );
+        }
+        return Ok(());
+    }
+
+    let mut esps = vec![esp];
+    esps.extend(args.mirror_esps.iter().cloned());
+    let outcomes = install::install_to_esps(&esps, installer);
+    save_hash_cache(&hash_cache);
+    // Comparing succeeds only if every ESP is up to date.
+    let require_all = args.require_all_esps || args.compare_with_installed;
+    report_esp_outcomes(&esps, outcomes, args.json, require_all)
+}
+
+/// Save the hash cache after all installations. The digests are only an optimization, so this
+/// cannot fail.
+fn save_hash_cache(hash_cache: &HashCache) {
+    if let Err(err) = hash_cache.save() {
+        log::warn!("Failed to save the hash cache: {err:?}");
+    }
+}
+
+/// Summarize the installations to several ESPs.
+///
+/// The installation succeeds if it succeeded on at least one ESP or, with `require_all`, on all
+/// of them.
+fn report_esp_outcomes(
+    esps: &[PathBuf],
+    outcomes: Vec<Result<bool>>,
+    json: bool,
+    require_all: bool,
+) -> Result<()> {
+    let mut failed = 0;
+    let mut report = Vec::new();
+    for (esp, outcome) in esps.iter().zip(&outcomes) {
+        match outcome {
+            Ok(true) => log::info!("Updated {esp:?}."),
+            Ok(false) => log::info!("{esp:?} was already up to date."),
+            Err(err) => {
+                failed += 1;
+                log::error!("Failed to install to {esp:?}: {err:#}");
+            }
+        }
+        report.push(serde_json::json!({
+            "esp": esp,
+            "changed": outcome.as_ref().ok(),
+            "error": outcome.as_ref().err().map(|err| format!("{err:#}")),
+        }));
+    }
 
-    install::Installer::new(
-        PathBuf::from(lanzaboote_stub),
-        Architecture::from_nixos_system(&args.system)?,
-        args.systemd,
-        args.systemd_boot_loader_config,
-        local_signer,
+    if json {
+        let changed = outcomes.iter().any(|outcome| matches!(outcome, Ok(true)));
+        println!(
+            "{}",
+            serde_json::to_string_pretty(
+                &serde_json::json!({ "changed": changed, "esps": report })
+            )
+            .context("Failed to serialize to JSON")?
+        );
+    }
+
+    // Keep the cause of the first failure, e.g. for the exit code.
+    let first_err = outcomes.into_iter().find_map(Result::err);
+    if let Some(err) = first_err {
+        if failed == esps.len() {
+            return Err(err.context(format!(
+                "Failed to install Lanzaboote to any of the {failed} ESPs."
+            )));
+        }
+        if require_all {
+            return Err(err.context(format!(
+                "Failed to install Lanzaboote to {failed} of {} ESPs.",
+                esps.len()
+            )));
+        }
+        log::warn!(
+            "Lanzaboote was only installed to {} of {} ESPs.",
+            esps.len() - failed,
+            esps.len()
+        );
+    }
+    Ok(())
+}
+
+fn inspect(args: InspectCommand) -> Result<()> {
+    match &args.extract {
+        Some(section_name) => inspect::extract(&args.file, section_name, args.output.as_deref()),
+        None => inspect::inspect(&args.file, args.public_key.as_deref(), args.format),
+    }
+}
+
+fn verify(args: VerifyCommand) -> Result<()> {
+    let files = match (&args.esp, &args.system) {
+        (Some(esp), Some(system)) => {
+            let arch = Architecture::from_nixos_system(system)?;
+            verify::installed_binaries(&SystemdEspPaths::new(esp, arch))?
+        }
+        _ => args.files,
+    };
+    verify::verify(
+        &files,
+        &args.public_key,
+        args.detached.as_deref(),
+        args.json,
+        args.offline,
+    )
+}
+
+fn build_uki(args: BuildUkiCommand) -> Result<()> {
+    if pkcs11::is_pkcs11_uri(&args.private_key) {
+        let pkcs11_signer =
+            Pkcs11KeyPair::new(&args.public_key, &args.private_key.to_string_lossy())?
+                .with_module(args.pkcs11_module.as_deref());
+        pkcs11_signer.ensure_matching_keys()?;
+        return match &args.audit_log {
+            Some(audit_log) => build_uki_with_signer(
+                &args,
+                &AuditedSigner::new(pkcs11_signer, "pkcs11", audit_log)?,
+            ),
+            None => build_uki_with_signer(&args, &pkcs11_signer),
+        };
+    }
+
+    ensure_no_pkcs11_module(args.pkcs11_module.as_deref())?;
+    let local_signer = LocalKeyPair::new(&args.public_key, &args.private_key);
+    local_signer.ensure_matching_keys()?;
+    match &args.audit_log {
+        Some(audit_log) => {
+            build_uki_with_signer(&args, &AuditedSigner::new(local_signer, "file", audit_log)?)
+        }
+        None => build_uki_with_signer(&args, &local_signer),
+    }
+}
+
+fn build_uki_with_signer(args: &BuildUkiCommand, signer: &impl Signer) -> Result<()> {
+    let kernel_cmdline = args
+        .cmdline
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let sbat = read_sbat(args.sbat.as_deref(), args.sbat_level)?;
+
+    uki::build_uki(
+        signer,
+        &args.stub,
+        &args.kernel,
+        args.initrd.as_deref(),
+        &kernel_cmdline,
+        sbat.as_ref(),
+        &args.esp,
+        &args.output,
+        args.check_reproducible,
+    )
+}
+
+fn generate_keys(args: GenerateKeysCommand) -> Result<()> {
+    keys::generate_keys(
+        &args.out_dir,
+        &args.common_name,
+        args.validity_days,
+        args.force,
+    )
+}
+
+fn sizes(args: SizesCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+
+    sizes::sizes(
+        Path::new(&lanzaboote_stub),
         args.configuration_limit,
-        args.esp,
-        args.generations,
+        &args.generations,
+    )
+}
+
+fn prune_store_refs(args: PruneStoreRefsCommand) -> Result<()> {
+    store_refs::prune_store_refs(args.configuration_limit, &args.generations)
+}
+
+fn status(args: StatusCommand) -> Result<()> {
+    let arch = Architecture::from_nixos_system(&args.system)?;
+    let esp_paths = SystemdEspPaths::new(&args.esp, arch);
+    let certificate = args
+        .public_key
+        .as_deref()
+        .map(|public_key| {
+            let pem = fs::read(public_key)
+                .with_context(|| format!("Failed to read the certificate {public_key:?}"))?;
+            authenticode::read_certificate(&pem)
+        })
+        .transpose()?;
+    let signed_manifest = args
+        .signed_manifest
+        .map(|path| args.esp.join(path.strip_prefix("/").unwrap_or(&path)));
+
+    let state = esp_state::scan(
+        &args.esp,
+        Some(&esp_paths.systemd_boot),
+        certificate.as_ref(),
+        signed_manifest.as_deref(),
+    )?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&state).context("Failed to serialize to JSON")?
+    );
+    Ok(())
+}
+
+fn bench(args: BenchCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+
+    let local_signer = LocalKeyPair::new(&args.public_key, &args.private_key);
+
+    bench::bench(
+        Path::new(&lanzaboote_stub),
+        &local_signer,
+        args.kernel_size,
+        args.initrd_size,
+        args.iterations,
     )
-    .install()
+}
+
+/// Ensure that a setting is given either on the command line or in the config file.
+fn required<T>(value: Option<T>, flag: &str) -> Result<T> {
+    value.with_context(|| format!("Missing --{flag}. Pass it or set it in the --config file."))
+}
+
+/// Parse a file name that does not contain a directory.
+fn parse_file_name(file_name: &str) -> Result<String> {
+    if file_name.is_empty() || file_name.contains('/') || file_name == "." || file_name == ".." {
+        anyhow::bail!("Invalid file name: {file_name:?}");
+    }
+    Ok(file_name.to_string())
+}
+
+/// Read a file with one garbage collection root per line.
+fn read_gc_roots(path: &Path) -> Result<Vec<PathBuf>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the garbage collection roots: {path:?}"))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Parse a section to embed, e.g. `.dtb=./board.dtb`.
+fn parse_section(section: &str) -> Result<(String, PathBuf)> {
+    let (name, path) = section
+        .split_once('=')
+        .context("Expected NAME=PATH, e.g. .dtb=./board.dtb")?;
+    Ok((name.to_owned(), PathBuf::from(path)))
+}
+
+/// Parse a generation or specialisation, e.g. `42` or `42:debug`.
+fn parse_default_entry(entry: &str) -> Result<install::DefaultEntry> {
+    let (version, specialisation) = match entry.split_once(':') {
+        Some((version, specialisation)) => (version, Some(specialisation.to_owned())),
+        None => (entry, None),
+    };
+    let version = version
+        .parse()
+        .with_context(|| format!("Invalid generation {version:?}, e.g. 42 or 42:debug"))?;
+    Ok(install::DefaultEntry {
+        version,
+        specialisation,
+    })
+}
+
+/// Parse a duration with a unit, e.g. `30d`.
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .with_context(|| format!("Missing unit in duration {duration:?}, e.g. 30d"))?;
+    let (value, unit) = duration.split_at(unit_start);
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("Invalid duration {duration:?}, e.g. 30d"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => bail!("Unknown unit {unit:?} in duration {duration:?}, e.g. 30d"),
+    };
+    value
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .with_context(|| format!("Duration {duration:?} is too long"))
+}
+
+/// Parse octal permission bits, e.g. `755`.
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode =
+        u32::from_str_radix(mode, 8).with_context(|| format!("Invalid octal mode: {mode}"))?;
+    if mode > 0o7777 {
+        anyhow::bail!("Mode {mode:#o} has bits beyond 0o7777 set");
+    }
+    Ok(mode)
 }
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Per-generation overrides of the kernel command line.
+///
+/// The map is read from a JSON file that maps generation numbers to overrides, e.g.:
+///
+/// ```json
+/// {
+///   "42": { "append": ["quiet"] },
+///   "43": { "replace": ["console=ttyS0", "loglevel=7"] },
+///   "44": "debug loglevel=7"
+/// }
+/// ```
+///
+/// A string is a shorthand for the parameters to append.
+///
+/// Generations that are not in the map use the kernel command line from their bootspec.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CmdlineMap(BTreeMap<u64, CmdlineOverride>);
+
+/// The override of the kernel command line of a single generation.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "CmdlineOverrideEntry")]
+pub struct CmdlineOverride {
+    /// Replace the kernel parameters from the bootspec.
+    ///
+    /// The `init=` parameter is always kept, as the generation cannot boot without it.
+    replace: Option<Vec<String>>,
+    /// Append these parameters to the (potentially replaced) kernel parameters.
+    append: Vec<String>,
+}
+
+/// A value of the cmdline map as it is written in the file.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum CmdlineOverrideEntry {
+    /// Only the parameters to append, separated by whitespace.
+    Append(String),
+    Override {
+        replace: Option<Vec<String>>,
+        #[serde(default)]
+        append: Vec<String>,
+    },
+}
+
+impl From<CmdlineOverrideEntry> for CmdlineOverride {
+    fn from(entry: CmdlineOverrideEntry) -> Self {
+        match entry {
+            CmdlineOverrideEntry::Append(append) => Self {
+                replace: None,
+                append: append.split_whitespace().map(String::from).collect(),
+            },
+            CmdlineOverrideEntry::Override { replace, append } => Self { replace, append },
+        }
+    }
+}
+
+impl CmdlineMap {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read the cmdline map: {path:?}"))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse the cmdline map: {path:?}"))
+    }
+
+    pub fn get(&self, version: u64) -> Option<&CmdlineOverride> {
+        self.0.get(&version)
+    }
+
+    /// Return the generations in the map that are not among the given generations.
+    pub fn stale_entries(&self, versions: &[u64]) -> Vec<u64> {
+        self.0
+            .keys()
+            .filter(|version| !versions.contains(version))
+            .copied()
+            .collect()
+    }
+}
+
+impl CmdlineOverride {
+    /// Apply the override to a kernel command line.
+    pub fn apply(&self, cmdline: Vec<String>) -> Vec<String> {
+        let cmdline = match &self.replace {
+            Some(replacement) => cmdline
+                .into_iter()
+                .filter(|param| param.starts_with("init="))
+                .chain(replacement.iter().cloned())
+                .collect(),
+            None => cmdline,
+        };
+        cmdline
+            .into_iter()
+            .chain(self.append.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmdline(params: &[&str]) -> Vec<String> {
+        params.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn apply_overrides() -> Result<()> {
+        let map: CmdlineMap = serde_json::from_str(
+            r#"{
+                "1": { "append": ["quiet"] },
+                "2": { "replace": ["console=ttyS0"], "append": ["loglevel=7"] }
+            }"#,
+        )?;
+        let bootspec_cmdline = cmdline(&["init=/init", "splash"]);
+
+        assert_eq!(
+            map.get(1).unwrap().apply(bootspec_cmdline.clone()),
+            cmdline(&["init=/init", "splash", "quiet"])
+        );
+        assert_eq!(
+            map.get(2).unwrap().apply(bootspec_cmdline),
+            cmdline(&["init=/init", "console=ttyS0", "loglevel=7"])
+        );
+        assert!(map.get(3).is_none());
+        assert_eq!(map.stale_entries(&[2, 3]), vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn append_parameters_from_string() -> Result<()> {
+        let map: CmdlineMap = serde_json::from_str(r#"{ "1": "debug  loglevel=7" }"#)?;
+
+        assert_eq!(
+            map.get(1).unwrap().apply(cmdline(&["init=/init"])),
+            cmdline(&["init=/init", "debug", "loglevel=7"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_map() {
+        assert!(serde_json::from_str::<CmdlineMap>(r#"{ "latest": {} }"#).is_err());
+        assert!(serde_json::from_str::<CmdlineMap>(r#"{ "1": { "prepend": [] } }"#).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Settings of `lzbt install` read from a JSON file.
+///
+/// The NixOS module writes this file from the system configuration so that it does not have to
+/// assemble a long command line, e.g.:
+///
+/// ```json
+/// {
+///   "system": "x86_64-linux",
+///   "publicKey": "/var/lib/sbctl/keys/db/db.pem",
+///   "privateKey": "/var/lib/sbctl/keys/db/db.key",
+///   "configurationLimit": 10
+/// }
+/// ```
+///
+/// Flags on the command line take precedence over the settings in the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    pub system: Option<String>,
+    pub systemd: Option<PathBuf>,
+    pub systemd_boot_loader_config: Option<PathBuf>,
+    pub public_key: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    pub configuration_limit: Option<usize>,
+}
+
+impl Config {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read the config: {path:?}"))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse the config: {path:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_partial_config() -> Result<()> {
+        let config: Config =
+            serde_json::from_str(r#"{ "system": "x86_64-linux", "configurationLimit": 10 }"#)?;
+
+        assert_eq!(config.system.as_deref(), Some("x86_64-linux"));
+        assert_eq!(config.configuration_limit, Some(10));
+        assert!(config.public_key.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_settings() {
+        assert!(serde_json::from_str::<Config>(r#"{ "configurationLimt": 10 }"#).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::esp_state;
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::pe;
+
+/// Sections of the stub that are short text and more useful to compare than their hash.
+const TEXT_SECTIONS: [&str; 2] = [".cmdline", ".uname"];
+
+/// Print what changed between two generations, e.g. between the last working and a broken one.
+///
+/// The generations are compared by their bootspecs. With an ESP, the sections of their installed
+/// stubs are compared as well, which also covers e.g. a `--cmdline-map` or a different stub.
+pub fn diff(profile_dir: &Path, esp: Option<&Path>, old: u64, new: u64) -> Result<()> {
+    let old_artifacts = artifacts(profile_dir, esp, old)?;
+    let new_artifacts = artifacts(profile_dir, esp, new)?;
+
+    let differences = differences(&old_artifacts, &new_artifacts);
+    if differences.is_empty() {
+        println!("Generations {old} and {new} do not differ.");
+    }
+    for (name, old_value, new_value) in differences {
+        println!(
+            "{name}: {} -> {}",
+            old_value.unwrap_or("(none)"),
+            new_value.unwrap_or("(none)")
+        );
+    }
+    Ok(())
+}
+
+/// Collect the boot artifacts of a generation as named values in a stable order.
+fn artifacts(
+    profile_dir: &Path,
+    esp: Option<&Path>,
+    version: u64,
+) -> Result<Vec<(String, String)>> {
+    let link = GenerationLink::from_path(profile_dir.join(format!("system-{version}-link")))?;
+    let generation = Generation::from_link(&link)
+        .with_context(|| format!("Failed to read the bootspec of {:?}", link.path))?;
+    let spec = &generation.spec;
+    let bootspec = &spec.bootspec.bootspec;
+
+    let mut artifacts = vec![
+        ("label".to_owned(), bootspec.label.clone()),
+        (
+            "toplevel".to_owned(),
+            bootspec.toplevel.0.display().to_string(),
+        ),
+        (
+            "kernel".to_owned(),
+            spec.kernel_path().display().to_string(),
+        ),
+        (
+            "kernel version".to_owned(),
+            spec.kernel_version()?.to_owned(),
+        ),
+        ("kernel size".to_owned(), file_size(spec.kernel_path())),
+        ("cmdline".to_owned(), spec.kernel_cmdline()?.join(" ")),
+        (
+            "sort key".to_owned(),
+            spec.lanzaboote_extension.sort_key.clone(),
+        ),
+    ];
+    if let Some(initrd) = spec.initrd_path() {
+        artifacts.push(("initrd".to_owned(), initrd.display().to_string()));
+        artifacts.push(("initrd size".to_owned(), file_size(initrd)));
+    }
+    if let Some(initrd_secrets) = &bootspec.initrd_secrets {
+        artifacts.push((
+            "initrd secrets".to_owned(),
+            initrd_secrets.display().to_string(),
+        ));
+    }
+    if let Some(title) = generation.title() {
+        artifacts.push(("title".to_owned(), title));
+    }
+
+    if let Some(esp) = esp {
+        match installed_stub(esp, version)? {
+            Some(stub) => {
+                let stub_data =
+                    fs::read(&stub).with_context(|| format!("Failed to read {stub:?}"))?;
+                for section in pe::read_sections(&stub_data)? {
+                    let data = section
+                        .data(&stub_data)
+                        .with_context(|| format!("Section {} is out of bounds", section.name))?;
+                    let value = if TEXT_SECTIONS.contains(&section.name.as_str()) {
+                        String::from_utf8_lossy(data).into_owned()
+                    } else {
+                        format!("{} bytes, sha256 {:x}", data.len(), Sha256::digest(data))
+                    };
+                    artifacts.push((format!("stub section {}", section.name), value));
+                }
+            }
+            None => artifacts.push(("stub".to_owned(), "not installed".to_owned())),
+        }
+    }
+
+    Ok(artifacts)
+}
+
+/// The names and values of the artifacts that differ, in the order of the old generation followed
+/// by the artifacts that only the new generation has.
+fn differences<'a>(
+    old: &'a [(String, String)],
+    new: &'a [(String, String)],
+) -> Vec<(&'a str, Option<&'a str>, Option<&'a str>)> {
+    let lookup = |artifacts: &'a [(String, String)], name: &str| {
+        artifacts
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let mut names: Vec<&str> = old.iter().map(|(name, _)| name.as_str()).collect();
+    names.extend(
+        new.iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| lookup(old, name).is_none()),
+    );
+    names
+        .into_iter()
+        .map(|name| (name, lookup(old, name), lookup(new, name)))
+        .filter(|(_, old_value, new_value)| old_value != new_value)
+        .collect()
+}
+
+/// Find the stub installed for the generation itself, not for one of its specialisations.
+fn installed_stub(esp: &Path, version: u64) -> Result<Option<PathBuf>> {
+    let stub = esp_state::scan(esp, None, None, None)?
+        .generations
+        .into_iter()
+        .find(|generation| generation.version == version && generation.specialisation.is_none())
+        .map(|generation| esp.join(generation.path.trim_start_matches('/')));
+    Ok(stub)
+}
+
+fn file_size(path: &Path) -> String {
+    match fs::metadata(path) {
+        Ok(metadata) => format!("{} bytes", metadata.len()),
+        Err(_) => "missing".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifacts(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn report_changed_added_and_removed_artifacts() {
+        let old = artifacts(&[("kernel", "a"), ("cmdline", "quiet"), ("initrd", "b")]);
+        let new = artifacts(&[("kernel", "c"), ("cmdline", "quiet"), ("title", "t")]);
+        assert_eq!(
+            differences(&old, &new),
+            [
+                ("kernel", Some("a"), Some("c")),
+                ("initrd", Some("b"), None),
+                ("title", None, Some("t")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::install::resolve_efi_path;
+use lanzaboote_tool::esp_state::installed_stubs;
+use lanzaboote_tool::generation::Generation;
+use lanzaboote_tool::pe;
+use lanzaboote_tool::utils::file_hash;
+
+/// Check that the stubs installed for a system boot its kernel and initrd.
+///
+/// The stubs of the system are found by the `init=` on their kernel command line. For each of
+/// them, the kernel and initrd hashes embedded in the stub are compared with the files of the
+/// system in the Nix store and with the files on the ESP the stub references. This catches a
+/// forgotten `lzbt install` after an update, which boots a stale image.
+///
+/// Stubs installed with a kernel command line override that drops `init=` cannot be found.
+pub fn check_drift(esp: &Path, system: &Path) -> Result<()> {
+    let bootspec_path = system.join("boot.json");
+    let bootspec = File::open(&bootspec_path)
+        .with_context(|| format!("Failed to open the bootspec {bootspec_path:?}"))?;
+    let generation = Generation::from_bootspec_reader(0, bootspec)
+        .with_context(|| format!("Failed to read the bootspec {bootspec_path:?}"))?;
+    let spec = &generation.spec;
+
+    let kernel_hash = file_hash(spec.kernel_path())?;
+    // With initrd secrets, the initrd on the ESP has the secrets appended and does not match the
+    // one in the store.
+    let initrd_hash = match spec.initrd_path() {
+        Some(initrd) if spec.bootspec.bootspec.initrd_secrets.is_none() => Some(file_hash(initrd)?),
+        _ => None,
+    };
+    let init = format!("init={}", spec.init_path().display());
+
+    let mut stubs = 0;
+    let mut drifted = 0;
+    for stub in installed_stubs(esp)? {
+        let stub_data = fs::read(&stub).with_context(|| format!("Failed to read {stub:?}"))?;
+        let boots_system = pe::read_section_data(&stub_data, ".cmdline").is_some_and(|cmdline| {
+            String::from_utf8_lossy(cmdline)
+                .split_whitespace()
+                .any(|param| param == init)
+        });
+        if !boots_system {
+            continue;
+        }
+        stubs += 1;
+
+        let mut drift = Vec::new();
+        let mut files = vec![("kernel", ".linux", ".linuxh", Some(kernel_hash))];
+        // The stub of a system without an initrd has no initrd sections.
+        if spec.initrd_path().is_some() {
+            files.push(("initrd", ".initrd", ".initrdh", initrd_hash));
+        }
+        for (description, path_section, hash_section, store_hash) in files {
+            let embedded_hash = pe::read_section_data(&stub_data, hash_section)
+                .with_context(|| format!("{stub:?} is missing the section {hash_section}"))?;
+            if store_hash.is_some_and(|hash| hash.as_slice() != embedded_hash) {
+                drift.push(format!(
+                    "the {description} differs from the one of the system"
+                ));
+            }
+
+            let efi_path = pe::read_section_data(&stub_data, path_section)
+                .with_context(|| format!("{stub:?} is missing the section {path_section}"))?;
+            let esp_path = resolve_efi_path(esp, efi_path)?;
+            match file_hash(&esp_path) {
+                Ok(hash) if hash.as_slice() == embedded_hash => (),
+                Ok(_) => drift.push(format!("the {description} {esp_path:?} was modified")),
+                Err(_) => drift.push(format!("the {description} {esp_path:?} is missing")),
+            }
+        }
+
+        if drift.is_empty() {
+            println!("{}: up to date", stub.display());
+        } else {
+            drifted += 1;
+            println!("{}: {}", stub.display(), drift.join(", "));
+        }
+    }
+
+    if stubs == 0 {
+        bail!("No stub on the ESP boots {system:?}. Run `lzbt install` to install it.");
+    }
+    if drifted > 0 {
+        bail!("{drifted} of {stubs} stubs of {system:?} do not match it. Run `lzbt install` to reinstall them.");
+    }
+    Ok(())
+}
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+
+/// Print the bootspec of a generation as Lanzaboote parsed it, as pretty JSON.
+///
+/// This is the bootspec with its paths resolved and the Lanzaboote extension with the defaults
+/// filled in for missing fields, i.e. exactly what `install` works with.
+pub fn dump_bootspec(profile_dir: &Path, generation: u64) -> Result<()> {
+    let link = GenerationLink::from_path(profile_dir.join(format!("system-{generation}-link")))?;
+    let generation = Generation::from_link(&link)
+        .with_context(|| format!("Failed to read the bootspec of {:?}", link.path))?;
+
+    let json = serde_json::to_string_pretty(&generation.spec)
+        .context("Failed to serialize the bootspec.")?;
+    println!("{json}");
+    Ok(())
+}
@@ -0,0 +1,342 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use der::{Decode, DecodePem, Encode};
+use lanzaboote_tool::signature::authenticode;
+use sha2::{Digest, Sha256};
+use x509_cert::Certificate;
+
+/// The name of the EFI variable with the signature database, including the GUID of
+/// `EFI_IMAGE_SECURITY_DATABASE_GUID`.
+const DB_VARIABLE: &str = "db-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+/// The name of the EFI variable with the forbidden signature database.
+const DBX_VARIABLE: &str = "dbx-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+/// `EFI_CERT_X509_GUID` in its binary (mixed-endian) representation.
+const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+
+/// Ensure that the certificate or one of its issuers is enrolled in the db of the firmware.
+///
+/// Otherwise, the firmware does not boot the signed files when Secure Boot is enforced. The
+/// certificate may be issued by an enrolled certificate via the intermediate certificates in
+/// `cert_chain`, like the chain that is embedded into the signatures. The db is read from
+/// `efivars`, usually `/sys/firmware/efi/efivars`, so this only works on the machine that boots
+/// the ESP.
+pub fn ensure_enrolled(
+    certificate: &Path,
+    cert_chain: Option<&Path>,
+    efivars: &Path,
+) -> Result<()> {
+    let pem = fs::read(certificate)
+        .with_context(|| format!("Failed to read the certificate {certificate:?}"))?;
+    let signing = Certificate::from_pem(&pem)
+        .with_context(|| format!("Failed to parse the certificate {certificate:?}"))?;
+    let intermediates = match cert_chain {
+        Some(cert_chain) => {
+            let pem = fs::read(cert_chain)
+                .with_context(|| format!("Failed to read the certificate chain {cert_chain:?}"))?;
+            Certificate::load_pem_chain(&pem)
+                .with_context(|| format!("Failed to parse the certificate chain {cert_chain:?}"))?
+        }
+        None => Vec::new(),
+    };
+
+    let db_path = efivars.join(DB_VARIABLE);
+    let db = fs::read(&db_path).with_context(|| {
+        format!("Failed to read the enrolled db from {db_path:?}. Is this an EFI system?")
+    })?;
+    let db = x509_certificates(db.get(4..).context("The db variable is truncated.")?)
+        .context("Failed to parse the enrolled db.")?;
+
+    if !is_enrolled(&signing, &intermediates, &db)? {
+        bail!(
+            "Neither the certificate {certificate:?} nor an issuer of it is enrolled in the db. The firmware would not boot the signed files."
+        );
+    }
+    Ok(())
+}
+
+/// Whether the signing certificate is enrolled in the db or chains up to an enrolled certificate
+/// via the intermediate certificates.
+fn is_enrolled(signing: &Certificate, intermediates: &[Certificate], db: &[&[u8]]) -> Result<bool> {
+    let signing_der = signing.to_der()?;
+    let intermediates = intermediates.iter().collect::<Vec<_>>();
+    Ok(db.iter().any(|der| {
+        *der == signing_der.as_slice()
+            || Certificate::from_der(der).is_ok_and(|enrolled| {
+                authenticode::ensure_trusted(signing, &intermediates, &enrolled).is_ok()
+            })
+    }))
+}
+
+/// The state of the enrolled db relative to the signing certificate.
+#[derive(Debug, Default)]
+struct Report {
+    /// Problems that stop the firmware from booting the signed files, the most severe first.
+    errors: Vec<String>,
+    /// Problems that do not affect booting the signed files, e.g. duplicate certificates.
+    warnings: Vec<String>,
+}
+
+/// Print the certificates enrolled in the db and the problems they pose for signing with
+/// `certificate`.
+///
+/// Fails if the firmware would not boot files signed with the certificate, i.e. if neither the
+/// certificate nor its issuer is enrolled or if it is revoked in the dbx.
+pub fn report_enrolled(certificate: &Path, efivars: &Path) -> Result<()> {
+    let pem = fs::read(certificate)
+        .with_context(|| format!("Failed to read the certificate {certificate:?}"))?;
+    let signing = Certificate::from_pem(&pem)
+        .with_context(|| format!("Failed to parse the certificate {certificate:?}"))?;
+
+    let db_path = efivars.join(DB_VARIABLE);
+    let db = fs::read(&db_path).with_context(|| {
+        format!("Failed to read the enrolled db from {db_path:?}. Is this an EFI system?")
+    })?;
+    let db = x509_certificates(db.get(4..).context("The db variable is truncated.")?)
+        .context("Failed to parse the enrolled db.")?;
+    // Many firmwares ship without a dbx.
+    let dbx_path = efivars.join(DBX_VARIABLE);
+    let dbx = match fs::read(&dbx_path) {
+        Ok(dbx) => dbx,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read the dbx from {dbx_path:?}"))
+        }
+    };
+    let dbx =
+        x509_certificates(dbx.get(4..).unwrap_or_default()).context("Failed to parse the dbx.")?;
+
+    let report = report(&signing, &db, &dbx, SystemTime::now())?;
+    for error in &report.errors {
+        println!("PROBLEM: {error}");
+    }
+    for warning in &report.warnings {
+        println!("warning: {warning}");
+    }
+
+    println!("Certificates enrolled in the db:");
+    for der in &db {
+        let fingerprint = format!("{:x}", Sha256::digest(der));
+        match Certificate::from_der(der) {
+            Ok(enrolled) => println!(
+                "  {fingerprint}  {}  (valid until {})",
+                enrolled.tbs_certificate.subject, enrolled.tbs_certificate.validity.not_after
+            ),
+            Err(_) => println!("  {fingerprint}  <invalid certificate>"),
+        }
+    }
+
+    if !report.errors.is_empty() {
+        bail!("The firmware would not boot files signed with {certificate:?}.");
+    }
+    Ok(())
+}
+
+/// Compare the enrolled certificates with the signing certificate.
+fn report(signing: &Certificate, db: &[&[u8]], dbx: &[&[u8]], now: SystemTime) -> Result<Report> {
+    let mut report = Report::default();
+    let signing_der = signing.to_der()?;
+    let signing_subject = &signing.tbs_certificate.subject;
+
+    let copies = db.iter().filter(|der| **der == signing_der).count();
+    if !is_enrolled(signing, &[], db)? {
+        report.errors.push(format!(
+            "Your signing certificate {signing_subject} is NOT enrolled in the db, neither is its issuer {}.",
+            signing.tbs_certificate.issuer
+        ));
+    }
+    if dbx.contains(&signing_der.as_slice()) {
+        report.errors.push(format!(
+            "Your signing certificate {signing_subject} is revoked in the dbx."
+        ));
+    }
+    if copies > 1 {
+        report.warnings.push(format!(
+            "Your signing certificate {signing_subject} is enrolled {copies} times."
+        ));
+    }
+
+    for (index, der) in db.iter().enumerate() {
+        let Ok(enrolled) = Certificate::from_der(der) else {
+            report.warnings.push(format!(
+                "Entry {index} of the db is not a valid certificate."
+            ));
+            continue;
+        };
+        let subject = &enrolled.tbs_certificate.subject;
+        let not_after = enrolled.tbs_certificate.validity.not_after;
+        // Firmware does not check the validity period, so this is only a hint that the
+        // certificate is not maintained anymore.
+        if not_after.to_system_time() < now {
+            report.warnings.push(format!(
+                "The enrolled certificate {subject} expired on {not_after}."
+            ));
+        }
+        if *der != signing_der.as_slice() && dbx.contains(der) {
+            report.warnings.push(format!(
+                "The enrolled certificate {subject} is revoked in the dbx."
+            ));
+        }
+    }
+    Ok(report)
+}
+
+/// Extract the X.509 certificates from the `EFI_SIGNATURE_LIST`s of a signature database.
+///
+/// The attributes that precede the contents of an EFI variable have to be removed already.
+/// Other types of signatures, e.g. SHA-256 hashes of images, are skipped.
+fn x509_certificates(mut db: &[u8]) -> Result<Vec<&[u8]>> {
+    let u32_at = |data: &[u8], offset: usize| -> Result<usize> {
+        let bytes = data
+            .get(offset..offset + 4)
+            .context("Signature list is truncated.")?;
+        Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+    };
+
+    let mut certificates = Vec::new();
+    while !db.is_empty() {
+        // EFI_SIGNATURE_LIST: SignatureType, SignatureListSize, SignatureHeaderSize and
+        // SignatureSize, followed by the header and the signatures.
+        let list_size = u32_at(db, 16)?;
+        let header_size = u32_at(db, 20)?;
+        let signature_size = u32_at(db, 24)?;
+        let list = db
+            .get(..list_size)
+            .context("Signature list is truncated.")?;
+        // An EFI_SIGNATURE_DATA starts with the GUID of its owner.
+        if list_size < 28 || signature_size <= 16 {
+            bail!("Signature list has an invalid size.");
+        }
+
+        if list[..16] == EFI_CERT_X509_GUID {
+            let signatures = list
+                .get(28 + header_size..)
+                .context("Signature list is truncated.")?;
+            certificates.extend(
+                signatures
+                    .chunks_exact(signature_size)
+                    .map(|signature| &signature[16..]),
+            );
+        }
+        db = &db[list_size..];
+    }
+    Ok(certificates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_cert::serial_number::SerialNumber;
+
+    fn signature_list(signature_type: [u8; 16], signatures: &[&[u8]]) -> Vec<u8> {
+        let signature_size = 16 + signatures[0].len();
+        let mut list = signature_type.to_vec();
+        list.extend(((28 + signatures.len() * signature_size) as u32).to_le_bytes());
+        list.extend(0u32.to_le_bytes());
+        list.extend((signature_size as u32).to_le_bytes());
+        for signature in signatures {
+            list.extend([0; 16]);
+            list.extend(*signature);
+        }
+        list
+    }
+
+    #[test]
+    fn extract_x509_certificates() -> Result<()> {
+        let mut db = signature_list([0; 16], &[&[1; 32]]);
+        db.extend(signature_list(EFI_CERT_X509_GUID, &[b"first", b"other"]));
+
+        assert_eq!(x509_certificates(&db)?, vec![&b"first"[..], &b"other"[..]]);
+        Ok(())
+    }
+
+    fn certificate(path: &str) -> Result<(Certificate, Vec<u8>)> {
+        let certificate = Certificate::from_pem(fs::read(path)?)?;
+        let der = certificate.to_der()?;
+        Ok((certificate, der))
+    }
+
+    #[test]
+    fn report_missing_signing_certificate_first() -> Result<()> {
+        let (signing, signing_der) = certificate("tests/fixtures/uefi-keys/db.pem")?;
+        let (_, other) = certificate("tests/fixtures/uefi-keys-ecdsa/db.pem")?;
+
+        let report = report(&signing, &[&other], &[&signing_der], SystemTime::now())?;
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].contains("is NOT enrolled"), "{report:?}");
+        assert!(
+            report.errors[1].contains("is revoked in the dbx"),
+            "{report:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_duplicate_and_expired_certificates() -> Result<()> {
+        let (signing, signing_der) = certificate("tests/fixtures/uefi-keys/db.pem")?;
+        let (_, other) = certificate("tests/fixtures/uefi-keys-ecdsa/db.pem")?;
+        let db = [&signing_der[..], &signing_der[..], &other[..], b"invalid"];
+        // The fixture expires in 2027, the other certificate much later.
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60 * 365 * 24 * 3600);
+
+        let report = report(&signing, &db, &[&other], now)?;
+        assert!(report.errors.is_empty(), "{report:?}");
+        assert_eq!(
+            report.warnings,
+            [
+                "Your signing certificate CN=Database Key,C=Database Key is enrolled 2 times.",
+                "The enrolled certificate CN=Database Key,C=Database Key expired on 2027-11-23T12:56:55Z.",
+                "The enrolled certificate CN=Database Key,C=Database Key expired on 2027-11-23T12:56:55Z.",
+                "The enrolled certificate CN=Database Key ECDSA is revoked in the dbx.",
+                "Entry 3 of the db is not a valid certificate.",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accept_enrolled_issuer() -> Result<()> {
+        let (signing, _) = certificate("tests/fixtures/uefi-keys-chain/db.pem")?;
+        let (_, intermediate) = certificate("tests/fixtures/uefi-keys-chain/intermediate.pem")?;
+
+        let report = report(&signing, &[&intermediate], &[], SystemTime::now())?;
+        assert!(report.errors.is_empty(), "{report:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn verify_issuers_via_cert_chain() -> Result<()> {
+        let (signing, _) = certificate("tests/fixtures/uefi-keys-chain/db.pem")?;
+        let (intermediate, _) = certificate("tests/fixtures/uefi-keys-chain/intermediate.pem")?;
+        let (_, root) = certificate("tests/fixtures/uefi-keys-chain/root.pem")?;
+
+        assert!(is_enrolled(&signing, &[intermediate], &[&root])?);
+        assert!(!is_enrolled(&signing, &[], &[&root])?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_issuer_that_only_matches_by_name() -> Result<()> {
+        let (mut signing, _) = certificate("tests/fixtures/uefi-keys-chain/db.pem")?;
+        let (_, intermediate) = certificate("tests/fixtures/uefi-keys-chain/intermediate.pem")?;
+        // The issuer name still matches, but the signature does not cover the certificate anymore.
+        signing.tbs_certificate.serial_number = SerialNumber::new(&[0x42])?;
+
+        assert!(!is_enrolled(&signing, &[], &[&intermediate])?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_truncated_signature_list() {
+        let db = signature_list(EFI_CERT_X509_GUID, &[b"certificate"]);
+        assert!(x509_certificates(&db[..db.len() - 1]).is_err());
+        assert!(x509_certificates(&db[..20]).is_err());
+    }
+}
@@ -12,14 +12,17 @@ pub struct SystemdEspPaths {
     pub nixos: PathBuf,
     pub linux: PathBuf,
     pub efi_fallback_dir: PathBuf,
-    pub efi_fallback: PathBuf,
+    /// `None` if no fallback is installed, e.g. to not replace the fallback of another OS on a
+    /// shared ESP.
+    pub efi_fallback: Option<PathBuf>,
     pub systemd: PathBuf,
     pub systemd_boot: PathBuf,
     pub loader: PathBuf,
     pub systemd_boot_loader_config: PathBuf,
+    pub entries: PathBuf,
 }
 
-impl EspPaths<10> for SystemdEspPaths {
+impl EspPaths for SystemdEspPaths {
     fn new(esp: impl AsRef<Path>, architecture: Architecture) -> Self {
         let esp = esp.as_ref();
         let efi = esp.join("EFI");
@@ -29,6 +32,7 @@ impl EspPaths<10> for SystemdEspPaths {
         let efi_efi_fallback_dir = efi.join("BOOT");
         let loader = esp.join("loader");
         let systemd_boot_loader_config = loader.join("loader.conf");
+        let entries = loader.join("entries");
 
         Self {
             esp: esp.to_path_buf(),
@@ -36,11 +40,12 @@ impl EspPaths<10> for SystemdEspPaths {
             nixos: efi_nixos,
             linux: efi_linux,
             efi_fallback_dir: efi_efi_fallback_dir.clone(),
-            efi_fallback: efi_efi_fallback_dir.join(architecture.efi_fallback_filename()),
+            efi_fallback: Some(efi_efi_fallback_dir.join(architecture.efi_fallback_filename())),
             systemd: efi_systemd.clone(),
             systemd_boot: efi_systemd.join(architecture.systemd_filename()),
             loader,
             systemd_boot_loader_config,
+            entries,
         }
     }
 
@@ -52,19 +57,50 @@ impl EspPaths<10> for SystemdEspPaths {
         &self.linux
     }
 
-    fn iter(&self) -> std::array::IntoIter<&PathBuf, 10> {
+    fn iter(&self) -> impl Iterator<Item = &PathBuf> {
         [
             &self.esp,
             &self.efi,
             &self.nixos,
             &self.linux,
             &self.efi_fallback_dir,
-            &self.efi_fallback,
             &self.systemd,
             &self.systemd_boot,
             &self.loader,
             &self.systemd_boot_loader_config,
+            &self.entries,
         ]
         .into_iter()
+        .chain(self.efi_fallback.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_boot_files_of_architecture() {
+        let x86 = SystemdEspPaths::new("/boot", Architecture::X86);
+        let aarch64 = SystemdEspPaths::new("/boot", Architecture::AArch64);
+        for (esp_paths, fallback, systemd_boot) in [
+            (
+                &x86,
+                "/boot/EFI/BOOT/BOOTX64.EFI",
+                "/boot/EFI/systemd/systemd-bootx64.efi",
+            ),
+            (
+                &aarch64,
+                "/boot/EFI/BOOT/BOOTAA64.EFI",
+                "/boot/EFI/systemd/systemd-bootaa64.efi",
+            ),
+        ] {
+            assert_eq!(esp_paths.efi_fallback.as_deref(), Some(Path::new(fallback)));
+            assert_eq!(esp_paths.systemd_boot, Path::new(systemd_boot));
+            // The boot files are garbage collection roots.
+            let roots = esp_paths.iter().collect::<Vec<_>>();
+            assert!(roots.contains(&&PathBuf::from(fallback)));
+            assert!(roots.contains(&&PathBuf::from(systemd_boot)));
+        }
     }
 }
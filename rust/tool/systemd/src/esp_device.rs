@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use nix::mount::{mount, umount, MsFlags};
+use tempfile::TempDir;
+
+/// An ESP that was mounted from a device at a temporary mountpoint.
+///
+/// It is unmounted when it is dropped.
+pub struct MountedEsp {
+    /// Only `None` while it is dropped.
+    mountpoint: Option<TempDir>,
+}
+
+impl MountedEsp {
+    /// Check that the device contains a FAT file system and mount it at a temporary mountpoint.
+    ///
+    /// This refuses every other device, so that a mistyped device node does not get its data
+    /// overwritten.
+    pub fn mount(device: &Path) -> Result<Self> {
+        ensure_fat(device)?;
+        let mountpoint = TempDir::new().context("Failed to create a temporary mountpoint.")?;
+        mount(
+            Some(device),
+            mountpoint.path(),
+            Some("vfat"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        )
+        .with_context(|| format!("Failed to mount {device:?} at {:?}", mountpoint.path()))?;
+        log::info!("Mounted {device:?} at {:?}.", mountpoint.path());
+        Ok(Self {
+            mountpoint: Some(mountpoint),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.mountpoint
+            .as_ref()
+            .expect("The mountpoint is only taken when the ESP is dropped.")
+            .path()
+    }
+}
+
+impl Drop for MountedEsp {
+    fn drop(&mut self) {
+        let Some(mountpoint) = self.mountpoint.take() else {
+            return;
+        };
+        // The temporary mountpoint is removed with everything below it, so it must only be
+        // removed once the ESP is unmounted. Otherwise, it is left behind.
+        if let Err(err) = umount(mountpoint.path()) {
+            let mountpoint = mountpoint.into_path();
+            log::error!("Failed to unmount {mountpoint:?}, leaving it in place: {err}");
+        }
+    }
+}
+
+/// Ensure that the boot sector of the device belongs to a FAT file system.
+fn ensure_fat(device: &Path) -> Result<()> {
+    let mut boot_sector = [0; 512];
+    File::open(device)
+        .and_then(|mut file| file.read_exact(&mut boot_sector))
+        .with_context(|| format!("Failed to read the boot sector of {device:?}"))?;
+    if !is_fat(&boot_sector) {
+        bail!("{device:?} does not contain a FAT file system. Refusing to install to it.");
+    }
+    Ok(())
+}
+
+/// Whether a boot sector belongs to a FAT12, FAT16 or FAT32 file system.
+///
+/// Like blkid, this checks the boot signature, the sector size and the file system type, which is
+/// stored at a different offset for FAT32.
+fn is_fat(boot_sector: &[u8; 512]) -> bool {
+    let bytes_per_sector = u16::from_le_bytes([boot_sector[11], boot_sector[12]]);
+    boot_sector[510..] == [0x55, 0xaa]
+        && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && (boot_sector[54..57] == *b"FAT" || boot_sector[82..87] == *b"FAT32")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot_sector(fs_type_offset: usize, fs_type: &[u8]) -> [u8; 512] {
+        let mut boot_sector = [0; 512];
+        boot_sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot_sector[fs_type_offset..fs_type_offset + fs_type.len()].copy_from_slice(fs_type);
+        boot_sector[510..].copy_from_slice(&[0x55, 0xaa]);
+        boot_sector
+    }
+
+    #[test]
+    fn keep_mountpoint_if_unmounting_fails() -> Result<()> {
+        // A directory that is not a mountpoint cannot be unmounted.
+        let mountpoint = TempDir::new()?;
+        let path = mountpoint.path().to_path_buf();
+        std::fs::write(path.join("kernel.efi"), b"kernel")?;
+
+        drop(MountedEsp {
+            mountpoint: Some(mountpoint),
+        });
+
+        let kept = path.join("kernel.efi").exists();
+        std::fs::remove_dir_all(&path)?;
+        assert!(kept);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_fat_boot_sectors() {
+        assert!(is_fat(&boot_sector(82, b"FAT32   ")));
+        assert!(is_fat(&boot_sector(54, b"FAT16   ")));
+        assert!(is_fat(&boot_sector(54, b"FAT12   ")));
+        // NTFS also has a boot signature.
+        assert!(!is_fat(&boot_sector(3, b"NTFS    ")));
+        assert!(!is_fat(&[0; 512]));
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::pe::{self, StubMetadata};
+use lanzaboote_tool::signature::local;
+
+/// The format `inspect` prints in.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// The sections and the signature of a PE binary.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Inspection {
+    sections: Vec<Section>,
+    /// The provenance of a stub installed with `--embed-metadata`.
+    metadata: Option<StubMetadata>,
+    signed: bool,
+    /// Whether the signature is valid for the given certificate. This is `None` if no certificate
+    /// was given.
+    valid: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Section {
+    name: String,
+    virtual_address: u32,
+    file_offset: u32,
+    size: u32,
+    sha256: String,
+}
+
+/// Print the sections of a PE binary and whether it carries a signature.
+///
+/// If a certificate is given, the signature is also verified with it.
+pub fn inspect(path: &Path, public_key: Option<&Path>, format: Format) -> Result<()> {
+    let file_data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let sections = pe::read_sections(&file_data)
+        .with_context(|| format!("Failed to read the sections of {path:?}"))?
+        .into_iter()
+        .map(|section| {
+            let data = section
+                .data(&file_data)
+                .with_context(|| format!("Section {} is out of bounds", section.name))?;
+            Ok(Section {
+                sha256: format!("{:x}", Sha256::digest(data)),
+                name: section.name,
+                virtual_address: section.virtual_address,
+                file_offset: section.file_offset,
+                size: section.size,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let metadata = StubMetadata::read(&file_data)
+        .with_context(|| format!("Failed to read the metadata of {path:?}"))?;
+    let signed = pe::read_pkcs7_signature(&file_data).is_ok();
+    let valid = public_key
+        .map(|public_key| local::verify_with_certificate(path, public_key))
+        .transpose()?;
+
+    let inspection = Inspection {
+        sections,
+        metadata,
+        signed,
+        valid,
+    };
+
+    match format {
+        Format::Text => print_text(&inspection),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&inspection).context("Failed to serialize to JSON")?
+        ),
+    }
+
+    Ok(())
+}
+
+fn print_text(inspection: &Inspection) {
+    println!(
+        "{:<10} {:>12} {:>12} {:>12}  sha256",
+        "section", "address", "offset", "size"
+    );
+    for section in &inspection.sections {
+        println!(
+            "{:<10} {:>#12x} {:>#12x} {:>12}  {}",
+            section.name,
+            section.virtual_address,
+            section.file_offset,
+            section.size,
+            section.sha256
+        );
+    }
+
+    if let Some(metadata) = &inspection.metadata {
+        println!("generation: {}", metadata.generation);
+        if let Some(specialisation) = &metadata.specialisation {
+            println!("specialisation: {specialisation}");
+        }
+        if let Some(profile) = &metadata.profile {
+            println!("profile: {}", profile.display());
+        }
+        println!("toplevel: {}", metadata.toplevel.display());
+    }
+
+    let yes_no = |value| if value { "yes" } else { "no" };
+    println!("signed: {}", yes_no(inspection.signed));
+    if let Some(valid) = inspection.valid {
+        println!("valid: {}", yes_no(valid));
+    }
+}
+
+/// Write the contents of a section of a PE binary to `output` or, if it is not set, to stdout.
+pub fn extract(path: &Path, section_name: &str, output: Option<&Path>) -> Result<()> {
+    let file_data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let section_data = pe::read_section_data(&file_data, section_name)
+        .with_context(|| format!("{path:?} does not contain a {section_name} section"))?;
+
+    match output {
+        Some(output) => fs::write(output, section_data)
+            .with_context(|| format!("Failed to write section to {output:?}")),
+        None => std::io::stdout()
+            .write_all(section_data)
+            .context("Failed to write section to stdout"),
+    }
+}
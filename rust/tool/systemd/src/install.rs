@@ -1,28 +1,44 @@
 use std::collections::BTreeSet;
-use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File};
 use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
 use std::os::unix::prelude::{OsStrExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::string::ToString;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime};
+use std::{iter, thread};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use base32ct::{Base32Unpadded, Encoding};
+use nix::sys::statvfs::statvfs;
 use nix::unistd::syncfs;
 use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
 use crate::architecture::SystemdArchitectureExt;
+use crate::bls::{self, BlsBoot, BlsEntry};
+use crate::cmdline_map::{CmdlineMap, CmdlineOverride};
 use crate::esp::SystemdEspPaths;
+use crate::kernel_install::KernelInstallEntries;
+use crate::signed_manifest::SignedManifest;
+use crate::snapshot;
+#[cfg(feature = "test-boot")]
+use crate::test_boot::TestBoot;
 use crate::version::SystemdVersion;
 use lanzaboote_tool::architecture::Architecture;
-use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::esp::{validate_file_name, EspPaths};
 use lanzaboote_tool::gc::Roots;
-use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::generation::{self, Generation, GenerationLink};
+use lanzaboote_tool::hash_cache::HashCache;
 use lanzaboote_tool::os_release::OsRelease;
-use lanzaboote_tool::pe::{self, append_initrd_secrets, lanzaboote_image};
+use lanzaboote_tool::pe::{
+    self, append_initrd_secrets, lanzaboote_image, AdditionalSection, StubMetadata,
+};
+use lanzaboote_tool::sbat::Sbat;
 use lanzaboote_tool::signature::Signer;
-use lanzaboote_tool::utils::{file_hash, SecureTempDirExt};
+use lanzaboote_tool::utils::{file_hash, tmpname, SecureTempDirExt};
 
 pub struct Installer<S: Signer> {
     broken_gens: BTreeSet<u64>,
@@ -35,10 +51,218 @@ pub struct Installer<S: Signer> {
     esp_paths: SystemdEspPaths,
     generation_links: Vec<PathBuf>,
     arch: Architecture,
+    bls_entries: bool,
+    parallel_copy: bool,
+    detached_signatures: Option<PathBuf>,
+    cmdline_map: Option<CmdlineMap>,
+    esp_permissions: EspPermissions,
+    os_release: Option<OsRelease>,
+    sort_key_template: Option<String>,
+    sbat: Option<Sbat>,
+    additional_sections: Vec<AdditionalSection>,
+    embed_metadata: bool,
+    pinned_gc_roots: Vec<PathBuf>,
+    signed_manifest: Option<PathBuf>,
+    signed_files: SignedManifest,
+    hash_cache: Arc<HashCache>,
+    kernel_cmdline: Option<Vec<String>>,
+    kernel_install_entries: Option<KernelInstallEntries>,
+    take_over: bool,
+    install_order: InstallOrder,
+    keep_since: Option<Duration>,
+    esp_budget: Option<u64>,
+    esp_reserve: Option<u64>,
+    fit_esp: bool,
+    booted_system: Option<PathBuf>,
+    running_generation_policy: RunningGenerationPolicy,
+    collapse_identical: bool,
+    compare_with_installed: bool,
+    reconcile: bool,
+    force: bool,
+    snapshots: Option<PathBuf>,
+    default_entry: Option<DefaultEntry>,
+    /// The ID of the default entry among the generations to install.
+    default_entry_id: Option<String>,
+    boot_mode: BootMode,
+    #[cfg(feature = "test-boot")]
+    test_boot: Option<TestBoot>,
+}
+
+/// A generation or one of its specialisations, e.g. `42` or `42:debug`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefaultEntry {
+    pub version: u64,
+    pub specialisation: Option<String>,
+}
+
+impl DefaultEntry {
+    fn matches(&self, generation: &Generation) -> bool {
+        generation.version == self.version
+            && generation.specialisation_name.as_ref().map(|name| &name.0)
+                == self.specialisation.as_ref()
+    }
+}
+
+impl fmt::Display for DefaultEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.specialisation {
+            Some(specialisation) => write!(f, "{}:{specialisation}", self.version),
+            None => write!(f, "{}", self.version),
+        }
+    }
+}
+
+/// The exit code of `lzbt install` if no generation links are found.
+pub const NO_GENERATIONS_EXIT_CODE: i32 = 3;
+
+/// No generation links were found, e.g. because the profile directory is wrong.
+///
+/// Installing nothing would garbage collect every generation on the ESP, so this is always an
+/// error.
+#[derive(Debug)]
+pub struct NoGenerations {
+    profile_dirs: BTreeSet<PathBuf>,
+}
+
+impl NoGenerations {
+    /// The error for the given paths, none of which is a generation link.
+    fn new(paths: &[PathBuf]) -> Self {
+        let profile_dirs = paths
+            .iter()
+            .filter_map(|path| path.parent())
+            .map(Path::to_path_buf)
+            .collect();
+        Self { profile_dirs }
+    }
+}
+
+impl fmt::Display for NoGenerations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.profile_dirs.is_empty() {
+            return f.write_str("No generation links were passed. Is the profile correct?");
+        }
+        let profile_dirs = self
+            .profile_dirs
+            .iter()
+            .map(|dir| format!("{dir:?}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "No generations found under {profile_dirs}. Is the profile correct?"
+        )
+    }
+}
+
+impl std::error::Error for NoGenerations {}
+
+/// The exit code of `lzbt install --compare-with-installed` if the ESP is not up to date.
+pub const NOT_UP_TO_DATE_EXIT_CODE: i32 = 4;
+
+/// Installing would change the ESP, so comparing with the installed generations fails.
+///
+/// This is distinct from other errors, so that e.g. CI can tell an outdated ESP from a broken
+/// configuration.
+#[derive(Debug)]
+pub struct NotUpToDate {
+    changes: usize,
+}
+
+impl fmt::Display for NotUpToDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The ESP is not up to date. Installing would make {} changes.",
+            self.changes
+        )
+    }
+}
+
+impl std::error::Error for NotUpToDate {}
+
+/// The order in which the selected generations are installed.
+///
+/// Installation stops at the first generation that fails. Thus, `Newest` is the safest order for
+/// production: the generations that are most likely booted next are installed before an older
+/// generation can fail. The other orders are deterministic regardless of which generations are
+/// new, e.g. for debugging and CI.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum InstallOrder {
+    /// From the largest to the smallest version
+    #[default]
+    Newest,
+    /// From the smallest to the largest version
+    Oldest,
+    /// In the order the generation links are passed
+    Profile,
+}
+
+impl InstallOrder {
+    /// Sort the links, which are sorted by version, into this order.
+    ///
+    /// `profile` are the generation links in the order they were passed.
+    fn sort(self, links: &mut [GenerationLink], profile: &[PathBuf]) {
+        match self {
+            Self::Newest => links.reverse(),
+            Self::Oldest => (),
+            Self::Profile => {
+                links.sort_by_key(|link| profile.iter().position(|path| *path == link.path))
+            }
+        }
+    }
+}
+
+/// How the generations are booted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BootMode {
+    /// Via a signed Lanzaboote stub that verifies the kernel and initrd
+    #[default]
+    Stub,
+    /// Via the EFI stub of the signed kernel and a boot loader entry. The initrd and the kernel
+    /// command line are not verified
+    Kernel,
+}
+
+/// What to do if the booted generation is beyond the configuration limit.
+///
+/// Its files on the ESP would be garbage collected, so the running system could not be booted
+/// again, e.g. if the newer generations turn out to be broken.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum RunningGenerationPolicy {
+    /// Abort the installation
+    Error,
+    /// Install the booted generation in addition to the ones within the limit
+    #[default]
+    Keep,
+    /// Garbage collect the booted generation like any other old generation
+    Collect,
+}
+
+/// The permission bits of files and directories created on the ESP.
+///
+/// FAT does not store Unix permissions. On a vfat ESP, they are determined by the `fmask` and
+/// `dmask` mount options (and the owner by `uid` and `gid`) and these modes have no visible
+/// effect. They matter when the ESP is a directory on a file system with Unix permissions, e.g.
+/// when producing a file system tree that is converted to an image, and for the temporary files
+/// of atomic copies.
+#[derive(Clone, Copy, Debug)]
+pub struct EspPermissions {
+    pub file_mode: u32,
+    pub dir_mode: u32,
+}
+
+impl Default for EspPermissions {
+    /// 0o755 are the expected permissions for a vfat ESP.
+    fn default() -> Self {
+        Self {
+            file_mode: 0o755,
+            dir_mode: 0o755,
+        }
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
-impl<S: Signer> Installer<S> {
+impl<S: Signer + Sync> Installer<S> {
     pub fn new(
         lanzaboote_stub: PathBuf,
         arch: Architecture,
@@ -49,335 +273,2093 @@ impl<S: Signer> Installer<S> {
         esp: PathBuf,
         generation_links: Vec<PathBuf>,
     ) -> Self {
-        let mut gc_roots = Roots::new();
-        let esp_paths = SystemdEspPaths::new(esp, arch);
-        gc_roots.extend(esp_paths.iter());
-
         Self {
             broken_gens: BTreeSet::new(),
-            gc_roots,
+            gc_roots: Roots::new(),
             lanzaboote_stub,
             systemd,
             systemd_boot_loader_config,
             signer,
             configuration_limit,
-            esp_paths,
+            esp_paths: SystemdEspPaths::new(esp, arch),
             generation_links,
             arch,
+            bls_entries: false,
+            parallel_copy: false,
+            detached_signatures: None,
+            cmdline_map: None,
+            esp_permissions: EspPermissions::default(),
+            os_release: None,
+            sort_key_template: None,
+            sbat: None,
+            additional_sections: Vec::new(),
+            embed_metadata: false,
+            pinned_gc_roots: Vec::new(),
+            signed_manifest: None,
+            signed_files: SignedManifest::default(),
+            hash_cache: Arc::new(HashCache::in_memory()),
+            kernel_cmdline: None,
+            kernel_install_entries: None,
+            take_over: false,
+            install_order: InstallOrder::default(),
+            keep_since: None,
+            esp_budget: None,
+            esp_reserve: None,
+            fit_esp: false,
+            booted_system: None,
+            running_generation_policy: RunningGenerationPolicy::default(),
+            collapse_identical: false,
+            compare_with_installed: false,
+            reconcile: false,
+            force: false,
+            snapshots: None,
+            default_entry: None,
+            default_entry_id: None,
+            boot_mode: BootMode::default(),
+            #[cfg(feature = "test-boot")]
+            test_boot: None,
         }
     }
 
-    pub fn install(&mut self) -> Result<()> {
-        log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
+    /// Boot every installed generation via a Boot Loader Specification Type #1 entry in
+    /// `loader/entries` that chainloads its stub.
+    ///
+    /// The stubs are installed to `EFI/nixos` then, so that systemd-boot lists every generation
+    /// only once.
+    pub fn with_bls_entries(mut self, bls_entries: bool) -> Self {
+        self.bls_entries = bls_entries;
+        self
+    }
 
-        let mut links = self
-            .generation_links
-            .iter()
-            .map(GenerationLink::from_path)
-            .collect::<Result<Vec<GenerationLink>>>()?;
-
-        // Sort the links by version, so that the limit actually skips the oldest generations.
-        links.sort_by_key(|l| l.version);
-
-        // A configuration limit of 0 means there is no limit.
-        if self.configuration_limit > 0 {
-            // Only install the number of generations configured. Reverse the list to only take the
-            // latest generations and then, after taking them, reverse the list again so that the
-            // generations are installed from oldest to newest, i.e. from smallest to largest
-            // generation version.
-            links = links
-                .into_iter()
-                .rev()
-                .take(self.configuration_limit)
-                .rev()
-                .collect()
+    /// Prepare the next generation while the previous one is copied to the ESP.
+    ///
+    /// Even then, only one signing operation runs at a time. Signers backed by a token that cannot
+    /// handle concurrent operations, e.g. an HSM, can thus be used with it.
+    pub fn with_parallel_copy(mut self, parallel_copy: bool) -> Self {
+        self.parallel_copy = parallel_copy;
+        self
+    }
+
+    /// Additionally write the PKCS#7 signature of every signed image to a `<name>.p7s` file in
+    /// the given directory.
+    pub fn with_detached_signatures(mut self, detached_signatures: Option<&Path>) -> Self {
+        self.detached_signatures = detached_signatures.map(Path::to_path_buf);
+        self
+    }
+
+    /// Override the kernel command line of individual generations.
+    pub fn with_cmdline_map(mut self, cmdline_map: Option<CmdlineMap>) -> Self {
+        self.cmdline_map = cmdline_map;
+        self
+    }
+
+    /// Set the permission bits of files and directories created on the ESP.
+    pub fn with_esp_permissions(mut self, esp_permissions: EspPermissions) -> Self {
+        self.esp_permissions = esp_permissions;
+        self
+    }
+
+    /// Embed this os-release into all stubs instead of the one generated from the generation.
+    pub fn with_os_release(mut self, os_release: Option<OsRelease>) -> Self {
+        self.os_release = os_release;
+        self
+    }
+
+    /// Render the sort key of the boot entries from this template, see [`Generation::sort_key`].
+    ///
+    /// The sort key is embedded as `IMAGE_ID` and the version of the generation as
+    /// `IMAGE_VERSION`. systemd-boot prefers them over `ID` and `VERSION_ID` to sort the menu:
+    /// ascending by sort key and then newest version first.
+    pub fn with_sort_key_template(mut self, sort_key_template: Option<String>) -> Self {
+        self.sort_key_template = sort_key_template;
+        self
+    }
+
+    /// Embed this SBAT metadata into the `.sbat` section of all stubs.
+    ///
+    /// The stub does not ship SBAT metadata of its own. This is e.g. for testing that shim
+    /// refuses to boot a stub with a revoked generation.
+    pub fn with_sbat(mut self, sbat: Option<Sbat>) -> Self {
+        self.sbat = sbat;
+        self
+    }
+
+    /// Embed these sections into all stubs after the ones Lanzaboote manages.
+    pub fn with_additional_sections(mut self, additional_sections: Vec<AdditionalSection>) -> Self {
+        self.additional_sections = additional_sections;
+        self
+    }
+
+    /// Embed the generation, profile and specialisation of every stub as a `.lzmeta` section.
+    pub fn with_embed_metadata(mut self, embed_metadata: bool) -> Self {
+        self.embed_metadata = embed_metadata;
+        self
+    }
+
+    /// Never garbage collect these files, e.g. files on the ESP that are managed manually.
+    ///
+    /// Relative paths are relative to the ESP. Only the exact paths are kept: pinning a directory
+    /// does not pin its contents.
+    pub fn with_pinned_gc_roots(mut self, pinned_gc_roots: Vec<PathBuf>) -> Self {
+        self.pinned_gc_roots = pinned_gc_roots;
+        self
+    }
+
+    /// Write a manifest of the installed files to this path on the ESP, e.g.
+    /// `EFI/nixos-signed/manifest.json`, that tells the signed files apart from the unsigned ones.
+    ///
+    /// Relative paths are relative to the ESP. The manifest is never garbage collected.
+    pub fn with_signed_manifest(mut self, signed_manifest: Option<PathBuf>) -> Self {
+        self.signed_manifest = signed_manifest;
+        self
+    }
+
+    /// Look up the digests of kernels and initrds in this cache instead of reading them again.
+    ///
+    /// The cache can be shared with the installers of other ESPs. The installer never saves it, so
+    /// that it can be saved once after all installations.
+    pub fn with_hash_cache(mut self, hash_cache: Arc<HashCache>) -> Self {
+        self.hash_cache = hash_cache;
+        self
+    }
+
+    /// Use this kernel command line for all generations instead of the one from their bootspec.
+    ///
+    /// A cmdline map is applied on top of it.
+    pub fn with_kernel_cmdline(mut self, kernel_cmdline: Option<Vec<String>>) -> Self {
+        self.kernel_cmdline = kernel_cmdline;
+        self
+    }
+
+    /// Install systemd-boot as the removable media fallback with this file name in `EFI/BOOT`
+    /// instead of the default name for the architecture.
+    pub fn with_efi_fallback_filename(mut self, filename: Option<&str>) -> Self {
+        if let Some(filename) = filename {
+            self.esp_paths.efi_fallback = Some(self.esp_paths.efi_fallback_dir.join(filename));
+        }
+        self
+    }
+
+    /// Whether to install systemd-boot as the removable media fallback at all.
+    ///
+    /// Garbage collection never touches `EFI/BOOT`, so an existing fallback, e.g. of another OS,
+    /// is kept as is.
+    pub fn with_efi_fallback(mut self, efi_fallback: bool) -> Self {
+        if !efi_fallback {
+            self.esp_paths.efi_fallback = None;
+        }
+        self
+    }
+
+    /// Do not garbage collect files that belong to kernel-install.
+    pub fn with_kernel_install_entries(
+        mut self,
+        kernel_install_entries: Option<KernelInstallEntries>,
+    ) -> Self {
+        self.kernel_install_entries = kernel_install_entries;
+        self
+    }
+
+    /// Garbage collect all unused files in esp/EFI/nixos, not only those named like the kernels
+    /// and initrds Lanzaboote installs there.
+    pub fn with_take_over(mut self, take_over: bool) -> Self {
+        self.take_over = take_over;
+        self
+    }
+
+    /// Install the selected generations in this order.
+    pub fn with_install_order(mut self, install_order: InstallOrder) -> Self {
+        self.install_order = install_order;
+        self
+    }
+
+    /// Also keep the generations beyond the configuration limit that were built within this
+    /// duration.
+    pub fn with_keep_since(mut self, keep_since: Option<Duration>) -> Self {
+        self.keep_since = keep_since;
+        self
+    }
+
+    /// Keep all files on the ESP that were modified within this duration during garbage
+    /// collection, even if no installed generation uses them.
+    pub fn with_keep_modified_since(mut self, keep_modified_since: Option<Duration>) -> Self {
+        if let Some(age) = keep_modified_since {
+            self.gc_roots.keep_newer_than(age);
+        }
+        self
+    }
+
+    /// Drop the oldest generations until the estimated size of the rest fits into this many
+    /// bytes.
+    pub fn with_esp_budget(mut self, esp_budget: Option<u64>) -> Self {
+        self.esp_budget = esp_budget;
+        self
+    }
+
+    /// Refuse to install if the generations would leave less than this many bytes free on the
+    /// ESP.
+    ///
+    /// With `fit_esp`, the oldest generations are dropped to keep this much space free instead.
+    pub fn with_esp_reserve(mut self, esp_reserve: Option<u64>) -> Self {
+        self.esp_reserve = esp_reserve;
+        self
+    }
+
+    /// Drop the oldest generations until the estimated size of the rest fits into the space
+    /// available on the ESP.
+    ///
+    /// This is combined with the ESP budget if there is one.
+    pub fn with_fit_esp(mut self, fit_esp: bool) -> Self {
+        self.fit_esp = fit_esp;
+        self
+    }
+
+    /// Never drop the generation of this toplevel to fit into the ESP, i.e. the running system.
+    pub fn with_booted_system(mut self, booted_system: Option<PathBuf>) -> Self {
+        self.booted_system = booted_system;
+        self
+    }
+
+    /// Decide what happens if the booted generation is beyond the configuration limit.
+    pub fn with_running_generation_policy(
+        mut self,
+        running_generation_policy: RunningGenerationPolicy,
+    ) -> Self {
+        self.running_generation_policy = running_generation_policy;
+        self
+    }
+
+    /// Only install the newest of consecutive generations with the same toplevel.
+    pub fn with_collapse_identical(mut self, collapse_identical: bool) -> Self {
+        self.collapse_identical = collapse_identical;
+        self
+    }
+
+    /// Only print the changes the installation would make to the ESP and fail if there are any.
+    ///
+    /// Nothing is written and nothing is signed, see [`Installer::compare_with_installed`].
+    pub fn with_compare_with_installed(mut self, compare_with_installed: bool) -> Self {
+        self.compare_with_installed = compare_with_installed;
+        self
+    }
+
+    /// Check that the files of installed generations are intact and correctly signed before
+    /// skipping them.
+    ///
+    /// A generation with a file that is not is installed again, which only replaces the broken
+    /// files. Intact generations are still skipped and nothing is signed for them.
+    pub fn with_reconcile(mut self, reconcile: bool) -> Self {
+        self.reconcile = reconcile;
+        self
+    }
+
+    /// Sign all generations and systemd-boot again, even if they are already installed and
+    /// validly signed.
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Copy the files Lanzaboote manages on the ESP into a new snapshot in this directory before
+    /// anything is written to the ESP.
+    pub fn with_snapshot(mut self, snapshots: Option<PathBuf>) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Boot this generation or specialisation by default instead of the newest one.
+    ///
+    /// It is written as the `default` key to loader.conf and has to be among the installed
+    /// generations.
+    pub fn with_default_entry(mut self, default_entry: Option<DefaultEntry>) -> Self {
+        self.default_entry = default_entry;
+        self
+    }
+
+    /// Boot the generations via the Lanzaboote stub or directly via their signed kernel.
+    ///
+    /// With [`BootMode::Kernel`], a boot loader entry is written for every generation
+    /// regardless of [`Installer::with_bls_entries`] because systemd-boot cannot find the kernel
+    /// otherwise.
+    pub fn with_boot_mode(mut self, boot_mode: BootMode) -> Self {
+        self.boot_mode = boot_mode;
+        self
+    }
+
+    /// Boot every newly assembled stub in a VM before installing it.
+    #[cfg(feature = "test-boot")]
+    pub fn with_test_boot(mut self, test_boot: Option<TestBoot>) -> Self {
+        self.test_boot = test_boot;
+        self
+    }
+
+    /// Install the generations and systemd-boot and collect garbage on the ESP.
+    ///
+    /// Returns whether anything on the ESP changed.
+    pub fn install(mut self) -> Result<bool> {
+        if self.compare_with_installed {
+            log::info!("Comparing Lanzaboote with {:?}...", self.esp_paths.esp);
+        } else {
+            log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
+        }
+
+        self.gc_roots.extend(self.esp_paths.iter());
+        self.pin_gc_roots()?;
+        let signed_manifest = self.signed_manifest_target()?;
+
+        let mut links = read_generation_links(&self.generation_links)?;
+        if links.is_empty() {
+            return Err(NoGenerations::new(&self.generation_links).into());
+        }
+        if self.collapse_identical {
+            links = collapse_identical(links);
+        }
+        let (mut dropped, mut links) = split_off_retained(links, self.configuration_limit);
+        if let Some(keep_since) = self.keep_since {
+            (dropped, links) = retain_built_since(dropped, links, keep_since);
+        }
+        let mut links = self.apply_running_generation_policy(dropped, links)?;
+        let available = if self.fit_esp || self.esp_reserve.is_some() {
+            Some(self.available_esp_space()?)
+        } else {
+            None
         };
-        self.install_generations_from_links(&links)?;
+        let mut esp_budget = self.esp_budget;
+        if let Some(available) = available.filter(|_| self.fit_esp) {
+            let usable = available.saturating_sub(self.esp_reserve.unwrap_or(0));
+            esp_budget = Some(esp_budget.map_or(usable, |budget| budget.min(usable)));
+        }
+        if let Some(esp_budget) = esp_budget {
+            // The dropped generations are not garbage collection roots and are thus removed.
+            links = self.fit_into_esp_budget(links, esp_budget)?;
+        }
+        if let Some(available) = available {
+            self.ensure_esp_reserve(&links, available)?;
+        }
+        self.install_order.sort(&mut links, &self.generation_links);
+
+        if let Some(cmdline_map) = &self.cmdline_map {
+            let versions = links.iter().map(|l| l.version).collect::<Vec<u64>>();
+            for version in cmdline_map.stale_entries(&versions) {
+                log::warn!(
+                    "The cmdline map contains generation {version}, which is not installed."
+                );
+            }
+        }
 
-        self.install_systemd_boot()?;
+        if self.compare_with_installed {
+            // Comparing fails if anything would change.
+            return self.compare_with_installed(&links).map(|()| false);
+        }
+
+        if let Some(snapshots) = &self.snapshots {
+            let collected = [
+                &self.esp_paths.nixos,
+                &self.esp_paths.linux,
+                &self.esp_paths.entries,
+            ]
+            .into_iter()
+            .any(|directory| snapshots.starts_with(directory));
+            if collected {
+                bail!("The snapshots cannot be saved in {snapshots:?}, a directory Lanzaboote collects garbage in.");
+            }
+            snapshot::take(&self.esp_paths, snapshots)?;
+        }
+
+        let mut changed = self.install_generations_from_links(&links)?;
+
+        changed |= self.install_systemd_boot()?;
 
         if self.broken_gens.is_empty() {
             log::info!("Collecting garbage...");
             // Only collect garbage in these two directories. This way, no files that do not belong to
-            // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
-            // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
-            // that need files in this directory will NOT work.
-            self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
-            // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
-            // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
-            // deleted).
-            self.gc_roots
-                .collect_garbage_with_filter(&self.esp_paths.linux, |p| {
-                    p.file_name()
-                        .and_then(|n| n.to_str())
-                        .map_or(false, |n| n.starts_with("nixos-"))
-                })?;
+            // the NixOS installation are deleted. Even in the esp/EFI/nixos directory, only files
+            // named like the ones Lanzaboote creates there are deleted, unless taking over.
+            let is_nixos_garbage_candidate = |path: &Path| self.is_nixos_garbage_candidate(path);
+            let mut removed = self
+                .gc_roots
+                .collect_garbage_with_filter(&self.esp_paths.nixos, is_nixos_garbage_candidate)?;
+            let is_garbage_candidate = |path: &Path| self.is_garbage_candidate(path);
+            removed += self
+                .gc_roots
+                .collect_garbage_with_filter(&self.esp_paths.linux, is_garbage_candidate)?;
+            // The loader/entries directory is shared in the same way. It is only touched at all
+            // when Lanzaboote is configured to write entries there.
+            if self.writes_bls_entries() {
+                removed += self
+                    .gc_roots
+                    .collect_garbage_with_filter(&self.esp_paths.entries, is_garbage_candidate)?;
+            }
+            changed |= removed > 0;
         } else {
             // This might produce a ridiculous message if you have a lot of malformed generations.
             let warning = indoc::formatdoc! {"
                 Garbage collection is disabled because you have malformed NixOS generations that do
                 not contain a readable bootspec document.
 
-                Remove the malformed generations to re-enable garbage collection with
-                `nix-env --delete-generations {}`
-            ", self.broken_gens.iter().map(ToString::to_string).collect::<Vec<String>>().join(" ")};
-            log::warn!("{warning}");
+                Remove the malformed generations to re-enable garbage collection with
+                `nix-env --delete-generations {}`
+            ", self.broken_gens.iter().map(ToString::to_string).collect::<Vec<String>>().join(" ")};
+            log::warn!("{warning}");
+        };
+
+        // The manifest is written last, so that it does not list garbage.
+        if let Some(target) = &signed_manifest {
+            changed |= self.write_signed_manifest(target)?;
+        }
+
+        if changed {
+            log::info!("Successfully installed Lanzaboote.");
+        } else {
+            log::info!("Nothing changed. The ESP is already up to date.");
+        }
+        Ok(changed)
+    }
+
+    /// Add the pinned paths and their parent directories to the garbage collection roots.
+    ///
+    /// Garbage is only collected in esp/EFI/nixos, in esp/EFI/Linux and, if entries are written,
+    /// in loader/entries. Everything else on the ESP is never collected, so pinning it has no
+    /// effect.
+    fn pin_gc_roots(&mut self) -> Result<()> {
+        let esp = &self.esp_paths.esp;
+        for root in &self.pinned_gc_roots {
+            let path = resolve_on_esp(esp, root).with_context(|| {
+                format!("The garbage collection root {root:?} is not on the ESP {esp:?}.")
+            })?;
+            let collected = [&self.esp_paths.nixos, &self.esp_paths.linux]
+                .into_iter()
+                .chain(self.writes_bls_entries().then_some(&self.esp_paths.entries))
+                .any(|directory| path.starts_with(directory));
+            if !collected {
+                log::warn!(
+                    "{path:?} is not in a directory Lanzaboote collects garbage in. Pinning it has no effect."
+                );
+            }
+            self.gc_roots.extend(&ancestors_on_esp(esp, &path));
+        }
+        Ok(())
+    }
+
+    /// Resolve the path of the signed manifest on the ESP and keep it from being garbage
+    /// collected.
+    fn signed_manifest_target(&mut self) -> Result<Option<PathBuf>> {
+        let Some(manifest) = &self.signed_manifest else {
+            return Ok(None);
+        };
+        let esp = &self.esp_paths.esp;
+        let target = resolve_on_esp(esp, manifest).with_context(|| {
+            format!("The signed manifest {manifest:?} is not on the ESP {esp:?}.")
+        })?;
+        self.gc_roots.extend(&ancestors_on_esp(esp, &target));
+        Ok(Some(target))
+    }
+
+    /// Write the manifest of the installed generations and systemd-boot to `target`.
+    ///
+    /// Returns whether the manifest changed.
+    fn write_signed_manifest(&mut self, target: &Path) -> Result<bool> {
+        for systemd_boot in self
+            .esp_paths
+            .efi_fallback
+            .iter()
+            .chain([&self.esp_paths.systemd_boot])
+        {
+            self.signed_files.add_signed(systemd_boot);
+        }
+        self.signed_files
+            .add_unsigned(&self.esp_paths.systemd_boot_loader_config);
+
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let manifest = tempdir
+            .write_secure_file(self.signed_files.to_json(&self.esp_paths.esp)?)
+            .context("Failed to write the signed manifest to the temporary directory.")?;
+        install(&manifest, target, self.esp_permissions)
+            .with_context(|| format!("Failed to install the signed manifest to {target:?}"))
+    }
+
+    /// Whether loader entries are written to the shared loader/entries directory.
+    fn writes_bls_entries(&self) -> bool {
+        self.bls_entries || self.boot_mode == BootMode::Kernel
+    }
+
+    /// Whether an entry in the esp/EFI/nixos directory can be garbage collected.
+    ///
+    /// Lanzaboote only creates content-addressed kernels and initrds directly in this directory,
+    /// stubs booted via boot loader entries and their temporary files while copying them.
+    /// Anything else, e.g. a directory of another tool, is kept with a warning, unless taking
+    /// over.
+    fn is_nixos_garbage_candidate(&self, path: &Path) -> bool {
+        if self.take_over {
+            return true;
+        }
+        if path.parent() != Some(self.esp_paths.nixos.as_path()) {
+            // The directory that contains the entry is kept already.
+            return false;
+        }
+        if is_nixos_file_name(path) || has_nixos_prefix(path) {
+            return true;
+        }
+        log::warn!(
+            "Not garbage collecting {path:?} because Lanzaboote does not create it. Use --take-over to remove it anyway."
+        );
+        false
+    }
+
+    /// Whether a file in the shared esp/EFI/Linux and loader/entries directories can be garbage
+    /// collected.
+    ///
+    /// The esp/EFI/Linux directory is assumed to be potentially shared with other distros. Thus,
+    /// only files that start with "nixos-" are garbage collected (i.e. potentially deleted). Files
+    /// of kernel-install can have the same prefix and are kept.
+    fn is_garbage_candidate(&self, path: &Path) -> bool {
+        if !has_nixos_prefix(path) {
+            return false;
+        }
+        let owned_by_kernel_install = self
+            .kernel_install_entries
+            .as_ref()
+            .is_some_and(|entries| entries.owns(path));
+        if owned_by_kernel_install {
+            log::debug!("Not garbage collecting {path:?} because it belongs to kernel-install.");
+        }
+        !owned_by_kernel_install
+    }
+
+    /// Print the changes that installing the generations would make to the ESP.
+    ///
+    /// A generation whose stub is not on the ESP would be installed. Nothing has to be signed to
+    /// find out: the name of the stub only depends on the public key. systemd-boot, the loader
+    /// config, the BLS entries and the garbage are compared like during the installation. Fails
+    /// with `NotUpToDate` if anything would change, e.g. so that CI can check that the ESP is up
+    /// to date.
+    fn compare_with_installed(&mut self, links: &[GenerationLink]) -> Result<()> {
+        let generations = self.generations_from_links(links)?;
+        self.default_entry_id = self.default_entry_id(&generations)?;
+        let mut gc_roots = std::mem::take(&mut self.gc_roots);
+        let stager = self.stager();
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let mut changes = Vec::new();
+
+        for generation in &generations {
+            let installed = stager
+                .installed_generation_files(generation)
+                .and_then(|installed| stager.ensure_intact(&installed).map(|()| installed));
+            match installed {
+                Ok(installed) => gc_roots.extend(&installed),
+                Err(_) => {
+                    changes.push(format!("install {}", describe_generation(generation)));
+                    gc_roots.extend(&stager.stub_targets(generation)?);
+                }
+            }
+            if stager.writes_stub_bls_entries() {
+                let boot = stager.stub_bls_boot(generation)?;
+                let (entry, entry_target) = stager.prepare_bls_entry(generation, &tempdir, boot)?;
+                if !same_contents(&entry, &entry_target)? {
+                    changes.push(format!("write {entry_target:?}"));
+                }
+                gc_roots.extend([&entry_target]);
+            }
+        }
+
+        let systemd_boot = self
+            .systemd
+            .join("lib/systemd/boot/efi")
+            .join(self.arch.systemd_filename());
+        ensure_source_exists("systemd-boot binary", &systemd_boot)?;
+        let paths = self
+            .esp_paths
+            .efi_fallback
+            .iter()
+            .chain([&self.esp_paths.systemd_boot]);
+        for to in paths {
+            let outdated = newer_systemd_boot(&systemd_boot, to)?
+                || ensure_architecture(to, self.arch).is_err()
+                || !self.signer.verify_path(to)?;
+            if outdated {
+                changes.push(format!("update {to:?}"));
+            }
+        }
+        let loader_config = &self.esp_paths.systemd_boot_loader_config;
+        if !same_contents(&self.loader_config(&tempdir)?, loader_config)? {
+            changes.push(format!("update {loader_config:?}"));
+        }
+
+        // Like the installation, garbage is only collected without malformed generations.
+        if self.broken_gens.is_empty() {
+            let is_garbage_candidate = |path: &Path| self.is_garbage_candidate(path);
+            let is_nixos_garbage_candidate = |path: &Path| self.is_nixos_garbage_candidate(path);
+            let mut garbage = gc_roots.collect_garbage_dry_run_with_filter(
+                &self.esp_paths.nixos,
+                is_nixos_garbage_candidate,
+            )?;
+            garbage.extend(gc_roots.collect_garbage_dry_run_with_filter(
+                &self.esp_paths.linux,
+                is_garbage_candidate,
+            )?);
+            if self.writes_bls_entries() {
+                garbage.extend(gc_roots.collect_garbage_dry_run_with_filter(
+                    &self.esp_paths.entries,
+                    is_garbage_candidate,
+                )?);
+            }
+            changes.extend(garbage.iter().map(|path| format!("remove {path:?}")));
+        }
+
+        if !changes.is_empty() {
+            for change in &changes {
+                println!("{change}");
+            }
+            return Err(NotUpToDate {
+                changes: changes.len(),
+            }
+            .into());
+        }
+        log::info!("The ESP is up to date.");
+        Ok(())
+    }
+
+    /// Handle the booted generation if it is among the generations beyond the configuration limit.
+    ///
+    /// Returns the generations to install, sorted from oldest to newest.
+    fn apply_running_generation_policy(
+        &self,
+        mut dropped: Vec<GenerationLink>,
+        mut links: Vec<GenerationLink>,
+    ) -> Result<Vec<GenerationLink>> {
+        let Some(booted) = dropped.iter().position(|link| self.is_booted(link)) else {
+            return Ok(links);
+        };
+        let version = dropped[booted].version;
+        match self.running_generation_policy {
+            RunningGenerationPolicy::Error => bail!(
+                "The booted generation {version} is beyond the configuration limit of {}. Increase the limit or boot a newer generation.",
+                self.configuration_limit
+            ),
+            RunningGenerationPolicy::Keep => {
+                log::info!("Keeping the booted generation {version} beyond the configuration limit.");
+                // The dropped generations are older than the retained ones.
+                links.insert(0, dropped.remove(booted));
+            }
+            RunningGenerationPolicy::Collect => log::warn!(
+                "Removing the booted generation {version} because it is beyond the configuration limit."
+            ),
+        }
+        Ok(links)
+    }
+
+    /// Drop the oldest generations until the estimated size of the rest fits into the budget.
+    ///
+    /// The newest generation is always kept, even if it alone exceeds the budget, because
+    /// installing no generation at all leaves the system unbootable. The booted generation is
+    /// kept as well.
+    fn fit_into_esp_budget(
+        &self,
+        mut links: Vec<GenerationLink>,
+        esp_budget: u64,
+    ) -> Result<Vec<GenerationLink>> {
+        let stub_size = self.stub_size()?;
+        let mut usages = links
+            .iter()
+            .map(GenerationEspUsage::from_link)
+            .collect::<Vec<_>>();
+
+        while estimate_esp_usage(&usages, stub_size) > esp_budget {
+            // The newest generation is the last one.
+            let Some(oldest) =
+                (0..links.len().saturating_sub(1)).find(|&i| !self.is_booted(&links[i]))
+            else {
+                break;
+            };
+            log::warn!(
+                "Dropping generation {} to fit into the ESP budget of {} MiB.",
+                links[oldest].version,
+                esp_budget / MIB
+            );
+            links.remove(oldest);
+            usages.remove(oldest);
+        }
+
+        let estimate = estimate_esp_usage(&usages, stub_size);
+        if estimate > esp_budget {
+            log::warn!(
+                "The generations that are always kept need about {} MiB, which exceeds the ESP budget of {} MiB.",
+                estimate.div_ceil(MIB),
+                esp_budget / MIB
+            );
+        }
+        Ok(links)
+    }
+
+    /// Report the free space the generations would leave on the ESP and ensure that it is at least
+    /// the reserved space.
+    fn ensure_esp_reserve(&self, links: &[GenerationLink], available: u64) -> Result<()> {
+        let usages = links
+            .iter()
+            .map(GenerationEspUsage::from_link)
+            .collect::<Vec<_>>();
+        let free_after = available.saturating_sub(estimate_esp_usage(&usages, self.stub_size()?));
+        log::info!(
+            "About {} MiB of the ESP will be free after the installation.",
+            free_after / MIB
+        );
+        if let Some(esp_reserve) = self.esp_reserve {
+            if free_after < esp_reserve {
+                bail!(
+                    "Installing would leave about {} MiB free on the ESP, less than the reserved {} MiB.",
+                    free_after / MIB,
+                    esp_reserve / MIB
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The size of the unsigned Lanzaboote stub, which estimates the size of every stub.
+    fn stub_size(&self) -> Result<u64> {
+        Ok(fs::metadata(&self.lanzaboote_stub)
+            .with_context(|| format!("Failed to read the size of {:?}", self.lanzaboote_stub))?
+            .len())
+    }
+
+    /// Whether the generation link points to the booted system.
+    fn is_booted(&self, link: &GenerationLink) -> bool {
+        self.booted_system.as_ref().is_some_and(|booted_system| {
+            fs::canonicalize(&link.path).is_ok_and(|toplevel| toplevel == *booted_system)
+        })
+    }
+
+    /// The space Lanzaboote can use on the ESP.
+    ///
+    /// This is the free space and the space used by the files of Lanzaboote that are already
+    /// installed. These are either kept or garbage collected. Files that are replaced need the
+    /// free space for their new version until the old version is garbage collected.
+    fn available_esp_space(&self) -> Result<u64> {
+        let esp = &self.esp_paths.esp;
+        let stat = statvfs(esp)
+            .with_context(|| format!("Failed to read the free space of the ESP {esp:?}"))?;
+        // The types of the fields are only smaller than u64 on some 32-bit platforms.
+        #[allow(clippy::useless_conversion)]
+        let free = u64::from(stat.blocks_available()) * u64::from(stat.fragment_size());
+
+        let used = directory_size(&self.esp_paths.nixos, |_| true)?
+            + directory_size(&self.esp_paths.linux, has_nixos_prefix)?;
+        log::debug!(
+            "{} MiB are free on the ESP and Lanzaboote uses {} MiB.",
+            free / MIB,
+            used / MIB
+        );
+        Ok(free + used)
+    }
+
+    /// Read the generations and their specialisations from the links.
+    ///
+    /// Malformed generations are skipped and recorded in `broken_gens`.
+    fn generations_from_links(&mut self, links: &[GenerationLink]) -> Result<Vec<Generation>> {
+        let generations = links
+            .iter()
+            .filter_map(|link| {
+                let generation_result = Generation::from_link(link)
+                    .with_context(|| format!("Failed to build generation from link: {link:?}"));
+
+                // Ignore failing to read a generation so that old malformed generations do not stop
+                // lzbt from working.
+                if generation_result.is_err() {
+                    // If there is ANY malformed generation present, completely disable all garbage
+                    // collection to protect the old generations from being deleted. The user has
+                    // to manually intervene by getting rid of the old generations to re-enable
+                    // garbage collection. This safeguard against catastrophic failure in case of
+                    // unhandled upstream changes to NixOS.
+                    self.broken_gens.insert(link.version);
+                }
+
+                generation_result.ok()
+            })
+            .collect::<Vec<Generation>>();
+
+        if generations.is_empty() {
+            // We can't continue, because we would remove all boot entries, if we did.
+            return Err(anyhow!("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!"));
+        }
+
+        // Specialisations are installed right after the generation they belong to.
+        let generations = generations
+            .into_iter()
+            .flat_map(|generation| {
+                let specialisations = generation
+                    .spec
+                    .bootspec
+                    .specialisations
+                    .iter()
+                    .map(|(name, bootspec)| generation.specialise(name, bootspec))
+                    .collect::<Vec<Generation>>();
+                iter::once(generation).chain(specialisations)
+            })
+            .collect::<Vec<Generation>>();
+
+        Ok(generations)
+    }
+
+    /// Find the ID of the selected default entry among the generations to install.
+    fn default_entry_id(&self, generations: &[Generation]) -> Result<Option<String>> {
+        let Some(default_entry) = &self.default_entry else {
+            return Ok(None);
+        };
+        let Some(generation) = generations.iter().find(|g| default_entry.matches(g)) else {
+            let installed = generations
+                .iter()
+                .map(|generation| match &generation.specialisation_name {
+                    Some(name) => format!("{}:{name}", generation.version),
+                    None => generation.version.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!("The default entry {default_entry} is not among the installed generations: {installed}.");
+        };
+        self.stager().entry_id(generation).map(Some)
+    }
+
+    /// The loader config to install, with the `default` key of the selected default entry.
+    fn loader_config(&self, tempdir: &TempDir) -> Result<PathBuf> {
+        let Some(id) = &self.default_entry_id else {
+            return Ok(self.systemd_boot_loader_config.clone());
+        };
+        let path = &self.systemd_boot_loader_config;
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the systemd-boot loader config {path:?}"))?;
+        tempdir
+            .write_secure_file(with_default_entry(&contents, id))
+            .context("Failed to write the loader config to the temporary directory.")
+    }
+
+    /// Borrow the parts of the installer needed to prepare generations.
+    fn stager(&self) -> GenerationStager<'_, S> {
+        GenerationStager {
+            signer: &self.signer,
+            esp_paths: &self.esp_paths,
+            lanzaboote_stub: &self.lanzaboote_stub,
+            bls_entries: self.bls_entries,
+            detached_signatures: self.detached_signatures.as_deref(),
+            cmdline_map: self.cmdline_map.as_ref(),
+            os_release: self.os_release.as_ref(),
+            sort_key_template: self.sort_key_template.as_deref(),
+            sbat: self.sbat.as_ref(),
+            additional_sections: &self.additional_sections,
+            embed_metadata: self.embed_metadata,
+            reconcile: self.reconcile,
+            force: self.force,
+            hash_cache: &self.hash_cache,
+            kernel_cmdline: self.kernel_cmdline.as_deref(),
+            boot_mode: self.boot_mode,
+            #[cfg(feature = "test-boot")]
+            test_boot: self.test_boot.as_ref(),
+        }
+    }
+
+    /// Install the generations to the ESP.
+    ///
+    /// Returns whether any file of a generation was copied.
+    fn install_generations_from_links(&mut self, links: &[GenerationLink]) -> Result<bool> {
+        let generations = self.generations_from_links(links)?;
+        self.default_entry_id = self.default_entry_id(&generations)?;
+
+        // The stager borrows the installer, so the roots are taken out while it is in use.
+        let mut gc_roots = std::mem::take(&mut self.gc_roots);
+        let mut signed_files = std::mem::take(&mut self.signed_files);
+        let stager = self.stager();
+        let permissions = self.esp_permissions;
+
+        // Fail before anything is signed if an input of a generation that has to be installed is
+        // missing, instead of at some point while copying. All missing inputs are reported at
+        // once, so that they can be fixed at once.
+        let installed = generations
+            .iter()
+            .map(|generation| stager.reusable_installed_files(generation))
+            .collect::<Vec<_>>();
+        let problems = generations
+            .iter()
+            .zip(&installed)
+            .filter(|(_, installed)| installed.is_err())
+            .filter_map(|(generation, _)| {
+                let err = stager.ensure_sources_exist(generation).err()?;
+                Some(format!(
+                    "Failed to install {}: {err:#}",
+                    describe_generation(generation)
+                ))
+            })
+            .collect::<Vec<_>>();
+        if !problems.is_empty() {
+            bail!("{}", problems.join("\n"));
+        }
+
+        // The kernels and initrds are content-addressed.
+        // Thus, this cannot overwrite files of old generation with different content.
+        let changed = if self.parallel_copy {
+            // Signing is CPU-bound while copying to the ESP is I/O-bound. Thus, the next
+            // generation is prepared while the previous one is copied.
+            thread::scope(|scope| {
+                let (sender, receiver) = mpsc::sync_channel(PARALLEL_COPY_QUEUE_SIZE);
+                let generations = &generations;
+                scope.spawn(move || {
+                    for (generation, installed) in generations.iter().zip(installed) {
+                        // The receiver only hangs up when copying failed. This error is reported
+                        // by the copy stage.
+                        if sender
+                            .send((generation, stager.prepare(generation, installed)))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+                let result = receiver
+                    .iter()
+                    .try_fold(false, |changed, (generation, prepared)| {
+                        let copied = commit_generation(
+                            &mut gc_roots,
+                            &mut signed_files,
+                            permissions,
+                            generation,
+                            prepared,
+                        )?;
+                        Ok::<_, anyhow::Error>(changed | copied)
+                    });
+                // Hang up so that the signing stage stops early if copying failed.
+                drop(receiver);
+                result
+            })?
+        } else {
+            let mut changed = false;
+            for (generation, installed) in generations.iter().zip(installed) {
+                changed |= commit_generation(
+                    &mut gc_roots,
+                    &mut signed_files,
+                    permissions,
+                    generation,
+                    stager.prepare(generation, installed),
+                )?;
+            }
+            changed
+        };
+        self.gc_roots = gc_roots;
+        self.signed_files = signed_files;
+
+        // Sync files to persistent storage. This may improve the
+        // chance of a consistent boot directory in case the system
+        // crashes.
+        let boot = File::open(&self.esp_paths.esp).context("Failed to open ESP root directory.")?;
+        syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+
+        Ok(changed)
+    }
+
+    /// Install systemd-boot to ESP.
+    ///
+    /// systemd-boot is only updated when a newer version is available OR when the currently
+    /// installed version is not signed. This enables switching to Lanzaboote without having to
+    /// manually delete previous unsigned systemd-boot binaries and minimizes the number of writes
+    /// to the ESP.
+    ///
+    /// Checking for the version also allows us to skip buggy systemd versions in the future.
+    ///
+    /// Returns whether systemd-boot or its loader.conf was updated.
+    fn install_systemd_boot(&self) -> Result<bool> {
+        let systemd_boot = self
+            .systemd
+            .join("lib/systemd/boot/efi")
+            .join(self.arch.systemd_filename());
+
+        ensure_source_exists("systemd-boot binary", &systemd_boot)?;
+        // Installing a systemd-boot binary for the wrong architecture leaves the system unbootable.
+        ensure_architecture(&systemd_boot, self.arch)
+            .context("The systemd-boot binary does not match the target architecture.")?;
+
+        let paths = self
+            .esp_paths
+            .efi_fallback
+            .iter()
+            .chain([&self.esp_paths.systemd_boot]);
+
+        let mut changed = false;
+        for to in paths {
+            let from = &systemd_boot;
+            let newer_systemd_boot_available = newer_systemd_boot(from, to)?;
+            if newer_systemd_boot_available {
+                log::info!("Updating {to:?}...")
+            };
+            let wrong_architecture = to.exists() && ensure_architecture(to, self.arch).is_err();
+            if wrong_architecture {
+                log::warn!("{to:?} is built for the wrong architecture. Replacing it...")
+            };
+            let systemd_boot_is_signed = &self.signer.verify_path(to)?;
+            if !systemd_boot_is_signed {
+                log::warn!("${to:?} is not signed. Replacing it with a signed binary...")
+            };
+
+            if self.force
+                || newer_systemd_boot_available
+                || !systemd_boot_is_signed
+                || wrong_architecture
+            {
+                install_signed(&self.signer, from, to, self.esp_permissions)
+                    .with_context(|| format!("Failed to install systemd-boot binary to: {to:?}"))?;
+                changed = true;
+            }
+            ensure_architecture(to, self.arch).with_context(|| {
+                format!("The installed systemd-boot binary {to:?} does not match the target architecture.")
+            })?;
+
+            if let Some(directory) = &self.detached_signatures {
+                write_detached_signature(directory, to)?;
+            }
+        }
+
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        changed |= install(
+            &self.loader_config(&tempdir)?,
+            &self.esp_paths.systemd_boot_loader_config,
+            self.esp_permissions,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to install systemd-boot loader.conf to {:?}",
+                &self.esp_paths.systemd_boot_loader_config
+            )
+        })?;
+
+        Ok(changed)
+    }
+}
+
+/// The number of prepared generations that may wait to be copied to the ESP when
+/// `--parallel-copy` is used.
+///
+/// Every prepared generation holds a signed stub and potentially an initrd in a temporary
+/// directory, so this is kept small.
+const PARALLEL_COPY_QUEUE_SIZE: usize = 1;
+
+/// Prepares generations for installation without writing to the ESP.
+///
+/// This only borrows the parts of the [`Installer`] needed to assemble and sign stubs so that the
+/// next generation can be prepared while the previous one is copied to the ESP.
+struct GenerationStager<'a, S: Signer> {
+    signer: &'a S,
+    esp_paths: &'a SystemdEspPaths,
+    lanzaboote_stub: &'a Path,
+    bls_entries: bool,
+    detached_signatures: Option<&'a Path>,
+    cmdline_map: Option<&'a CmdlineMap>,
+    os_release: Option<&'a OsRelease>,
+    sort_key_template: Option<&'a str>,
+    sbat: Option<&'a Sbat>,
+    additional_sections: &'a [AdditionalSection],
+    embed_metadata: bool,
+    reconcile: bool,
+    force: bool,
+    hash_cache: &'a HashCache,
+    kernel_cmdline: Option<&'a [String]>,
+    boot_mode: BootMode,
+    #[cfg(feature = "test-boot")]
+    test_boot: Option<&'a TestBoot>,
+}
+
+impl<S: Signer> GenerationStager<'_, S> {
+    /// Prepare the given `Generation` for installation.
+    ///
+    /// The stub is assembled and signed in a temporary directory. If the generation is already
+    /// properly installed, i.e. `installed` holds the files found by `reusable_installed_files`,
+    /// its files on the ESP are kept and nothing is signed again.
+    fn prepare(
+        &self,
+        generation: &Generation,
+        installed: Result<Vec<PathBuf>>,
+    ) -> Result<PreparedGeneration> {
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+
+        let (mut files, installed) = match installed {
+            Ok(installed) => {
+                if let Some(directory) = self.detached_signatures {
+                    // The signed file is the stub or, in kernel mode, the kernel.
+                    let signed = match self.boot_mode {
+                        BootMode::Stub => self.stub_target(generation)?,
+                        BootMode::Kernel => installed[1].clone(),
+                    };
+                    write_detached_signature(directory, &signed)?;
+                }
+                (Vec::new(), installed)
+            }
+            Err(_) => {
+                let files = match self.boot_mode {
+                    BootMode::Stub => self.prepare_stub(generation, &tempdir)?,
+                    BootMode::Kernel => self.prepare_signed_kernel(generation, &tempdir)?,
+                };
+                // Nothing has been written to the ESP yet. Thus, a stub that does not boot never
+                // replaces a working one.
+                #[cfg(feature = "test-boot")]
+                if let Some(test_boot) = self.test_boot {
+                    test_boot
+                        .run(&self.esp_paths.esp, &files)
+                        .context("Failed to test boot the stub.")?;
+                }
+                (files, Vec::new())
+            }
+        };
+
+        // The signed file is the stub or, in kernel mode, the kernel. A signed kernel is the second
+        // installed file or the first file to copy.
+        let signed = match self.boot_mode {
+            BootMode::Stub => self.stub_target(generation)?,
+            BootMode::Kernel => installed
+                .get(1)
+                .or_else(|| files.first().map(|(_, to)| to))
+                .context("The generation has no signed kernel.")?
+                .clone(),
+        };
+
+        if self.writes_stub_bls_entries() {
+            let boot = self.stub_bls_boot(generation)?;
+            files.push(self.prepare_bls_entry(generation, &tempdir, boot)?);
+        }
+
+        Ok(PreparedGeneration {
+            files,
+            installed,
+            signed,
+            _tempdir: tempdir,
+        })
+    }
+
+    /// Assemble and sign the stub of the given `Generation`.
+    ///
+    /// The kernel and initrd are content-addressed, and the stub name identifies the generation.
+    /// Hence, the returned files cannot overwrite files of other generations with different
+    /// contents. They are returned in the order they need to be copied to the ESP so that a stub
+    /// never references a missing kernel or initrd.
+    fn prepare_stub(
+        &self,
+        generation: &Generation,
+        tempdir: &TempDir,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let spec = &generation.spec;
+        let kernel_version = spec.kernel_version()?;
+
+        // Compute the path of the kernel on the ESP.
+        let kernel_target = self
+            .nixos_ca_target(spec.kernel_path(), &format!("kernel-{}", kernel_version))
+            .context("Failed to hash the kernel.")?;
+
+        let initrd = self.prepare_initrd(generation, tempdir)?;
+
+        // Assemble and sign the Lanzaboote stub.
+        let os_release_contents = self.os_release_contents(generation)?;
+
+        let kernel_cmdline = self.kernel_cmdline(generation)?;
+
+        let parameters = pe::StubParameters::new(
+            self.lanzaboote_stub,
+            spec.kernel_path(),
+            &kernel_target,
+            initrd
+                .as_ref()
+                .map(|(location, target)| (location.as_path(), target.as_path())),
+            &self.esp_paths.esp,
+        )?
+        .with_cmdline(&kernel_cmdline)
+        .with_os_release_contents(os_release_contents.as_bytes())
+        .with_uname(kernel_version)
+        .with_input_hashes(
+            &self.hash_cache.file_hash(spec.kernel_path())?,
+            initrd
+                .as_ref()
+                .map(|(location, _)| self.hash_cache.file_hash(location))
+                .transpose()?
+                .as_deref(),
+        )
+        .with_additional_sections(self.additional_sections);
+        let parameters = match self.sbat {
+            Some(sbat) => parameters.with_sbat(sbat.as_bytes()),
+            None => parameters,
+        };
+        let parameters = match self.metadata(generation) {
+            Some(metadata) => parameters.with_metadata(metadata),
+            None => parameters,
+        };
+
+        let lanzaboote_image_path = lanzaboote_image(tempdir, &parameters)
+            .context("Failed to build lanzaboote stub image.")?;
+
+        let stub_name = self.stub_name(generation).context("Get stub name")?;
+        let signed_stub = tempdir.path().join(&stub_name);
+        log::debug!("Signing {stub_name:?}...");
+        self.signer
+            .sign_and_copy(&lanzaboote_image_path, &signed_stub)
+            .context("Failed to sign the Lanzaboote stub.")?;
+        if let Some(directory) = self.detached_signatures {
+            write_detached_signature(directory, &signed_stub)?;
+        }
+
+        let mut files = vec![(spec.kernel_path().to_path_buf(), kernel_target)];
+        files.extend(initrd);
+        files.push((signed_stub, self.stub_target(generation)?));
+        Ok(files)
+    }
+
+    /// Assemble the initrd of the given `Generation` and compute its path on the ESP.
+    ///
+    /// Returns `None` if the generation has no initrd.
+    fn prepare_initrd(
+        &self,
+        generation: &Generation,
+        tempdir: &TempDir,
+    ) -> Result<Option<(PathBuf, PathBuf)>> {
+        let spec = &generation.spec;
+        let bootspec = &spec.bootspec.bootspec;
+        let kernel_version = spec.kernel_version()?;
+        let Some(initrd) = spec.initrd_path() else {
+            return Ok(None);
+        };
+
+        // It is not needed to write the initrd in a temporary directory
+        // if we do not have any initrd secret.
+        let initrd_location = if bootspec.initrd_secrets.is_some() {
+            tempdir
+                .copy_secure_file(initrd)
+                .context("Failed to copy the initrd to the temporary directory.")?
+        } else {
+            initrd.to_path_buf()
+        };
+
+        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
+            append_initrd_secrets(initrd_secrets_script, &initrd_location, generation.version)?;
+        }
+        let initrd_target = self
+            .nixos_ca_target(&initrd_location, &format!("initrd-{}", kernel_version))
+            .context("Failed to hash the initrd.")?;
+        Ok(Some((initrd_location, initrd_target)))
+    }
+
+    /// Sign the kernel of the given `Generation` and prepare an entry that boots it directly.
+    ///
+    /// The firmware only verifies the kernel, which is content-addressed by its signed contents
+    /// so that rotating the key installs a new kernel. The files are returned in the order they
+    /// need to be copied to the ESP so that the entry never references a missing kernel or
+    /// initrd.
+    fn prepare_signed_kernel(
+        &self,
+        generation: &Generation,
+        tempdir: &TempDir,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let spec = &generation.spec;
+        let kernel_version = spec.kernel_version()?;
+
+        let signed = tempdir.path().join(tmpname());
+        log::debug!(
+            "Signing the kernel of {}...",
+            describe_generation(generation)
+        );
+        self.signer
+            .sign_and_copy(spec.kernel_path(), &signed)
+            .context("Failed to sign the kernel.")?;
+        let kernel_target = self
+            .nixos_ca_target(&signed, &format!("kernel-{}", kernel_version))
+            .context("Failed to hash the signed kernel.")?;
+        // Name the signed kernel like its target, e.g. for its detached signature.
+        let signed_kernel = tempdir.path().join(
+            kernel_target
+                .file_name()
+                .context("The kernel target has no file name.")?,
+        );
+        fs::rename(&signed, &signed_kernel).context("Failed to rename the signed kernel.")?;
+        if let Some(directory) = self.detached_signatures {
+            write_detached_signature(directory, &signed_kernel)?;
+        }
+
+        let initrd = self.prepare_initrd(generation, tempdir)?;
+
+        let boot = BlsBoot::Linux {
+            linux: bls::esp_relative_path(&self.esp_paths.esp, &kernel_target)?,
+            initrd: initrd
+                .as_ref()
+                .map(|(_, target)| bls::esp_relative_path(&self.esp_paths.esp, target))
+                .transpose()?,
+        };
+        let entry = self.prepare_bls_entry(generation, tempdir, boot)?;
+
+        let mut files = vec![(signed_kernel, kernel_target)];
+        files.extend(initrd);
+        files.push(entry);
+        Ok(files)
+    }
+
+    /// Whether the stub is booted via an entry that chainloads it.
+    fn writes_stub_bls_entries(&self) -> bool {
+        self.bls_entries && self.boot_mode == BootMode::Stub
+    }
+
+    /// Chainload the signed stub of the given `Generation`.
+    fn stub_bls_boot(&self, generation: &Generation) -> Result<BlsBoot> {
+        Ok(BlsBoot::Efi(bls::esp_relative_path(
+            &self.esp_paths.esp,
+            &self.stub_target(generation)?,
+        )?))
+    }
+
+    /// Prepare a Boot Loader Specification Type #1 entry for the given `Generation`.
+    fn prepare_bls_entry(
+        &self,
+        generation: &Generation,
+        tempdir: &TempDir,
+        boot: BlsBoot,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let spec = &generation.spec;
+        let extension = &spec.lanzaboote_extension;
+        if let Some(machine_id) = &extension.machine_id {
+            bls::validate_machine_id(machine_id)?;
+        }
+        let entry = BlsEntry {
+            title: generation
+                .title()
+                .unwrap_or_else(|| spec.bootspec.bootspec.label.clone()),
+            version: generation.describe(),
+            sort_key: self.sort_key_template.map_or_else(
+                || extension.sort_key.clone(),
+                |template| generation.sort_key(template),
+            ),
+            machine_id: extension.machine_id.clone(),
+            boot,
+            options: self.kernel_cmdline(generation)?,
+        };
+        entry.validate()?;
+
+        let entry_file = tempdir
+            .write_secure_file(entry.to_string())
+            .context("Failed to write the BLS entry to the temporary directory.")?;
+        Ok((entry_file, self.bls_entry_target(generation)?))
+    }
+
+    /// The ID of the boot entry of the given `Generation` in systemd-boot.
+    ///
+    /// This is the file name of the stub or, if the generation is booted via a Type #1 entry, of
+    /// the entry.
+    fn entry_id(&self, generation: &Generation) -> Result<String> {
+        let target = match self.boot_mode {
+            BootMode::Stub if !self.writes_stub_bls_entries() => self.stub_target(generation)?,
+            BootMode::Stub | BootMode::Kernel => self.bls_entry_target(generation)?,
+        };
+        target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(String::from)
+            .with_context(|| format!("{target:?} has no valid file name"))
+    }
+
+    /// Compute the path of the boot loader entry of the given `Generation` on the ESP.
+    fn bls_entry_target(&self, generation: &Generation) -> Result<PathBuf> {
+        let stub_name = self.stub_name(generation).context("Get stub name")?;
+        Ok(self
+            .esp_paths
+            .entries
+            .join(stub_name.with_extension("conf")))
+    }
+
+    /// Ensure that all inputs of a generation exist and can be read.
+    ///
+    /// All missing inputs are reported at once, e.g. both the kernel and the initrd of a
+    /// generation whose store paths were garbage collected.
+    fn ensure_sources_exist(&self, generation: &Generation) -> Result<()> {
+        let spec = &generation.spec;
+        let mut sources = vec![("kernel", spec.kernel_path())];
+        if let Some(initrd) = spec.initrd_path() {
+            sources.push(("initrd", initrd));
+        }
+        if self.boot_mode == BootMode::Stub {
+            sources.push(("Lanzaboote stub", self.lanzaboote_stub));
+        }
+
+        let problems: Vec<String> = sources
+            .into_iter()
+            .filter_map(|(description, path)| source_problem(description, path))
+            .collect();
+        if !problems.is_empty() {
+            bail!(
+                "{}\nThey might have been garbage collected from the Nix store.",
+                problems.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Find the files of an already installed generation on the ESP that can be kept.
+    ///
+    /// Fails if the generation is not properly installed or signing is forced, in which case the
+    /// generation has to be installed again.
+    fn reusable_installed_files(&self, generation: &Generation) -> Result<Vec<PathBuf>> {
+        let installed = self.installed_generation_files(generation)?;
+        if self.force {
+            bail!("Signing is forced.");
+        }
+        self.ensure_intact(&installed).inspect_err(|err| {
+            log::warn!("Repairing {}: {err:#}", describe_generation(generation));
+        })?;
+        Ok(installed)
+    }
+
+    /// Find the files of an already installed generation on the ESP.
+    ///
+    /// An error should not be considered fatal; the generation should be (re-)installed instead.
+    fn installed_generation_files(&self, generation: &Generation) -> Result<Vec<PathBuf>> {
+        if self.boot_mode == BootMode::Kernel {
+            return self.installed_signed_kernel_files(generation);
+        }
+        let stub_target = self.stub_target(generation)?;
+        let stub = fs::read(&stub_target)
+            .with_context(|| format!("Failed to read the stub: {}", stub_target.display()))?;
+        let kernel_path = resolve_efi_path(
+            &self.esp_paths.esp,
+            pe::read_section_data(&stub, ".linux").context("Missing kernel path.")?,
+        )?;
+        // A stub of a generation without an initrd has no `.initrd` section.
+        let initrd_path = pe::read_section_data(&stub, ".initrd")
+            .map(|initrd| resolve_efi_path(&self.esp_paths.esp, initrd))
+            .transpose()?;
+
+        if !kernel_path.exists() && initrd_path.as_ref().is_none_or(|initrd| !initrd.exists()) {
+            anyhow::bail!("Missing kernel or initrd.");
+        }
+
+        Ok([stub_target, kernel_path]
+            .into_iter()
+            .chain(initrd_path)
+            .collect())
+    }
+
+    /// Ensure that the installed files of a generation are intact if reconciling.
+    ///
+    /// The stub or, in kernel mode, the kernel has to be correctly signed and the kernel and the
+    /// initrd have to match the digests in their content-addressed names. The files are always
+    /// read again instead of trusting the hash cache, because a corrupted file on the ESP can keep
+    /// its size and modification time.
+    fn ensure_intact(&self, installed: &[PathBuf]) -> Result<()> {
+        if !self.reconcile {
+            return Ok(());
+        }
+        // The files are the stub or entry, the kernel and the initrd, if there is one.
+        let (first, kernel, initrd) = match installed {
+            [first, kernel] => (first, kernel, None),
+            [first, kernel, initrd] => (first, kernel, Some(initrd)),
+            _ => bail!("Unexpected installed files {installed:?}."),
+        };
+        let signed = match self.boot_mode {
+            BootMode::Stub => first,
+            BootMode::Kernel => kernel,
+        };
+        if !self.signer.verify_path(signed)? {
+            bail!("{signed:?} is not correctly signed.");
+        }
+        for file in iter::once(kernel).chain(initrd) {
+            ensure_content_addressed(file)?;
+        }
+        Ok(())
+    }
+
+    /// Find the entry, signed kernel and initrd of a generation installed in kernel mode.
+    fn installed_signed_kernel_files(&self, generation: &Generation) -> Result<Vec<PathBuf>> {
+        let entry_target = self.bls_entry_target(generation)?;
+        let entry = fs::read_to_string(&entry_target)
+            .with_context(|| format!("Failed to read the entry: {}", entry_target.display()))?;
+        let path = |key: &str| {
+            entry
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+                .map(|value| self.esp_paths.esp.join(value.trim_start_matches('/')))
+        };
+        let kernel_path = path("linux").context("Missing linux path.")?;
+        // An entry of a generation without an initrd has no `initrd` key.
+        let initrd_path = path("initrd");
+
+        if !kernel_path.exists() || initrd_path.as_ref().is_some_and(|initrd| !initrd.exists()) {
+            anyhow::bail!("Missing kernel or initrd.");
+        }
+
+        Ok([entry_target, kernel_path]
+            .into_iter()
+            .chain(initrd_path)
+            .collect())
+    }
+
+    /// Compute the paths of the stub, kernel and initrd of the given `Generation` on the ESP
+    /// without assembling the stub.
+    ///
+    /// With initrd secrets, the path of the initrd depends on the secrets and is unknown.
+    /// In kernel mode, the path of the signed kernel depends on the signature and is unknown.
+    fn stub_targets(&self, generation: &Generation) -> Result<Vec<PathBuf>> {
+        let spec = &generation.spec;
+        let kernel_version = spec.kernel_version()?;
+        let mut targets = match self.boot_mode {
+            BootMode::Stub => vec![
+                self.stub_target(generation)?,
+                self.nixos_ca_target(spec.kernel_path(), &format!("kernel-{kernel_version}"))
+                    .context("Failed to hash the kernel.")?,
+            ],
+            BootMode::Kernel => vec![self.bls_entry_target(generation)?],
+        };
+        if let Some(initrd) = spec.initrd_path() {
+            if spec.bootspec.bootspec.initrd_secrets.is_none() {
+                targets.push(
+                    self.nixos_ca_target(initrd, &format!("initrd-{kernel_version}"))
+                        .context("Failed to hash the initrd.")?,
+                );
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Compute the path of the stub of the given `Generation` on the ESP.
+    ///
+    /// A stub that is booted via a boot loader entry is installed to esp/EFI/nixos instead of
+    /// esp/EFI/Linux. Otherwise, systemd-boot would list the generation a second time.
+    fn stub_target(&self, generation: &Generation) -> Result<PathBuf> {
+        let directory = if self.writes_stub_bls_entries() {
+            &self.esp_paths.nixos
+        } else {
+            &self.esp_paths.linux
+        };
+        Ok(directory.join(
+            self.stub_name(generation)
+                .context("While getting stub name")?,
+        ))
+    }
+
+    /// Compute the file name of the stub of the given `Generation`.
+    ///
+    /// If the kernel command line or the os-release of the generation is overridden or SBAT
+    /// metadata is embedded, the name depends on the result, so that changing it re-generates
+    /// the stub.
+    fn stub_name(&self, generation: &Generation) -> Result<PathBuf> {
+        let kernel_cmdline =
+            if self.kernel_cmdline.is_some() || self.cmdline_override(generation).is_some() {
+                Some(self.kernel_cmdline(generation)?.join(" "))
+            } else {
+                None
+            };
+        let os_release = if self.os_release.is_some() || self.sort_key_template.is_some() {
+            Some(self.os_release_contents(generation)?)
+        } else {
+            None
+        };
+
+        let metadata = self
+            .metadata(generation)
+            .map(|metadata| serde_json::to_vec(&metadata))
+            .transpose()
+            .context("Failed to serialize the metadata")?;
+
+        let mut overrides = Vec::new();
+        if let Some(kernel_cmdline) = &kernel_cmdline {
+            overrides.push(("kernel_cmdline", kernel_cmdline.as_bytes()));
+        }
+        if let Some(os_release) = &os_release {
+            overrides.push(("os_release", os_release.as_bytes()));
+        }
+        if let Some(sbat) = self.sbat {
+            overrides.push(("sbat", sbat.as_bytes()));
+        }
+        if let Some(metadata) = &metadata {
+            overrides.push(("metadata", metadata.as_slice()));
+        }
+        for section in self.additional_sections {
+            overrides.push((section.name.as_str(), section.contents.as_slice()));
+        }
+        stub_name(generation, self.signer, &overrides)
+    }
+
+    /// The metadata embedded into the stub of the given `Generation`, if it is enabled.
+    fn metadata(&self, generation: &Generation) -> Option<StubMetadata> {
+        self.embed_metadata.then(|| StubMetadata {
+            generation: generation.version,
+            profile: generation.profile.clone(),
+            specialisation: generation
+                .specialisation_name
+                .as_ref()
+                .map(ToString::to_string),
+            toplevel: generation.spec.bootspec.bootspec.toplevel.0.clone(),
+        })
+    }
+
+    /// Assemble the os-release of the given `Generation`.
+    fn os_release_contents(&self, generation: &Generation) -> Result<String> {
+        let mut os_release = match self.os_release {
+            Some(os_release) => os_release.clone(),
+            None => OsRelease::from_generation(generation)
+                .context("Failed to build OsRelease from generation.")?,
         };
+        if let Some(template) = self.sort_key_template {
+            os_release
+                .0
+                .insert("IMAGE_ID".into(), generation.sort_key(template));
+            os_release
+                .0
+                .insert("IMAGE_VERSION".into(), generation.version.to_string());
+        }
+        os_release
+            .to_normalized_string()
+            .context("Failed to validate the os-release.")
+    }
 
-        log::info!("Successfully installed Lanzaboote.");
-        Ok(())
+    /// Assemble the kernel command line of the given `Generation`.
+    fn kernel_cmdline(&self, generation: &Generation) -> Result<Vec<String>> {
+        let kernel_cmdline = match self.kernel_cmdline {
+            Some(kernel_cmdline) => kernel_cmdline.to_vec(),
+            None => generation.spec.kernel_cmdline()?,
+        };
+        Ok(match self.cmdline_override(generation) {
+            Some(cmdline_override) => cmdline_override.apply(kernel_cmdline),
+            None => kernel_cmdline,
+        })
     }
 
-    /// Install all generations from the provided `GenerationLinks`.
-    fn install_generations_from_links(&mut self, links: &[GenerationLink]) -> Result<()> {
-        let generations = links
-            .iter()
-            .filter_map(|link| {
-                let generation_result = Generation::from_link(link)
-                    .with_context(|| format!("Failed to build generation from link: {link:?}"));
+    fn cmdline_override(&self, generation: &Generation) -> Option<&CmdlineOverride> {
+        self.cmdline_map
+            .and_then(|cmdline_map| cmdline_map.get(generation.version))
+    }
 
-                // Ignore failing to read a generation so that old malformed generations do not stop
-                // lzbt from working.
-                if generation_result.is_err() {
-                    // If there is ANY malformed generation present, completely disable all garbage
-                    // collection to protect the old generations from being deleted. The user has
-                    // to manually intervene by getting rid of the old generations to re-enable
-                    // garbage collection. This safeguard against catastrophic failure in case of
-                    // unhandled upstream changes to NixOS.
-                    self.broken_gens.insert(link.version);
-                }
+    /// Compute the path of a content-addressed file in the `EFI/nixos` directory on the ESP.
+    fn nixos_ca_target(&self, from: &Path, label: &str) -> Result<PathBuf> {
+        let hash = self
+            .hash_cache
+            .file_hash(from)
+            .context("Failed to read the source file.")?;
+        // The label contains the kernel version, which is taken from the store path and may
+        // contain e.g. a `?`.
+        let name = format!("{}-{}.efi", label, Base32Unpadded::encode_string(&hash));
+        validate_file_name(&name)?;
+        Ok(self.esp_paths.nixos.join(name))
+    }
+}
 
-                generation_result.ok()
-            })
-            .collect::<Vec<Generation>>();
+/// The number of bytes in a MiB, the unit of the ESP budget.
+pub const MIB: u64 = 1024 * 1024;
 
-        if generations.is_empty() {
-            // We can't continue, because we would remove all boot entries, if we did.
-            return Err(anyhow!("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!"));
-        }
+/// The files a generation and its specialisations install on the ESP.
+pub struct GenerationEspUsage {
+    /// The kernels and initrds. They are shared between generations with the same store paths.
+    files: Vec<PathBuf>,
+    /// The number of stubs, one for the generation and one for every specialisation.
+    stubs: u64,
+}
 
-        for generation in generations {
-            // The kernels and initrds are content-addressed.
-            // Thus, this cannot overwrite files of old generation with different content.
-            self.install_generation(&generation)
-                .with_context(|| format!("Failed to install generation {}", generation.version))?;
-            for (name, bootspec) in &generation.spec.bootspec.specialisations {
-                let specialised_generation = generation.specialise(name, bootspec);
-                self.install_generation(&specialised_generation)
-                    .context("Failed to install specialisation.")?;
-            }
+impl GenerationEspUsage {
+    /// Read the usage from the bootspec of the generation.
+    ///
+    /// A generation that cannot be read is not installed and thus uses no space.
+    pub fn from_link(link: &GenerationLink) -> Self {
+        let Ok(generation) = Generation::from_link(link) else {
+            return Self {
+                files: Vec::new(),
+                stubs: 0,
+            };
+        };
+        let bootspec = &generation.spec.bootspec;
+        let specs = iter::once(bootspec)
+            .chain(bootspec.specialisations.values())
+            .map(|spec| &spec.bootspec)
+            .collect::<Vec<_>>();
+        Self {
+            files: specs
+                .iter()
+                .flat_map(|spec| iter::once(spec.kernel.clone()).chain(spec.initrd.clone()))
+                .collect(),
+            stubs: specs.len() as u64,
         }
+    }
+}
 
-        // Sync files to persistent storage. This may improve the
-        // chance of a consistent boot directory in case the system
-        // crashes.
-        let boot = File::open(&self.esp_paths.esp).context("Failed to open ESP root directory.")?;
-        syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+/// Estimate the number of bytes the generations use on the ESP.
+///
+/// The stubs are assumed to be as large as the unsigned Lanzaboote stub. The systemd-boot
+/// binaries and the loader config are not included.
+pub fn estimate_esp_usage(usages: &[GenerationEspUsage], stub_size: u64) -> u64 {
+    let files = usages
+        .iter()
+        .flat_map(|usage| &usage.files)
+        .collect::<BTreeSet<_>>();
+    let files_size: u64 = files
+        .into_iter()
+        .filter_map(|file| fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let stubs: u64 = usages.iter().map(|usage| usage.stubs).sum();
+    files_size + stubs * stub_size
+}
 
-        Ok(())
-    }
+/// A generation that has been prepared for installation, but not yet copied to the ESP.
+struct PreparedGeneration {
+    /// Files to copy to the ESP as (source, destination) pairs, in order.
+    ///
+    /// Every file only references files before it, so the last one is the stub or the entry
+    /// that makes the generation bootable.
+    files: Vec<(PathBuf, PathBuf)>,
+    /// Files of the generation that are already installed on the ESP.
+    installed: Vec<PathBuf>,
+    /// The file of the generation on the ESP that is signed.
+    signed: PathBuf,
+    /// Holds the assembled files until they are copied to the ESP.
+    _tempdir: TempDir,
+}
 
-    /// Install the given `Generation`.
+impl PreparedGeneration {
+    /// Copy the prepared files to the ESP.
     ///
-    /// The kernel and initrd are content-addressed, and the stub name identifies the generation.
-    /// Hence, this function cannot overwrite files of other generations with different contents.
-    /// All installed files are added as garbage collector roots.
-    fn install_generation(&mut self, generation: &Generation) -> Result<()> {
-        // If the generation is already properly installed, don't overwrite it.
-        if self.register_installed_generation(generation).is_ok() {
-            return Ok(());
+    /// The changed files are first staged next to their destinations and synced. Only when all
+    /// of them are staged are they renamed to their destinations in order, so the stub or the
+    /// entry that makes the generation bootable is renamed last. If staging fails, the files
+    /// already on the ESP are left untouched, so a generation that was installed before stays
+    /// bootable. If renaming fails, the remaining staged files are discarded, so the generation
+    /// never becomes bootable with missing files.
+    ///
+    /// All files of the generation are added as garbage collector roots and to the signed
+    /// manifest once they are installed. Returns whether any file was copied.
+    fn commit(
+        self,
+        gc_roots: &mut Roots,
+        signed_files: &mut SignedManifest,
+        permissions: EspPermissions,
+    ) -> Result<bool> {
+        let mut staged = Vec::new();
+        for (from, to) in &self.files {
+            let result = needs_install(from, to)
+                .and_then(|needed| needed.then(|| stage(from, to, permissions)).transpose())
+                .with_context(|| format!("Failed to install {to:?}"));
+            match result {
+                Ok(Some(tmp)) => staged.push((tmp, to)),
+                Ok(None) => (),
+                Err(err) => {
+                    discard_staged(staged.iter().map(|(tmp, _)| tmp));
+                    return Err(err);
+                }
+            }
+        }
+        let changed = !staged.is_empty();
+        for (index, (tmp, to)) in staged.iter().enumerate() {
+            if let Err(err) = fs::rename(tmp, to) {
+                discard_staged(staged[index..].iter().map(|(tmp, _)| tmp));
+                return Err(err).with_context(|| {
+                    format!("Failed to move temporary file {tmp:?} to target {to:?}")
+                });
+            }
         }
 
-        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
-        let bootspec = &generation.spec.bootspec.bootspec;
-
-        // The kernel is a file in /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-<version>/.
-        // (On x86, that file is called bzImage, but other architectures may differ.)
-        let kernel_dirname = bootspec
-            .kernel
-            .parent()
-            .and_then(Path::file_name)
-            .and_then(OsStr::to_str)
-            .context("Failed to extract the kernel directory name.")?;
-        let kernel_version = kernel_dirname
-            .rsplit('-')
-            .next()
-            .context("Failed to extract the kernel version.")?;
-
-        // Install the kernel and record its path on the ESP.
-        let kernel_target = self
-            .install_nixos_ca(&bootspec.kernel, &format!("kernel-{}", kernel_version))
-            .context("Failed to install the kernel.")?;
-
-        // Assemble and install the initrd, and record its path on the ESP.
-        // It is not needed to write the initrd in a temporary directory
-        // if we do not have any initrd secret.
-        let initrd_location = if bootspec.initrd_secrets.is_some() {
-            tempdir
-                .write_secure_file(
-                    fs::read(
-                        bootspec
-                            .initrd
-                            .as_ref()
-                            .context("Lanzaboote does not support missing initrd yet.")?,
-                    )
-                    .context("Failed to read the initrd.")?,
-                )
-                .context("Failed to copy the initrd to the temporary directory.")?
-        } else {
-            bootspec
-                .initrd
-                .clone()
-                .expect("Lanzaboote does not support missing initrd yet.")
-        };
+        gc_roots.extend(&self.installed);
+        gc_roots.extend(self.files.iter().map(|(_, to)| to));
+        let files = self
+            .installed
+            .iter()
+            .chain(self.files.iter().map(|(_, to)| to));
+        for file in files {
+            signed_files.add_unsigned(file);
+        }
+        signed_files.add_signed(&self.signed);
+        Ok(changed)
+    }
+}
 
-        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
-            append_initrd_secrets(initrd_secrets_script, &initrd_location, generation.version)?;
+/// Remove staged temporary files that are not renamed to their destinations anymore.
+fn discard_staged<'a>(staged: impl IntoIterator<Item = &'a PathBuf>) {
+    for tmp in staged {
+        if let Err(err) = fs::remove_file(tmp) {
+            log::warn!("Failed to remove the temporary file {tmp:?}: {err}");
         }
-        let initrd_target = self
-            .install_nixos_ca(&initrd_location, &format!("initrd-{}", kernel_version))
-            .context("Failed to install the initrd.")?;
+    }
+}
 
-        // Assemble, sign and install the Lanzaboote stub.
-        let os_release = OsRelease::from_generation(generation)
-            .context("Failed to build OsRelease from generation.")?;
+/// Copy a prepared `Generation` to the ESP, reporting failures of either stage.
+fn commit_generation(
+    gc_roots: &mut Roots,
+    signed_files: &mut SignedManifest,
+    permissions: EspPermissions,
+    generation: &Generation,
+    prepared: Result<PreparedGeneration>,
+) -> Result<bool> {
+    prepared
+        .and_then(|prepared| prepared.commit(gc_roots, signed_files, permissions))
+        .with_context(|| format!("Failed to install {}", describe_generation(generation)))
+}
 
-        let os_release_contents = os_release.to_string();
+/// Describe a generation or specialisation for messages, e.g. "specialisation debug of
+/// generation 3".
+fn describe_generation(generation: &Generation) -> String {
+    match &generation.specialisation_name {
+        Some(name) => format!("specialisation {name} of generation {}", generation.version),
+        None => format!("generation {}", generation.version),
+    }
+}
 
-        let kernel_cmdline =
-            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
+/// Resolve a path on the ESP mounted at `esp`. Relative paths are relative to the ESP.
+///
+/// Returns `None` if the path is not on the ESP.
+fn resolve_on_esp(esp: &Path, path: &Path) -> Option<PathBuf> {
+    let resolved = esp.join(path);
+    let on_esp = resolved.starts_with(esp) && !path.components().any(|c| c == Component::ParentDir);
+    on_esp.then_some(resolved)
+}
 
-        let parameters = pe::StubParameters::new(
-            &self.lanzaboote_stub,
-            &bootspec.kernel,
-            &initrd_location,
-            &kernel_target,
-            &initrd_target,
-            &self.esp_paths.esp,
-        )?
-        .with_cmdline(&kernel_cmdline)
-        .with_os_release_contents(os_release_contents.as_bytes());
+/// The path and its parent directories on the ESP.
+///
+/// The parent directories of a garbage collection root have to be roots as well, otherwise they
+/// are removed with everything in them.
+fn ancestors_on_esp(esp: &Path, path: &Path) -> Vec<PathBuf> {
+    path.ancestors()
+        .take_while(|ancestor| ancestor.starts_with(esp))
+        .map(Path::to_path_buf)
+        .collect()
+}
 
-        let lanzaboote_image_path = lanzaboote_image(&tempdir, &parameters)
-            .context("Failed to build and sign lanzaboote stub image.")?;
+/// Ensure that a content-addressed file in `EFI/nixos` matches the digest in its name.
+fn ensure_content_addressed(file: &Path) -> Result<()> {
+    let expected = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit_once('-'))
+        .and_then(|(_, hash)| Base32Unpadded::decode_vec(hash).ok())
+        .with_context(|| format!("{file:?} is not content-addressed."))?;
+    if file_hash(file)?.as_slice() != expected {
+        bail!("{file:?} does not match the digest in its name.");
+    }
+    Ok(())
+}
 
-        let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.signer).context("Get stub name")?);
-        self.gc_roots.extend([&stub_target]);
-        install_signed(&self.signer, &lanzaboote_image_path, &stub_target)
-            .context("Failed to install the Lanzaboote stub.")?;
+/// Whether `installed` exists and has the same contents as `expected`.
+fn same_contents(expected: &Path, installed: &Path) -> Result<bool> {
+    let expected = fs::read(expected).with_context(|| format!("Failed to read {expected:?}"))?;
+    Ok(fs::read(installed).is_ok_and(|installed| installed == expected))
+}
 
-        Ok(())
+/// The total size of the files in `directory` for which the filter returns true.
+///
+/// Subdirectories are not included. A directory that does not exist is empty.
+fn directory_size(directory: &Path, filter: impl Fn(&Path) -> bool) -> Result<u64> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read directory {directory:?}"))
+        }
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read directory {directory:?}"))?;
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {:?}", entry.path()))?;
+        if metadata.is_file() && filter(&entry.path()) {
+            size += metadata.len();
+        }
     }
+    Ok(size)
+}
 
-    /// Register the files of an already installed generation as garbage collection roots.
-    ///
-    /// An error should not be considered fatal; the generation should be (re-)installed instead.
-    fn register_installed_generation(&mut self, generation: &Generation) -> Result<()> {
-        let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.signer).context("While getting stub name")?);
-        let stub = fs::read(&stub_target)
-            .with_context(|| format!("Failed to read the stub: {}", stub_target.display()))?;
-        let kernel_path = resolve_efi_path(
-            &self.esp_paths.esp,
-            pe::read_section_data(&stub, ".linux").context("Missing kernel path.")?,
-        )?;
-        let initrd_path = resolve_efi_path(
-            &self.esp_paths.esp,
-            pe::read_section_data(&stub, ".initrd").context("Missing initrd path.")?,
-        )?;
+/// Set the `default` key of a loader.conf to the entry ID, replacing an existing one.
+fn with_default_entry(loader_config: &str, id: &str) -> String {
+    let mut contents = loader_config
+        .lines()
+        .filter(|line| line.split_whitespace().next() != Some("default"))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    contents.push_str(&format!("default {id}\n"));
+    contents
+}
 
-        if !kernel_path.exists() && !initrd_path.exists() {
-            anyhow::bail!("Missing kernel or initrd.");
-        }
-        self.gc_roots
-            .extend([&stub_target, &kernel_path, &initrd_path]);
+/// Whether the path is named like a kernel or initrd Lanzaboote installs to esp/EFI/nixos, e.g.
+/// `kernel-6.1.1-<hash>.efi`, or like the temporary file of one.
+///
+/// Older versions named them after their store paths, e.g.
+/// `0n01vj3mq06pc31i2yhxndvhv4kwl2vp-linux-6.1.3-bzImage.efi`.
+fn is_nixos_file_name(path: &Path) -> bool {
+    const NIX_BASE32: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+    path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+        let content_addressed = (n.starts_with("kernel-") || n.starts_with("initrd-"))
+            && (n.ends_with(".efi") || n.ends_with(".tmp"));
+        let store_derived = n.ends_with(".efi")
+            && n.split_once('-').is_some_and(|(hash, _)| {
+                hash.len() == 32 && hash.chars().all(|c| NIX_BASE32.contains(c))
+            });
+        content_addressed || store_derived
+    })
+}
 
-        Ok(())
+/// Whether the file name of a path starts with `nixos-`.
+///
+/// This is used to only garbage collect files in directories which are potentially shared with
+/// other distros.
+fn has_nixos_prefix(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("nixos-"))
+}
+
+/// Ensure that a file to install exists.
+///
+/// The files are usually in the Nix store and are missing if they were garbage collected.
+fn ensure_source_exists(description: &str, path: &Path) -> Result<()> {
+    if !path.exists() {
+        bail!("The {description} {path:?} does not exist. It might have been garbage collected from the Nix store.");
     }
+    Ok(())
+}
 
-    /// Install a content-addressed file to the `EFI/nixos` directory on the ESP.
-    ///
-    /// It is automatically added to the garbage collector roots.
-    /// The full path to the target file is returned.
-    fn install_nixos_ca(&mut self, from: &Path, label: &str) -> Result<PathBuf> {
-        let hash = file_hash(from).context("Failed to read the source file.")?;
-        let to = self.esp_paths.nixos.join(format!(
-            "{}-{}.efi",
-            label,
-            Base32Unpadded::encode_string(&hash)
-        ));
-        self.gc_roots.extend([&to]);
-        install(from, &to)?;
-        Ok(to)
+/// Describe why an input cannot be read, if it cannot.
+fn source_problem(description: &str, path: &Path) -> Option<String> {
+    let err = File::open(path).err()?;
+    if err.kind() != std::io::ErrorKind::NotFound {
+        return Some(format!("The {description} {path:?} cannot be read: {err}."));
+    }
+    match fs::read_link(path) {
+        Ok(target) => Some(format!(
+            "The {description} {path:?} is a symlink to {target:?}, which does not exist."
+        )),
+        Err(_) => Some(format!("The {description} {path:?} does not exist.")),
     }
+}
 
-    /// Install systemd-boot to ESP.
-    ///
-    /// systemd-boot is only updated when a newer version is available OR when the currently
-    /// installed version is not signed. This enables switching to Lanzaboote without having to
-    /// manually delete previous unsigned systemd-boot binaries and minimizes the number of writes
-    /// to the ESP.
-    ///
-    /// Checking for the version also allows us to skip buggy systemd versions in the future.
-    fn install_systemd_boot(&self) -> Result<()> {
-        let systemd_boot = self
-            .systemd
-            .join("lib/systemd/boot/efi")
-            .join(self.arch.systemd_filename());
+/// Ensure that the PE binary at `path` is built for the given architecture.
+fn ensure_architecture(path: &Path, arch: Architecture) -> Result<()> {
+    let file_data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let actual = pe::read_architecture(&file_data)
+        .with_context(|| format!("Failed to read the architecture of {path:?}"))?;
+    if actual != arch {
+        bail!("{path:?} is built for {actual:?}, but the target architecture is {arch:?}.");
+    }
+    Ok(())
+}
 
-        let paths = [
-            (&systemd_boot, &self.esp_paths.efi_fallback),
-            (&systemd_boot, &self.esp_paths.systemd_boot),
-        ];
+/// Read the generation links, sorted by version from oldest to newest.
+///
+/// Paths that are not named like generation links are skipped, e.g. the "system" link to the
+/// current generation or backup files when all entries of the profile directory are passed.
+pub fn read_generation_links(paths: &[PathBuf]) -> Result<Vec<GenerationLink>> {
+    let mut links = paths
+        .iter()
+        .filter(|path| {
+            let is_generation_link = generation::is_generation_link(path);
+            if !is_generation_link {
+                log::debug!("Skipping {path:?} because it is not a generation link.");
+            }
+            is_generation_link
+        })
+        .map(GenerationLink::from_path)
+        .collect::<Result<Vec<GenerationLink>>>()?;
 
-        for (from, to) in paths {
-            let newer_systemd_boot_available = newer_systemd_boot(from, to)?;
-            if newer_systemd_boot_available {
-                log::info!("Updating {to:?}...")
-            };
-            let systemd_boot_is_signed = &self.signer.verify_path(to)?;
-            if !systemd_boot_is_signed {
-                log::warn!("${to:?} is not signed. Replacing it with a signed binary...")
-            };
+    // Sort the links by version, so that the limit actually skips the oldest generations.
+    links.sort_by_key(|l| l.version);
 
-            if newer_systemd_boot_available || !systemd_boot_is_signed {
-                install_signed(&self.signer, from, to)
-                    .with_context(|| format!("Failed to install systemd-boot binary to: {to:?}"))?;
-            }
+    // Zero-padded versions are parsed to the same number, e.g. system-007-link and
+    // system-7-link. Only install one of them because the files on the ESP are named after the
+    // parsed version.
+    links.dedup_by(|duplicate, link| {
+        let is_duplicate = duplicate.version == link.version;
+        if is_duplicate {
+            log::warn!(
+                "Ignoring {:?} because it has the same version as {:?}.",
+                duplicate.path,
+                link.path
+            );
         }
+        is_duplicate
+    });
 
-        install(
-            &self.systemd_boot_loader_config,
-            &self.esp_paths.systemd_boot_loader_config,
-        )
-        .with_context(|| {
-            format!(
-                "Failed to install systemd-boot loader.conf to {:?}",
-                &self.esp_paths.systemd_boot_loader_config
-            )
-        })?;
+    Ok(links)
+}
 
-        Ok(())
+/// Split the sorted links into the old ones beyond the configuration limit and the latest ones
+/// that are installed.
+///
+/// A configuration limit of 0 means there is no limit. Both lists stay sorted from oldest to
+/// newest.
+pub fn split_off_retained(
+    mut links: Vec<GenerationLink>,
+    configuration_limit: usize,
+) -> (Vec<GenerationLink>, Vec<GenerationLink>) {
+    if configuration_limit == 0 {
+        return (Vec::new(), links);
+    }
+    let retained = links.split_off(links.len().saturating_sub(configuration_limit));
+    (links, retained)
+}
+
+/// Move the dropped generations that were built within `age` back to the retained ones.
+///
+/// This keeps e.g. everything from the last 30 days regardless of the configuration limit. Both
+/// lists stay sorted from oldest to newest.
+pub fn retain_built_since(
+    dropped: Vec<GenerationLink>,
+    mut retained: Vec<GenerationLink>,
+    age: Duration,
+) -> (Vec<GenerationLink>, Vec<GenerationLink>) {
+    let now = SystemTime::now();
+    let (recent, dropped): (Vec<_>, Vec<_>) = dropped
+        .into_iter()
+        .partition(|link| link.built_within(age, now));
+    for link in &recent {
+        log::info!(
+            "Keeping generation {} beyond the configuration limit because it was built recently.",
+            link.version
+        );
     }
+    retained.extend(recent);
+    retained.sort_by_key(|link| link.version);
+    (dropped, retained)
+}
+
+/// Drop every generation whose toplevel is the same as the one of the next generation.
+///
+/// Such generations are created e.g. by `nixos-rebuild switch` without any changes. Of each run
+/// of identical generations, only the newest is kept, so it is shown once in the boot menu and
+/// the configuration limit counts distinct generations. The toplevels are compared as recorded
+/// in the bootspecs. Generations without a readable bootspec are always kept.
+pub fn collapse_identical(links: Vec<GenerationLink>) -> Vec<GenerationLink> {
+    let mut collapsed = Vec::with_capacity(links.len());
+    let mut links = links
+        .into_iter()
+        .map(|link| {
+            let toplevel = Generation::from_link(&link)
+                .ok()
+                .map(|generation| generation.spec.bootspec.bootspec.toplevel.0);
+            (link, toplevel)
+        })
+        .peekable();
+    while let Some((link, toplevel)) = links.next() {
+        if let Some((next, next_toplevel)) = links.peek() {
+            if toplevel.is_some() && *next_toplevel == toplevel {
+                log::info!(
+                    "Skipping generation {} because generation {} has the same toplevel.",
+                    link.version,
+                    next.version
+                );
+                continue;
+            }
+        }
+        collapsed.push(link);
+    }
+    collapsed
 }
 
 /// Translate an EFI path to an absolute path on the mounted ESP.
-fn resolve_efi_path(esp: &Path, efi_path: &[u8]) -> Result<PathBuf> {
+pub fn resolve_efi_path(esp: &Path, efi_path: &[u8]) -> Result<PathBuf> {
     Ok(esp.join(std::str::from_utf8(&efi_path[1..])?.replace('\\', "/")))
 }
 
 /// Compute the file name to be used for the stub of a certain generation, signed with the given key.
 ///
 /// The generated name is input-addressed by the toplevel corresponding to the generation and the public part of the signing key.
-fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf> {
+/// Overridden inputs of the stub (e.g. the kernel command line) are inputs as well.
+fn stub_name<S: Signer>(
+    generation: &Generation,
+    signer: &S,
+    overrides: &[(&str, &[u8])],
+) -> Result<PathBuf> {
     let bootspec = &generation.spec.bootspec.bootspec;
     let public_key = signer.get_public_key()?;
-    let stub_inputs = [
+    let mut stub_inputs = vec![
         // Generation numbers can be reused if the latest generation was deleted.
         // To detect this, the stub path depends on the actual toplevel used.
         ("toplevel", bootspec.toplevel.0.as_os_str().as_bytes()),
@@ -385,20 +2367,23 @@ fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf>
         // So we make their path depend on the public key used for signature.
         ("public_key", &public_key),
     ];
+    // Only add the inputs that are overridden, so that the names of all other stubs stay the
+    // same.
+    stub_inputs.extend_from_slice(overrides);
     let stub_input_hash = Base32Unpadded::encode_string(&Sha256::digest(
         serde_json::to_string(&stub_inputs).unwrap(),
     ));
-    if let Some(specialisation_name) = &generation.specialisation_name {
-        Ok(PathBuf::from(format!(
+    let stub_name = if let Some(specialisation_name) = &generation.specialisation_name {
+        format!(
             "nixos-generation-{}-specialisation-{}-{}.efi",
             generation, specialisation_name, stub_input_hash
-        )))
+        )
     } else {
-        Ok(PathBuf::from(format!(
-            "nixos-generation-{}-{}.efi",
-            generation, stub_input_hash
-        )))
-    }
+        format!("nixos-generation-{}-{}.efi", generation, stub_input_hash)
+    };
+    // Specialisation names are arbitrary strings.
+    validate_file_name(&stub_name).context("Invalid specialisation name.")?;
+    Ok(PathBuf::from(stub_name))
 }
 
 /// Install a PE file. The PE gets signed in the process.
@@ -408,29 +2393,107 @@ fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf>
 /// This is implemented as an atomic write. The file is first written to the destination with a
 /// `.tmp` suffix and then renamed to its final name. This is atomic, because a rename is an atomic
 /// operation on POSIX platforms.
-fn install_signed(signer: &impl Signer, from: &Path, to: &Path) -> Result<()> {
+fn install_signed(
+    signer: &impl Signer,
+    from: &Path,
+    to: &Path,
+    permissions: EspPermissions,
+) -> Result<()> {
     log::debug!("Signing and installing {to:?}...");
     let to_tmp = to.with_extension(".tmp");
-    ensure_parent_dir(&to_tmp);
-    signer
+    ensure_parent_dir(&to_tmp, permissions.dir_mode);
+    let staged = signer
         .sign_and_copy(from, &to_tmp)
-        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))?;
+        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))
+        .and_then(|()| set_permission_bits(&to_tmp, permissions.file_mode))
+        .and_then(|()| sync_file(&to_tmp));
+    if let Err(err) = staged {
+        // Signing might have failed after writing part of the file.
+        if to_tmp.exists() {
+            discard_staged([&to_tmp]);
+        }
+        return Err(err);
+    }
     fs::rename(&to_tmp, to).with_context(|| {
         format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
     })?;
     Ok(())
 }
 
+/// Sync the data and metadata of a file to disk.
+fn sync_file(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed to sync {path:?}"))
+}
+
+/// Write the PKCS#7 signature of a signed PE binary to `<file name>.p7s` in `directory`.
+fn write_detached_signature(directory: &Path, signed: &Path) -> Result<()> {
+    let signed_data =
+        fs::read(signed).with_context(|| format!("Failed to read the signed file {signed:?}"))?;
+    let signature = pe::read_pkcs7_signature(&signed_data)
+        .with_context(|| format!("Failed to read the signature of {signed:?}"))?;
+
+    let mut file_name = signed
+        .file_name()
+        .with_context(|| format!("Signed file {signed:?} has no file name"))?
+        .to_os_string();
+    file_name.push(".p7s");
+    let to = directory.join(file_name);
+
+    log::debug!("Writing detached signature {to:?}...");
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create the directory {directory:?}"))?;
+    fs::write(&to, signature)
+        .with_context(|| format!("Failed to write the detached signature {to:?}"))
+}
+
+/// Install to several ESPs concurrently, with a separate installer for every ESP.
+///
+/// Every ESP is installed to in its own thread, so that a slow ESP, e.g. on a USB stick, does
+/// not delay the others. The installations are independent of each other: a failure on one ESP
+/// never stops the installation to another. Returns whether each installation changed its ESP,
+/// in the order of `esps`.
+pub fn install_to_esps<S: Signer + Sync>(
+    esps: &[PathBuf],
+    installer: impl Fn(PathBuf) -> Installer<S> + Sync,
+) -> Vec<Result<bool>> {
+    let installer = &installer;
+    thread::scope(|scope| {
+        let handles = esps
+            .iter()
+            .map(|esp| scope.spawn(move || installer(esp.clone()).install()))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .zip(esps)
+            .map(|(handle, esp)| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("Installing to {esp:?} panicked")))
+            })
+            .collect()
+    })
+}
+
 /// Install an arbitrary file.
 ///
 /// The file is only copied if
 ///     (1) it doesn't exist at the destination or,
 ///     (2) the hash of the file at the destination does not match the hash of the source file.
-fn install(from: &Path, to: &Path) -> Result<()> {
-    if !to.exists() || file_hash(from)? != file_hash(to)? {
-        force_install(from, to)?;
+///
+/// Returns whether the file was copied.
+pub fn install(from: &Path, to: &Path, permissions: EspPermissions) -> Result<bool> {
+    if needs_install(from, to)? {
+        force_install(from, to, permissions)?;
+        return Ok(true);
     }
-    Ok(())
+    Ok(false)
+}
+
+/// Whether `to` is missing or differs from `from`.
+fn needs_install(from: &Path, to: &Path) -> Result<bool> {
+    Ok(!to.exists() || file_hash(from)? != file_hash(to)?)
 }
 
 /// Forcibly install an arbitrary file.
@@ -438,52 +2501,87 @@ fn install(from: &Path, to: &Path) -> Result<()> {
 /// If the file already exists at the destination, it is overwritten.
 ///
 /// This function is only designed to copy files to the ESP. It sets the permission bits of the
-/// file at the destination to 0o755, the expected permissions for a vfat ESP. This is useful for
-/// producing file systems trees which can then be converted to a file system image.
-fn force_install(from: &Path, to: &Path) -> Result<()> {
-    log::debug!("Installing {to:?}...");
-    ensure_parent_dir(to);
-    atomic_copy(from, to)?;
-    set_permission_bits(to, 0o755)
-        .with_context(|| format!("Failed to set permission bits to 0o755 on file: {to:?}"))?;
-    Ok(())
-}
-
-fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<String> {
-    let init_string = String::from(
-        init.to_str()
-            .expect("Failed to convert init path to string"),
-    );
-    let mut kernel_cmdline: Vec<String> = vec![format!("init={}", init_string)];
-    kernel_cmdline.extend(kernel_params);
-    kernel_cmdline
+/// file at the destination to the configured file mode, 0o755 by default, the expected
+/// permissions for a vfat ESP. This is useful for producing file systems trees which can then be
+/// converted to a file system image.
+fn force_install(from: &Path, to: &Path, permissions: EspPermissions) -> Result<()> {
+    let tmp = stage(from, to, permissions)?;
+    fs::rename(&tmp, to)
+        .with_context(|| format!("Failed to move temporary file {tmp:?} to target {to:?}"))
 }
 
-/// Atomically copy a file.
+/// Stage a copy of a file next to its destination for an atomic copy.
 ///
-/// First, the content is written to a temporary file (with a `.tmp` extension).
-/// Then, this file is synced, to ensure its data and metadata are fully on disk before continuing.
-/// In the last step, the temporary file is renamed to the final destination.
+/// The content is written to a temporary file (with a `.tmp` extension) in the directory of the
+/// destination, so that it is on the same file system. Then, this file is synced, to ensure its
+/// data and metadata are fully on disk before continuing. Renaming the temporary file to the
+/// destination completes the copy. Returns the path of the temporary file.
 ///
 /// Due to the deficiencies of FAT32, it is possible for the filesystem to become corrupted after power loss.
 /// It is not possible to fully defend against this situation, so this operation is not actually fully atomic.
 /// However, in all other cases, the target file is either present with its correct content or not present at all.
-fn atomic_copy(from: &Path, to: &Path) -> Result<()> {
+///
+/// If possible, the temporary file is a copy-on-write clone of the source, which is instant.
+fn stage(from: &Path, to: &Path, permissions: EspPermissions) -> Result<PathBuf> {
+    log::debug!("Installing {to:?}...");
+    ensure_parent_dir(to, permissions.dir_mode);
     let tmp = to.with_extension(".tmp");
-    {
-        let mut from_file =
-            File::open(from).with_context(|| format!("Failed to read the source file {from:?}"))?;
-        let mut tmp_file = File::create(&tmp)
-            .with_context(|| format!("Failed to create the temporary file {tmp:?}"))?;
+    let staged = copy_and_sync(from, &tmp, permissions.file_mode).and_then(|()| {
+        set_permission_bits(&tmp, permissions.file_mode).with_context(|| {
+            format!(
+                "Failed to set permission bits to {:#o} on file: {tmp:?}",
+                permissions.file_mode
+            )
+        })
+    });
+    if let Err(err) = staged {
+        if tmp.exists() {
+            discard_staged([&tmp]);
+        }
+        return Err(err);
+    }
+    Ok(tmp)
+}
+
+/// Copy a file to `tmp` and sync it.
+fn copy_and_sync(from: &Path, tmp: &Path, mode: u32) -> Result<()> {
+    let mut from_file =
+        File::open(from).with_context(|| format!("Failed to read the source file {from:?}"))?;
+    let mut tmp_file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(tmp)
+        .with_context(|| format!("Failed to create the temporary file {tmp:?}"))?;
+    if reflink(&from_file, &tmp_file) {
+        log::debug!("Reflinked {from:?} to {tmp:?}.");
+    } else {
         std::io::copy(&mut from_file, &mut tmp_file).with_context(|| {
             format!("Failed to copy from {from:?} to the temporary file {tmp:?}")
         })?;
-        tmp_file
-            .sync_all()
-            .with_context(|| format!("Failed to sync the temporary file {tmp:?}"))?;
     }
-    fs::rename(&tmp, to)
-        .with_context(|| format!("Failed to move temporary file {tmp:?} to target {to:?}"))
+    tmp_file
+        .sync_all()
+        .with_context(|| format!("Failed to sync the temporary file {tmp:?}"))
+}
+
+// FICLONE from linux/fs.h.
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Try to make the empty file `to` a copy-on-write clone of `from`.
+///
+/// This only works if both files are on the same file system and it supports reflinks, e.g.
+/// btrfs or XFS, but not FAT. If it fails, `to` is unchanged.
+fn reflink(from: &File, to: &File) -> bool {
+    // SAFETY: Both file descriptors stay open for the duration of the call.
+    unsafe {
+        ficlone(
+            to.as_raw_fd(),
+            from.as_raw_fd() as nix::sys::ioctl::ioctl_param_type,
+        )
+    }
+    .is_ok()
 }
 
 /// Set the octal permission bits of the specified file.
@@ -497,9 +2595,13 @@ fn set_permission_bits(path: &Path, permission_bits: u32) -> Result<()> {
 }
 
 // Ensures the parent directory of an arbitrary path exists
-fn ensure_parent_dir(path: &Path) {
+fn ensure_parent_dir(path: &Path, mode: u32) {
     if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).ok();
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(mode)
+            .create(parent)
+            .ok();
     }
 }
 
@@ -528,3 +2630,144 @@ fn newer_systemd_boot(from: &Path, to: &Path) -> Result<bool> {
 
     Ok(from_version > to_version)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retain_recently_built_generations() {
+        let now = SystemTime::now();
+        let link = |version, age: u64| GenerationLink {
+            version,
+            path: PathBuf::from(format!("system-{version}-link")),
+            build_time: Some(now - Duration::from_secs(age * 24 * 60 * 60)),
+        };
+        let links = vec![link(1, 40), link(2, 10), link(3, 20), link(4, 1)];
+
+        let (dropped, retained) = split_off_retained(links, 1);
+        let (dropped, retained) =
+            retain_built_since(dropped, retained, Duration::from_secs(30 * 24 * 60 * 60));
+
+        let versions =
+            |links: &[GenerationLink]| links.iter().map(|l| l.version).collect::<Vec<_>>();
+        assert_eq!(versions(&dropped), [1]);
+        assert_eq!(versions(&retained), [2, 3, 4]);
+    }
+
+    #[test]
+    fn recognize_nixos_file_names() {
+        for name in [
+            "kernel-6.1.1-abc.efi",
+            "initrd-6.1.1-abc.efi",
+            "initrd-6.1.1-abc..tmp",
+            "0n01vj3mq06pc31i2yhxndvhv4kwl2vp-linux-6.1.3-bzImage.efi",
+        ] {
+            assert!(is_nixos_file_name(Path::new(name)), "{name}");
+        }
+        for name in [
+            "grub.efi",
+            "kernel-6.1.1-abc.efi.bak",
+            "custom",
+            "initrd",
+            "memtest86-plus-6.20.efi",
+        ] {
+            assert!(!is_nixos_file_name(Path::new(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn keep_installed_files_if_staging_fails() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp = tmpdir.path().join("esp");
+        fs::create_dir(&esp)?;
+        let kernel = tmpdir.path().join("kernel");
+        fs::write(&kernel, "new kernel")?;
+        let installed_kernel = esp.join("kernel.efi");
+        fs::write(&installed_kernel, "old kernel")?;
+
+        let prepared = PreparedGeneration {
+            files: vec![
+                (kernel, installed_kernel.clone()),
+                (tmpdir.path().join("missing"), esp.join("stub.efi")),
+            ],
+            installed: Vec::new(),
+            signed: esp.join("stub.efi"),
+            _tempdir: tempfile::tempdir()?,
+        };
+        let result = prepared.commit(
+            &mut Roots::new(),
+            &mut SignedManifest::default(),
+            EspPermissions::default(),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&installed_kernel)?, "old kernel");
+        assert_eq!(fs::read_dir(&esp)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn discard_remaining_files_if_renaming_fails() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp = tmpdir.path().join("esp");
+        fs::create_dir(&esp)?;
+        let source = |name: &str| -> Result<PathBuf> {
+            let path = tmpdir.path().join(name);
+            fs::write(&path, name)?;
+            Ok(path)
+        };
+
+        // Both files are staged at `kernel..tmp`, so the second rename finds nothing to move.
+        let prepared = PreparedGeneration {
+            files: vec![
+                (source("kernel")?, esp.join("kernel.efi")),
+                (source("initrd")?, esp.join("kernel.initrd")),
+                (source("stub")?, esp.join("stub.efi")),
+            ],
+            installed: Vec::new(),
+            signed: esp.join("stub.efi"),
+            _tempdir: tempfile::tempdir()?,
+        };
+        let result = prepared.commit(
+            &mut Roots::new(),
+            &mut SignedManifest::default(),
+            EspPermissions::default(),
+        );
+
+        assert!(result.is_err());
+        assert!(esp.join("kernel.efi").exists());
+        assert!(!esp.join("kernel.initrd").exists());
+        assert!(!esp.join("stub.efi").exists());
+        assert_eq!(fs::read_dir(&esp)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corruption_that_keeps_the_metadata() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let original = tmpdir.path().join("initrd");
+        fs::write(&original, "initrd")?;
+        let hash = file_hash(&original)?;
+        let file = tmpdir.path().join(format!(
+            "initrd-6.1.1-{}.efi",
+            Base32Unpadded::encode_string(&hash)
+        ));
+        fs::copy(&original, &file)?;
+        let mtime = fs::metadata(&file)?.modified()?;
+        let hash_cache = HashCache::in_memory();
+        hash_cache.file_hash(&file)?;
+        ensure_content_addressed(&file)?;
+
+        // Flip the contents without changing the size or the modification time, as a failing
+        // disk might.
+        fs::write(&file, b"INITRD")?;
+        fs::File::options()
+            .write(true)
+            .open(&file)?
+            .set_modified(mtime)?;
+        assert_eq!(hash_cache.file_hash(&file)?, hash);
+        assert!(ensure_content_addressed(&file).is_err());
+        Ok(())
+    }
+}
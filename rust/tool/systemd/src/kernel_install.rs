@@ -0,0 +1,93 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Entries on the ESP that are managed by kernel-install.
+///
+/// kernel-install names its unified kernel images in `EFI/Linux` and its entries in
+/// `loader/entries` after an entry token, e.g. `<token>-<kernel version>.efi`. By default, the
+/// token is the machine ID, which never collides with the `nixos-` prefix of the files
+/// Lanzaboote garbage collects. If the token is configured to be e.g. `nixos` in
+/// `/etc/kernel/entry-token`, these entries would be treated as garbage.
+#[derive(Clone, Debug)]
+pub struct KernelInstallEntries {
+    entry_token: String,
+}
+
+impl KernelInstallEntries {
+    /// Read the configured entry token from `kernel/entry-token` in the given `/etc` directory.
+    ///
+    /// Returns `None` if no entry token is configured.
+    pub fn detect(etc: &Path) -> Result<Option<Self>> {
+        let path = etc.join("kernel/entry-token");
+        let entry_token = match fs::read_to_string(&path) {
+            Ok(entry_token) => entry_token.trim().to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read the kernel-install entry token: {path:?}")
+                })
+            }
+        };
+
+        if entry_token.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self { entry_token }))
+    }
+
+    /// Whether the file at `path` was installed by kernel-install.
+    ///
+    /// Files that are named like the ones Lanzaboote installs are never considered to belong to
+    /// kernel-install.
+    pub fn owns(&self, path: &Path) -> bool {
+        path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+            n.starts_with(&format!("{}-", self.entry_token)) && !n.starts_with("nixos-generation-")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_entry_token() -> Result<()> {
+        let etc = tempfile::tempdir()?;
+        assert!(KernelInstallEntries::detect(etc.path())?.is_none());
+
+        fs::create_dir(etc.path().join("kernel"))?;
+        fs::write(etc.path().join("kernel/entry-token"), "nixos\n")?;
+        let entries = KernelInstallEntries::detect(etc.path())?.unwrap();
+        assert_eq!(entries.entry_token, "nixos");
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_empty_entry_token() -> Result<()> {
+        let etc = tempfile::tempdir()?;
+        fs::create_dir(etc.path().join("kernel"))?;
+        fs::write(etc.path().join("kernel/entry-token"), "\n")?;
+        assert!(KernelInstallEntries::detect(etc.path())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_own_kernel_install_entries() {
+        let entries = KernelInstallEntries {
+            entry_token: String::from("nixos"),
+        };
+
+        assert!(entries.owns(Path::new("EFI/Linux/nixos-6.6.1.efi")));
+        assert!(entries.owns(Path::new("loader/entries/nixos-6.6.1+3-0.conf")));
+        assert!(!entries.owns(Path::new("EFI/Linux/nixos-generation-1-abc.efi")));
+        assert!(!entries.owns(Path::new(
+            "loader/entries/nixos-generation-1-specialisation-foo-abc.conf"
+        )));
+        assert!(!entries.owns(Path::new("EFI/Linux/arch-6.6.1.efi")));
+    }
+}
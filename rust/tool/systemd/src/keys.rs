@@ -0,0 +1,135 @@
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use lanzaboote_tool::utils::tmpname;
+
+/// The file name of the generated private key.
+const PRIVATE_KEY_FILENAME: &str = "db.key";
+
+/// The file name of the generated certificate.
+const CERTIFICATE_FILENAME: &str = "db.pem";
+
+/// Generate a self-signed key and certificate to sign boot files with.
+///
+/// The certificate can be enrolled in the db. RSA 2048 keys are used because many firmwares only
+/// support them. The private key is only readable by its owner.
+pub fn generate_keys(
+    out_dir: &Path,
+    common_name: &str,
+    validity_days: u32,
+    force: bool,
+) -> Result<()> {
+    let private_key = out_dir.join(PRIVATE_KEY_FILENAME);
+    let certificate = out_dir.join(CERTIFICATE_FILENAME);
+
+    if !force {
+        for path in [&private_key, &certificate] {
+            if path.exists() {
+                bail!("{path:?} already exists. Use --force to overwrite it.");
+            }
+        }
+    }
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create directory: {out_dir:?}"))?;
+
+    // openssl writes to temporary files that only replace the keys once both are complete, so
+    // that a failure neither leaves an empty key behind nor destroys the keys it was to replace.
+    let tmp_private_key = out_dir.join(tmpname());
+    let tmp_certificate = out_dir.join(tmpname());
+    let result = run_openssl(
+        common_name,
+        validity_days,
+        &tmp_private_key,
+        &tmp_certificate,
+    )
+    .and_then(|()| {
+        fs::rename(&tmp_private_key, &private_key)
+            .with_context(|| format!("Failed to move the private key to {private_key:?}"))?;
+        fs::rename(&tmp_certificate, &certificate)
+            .with_context(|| format!("Failed to move the certificate to {certificate:?}"))
+    });
+    if let Err(err) = result {
+        for tmp in [&tmp_private_key, &tmp_certificate] {
+            if tmp.exists() {
+                if let Err(err) = fs::remove_file(tmp) {
+                    log::warn!("Failed to remove the temporary file {tmp:?}: {err}");
+                }
+            }
+        }
+        return Err(err);
+    }
+
+    log::info!("Generated {private_key:?} and {certificate:?}.");
+    Ok(())
+}
+
+/// Generate the key and the certificate with openssl.
+fn run_openssl(
+    common_name: &str,
+    validity_days: u32,
+    private_key: &Path,
+    certificate: &Path,
+) -> Result<()> {
+    // Create the private key with restrictive permissions before openssl writes to it. openssl
+    // keeps the permissions of an existing file.
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(private_key)
+        .with_context(|| format!("Failed to create the private key: {private_key:?}"))?;
+
+    let args: Vec<OsString> = vec![
+        "req".into(),
+        "-new".into(),
+        "-x509".into(),
+        "-newkey".into(),
+        "rsa:2048".into(),
+        "-nodes".into(),
+        "-sha256".into(),
+        "-days".into(),
+        validity_days.to_string().into(),
+        "-subj".into(),
+        format!("/CN={}", escape_subject(common_name)).into(),
+        "-keyout".into(),
+        private_key.into(),
+        "-out".into(),
+        certificate.into(),
+    ];
+
+    let output = Command::new("openssl")
+        .args(&args)
+        .output()
+        .context("Failed to run openssl. Most likely, the binary is not on PATH.")?;
+
+    if !output.status.success() {
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of openssl to stderr.")?;
+        log::debug!("openssl failed with args: `{args:?}`.");
+        bail!("Failed to generate the keys.");
+    }
+    Ok(())
+}
+
+/// Escape the characters that openssl interprets in the subject of a certificate.
+fn escape_subject(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('/', "\\/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_separators_in_subject() {
+        assert_eq!(escape_subject("Lanzaboote"), "Lanzaboote");
+        assert_eq!(escape_subject(r"a/b\c"), r"a\/b\\c");
+    }
+}
@@ -1,7 +1,27 @@
 mod architecture;
+mod bench;
+mod bls;
 mod cli;
+mod cmdline_map;
+mod config;
+mod diff;
+mod drift;
+mod dump_bootspec;
+mod enrolled;
 mod esp;
+mod esp_device;
+mod inspect;
 mod install;
+mod kernel_install;
+mod keys;
+mod signed_manifest;
+mod sizes;
+mod snapshot;
+mod store_refs;
+#[cfg(feature = "test-boot")]
+mod test_boot;
+mod uki;
+mod verify;
 mod version;
 
 use clap::Parser;
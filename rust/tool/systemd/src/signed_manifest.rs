@@ -0,0 +1,116 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::bls;
+use lanzaboote_tool::utils::file_hash;
+
+/// The files Lanzaboote installed on the ESP, split into the signed and the unsigned ones.
+///
+/// The firmware and systemd-boot only find the signed files at fixed paths, e.g. the stubs in
+/// `EFI/Linux`. These directories also contain unsigned files and FAT has no symlinks, so the
+/// signed files cannot be moved to a directory of their own. Instead, the manifest lists them at
+/// their canonical paths, so that an auditor can assert that every signed file is signed and
+/// unchanged, e.g. with `lzbt verify`.
+#[derive(Debug, Default)]
+pub struct SignedManifest {
+    signed: BTreeSet<PathBuf>,
+    unsigned: BTreeSet<PathBuf>,
+}
+
+/// A file in the manifest as it is written to the ESP.
+#[derive(Serialize)]
+struct ManifestEntry {
+    /// The path from the root of the ESP, e.g. `/EFI/Linux/nixos-generation-1.efi`.
+    path: String,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct ManifestContents {
+    signed: Vec<ManifestEntry>,
+    unsigned: Vec<ManifestEntry>,
+}
+
+impl SignedManifest {
+    /// Add a file on the ESP that Lanzaboote signed.
+    pub fn add_signed(&mut self, file: &Path) {
+        self.unsigned.remove(file);
+        self.signed.insert(file.to_path_buf());
+    }
+
+    /// Add a file on the ESP that is not signed, e.g. a kernel that is verified by the stub.
+    pub fn add_unsigned(&mut self, file: &Path) {
+        if !self.signed.contains(file) {
+            self.unsigned.insert(file.to_path_buf());
+        }
+    }
+
+    /// Serialize the manifest of the ESP mounted at `esp` to JSON.
+    ///
+    /// Files that no longer exist, e.g. because they were garbage collected, are left out.
+    pub fn to_json(&self, esp: &Path) -> Result<Vec<u8>> {
+        let entries = |files: &BTreeSet<PathBuf>| {
+            files
+                .iter()
+                .filter(|file| file.is_file())
+                .map(|file| {
+                    Ok(ManifestEntry {
+                        path: bls::esp_relative_path(esp, file)?,
+                        sha256: format!("{:x}", file_hash(file)?),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let contents = ManifestContents {
+            signed: entries(&self.signed)?,
+            unsigned: entries(&self.unsigned)?,
+        };
+        serde_json::to_vec_pretty(&contents).context("Failed to serialize the signed manifest")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn list_existing_files_by_signature() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let stub = esp.path().join("EFI/Linux/nixos-generation-1.efi");
+        let kernel = esp.path().join("EFI/nixos/kernel.efi");
+        let removed = esp.path().join("EFI/nixos/initrd.efi");
+        fs::create_dir_all(esp.path().join("EFI/Linux"))?;
+        fs::create_dir_all(esp.path().join("EFI/nixos"))?;
+        fs::write(&stub, "stub")?;
+        fs::write(&kernel, "kernel")?;
+
+        let mut manifest = SignedManifest::default();
+        manifest.add_unsigned(&kernel);
+        manifest.add_unsigned(&removed);
+        // In kernel mode, the kernel is signed, even though it is installed like an initrd.
+        manifest.add_signed(&kernel);
+        manifest.add_unsigned(&kernel);
+        manifest.add_signed(&stub);
+
+        let contents: serde_json::Value = serde_json::from_slice(&manifest.to_json(esp.path())?)?;
+        assert_eq!(
+            contents["signed"],
+            serde_json::json!([
+                {
+                    "path": "/EFI/Linux/nixos-generation-1.efi",
+                    "sha256": format!("{:x}", file_hash(&stub)?),
+                },
+                {
+                    "path": "/EFI/nixos/kernel.efi",
+                    "sha256": format!("{:x}", file_hash(&kernel)?),
+                },
+            ])
+        );
+        assert_eq!(contents["unsigned"], serde_json::json!([]));
+        Ok(())
+    }
+}
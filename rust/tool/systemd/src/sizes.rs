@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::install::{
+    estimate_esp_usage, read_generation_links, split_off_retained, GenerationEspUsage, MIB,
+};
+
+/// Print the estimated ESP usage of every generation that `install` would install and the total.
+///
+/// Nothing is assembled or written. Generations that share a kernel or initrd only need it once
+/// on the ESP. Thus, the total can be smaller than the sum of the generations.
+pub fn sizes(
+    lanzaboote_stub: &Path,
+    configuration_limit: usize,
+    generation_links: &[PathBuf],
+) -> Result<()> {
+    let stub_size = fs::metadata(lanzaboote_stub)
+        .with_context(|| format!("Failed to read the size of {lanzaboote_stub:?}"))?
+        .len();
+    let links = read_generation_links(generation_links)?;
+    let (_, links) = split_off_retained(links, configuration_limit);
+
+    let usages = links
+        .iter()
+        .map(GenerationEspUsage::from_link)
+        .collect::<Vec<_>>();
+
+    println!("{:<12} {:>12}", "generation", "size");
+    for (link, usage) in links.iter().zip(&usages) {
+        let size = estimate_esp_usage(std::slice::from_ref(usage), stub_size);
+        println!("{:<12} {:>12}", link.version, format_mib(size));
+    }
+    println!(
+        "{:<12} {:>12}",
+        "total",
+        format_mib(estimate_esp_usage(&usages, stub_size))
+    );
+    Ok(())
+}
+
+/// Format a number of bytes as MiB with one decimal, e.g. `12.5 MiB`.
+fn format_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / MIB as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_sizes_in_mib() {
+        assert_eq!(format_mib(0), "0.0 MiB");
+        assert_eq!(format_mib(MIB * 25 / 2), "12.5 MiB");
+    }
+}
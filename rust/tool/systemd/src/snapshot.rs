@@ -0,0 +1,319 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::syncfs;
+use serde::{Deserialize, Serialize};
+
+use crate::bls;
+use crate::esp::SystemdEspPaths;
+use crate::install::{self, EspPermissions};
+use lanzaboote_tool::utils::file_hash;
+
+/// The name of the manifest of a snapshot. It is written last, so a snapshot without it is
+/// incomplete.
+const MANIFEST: &str = "snapshot.json";
+
+/// The manifest of a snapshot of the files Lanzaboote manages on the ESP.
+///
+/// Next to the files, it records the directories the snapshot owns, so that restoring it also
+/// removes the files that were installed after it was taken.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    files: Vec<SnapshotFile>,
+    directories: Vec<OwnedDirectory>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotFile {
+    /// The path from the root of the ESP, e.g. `/EFI/nixos/kernel.efi`.
+    path: String,
+    sha256: String,
+}
+
+/// A directory on the ESP in which all files whose name starts with `prefix` belong to
+/// Lanzaboote.
+#[derive(Debug, Serialize, Deserialize)]
+struct OwnedDirectory {
+    /// The path from the root of the ESP, e.g. `/EFI/Linux`.
+    path: String,
+    prefix: String,
+}
+
+/// Copy the files Lanzaboote manages on the ESP into a new snapshot in `snapshots`.
+///
+/// These are the files in `EFI/nixos`, the files starting with `nixos-` in `EFI/Linux` and
+/// `loader/entries`, systemd-boot and its loader config. Returns the ID of the snapshot.
+pub fn take(esp_paths: &SystemdEspPaths, snapshots: &Path) -> Result<String> {
+    let esp = &esp_paths.esp;
+    let directories = [
+        (&esp_paths.nixos, ""),
+        (&esp_paths.linux, "nixos-"),
+        (&esp_paths.entries, "nixos-"),
+    ];
+    let mut files = BTreeSet::new();
+    for (directory, prefix) in directories {
+        files.extend(owned_files(directory, prefix)?);
+    }
+    files.extend(
+        esp_paths
+            .efi_fallback
+            .iter()
+            .chain([
+                &esp_paths.systemd_boot,
+                &esp_paths.systemd_boot_loader_config,
+            ])
+            .filter(|file| file.is_file())
+            .cloned(),
+    );
+
+    let (id, directory) = create_snapshot_dir(snapshots)?;
+    let mut snapshot = Snapshot {
+        files: Vec::new(),
+        directories: directories
+            .into_iter()
+            .map(|(directory, prefix)| {
+                Ok(OwnedDirectory {
+                    path: bls::esp_relative_path(esp, directory)?,
+                    prefix: prefix.to_string(),
+                })
+            })
+            .collect::<Result<_>>()?,
+    };
+    for file in &files {
+        let path = bls::esp_relative_path(esp, file)?;
+        let to = directory.join(path.trim_start_matches('/'));
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create the directory {parent:?}"))?;
+        }
+        fs::copy(file, &to).with_context(|| format!("Failed to copy {file:?} to {to:?}"))?;
+        snapshot.files.push(SnapshotFile {
+            path,
+            sha256: format!("{:x}", file_hash(&to)?),
+        });
+    }
+
+    let manifest =
+        serde_json::to_vec_pretty(&snapshot).context("Failed to serialize the snapshot")?;
+    fs::write(directory.join(MANIFEST), manifest)
+        .with_context(|| format!("Failed to write the manifest of the snapshot {id}"))?;
+    log::info!(
+        "Saved {} files of {esp:?} as snapshot {id} in {snapshots:?}.",
+        snapshot.files.len()
+    );
+    Ok(id)
+}
+
+/// Restore the snapshot `id` in `snapshots` to the ESP mounted at `esp`.
+///
+/// The files of the snapshot are put back and the files it owns that were installed after it was
+/// taken are removed. Nothing is changed if a file of the snapshot is corrupted.
+pub fn restore(snapshots: &Path, id: &str, esp: &Path) -> Result<()> {
+    let directory = snapshots.join(id);
+    let manifest = match fs::read(directory.join(MANIFEST)) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            bail!(
+                "There is no snapshot {id} in {snapshots:?}. Available snapshots: {}",
+                list(snapshots)?.join(", ")
+            );
+        }
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read the manifest of the snapshot {id}"))
+        }
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&manifest)
+        .with_context(|| format!("Failed to parse the manifest of the snapshot {id}"))?;
+
+    let mut restored = BTreeSet::new();
+    for file in &snapshot.files {
+        let relative_path = Path::new(file.path.trim_start_matches('/'));
+        let from = directory.join(relative_path);
+        if format!("{:x}", file_hash(&from)?) != file.sha256 {
+            bail!("{from:?} does not match the manifest. The snapshot {id} is corrupted.");
+        }
+        restored.insert((from, esp.join(relative_path)));
+    }
+
+    let mut changed = 0;
+    for (from, to) in &restored {
+        if install::install(from, to, EspPermissions::default())
+            .with_context(|| format!("Failed to restore {to:?}"))?
+        {
+            changed += 1;
+        }
+    }
+
+    let kept = restored
+        .into_iter()
+        .map(|(_, to)| to)
+        .collect::<BTreeSet<_>>();
+    let mut removed = 0;
+    for owned in &snapshot.directories {
+        let directory = esp.join(owned.path.trim_start_matches('/'));
+        for file in owned_files(&directory, &owned.prefix)? {
+            if !kept.contains(&file) {
+                log::debug!("Removing {file:?}...");
+                fs::remove_file(&file).with_context(|| format!("Failed to remove {file:?}"))?;
+                removed += 1;
+            }
+        }
+    }
+
+    let boot = File::open(esp).context("Failed to open ESP root directory.")?;
+    syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+    log::info!("Restored snapshot {id}: replaced {changed} files and removed {removed} files.");
+    Ok(())
+}
+
+/// The IDs of the complete snapshots in `snapshots`, from oldest to newest.
+fn list(snapshots: &Path) -> Result<Vec<String>> {
+    let mut ids = fs::read_dir(snapshots)
+        .with_context(|| format!("Failed to read the snapshots in {snapshots:?}"))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(MANIFEST).is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    ids.sort_by_key(|id| id_sort_key(id));
+    Ok(ids)
+}
+
+/// Sort snapshot IDs by the time they were taken, e.g. `1700000000` before `1700000000-1`.
+fn id_sort_key(id: &str) -> (u64, u64) {
+    let (seconds, counter) = id.split_once('-').unwrap_or((id, "0"));
+    (
+        seconds.parse().unwrap_or(u64::MAX),
+        counter.parse().unwrap_or(u64::MAX),
+    )
+}
+
+/// Create the directory of a new snapshot, named after the current time. Snapshots taken in the
+/// same second get a counter appended to their ID.
+fn create_snapshot_dir(snapshots: &Path) -> Result<(String, PathBuf)> {
+    fs::create_dir_all(snapshots)
+        .with_context(|| format!("Failed to create the directory {snapshots:?}"))?;
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    for counter in 0.. {
+        let id = match counter {
+            0 => seconds.to_string(),
+            counter => format!("{seconds}-{counter}"),
+        };
+        let directory = snapshots.join(&id);
+        match fs::create_dir(&directory) {
+            Ok(()) => return Ok((id, directory)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to create the directory {directory:?}"))
+            }
+        }
+    }
+    unreachable!("The snapshot IDs are exhausted")
+}
+
+/// The regular files in `directory` whose name starts with `prefix`.
+///
+/// Subdirectories are not included. A directory that does not exist is empty.
+fn owned_files(directory: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read directory {directory:?}"))
+        }
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read directory {directory:?}"))?;
+        let owned = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(prefix));
+        if owned && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lanzaboote_tool::architecture::Architecture;
+    use lanzaboote_tool::esp::EspPaths;
+
+    #[test]
+    fn restore_managed_files() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let snapshots = tempfile::tempdir()?;
+        let esp_paths = SystemdEspPaths::new(esp.path(), Architecture::X86);
+        let stub = esp_paths.linux.join("nixos-generation-1.efi");
+        let other_os = esp_paths.linux.join("other-os.efi");
+        let kernel = esp_paths.nixos.join("kernel.efi");
+        fs::create_dir_all(&esp_paths.linux)?;
+        fs::create_dir_all(&esp_paths.nixos)?;
+        fs::write(&stub, "good stub")?;
+        fs::write(&other_os, "other os")?;
+        fs::write(&kernel, "kernel")?;
+
+        let id = take(&esp_paths, snapshots.path())?;
+        assert_eq!(list(snapshots.path())?, [id.as_str()]);
+
+        // A bad installation replaces the stub and adds a generation.
+        let new_stub = esp_paths.linux.join("nixos-generation-2.efi");
+        fs::write(&stub, "bad stub")?;
+        fs::write(&new_stub, "bad stub")?;
+        let other_os_update = esp_paths.linux.join("other-os-2.efi");
+        fs::write(&other_os_update, "other os")?;
+
+        restore(snapshots.path(), &id, esp.path())?;
+        assert_eq!(fs::read_to_string(&stub)?, "good stub");
+        assert_eq!(fs::read_to_string(&kernel)?, "kernel");
+        assert!(!new_stub.exists());
+        // Files of other operating systems are neither part of a snapshot nor removed.
+        assert!(other_os.exists());
+        assert!(other_os_update.exists());
+
+        assert!(restore(snapshots.path(), "missing", esp.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_corrupted_snapshot() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let snapshots = tempfile::tempdir()?;
+        let esp_paths = SystemdEspPaths::new(esp.path(), Architecture::X86);
+        let kernel = esp_paths.nixos.join("kernel.efi");
+        fs::create_dir_all(&esp_paths.nixos)?;
+        fs::write(&kernel, "kernel")?;
+
+        let id = take(&esp_paths, snapshots.path())?;
+        fs::write(
+            snapshots.path().join(&id).join("EFI/nixos/kernel.efi"),
+            "corrupted",
+        )?;
+        fs::write(&kernel, "new kernel")?;
+
+        assert!(restore(snapshots.path(), &id, esp.path()).is_err());
+        assert_eq!(fs::read_to_string(&kernel)?, "new kernel");
+        Ok(())
+    }
+
+    #[test]
+    fn sort_snapshot_ids_by_time() {
+        let mut ids = ["1700000001", "1700000000-2", "1700000000", "1700000000-1"];
+        ids.sort_by_key(|id| id_sort_key(id));
+        assert_eq!(
+            ids,
+            ["1700000000", "1700000000-1", "1700000000-2", "1700000001"]
+        );
+    }
+}
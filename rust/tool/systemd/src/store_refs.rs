@@ -0,0 +1,92 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::install::{read_generation_links, split_off_retained};
+use lanzaboote_tool::generation::Generation;
+
+/// Print the store paths that are only referenced by generations beyond the configuration limit.
+///
+/// These generations are not installed on the ESP anymore. Once they are deleted, their store
+/// paths can be removed with `nix-store --delete`. Only the paths referenced directly by the
+/// bootspecs (the toplevel and the directories of the kernel and initrd) are considered. Their
+/// closures are left to Nix, which refuses to delete paths that are still alive.
+pub fn prune_store_refs(configuration_limit: usize, generation_links: &[PathBuf]) -> Result<()> {
+    let links = read_generation_links(generation_links)?;
+    let (dropped, retained) = split_off_retained(links, configuration_limit);
+
+    let mut retained_refs = BTreeSet::new();
+    for link in &retained {
+        // If the references of an installed generation are unknown, every path could still be in
+        // use.
+        let generation = Generation::from_link(link).with_context(|| {
+            format!("Failed to read the store references of the installed generation: {link:?}")
+        })?;
+        retained_refs.extend(store_references(&generation));
+    }
+
+    let mut dropped_refs = BTreeSet::new();
+    for link in &dropped {
+        match Generation::from_link(link) {
+            Ok(generation) => dropped_refs.extend(store_references(&generation)),
+            Err(err) => log::warn!("Skipping unreadable generation {link:?}: {err:#}"),
+        }
+    }
+
+    for path in unreferenced(dropped_refs, &retained_refs) {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+/// The store paths referenced by the generation and its specialisations.
+fn store_references(generation: &Generation) -> BTreeSet<PathBuf> {
+    let bootspec = &generation.spec.bootspec;
+    let mut references = BTreeSet::new();
+    for spec in bootspec.specialisations.values().chain([bootspec]) {
+        let spec = &spec.bootspec;
+        references.insert(spec.toplevel.0.clone());
+        // The kernel and initrd are files inside of their store paths, e.g.
+        // /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage.
+        let files = [Some(&spec.kernel), spec.initrd.as_ref()];
+        references.extend(
+            files
+                .into_iter()
+                .flatten()
+                .filter_map(|file| file.parent())
+                .map(Path::to_path_buf),
+        );
+    }
+    references
+}
+
+/// The references that are not also retained.
+fn unreferenced(
+    dropped: BTreeSet<PathBuf>,
+    retained: &BTreeSet<PathBuf>,
+) -> impl Iterator<Item = PathBuf> + '_ {
+    dropped.into_iter().filter(|path| !retained.contains(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_paths_shared_with_retained_generations() {
+        let dropped = BTreeSet::from([
+            PathBuf::from("/nix/store/aaaa-nixos-system"),
+            PathBuf::from("/nix/store/bbbb-linux-6.1.1"),
+        ]);
+        let retained = BTreeSet::from([
+            PathBuf::from("/nix/store/cccc-nixos-system"),
+            PathBuf::from("/nix/store/bbbb-linux-6.1.1"),
+        ]);
+
+        assert_eq!(
+            unreferenced(dropped, &retained).collect::<Vec<PathBuf>>(),
+            vec![PathBuf::from("/nix/store/aaaa-nixos-system")]
+        );
+    }
+}
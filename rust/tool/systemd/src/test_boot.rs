@@ -0,0 +1,212 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use lanzaboote_tool::architecture::Architecture;
+
+/// Boot freshly assembled stubs in a QEMU VM before they are installed on the ESP.
+///
+/// The stub, kernel and initrd are copied to a temporary ESP at the same paths as on the real ESP
+/// and the stub is additionally installed as the removable media fallback. The VM boots it with
+/// the given UEFI firmware and the boot is successful if the marker appears on the serial console
+/// before the timeout.
+///
+/// Secure Boot is only enforced if the variable store has it enabled and the signing certificate
+/// enrolled. The kernel only writes to the serial console if the kernel command line of the
+/// generation contains e.g. `console=ttyS0`.
+#[derive(Clone)]
+pub struct TestBoot {
+    pub arch: Architecture,
+    /// The UEFI firmware code, e.g. `OVMF_CODE.fd`.
+    pub firmware: PathBuf,
+    /// The UEFI variable store, e.g. `OVMF_VARS.fd`. Every boot uses a fresh copy of it.
+    pub firmware_vars: PathBuf,
+    pub timeout: Duration,
+    pub marker: String,
+}
+
+impl TestBoot {
+    /// Boot the stub referencing the other `files`.
+    ///
+    /// The files are the `(from, to)` pairs of a prepared generation, where `to` is a path on the
+    /// ESP mounted at `esp`. The stub is the last file.
+    pub fn run(&self, esp: &Path, files: &[(PathBuf, PathBuf)]) -> Result<()> {
+        let vm_dir = TempDir::new().context("Failed to create temporary directory.")?;
+        let vm_esp = vm_dir.path().join("esp");
+
+        for (from, to) in files {
+            let relative_path = to
+                .strip_prefix(esp)
+                .with_context(|| format!("{to:?} is not on the ESP."))?;
+            copy(from, &vm_esp.join(relative_path))?;
+        }
+        let (stub, _) = files.last().context("No stub to boot.")?;
+        copy(
+            stub,
+            &vm_esp
+                .join("EFI/BOOT")
+                .join(self.arch.efi_fallback_filename()),
+        )?;
+
+        let firmware_vars = vm_dir.path().join("vars.fd");
+        fs::copy(&self.firmware_vars, &firmware_vars).with_context(|| {
+            format!(
+                "Failed to copy the UEFI variable store: {:?}",
+                self.firmware_vars
+            )
+        })?;
+
+        let args = self.qemu_args(&firmware_vars, &vm_esp)?;
+        let (qemu, _) = self.qemu()?;
+        log::info!("Test booting {stub:?} with {qemu}...");
+        let child = Command::new(qemu)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to run {qemu}. Most likely, it is not on PATH."))?;
+        log::debug!("Started {qemu} with args: `{args:?}`.");
+
+        wait_for_marker(child, &self.marker, self.timeout)
+    }
+
+    /// The QEMU binary and the machine specific arguments for the architecture.
+    fn qemu(&self) -> Result<(&'static str, &'static [&'static str])> {
+        Ok(match self.arch {
+            Architecture::X86 => (
+                "qemu-system-x86_64",
+                &[
+                    "-machine",
+                    "q35,smm=on,accel=kvm:tcg",
+                    // OVMF with Secure Boot only protects its variable store when SMM is enabled.
+                    "-global",
+                    "driver=cfi.pflash01,property=secure,value=on",
+                ],
+            ),
+            Architecture::AArch64 => (
+                "qemu-system-aarch64",
+                &["-machine", "virt,accel=kvm:tcg", "-cpu", "max"],
+            ),
+            _ => bail!("Test booting is not supported on {:?}.", self.arch),
+        })
+    }
+
+    /// Assemble the arguments for QEMU to boot from `esp` with the given variable store.
+    fn qemu_args(&self, firmware_vars: &Path, esp: &Path) -> Result<Vec<OsString>> {
+        let (_, machine_args) = self.qemu()?;
+        let mut args: Vec<OsString> = machine_args.iter().map(OsString::from).collect();
+        args.extend(["-m", "1024", "-nographic", "-no-reboot"].map(OsString::from));
+
+        let mut drive = |drive: &str, path: &Path| {
+            let mut value = OsString::from(drive);
+            value.push(path);
+            args.extend([OsString::from("-drive"), value]);
+        };
+        drive(
+            "if=pflash,format=raw,unit=0,readonly=on,file=",
+            &self.firmware,
+        );
+        drive("if=pflash,format=raw,unit=1,file=", firmware_vars);
+        drive("format=raw,file=fat:", esp);
+
+        Ok(args)
+    }
+}
+
+/// Copy `from` to `to`, creating the parent directories of `to`.
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+    }
+    fs::copy(from, to).with_context(|| format!("Failed to copy {from:?} to {to:?}"))?;
+    Ok(())
+}
+
+/// Wait until `marker` appears in the output of the `child` and kill it afterwards.
+fn wait_for_marker(mut child: Child, marker: &str, timeout: Duration) -> Result<()> {
+    let stdout = child
+        .stdout
+        .take()
+        .context("Failed to open stdout of QEMU.")?;
+    let (sender, receiver) = mpsc::channel();
+    let thread_marker = marker.to_string();
+    // Reading blocks. Thus, the output is scanned in a thread so that the timeout can be enforced.
+    thread::spawn(move || {
+        let found = scan_for_marker(stdout, &thread_marker);
+        sender.send(found).ok();
+    });
+
+    let deadline = Instant::now() + timeout;
+    let result = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    child.kill().ok();
+    child.wait().ok();
+
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("The VM stopped before {marker:?} appeared on the serial console."),
+        Err(_) => bail!(
+            "The boot did not succeed within {} seconds.",
+            timeout.as_secs()
+        ),
+    }
+}
+
+/// Whether `marker` appears in a line of `output`.
+fn scan_for_marker(output: impl Read, marker: &str) -> bool {
+    BufReader::new(output)
+        .split(b'\n')
+        .map_while(|line| line.ok())
+        .any(|line| String::from_utf8_lossy(&line).contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_marker_in_output() {
+        let output = "BdsDxe: starting Boot0001\r\n<<< NixOS Stage 1 >>>\r\n";
+        assert!(scan_for_marker(output.as_bytes(), "<<< NixOS Stage 1 >>>"));
+        assert!(!scan_for_marker(output.as_bytes(), "Stage 2"));
+    }
+
+    #[test]
+    fn time_out_without_marker() -> Result<()> {
+        let child = Command::new("sleep")
+            .arg("10")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let result = wait_for_marker(child, "marker", Duration::from_millis(100));
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn boot_disk_from_esp() -> Result<()> {
+        let test_boot = TestBoot {
+            arch: Architecture::X86,
+            firmware: PathBuf::from("OVMF_CODE.fd"),
+            firmware_vars: PathBuf::from("OVMF_VARS.fd"),
+            timeout: Duration::from_secs(1),
+            marker: String::from("marker"),
+        };
+        let args = test_boot.qemu_args(Path::new("vars.fd"), Path::new("esp"))?;
+
+        assert!(args.contains(&OsString::from(
+            "if=pflash,format=raw,unit=0,readonly=on,file=OVMF_CODE.fd"
+        )));
+        assert!(args.contains(&OsString::from("if=pflash,format=raw,unit=1,file=vars.fd")));
+        assert!(args.contains(&OsString::from("format=raw,file=fat:esp")));
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use crate::install::{self, EspPermissions};
+use lanzaboote_tool::pe::{self, lanzaboote_image, StubParameters};
+use lanzaboote_tool::sbat::Sbat;
+use lanzaboote_tool::signature::Signer;
+use lanzaboote_tool::utils::SecureTempDirExt;
+
+/// Build, sign and install a stub from an explicit kernel, initrd and kernel command line.
+///
+/// With `sbat`, the image carries the SBAT metadata in its `.sbat` section so that shim can revoke
+/// it.
+///
+/// This does not need a bootspec or a generation and is meant for experiments, e.g. testing a
+/// self-built kernel with Secure Boot. The stub is installed at `output`, which has to be on the
+/// ESP. The kernel and the initrd, if any, are installed next to it as `<name>.kernel` and
+/// `<name>.initrd`. They do not end in `.efi` so that systemd-boot does not list them as boot
+/// entries.
+///
+/// Garbage collection of `install` removes the files if they are in a directory it manages, e.g.
+/// `EFI/nixos`, or if their name starts with `nixos-`.
+///
+/// With `check_reproducible`, the unsigned image is assembled twice first. If the two builds
+/// differ, nothing is signed or installed.
+#[allow(clippy::too_many_arguments)]
+pub fn build_uki<S: Signer>(
+    signer: &S,
+    lanzaboote_stub: &Path,
+    kernel: &Path,
+    initrd: Option<&Path>,
+    kernel_cmdline: &[String],
+    sbat: Option<&Sbat>,
+    esp: &Path,
+    output: &Path,
+    check_reproducible: bool,
+) -> Result<()> {
+    output
+        .strip_prefix(esp)
+        .with_context(|| format!("{output:?} is not on the ESP {esp:?}."))?;
+
+    let kernel_target = output.with_extension("kernel");
+    let initrd_target = output.with_extension("initrd");
+
+    let parameters = StubParameters::new(
+        lanzaboote_stub,
+        kernel,
+        &kernel_target,
+        initrd.map(|initrd| (initrd, initrd_target.as_path())),
+        esp,
+    )?
+    .with_cmdline(kernel_cmdline);
+    let parameters = match sbat {
+        Some(sbat) => parameters.with_sbat(sbat.as_bytes()),
+        None => parameters,
+    };
+
+    if check_reproducible {
+        ensure_reproducible(&parameters)?;
+    }
+
+    log::info!("Building {output:?}...");
+    let signed_stub = signer
+        .build_and_sign_stub(&parameters)
+        .context("Failed to build and sign the stub.")?;
+    let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+    let signed_stub = tempdir
+        .write_secure_file(signed_stub)
+        .context("Failed to write the signed stub.")?;
+
+    let files = [
+        Some((kernel, kernel_target.as_path())),
+        initrd.map(|initrd| (initrd, initrd_target.as_path())),
+        // The stub comes last so that it is only installed if the files it references are.
+        Some((signed_stub.as_path(), output)),
+    ];
+    for (from, to) in files.into_iter().flatten() {
+        install::install(from, to, EspPermissions::default())
+            .with_context(|| format!("Failed to install {to:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Assemble the unsigned image twice in separate temporary directories and ensure that both
+/// builds are identical.
+///
+/// This exercises the whole assembly, e.g. to find a section that carries nondeterministic data.
+fn ensure_reproducible(parameters: &StubParameters) -> Result<()> {
+    let assemble = || -> Result<Vec<u8>> {
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let image =
+            lanzaboote_image(&tempdir, parameters).context("Failed to build a lanzaboote image")?;
+        fs::read(&image).with_context(|| format!("Failed to read {image:?}"))
+    };
+    let first = assemble()?;
+    let second = assemble()?;
+
+    let Some(offset) = first_difference(&first, &second) else {
+        log::info!("The image is reproducible.");
+        return Ok(());
+    };
+    bail!(
+        "The image is not reproducible. The builds first differ at offset {offset:#x} in {}.",
+        describe_offset(&first, offset)
+    )
+}
+
+/// The offset of the first byte that differs, including a difference in length.
+fn first_difference(first: &[u8], second: &[u8]) -> Option<usize> {
+    first
+        .iter()
+        .zip(second)
+        .position(|(a, b)| a != b)
+        .or_else(|| (first.len() != second.len()).then(|| first.len().min(second.len())))
+}
+
+/// Describe where an offset of a PE binary lies, e.g. "section .initrd".
+fn describe_offset(pe_binary: &[u8], offset: usize) -> String {
+    let Ok(sections) = pe::read_sections(pe_binary) else {
+        return "an unparsable PE binary".to_string();
+    };
+    let section = sections.iter().find(|section| {
+        let start = section.file_offset as usize;
+        (start..start + section.file_size as usize).contains(&offset)
+    });
+    match section {
+        Some(section) => format!("section {}", section.name),
+        None if sections
+            .iter()
+            .all(|section| offset < section.file_offset as usize) =>
+        {
+            "the PE headers".to_string()
+        }
+        None => "no section".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_first_difference() {
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"axc"), Some(1));
+        assert_eq!(first_difference(b"abc", b"abcd"), Some(3));
+    }
+
+    #[test]
+    fn describe_offsets_of_pe_binary() -> Result<()> {
+        let binary = fs::read("tests/fixtures/authenticode/unsigned.efi")?;
+        let sections = pe::read_sections(&binary)?;
+        let first = &sections[0];
+
+        assert_eq!(describe_offset(&binary, 0), "the PE headers");
+        assert_eq!(
+            describe_offset(&binary, first.file_offset as usize),
+            format!("section {}", first.name)
+        );
+        assert_eq!(
+            describe_offset(b"not a PE binary", 0),
+            "an unparsable PE binary"
+        );
+        Ok(())
+    }
+}
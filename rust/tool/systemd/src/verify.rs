@@ -0,0 +1,310 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tempfile::tempdir;
+
+use crate::esp::SystemdEspPaths;
+use lanzaboote_tool::esp_state;
+use lanzaboote_tool::pe;
+use lanzaboote_tool::signature::{authenticode, local::LocalKeyPair, Signer};
+
+/// The result of verifying a single file.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Verification {
+    path: PathBuf,
+    signed: bool,
+    valid: bool,
+    /// The subject of the signing certificate as in RFC 4514, e.g. `CN=Database Key`.
+    signer: Option<String>,
+    /// Why the file failed verification.
+    error: Option<String>,
+}
+
+/// Verify the signatures of PE binaries with a certificate and fail if any is not valid.
+///
+/// With `detached`, the PKCS#7 signature from this file (e.g. a `.p7s` written by `install`) is
+/// verified instead of the embedded one. It is attached to a copy of the binary in place of the
+/// embedded signature. The Authenticode digest does not cover the signature, so the detached
+/// signature is only valid if it signs the same digest as the embedded one would.
+///
+/// With `json`, an array with the result for every file is printed, e.g. for monitoring.
+///
+/// With `offline`, the signatures are verified in-process instead of with `sbverify`, `sbattach`
+/// and `openssl`, e.g. in a rescue environment without them.
+pub fn verify(
+    paths: &[PathBuf],
+    public_key: &Path,
+    detached: Option<&Path>,
+    json: bool,
+    offline: bool,
+) -> Result<()> {
+    if detached.is_some() && paths.len() > 1 {
+        bail!("A detached signature can only be verified against a single file.");
+    }
+
+    let verifications = paths
+        .iter()
+        .map(|path| verify_file(path, public_key, detached, offline))
+        .collect::<Vec<_>>();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&verifications).context("Failed to serialize to JSON")?
+        );
+    } else {
+        for verification in &verifications {
+            match &verification.error {
+                None => log::info!("The signature of {:?} is valid.", verification.path),
+                Some(error) => log::error!("{:?}: {error}", verification.path),
+            }
+        }
+    }
+
+    let failed = verifications.iter().filter(|v| !v.valid).count();
+    if failed > 0 {
+        bail!("{failed} of {} files failed verification.", paths.len());
+    }
+    Ok(())
+}
+
+/// The signed binaries installed on the ESP: the stubs, the kernels booted directly by loader
+/// entries, systemd-boot and the EFI fallback.
+pub fn installed_binaries(esp_paths: &SystemdEspPaths) -> Result<Vec<PathBuf>> {
+    let mut binaries = esp_state::installed_stubs(&esp_paths.esp)?;
+    binaries.extend(booted_kernels(esp_paths)?);
+    binaries.extend(
+        [&esp_paths.systemd_boot]
+            .into_iter()
+            .chain(esp_paths.efi_fallback.as_ref())
+            .filter(|binary| binary.is_file())
+            .cloned(),
+    );
+    if binaries.is_empty() {
+        bail!(
+            "There are no binaries installed by Lanzaboote on {:?}.",
+            esp_paths.esp
+        );
+    }
+    Ok(binaries)
+}
+
+/// The kernels that Lanzaboote's loader entries boot directly, e.g. with `--boot-mode kernel`.
+///
+/// Unlike the kernels embedded into stubs, these have to be signed themselves.
+fn booted_kernels(esp_paths: &SystemdEspPaths) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(&esp_paths.entries) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {:?}", esp_paths.entries))
+        }
+    };
+
+    let mut kernels = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_lanzaboote_entry = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.starts_with("nixos-") && name.ends_with(".conf"));
+        if !is_lanzaboote_entry {
+            continue;
+        }
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        kernels.extend(linux_paths(&contents).map(|linux| esp_paths.esp.join(linux)));
+    }
+    kernels.sort();
+    kernels.dedup();
+    Ok(kernels)
+}
+
+/// The ESP-relative paths of the `linux` lines of a loader entry, without the leading `/`.
+fn linux_paths(entry: &str) -> impl Iterator<Item = &str> {
+    entry.lines().filter_map(|line| {
+        line.trim_start()
+            .strip_prefix("linux")
+            .filter(|value| value.starts_with(char::is_whitespace))
+            .map(|value| value.trim().trim_start_matches('/'))
+    })
+}
+
+fn verify_file(
+    path: &Path,
+    public_key: &Path,
+    detached: Option<&Path>,
+    offline: bool,
+) -> Verification {
+    let mut verification = Verification {
+        path: path.to_path_buf(),
+        signed: false,
+        valid: false,
+        signer: None,
+        error: None,
+    };
+    let result = if offline {
+        check_signature_offline(&mut verification, public_key, detached)
+    } else {
+        check_signature(&mut verification, public_key, detached)
+    };
+    if let Err(err) = result {
+        verification.error = Some(format!("{err:#}"));
+    }
+    verification
+}
+
+/// Fill in the verification and fail if the signature is missing or not valid.
+fn check_signature(
+    verification: &mut Verification,
+    public_key: &Path,
+    detached: Option<&Path>,
+) -> Result<()> {
+    let path = &verification.path;
+    let signature = match detached {
+        Some(signature) => fs::read(signature)
+            .with_context(|| format!("Failed to read the detached signature {signature:?}"))?,
+        None => {
+            let file_data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+            match pe::read_pkcs7_signature(&file_data) {
+                Ok(signature) => signature,
+                Err(_) => bail!("The file is not signed."),
+            }
+        }
+    };
+    verification.signed = true;
+    verification.signer = Some(signer_subject(&signature)?);
+
+    // Only the certificate is needed to verify a signature.
+    let verifier = LocalKeyPair::new(public_key, Path::new(""));
+    verification.valid = match detached {
+        Some(signature) => {
+            let working_tree = tempdir().context("Failed to create temporary directory.")?;
+            let image = attach_signature(path, signature, working_tree.path())?;
+            verifier.verify_path(&image)?
+        }
+        None => verifier.verify_path(path)?,
+    };
+
+    if !verification.valid {
+        bail!("The signature is not valid for {public_key:?}.");
+    }
+    Ok(())
+}
+
+/// Fill in the verification like [`check_signature`] without any external tools.
+fn check_signature_offline(
+    verification: &mut Verification,
+    public_key: &Path,
+    detached: Option<&Path>,
+) -> Result<()> {
+    let path = &verification.path;
+    let file_data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let signature = match detached {
+        Some(signature) => fs::read(signature)
+            .with_context(|| format!("Failed to read the detached signature {signature:?}"))?,
+        None => match pe::read_pkcs7_signature(&file_data) {
+            Ok(signature) => signature,
+            Err(_) => bail!("The file is not signed."),
+        },
+    };
+    verification.signed = true;
+    verification.signer = Some(signer_subject(&signature)?);
+
+    let certificate = fs::read(public_key)
+        .with_context(|| format!("Failed to read the certificate {public_key:?}"))?;
+    let certificate = authenticode::read_certificate(&certificate)?;
+    authenticode::verify(&file_data, Some(&signature), &certificate)
+        .with_context(|| format!("The signature is not valid for {public_key:?}"))?;
+    verification.valid = true;
+    Ok(())
+}
+
+/// Render the subject of the certificate that made a DER-encoded PKCS#7 signature.
+///
+/// The subject is rendered in-process for both `check_signature` and `check_signature_offline`,
+/// so that it does not depend on how the signature was verified.
+fn signer_subject(signature: &[u8]) -> Result<String> {
+    Ok(authenticode::signer(signature)?
+        .tbs_certificate
+        .subject
+        .to_string())
+}
+
+/// Copy the PE binary at `path` to `directory` and replace its signature with `signature`.
+fn attach_signature(path: &Path, signature: &Path, directory: &Path) -> Result<PathBuf> {
+    let image = directory.join("image.efi");
+    fs::copy(path, &image).with_context(|| format!("Failed to copy {path:?} to {image:?}"))?;
+
+    let file_data = fs::read(&image).with_context(|| format!("Failed to read {image:?}"))?;
+    if pe::read_pkcs7_signature(&file_data).is_ok() {
+        sbattach([OsStr::new("--remove"), image.as_os_str()])?;
+    }
+    sbattach([
+        OsStr::new("--attach"),
+        signature.as_os_str(),
+        image.as_os_str(),
+    ])?;
+
+    Ok(image)
+}
+
+fn sbattach<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> Result<()> {
+    let args = args.into_iter().collect::<Vec<_>>();
+    let output = Command::new("sbattach")
+        .args(&args)
+        .output()
+        .context("Failed to run sbattach. Most likely, the binary is not on PATH.")?;
+
+    if !output.status.success() {
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of sbattach to stderr.")?;
+        log::debug!("sbattach failed with args: `{args:?}`.");
+        bail!("Failed to attach the detached signature.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_linux_paths_of_entry() {
+        let entry = "title NixOS\n\
+                     version Generation 1\n\
+                     linux /EFI/nixos/kernel-6.1.1-abc.efi\n\
+                     initrd /EFI/nixos/initrd-6.1.1-def.efi\n\
+                     linuxfoo /EFI/nixos/other.efi\n";
+        assert_eq!(
+            linux_paths(entry).collect::<Vec<_>>(),
+            ["EFI/nixos/kernel-6.1.1-abc.efi"]
+        );
+        assert_eq!(linux_paths("efi /EFI/Linux/nixos.efi\n").count(), 0);
+    }
+
+    #[test]
+    fn report_unsigned_file() -> Result<()> {
+        let tmpdir = tempdir()?;
+        let path = tmpdir.path().join("unsigned.efi");
+        fs::write(&path, b"not a PE binary")?;
+
+        for offline in [false, true] {
+            let verification = verify_file(&path, Path::new("db.pem"), None, offline);
+            assert!(!verification.signed);
+            assert!(!verification.valid);
+            assert_eq!(
+                verification.error.as_deref(),
+                Some("The file is not signed.")
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use lanzaboote_tool::pe;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn embed_additional_section() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let dtb = tmpdir.path().join("board.dtb");
+    fs::write(&dtb, b"device tree")?;
+    let mut section = std::ffi::OsString::from(".dtb=");
+    section.push(&dtb);
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--add-section".as_ref(), section.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    // The stub has a different name because the section is part of its inputs.
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+    let stub = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("nixos-generation-1-"))
+        .context("Missing stub of generation 1")?;
+    let stub = fs::read(stub)?;
+    let embedded = pe::read_section_data(&stub, ".dtb").context("Missing .dtb")?;
+    assert_eq!(embedded, b"device tree");
+
+    Ok(())
+}
+
+#[test]
+fn reject_managed_section() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let cmdline = tmpdir.path().join("cmdline");
+    fs::write(&cmdline, b"init=/bin/sh")?;
+    let mut section = std::ffi::OsString::from(".cmdline=");
+    section.push(&cmdline);
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--add-section".as_ref(), section.as_os_str()],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("is managed by Lanzaboote"));
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common::{self, count_files};
+
+#[test]
+fn write_bls_entries_for_installed_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), [generation_link], ["--bls-entries"])?;
+    assert!(output0.status.success());
+
+    // The stub is named like a stub in EFI/Linux but installed to EFI/nixos.
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    assert!(!image.exists());
+    let entry = esp
+        .path()
+        .join("loader/entries")
+        .join(image.with_extension("conf").file_name().unwrap());
+    let entry_contents = fs::read_to_string(entry)?;
+
+    assert!(entry_contents.contains("title LanzaOS\n"));
+    assert!(entry_contents.contains("sort-key lanzaboote\n"));
+    assert!(entry_contents.contains(&format!(
+        "efi /EFI/nixos/{}\n",
+        image.file_name().unwrap().to_str().unwrap()
+    )));
+    assert!(esp
+        .path()
+        .join("EFI/nixos")
+        .join(image.file_name().unwrap())
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn list_every_generation_only_once() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), generation_links, ["--bls-entries"])?;
+    assert!(output0.status.success());
+
+    // systemd-boot lists the Type #1 entries in loader/entries and the Type #2 entries in
+    // EFI/Linux.
+    let mut menu = Vec::new();
+    for directory in ["loader/entries", "EFI/Linux"] {
+        let Ok(entries) = fs::read_dir(esp.path().join(directory)) else {
+            continue;
+        };
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("nixos-generation-") {
+                menu.push(name);
+            }
+        }
+    }
+    for version in [1, 2, 3] {
+        let prefix = format!("nixos-generation-{version}-");
+        let entries = menu.iter().filter(|name| name.starts_with(&prefix)).count();
+        assert_eq!(
+            entries, 1,
+            "Generation {version} is listed {entries} times: {menu:?}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn garbage_collect_bls_entries() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let entry_count = || count_files(&esp.path().join("loader/entries")).unwrap();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links.clone(),
+        ["--bls-entries"],
+    )?;
+    assert!(output0.status.success());
+    assert_eq!(
+        entry_count(),
+        3,
+        "Wrong number of entries after installation"
+    );
+
+    let unrelated_entry = esp.path().join("loader/entries/ubuntu.conf");
+    fs::File::create(&unrelated_entry)?;
+
+    let output1 =
+        common::lanzaboote_install_with_args(2, esp.path(), generation_links, ["--bls-entries"])?;
+    assert!(output1.status.success());
+    assert_eq!(entry_count(), 3, "Wrong number of entries after gc");
+    assert!(unrelated_entry.exists());
+
+    Ok(())
+}
+
+#[test]
+fn boot_signed_kernel_without_stub() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--boot-mode", "kernel"],
+    )?;
+    assert!(output0.status.success());
+
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+    let entries = esp.path().join("loader/entries");
+    assert_eq!(count_files(&entries)?, 1);
+    let entry = fs::read_dir(&entries)?.next().unwrap()?.path();
+    let entry_contents = fs::read_to_string(entry)?;
+
+    let kernel = entry_contents
+        .lines()
+        .find_map(|line| line.strip_prefix("linux /"))
+        .expect("The entry does not boot a kernel");
+    assert!(kernel.starts_with("EFI/nixos/kernel-6.1.1-"));
+    assert!(entry_contents.contains("\ninitrd /EFI/nixos/initrd-6.1.1-"));
+    assert!(common::verify_signature(&esp.path().join(kernel))?);
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+/// Check that `bootctl list` understands the installed stubs as Boot Loader Specification Type #2
+/// entries.
+#[test]
+fn bootctl_lists_installed_stubs() -> Result<()> {
+    let bootctl = Path::new(&std::env::var("TEST_SYSTEMD")?).join("bin/bootctl");
+    if !bootctl.exists() {
+        println!("Skipping because {bootctl:?} does not exist.");
+        return Ok(());
+    }
+
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+
+    let output1 = Command::new(bootctl)
+        // The staging ESP is a plain directory, not a FAT partition.
+        .env("SYSTEMD_RELAX_ESP_CHECKS", "1")
+        .arg("--esp-path")
+        .arg(esp.path())
+        .arg("--no-variables")
+        .arg("list")
+        .output()?;
+    let stdout = String::from_utf8(output1.stdout)?;
+    println!("{stdout}");
+    println!("{}", String::from_utf8(output1.stderr)?);
+    assert!(output1.status.success());
+
+    assert!(stdout.contains("LanzaOS (Generation 1, 1970-01-01)"));
+    assert!(stdout.contains("init="));
+
+    Ok(())
+}
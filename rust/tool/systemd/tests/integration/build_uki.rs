@@ -0,0 +1,137 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Output;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common::{self, verify_signature};
+
+/// Run `lzbt build-uki` with the test keys and the stub, kernel and initrd of `store_path`.
+///
+/// To simplify the test setup, the systemd stub is used as the stub and the kernel. See the
+/// comment in setup_toplevel for details.
+fn build_uki<I, A>(
+    store_path: &Path,
+    esp: &Path,
+    output: &Path,
+    initrd: bool,
+    extra_args: I,
+) -> Result<Output>
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<OsStr>,
+{
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    cmd.args([
+        "build-uki",
+        "--public-key",
+        "tests/fixtures/uefi-keys/db.pem",
+        "--private-key",
+        "tests/fixtures/uefi-keys/db.key",
+    ])
+    .arg("--stub")
+    .arg(store_path.join("kernel"))
+    .arg("--kernel")
+    .arg(store_path.join("kernel"))
+    .arg("--esp")
+    .arg(esp)
+    .arg("--output")
+    .arg(output)
+    .args(extra_args);
+    if initrd {
+        cmd.arg("--initrd").arg(store_path.join("initrd"));
+    }
+    let output = cmd.output()?;
+    print!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+#[test]
+fn build_uki_from_explicit_files() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    let output = esp.path().join("EFI/Linux/test.efi");
+
+    let output0 = build_uki(
+        &store_path,
+        esp.path(),
+        &output,
+        true,
+        ["--cmdline", "console=ttyS0 debug", "--check-reproducible"],
+    )?;
+    assert!(output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("The image is reproducible."));
+
+    assert!(verify_signature(&output)?);
+    assert!(esp.path().join("EFI/Linux/test.kernel").exists());
+    assert!(esp.path().join("EFI/Linux/test.initrd").exists());
+
+    let image = std::fs::read(&output)?;
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&image, ".cmdline"),
+        Some(&b"console=ttyS0 debug"[..])
+    );
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&image, ".sbat"),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_uki_without_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    let output = esp.path().join("EFI/Linux/test.efi");
+
+    let output0 = build_uki(&store_path, esp.path(), &output, false, [] as [&str; 0])?;
+    assert!(output0.status.success());
+
+    assert!(verify_signature(&output)?);
+    assert!(esp.path().join("EFI/Linux/test.kernel").exists());
+    assert!(!esp.path().join("EFI/Linux/test.initrd").exists());
+
+    let image = std::fs::read(&output)?;
+    assert!(lanzaboote_tool::pe::read_section_data(&image, ".linux").is_some());
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&image, ".initrd"),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_uki_with_sbat_level() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    let output = esp.path().join("EFI/Linux/test.efi");
+
+    let output0 = build_uki(
+        &store_path,
+        esp.path(),
+        &output,
+        true,
+        ["--sbat-level", "3"],
+    )?;
+    assert!(output0.status.success());
+
+    assert!(verify_signature(&output)?);
+    let image = std::fs::read(&output)?;
+    let sbat = lanzaboote_tool::pe::read_section_data(&image, ".sbat").expect("Missing .sbat");
+    let sbat = String::from_utf8_lossy(sbat);
+    assert!(sbat.starts_with("sbat,1,"));
+    assert!(sbat.contains("\nlanzaboote,3,Lanzaboote,lanzaboote,"));
+
+    Ok(())
+}
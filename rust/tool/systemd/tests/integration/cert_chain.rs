@@ -0,0 +1,38 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common::{self, verify_signature_with_cert};
+
+/// Sign with a leaf certificate issued by an intermediate CA and check that the signature
+/// validates against the root CA alone, i.e. that the intermediate is embedded.
+#[test]
+fn embed_intermediate_certificates() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let keys = Path::new("tests/fixtures/uefi-keys-chain");
+
+    let output0 = common::lanzaboote_install_with_keys(
+        0,
+        esp.path(),
+        [generation_link],
+        keys,
+        [
+            "--cert-chain",
+            "tests/fixtures/uefi-keys-chain/intermediate.pem",
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    for entry in fs::read_dir(esp.path().join("EFI/Linux"))? {
+        let stub = entry?.path();
+        assert!(verify_signature_with_cert(&stub, &keys.join("root.pem"))?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use lanzaboote_tool::pe;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn override_cmdline_of_single_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links = (1..=2)
+        .map(|version| {
+            common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), version)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let cmdline_map = tmpdir.path().join("cmdline-map.json");
+    fs::write(
+        &cmdline_map,
+        r#"{ "2": { "append": ["lanzaboote.test=1"] }, "3": { "append": ["stale"] } }"#,
+    )?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links,
+        ["--cmdline-map".as_ref(), cmdline_map.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("generation 3"));
+
+    // Generation 1 is not in the map and keeps the default command line. Its stub name does not
+    // change.
+    let stub1 = fs::read(common::image_path(&esp, 1, &toplevel)?)?;
+    let cmdline1 = pe::read_section_data(&stub1, ".cmdline").context("Missing .cmdline")?;
+    assert!(!String::from_utf8_lossy(cmdline1).contains("lanzaboote.test=1"));
+
+    // The stub of generation 2 has a different name because its command line is overridden.
+    assert!(!common::image_path(&esp, 2, &toplevel)?.exists());
+    let stub2 = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("nixos-generation-2-"))
+        .context("Missing stub of generation 2")?;
+    let stub2 = fs::read(stub2)?;
+    let cmdline2 = pe::read_section_data(&stub2, ".cmdline").context("Missing .cmdline")?;
+    assert!(String::from_utf8_lossy(cmdline2).ends_with(" lanzaboote.test=1"));
+
+    Ok(())
+}
@@ -126,6 +126,35 @@ pub fn setup_toplevel(tmpdir: &Path) -> Result<PathBuf> {
     Ok(toplevel)
 }
 
+/// Setup generations 1 to `count`, each with its own toplevel whose kernel is padded with
+/// `kernel_padding` bytes, e.g. so that only some of them fit on the ESP.
+///
+/// Returns the toplevels and the generation links.
+pub fn setup_padded_generations(
+    tmpdir: &Path,
+    profiles_directory: &Path,
+    count: u64,
+    kernel_padding: usize,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut toplevels = Vec::new();
+    let mut generation_links = Vec::new();
+    for version in 1..=count {
+        let toplevel = setup_toplevel(tmpdir)?;
+        let kernel = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&kernel)?
+            .write_all(&vec![0; kernel_padding])?;
+        generation_links.push(setup_generation_link_from_toplevel(
+            &toplevel,
+            profiles_directory,
+            version,
+        )?);
+        toplevels.push(toplevel);
+    }
+    Ok((toplevels, generation_links))
+}
+
 fn random_string(length: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
@@ -139,6 +168,39 @@ pub fn lanzaboote_install(
     config_limit: u64,
     esp_mountpoint: &Path,
     generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_args(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        Vec::<&OsStr>::new(),
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments.
+pub fn lanzaboote_install_with_args(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_keys(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        Path::new("tests/fixtures/uefi-keys"),
+        extra_args,
+    )
+}
+
+/// Call the `lanzaboote install` command with the `db.pem` and `db.key` from a different
+/// directory and additional arguments.
+pub fn lanzaboote_install_with_keys(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    keys: &Path,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
 ) -> Result<Output> {
     // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
     // the comment in setup_toplevel for details.
@@ -166,11 +228,12 @@ pub fn lanzaboote_install(
         .arg("--systemd-boot-loader-config")
         .arg(test_loader_config_path.path())
         .arg("--public-key")
-        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(keys.join("db.pem"))
         .arg("--private-key")
-        .arg("tests/fixtures/uefi-keys/db.key")
+        .arg(keys.join("db.key"))
         .arg("--configuration-limit")
         .arg(config_limit.to_string())
+        .args(extra_args)
         .arg(esp_mountpoint)
         .args(generation_links)
         .output()?;
@@ -221,10 +284,15 @@ pub fn remove_signature(path: &Path) -> Result<()> {
 
 /// Verify signature of PE file.
 pub fn verify_signature(path: &Path) -> Result<bool> {
+    verify_signature_with_cert(path, Path::new("tests/fixtures/uefi-keys/db.pem"))
+}
+
+/// Verify signature of PE file against a specific certificate.
+pub fn verify_signature_with_cert(path: &Path, cert: &Path) -> Result<bool> {
     let output = Command::new("sbverify")
         .arg(path.as_os_str())
         .arg("--cert")
-        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(cert)
         .output()
         .context("Failed to run sbverify. Most likely, the binary is not on PATH.")?;
     print!("{}", String::from_utf8(output.stdout)?);
@@ -0,0 +1,52 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn flags_override_config() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let config = tmpdir.path().join("config.json");
+    fs::write(
+        &config,
+        r#"{ "publicKey": "/nonexistent/db.pem", "privateKey": "/nonexistent/db.key" }"#,
+    )?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--config".as_ref(), config.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_config_settings() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let config = tmpdir.path().join("config.json");
+    fs::write(&config, r#"{ "configurationLimt": 10 }"#)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--config".as_ref(), config.as_os_str()],
+    )?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("Failed to parse the config"));
+
+    Ok(())
+}
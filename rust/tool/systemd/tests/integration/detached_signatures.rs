@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use lanzaboote_tool::architecture::Architecture;
+use lzbt_systemd::architecture::SystemdArchitectureExt;
+use tempfile::tempdir;
+
+use crate::common::{self, SYSTEM};
+
+#[test]
+fn write_detached_signatures() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let signatures = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [
+            Path::new("--detached-signatures").as_os_str(),
+            signatures.path().as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let mut signature_name = image.file_name().unwrap().to_os_string();
+    signature_name.push(".p7s");
+    let signature = fs::read(signatures.path().join(signature_name))?;
+
+    // The detached signature is a DER SEQUENCE that is embedded verbatim in the signed image.
+    assert_eq!(signature[0], 0x30);
+    let image_data = fs::read(&image)?;
+    assert!(image_data
+        .windows(signature.len())
+        .any(|window| window == signature));
+
+    // systemd-boot is signed as well.
+    let arch = Architecture::from_nixos_system(SYSTEM)?;
+    let mut systemd_boot_signature_name = arch.systemd_filename().into_os_string();
+    systemd_boot_signature_name.push(".p7s");
+    assert!(signatures.path().join(systemd_boot_signature_name).exists());
+
+    Ok(())
+}
+
+#[test]
+fn verify_detached_signature() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let signatures = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [
+            Path::new("--detached-signatures").as_os_str(),
+            signatures.path().as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let mut signature_name = image.file_name().unwrap().to_os_string();
+    signature_name.push(".p7s");
+    let signature = signatures.path().join(signature_name);
+
+    let verify = |image: &Path| -> Result<std::process::Output> {
+        Ok(Command::cargo_bin("lzbt-systemd")?
+            .args(["verify", "--public-key", "tests/fixtures/uefi-keys/db.pem"])
+            .arg("--detached")
+            .arg(&signature)
+            .arg(image)
+            .output()?)
+    };
+
+    // The detached signature is also valid for the image without the embedded signature.
+    let unsigned_image = tmpdir.path().join("unsigned.efi");
+    fs::copy(&image, &unsigned_image)?;
+    common::remove_signature(&unsigned_image)?;
+    assert!(verify(&image)?.status.success());
+    assert!(verify(&unsigned_image)?.status.success());
+
+    // It does not cover a different image.
+    let kernel = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+    assert!(!verify(&kernel)?.status.success());
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use serde_json::json;
+use tempfile::tempdir;
+
+fn setup_generation(tmpdir: &Path, profiles: &Path, version: u64, kernel: &str) -> Result<()> {
+    let toplevel = tmpdir.join(format!("toplevel-{version}"));
+    fs::create_dir(&toplevel)?;
+    let initrd = tmpdir.join(format!("initrd-{version}"));
+    fs::write(&initrd, vec![0; version as usize])?;
+    let bootspec = json!({
+        "org.nixos.bootspec.v1": {
+          "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+          "initrd": initrd,
+          "kernel": format!("/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-{kernel}/bzImage"),
+          "kernelParams": ["quiet"],
+          "label": "LanzaOS",
+          "toplevel": toplevel,
+          "system": "x86_64-linux",
+        },
+        "org.nix-community.lanzaboote": {
+            "sort_key": "lanza",
+        }
+    });
+    fs::write(toplevel.join("boot.json"), serde_json::to_vec(&bootspec)?)?;
+    symlink(&toplevel, profiles.join(format!("system-{version}-link")))?;
+    Ok(())
+}
+
+#[test]
+fn diff_generations() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    setup_generation(tmpdir.path(), profiles.path(), 1, "6.1.1")?;
+    setup_generation(tmpdir.path(), profiles.path(), 2, "6.1.2")?;
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["diff", "1", "2", "--profile-dir"])
+        .arg(profiles.path())
+        .output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("kernel version: 6.1.1 -> 6.1.2\n"));
+    assert!(stdout.contains("initrd size: 1 bytes -> 2 bytes\n"));
+    // Unchanged artifacts are not printed.
+    assert!(!stdout.contains("cmdline"));
+    assert!(!stdout.contains("sort key"));
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["diff", "2", "2", "--profile-dir"])
+        .arg(profiles.path())
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "Generations 2 and 2 do not differ.\n"
+    );
+
+    Ok(())
+}
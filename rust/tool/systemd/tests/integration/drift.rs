@@ -0,0 +1,66 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+fn check_drift(esp: &Path, system: &Path) -> Result<std::process::Output> {
+    Ok(Command::cargo_bin("lzbt-systemd")?
+        .arg("check-drift")
+        .arg(esp)
+        .arg("--system")
+        .arg(system)
+        .output()?)
+}
+
+#[test]
+fn detect_stale_stub() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let uninstalled_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output0.status.success());
+
+    let output1 = check_drift(esp.path(), &generation_link)?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("up to date"));
+
+    let output2 = check_drift(esp.path(), &uninstalled_link)?;
+    assert!(!output2.status.success());
+    assert!(String::from_utf8(output2.stderr)?.contains("No stub on the ESP boots"));
+
+    // The kernel of the system changes without reinstalling.
+    let kernel = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+    OpenOptions::new()
+        .append(true)
+        .open(&kernel)?
+        .write_all(b"x")?;
+    let output3 = check_drift(esp.path(), &generation_link)?;
+    assert!(!output3.status.success());
+    let stdout = String::from_utf8(output3.stdout)?;
+    assert!(stdout.contains("the kernel differs from the one of the system"));
+    assert!(!stdout.contains("initrd"));
+
+    // The initrd on the ESP is removed.
+    let image = fs::read(common::image_path(&esp, 1, &toplevel)?)?;
+    let initrd = lanzaboote_tool::pe::read_section_data(&image, ".initrd").unwrap();
+    let initrd = esp
+        .path()
+        .join(std::str::from_utf8(&initrd[1..])?.replace('\\', "/"));
+    fs::remove_file(&initrd)?;
+    let output4 = check_drift(esp.path(), &generation_link)?;
+    assert!(!output4.status.success());
+    assert!(String::from_utf8(output4.stdout)?.contains("is missing"));
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use serde_json::{json, Value};
+use tempfile::tempdir;
+
+#[test]
+fn dump_parsed_bootspec() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = tmpdir.path().join("toplevel");
+    fs::create_dir(&toplevel)?;
+    // The Lanzaboote extension lacks the optional fields.
+    let bootspec = json!({
+        "org.nixos.bootspec.v1": {
+          "init": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system/init",
+          "kernel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-6.1.1/bzImage",
+          "kernelParams": ["quiet"],
+          "label": "LanzaOS",
+          "toplevel": "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system",
+          "system": "x86_64-linux",
+        },
+        "org.nix-community.lanzaboote": {
+            "sort_key": "custom",
+        }
+    });
+    fs::write(toplevel.join("boot.json"), serde_json::to_vec(&bootspec)?)?;
+    symlink(&toplevel, profiles.path().join("system-3-link"))?;
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["dump-bootspec", "--generation", "3", "--profile-dir"])
+        .arg(profiles.path())
+        .output()?;
+    assert!(output.status.success());
+
+    let dump: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(
+        dump["bootspec"]["org.nixos.bootspec.v1"]["kernelParams"],
+        json!(["quiet"])
+    );
+    assert_eq!(
+        dump["lanzaboote_extension"],
+        json!({ "sort_key": "custom", "title": null, "machine_id": null })
+    );
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["dump-bootspec", "--generation", "4", "--profile-dir"])
+        .arg(profiles.path())
+        .output()?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common::{self, verify_signature_with_cert};
+
+#[test]
+fn sign_with_ecdsa_key() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let keys = Path::new("tests/fixtures/uefi-keys-ecdsa");
+
+    let output0 = common::lanzaboote_install_with_keys(
+        0,
+        esp.path(),
+        [generation_link],
+        keys,
+        Vec::<&str>::new(),
+    )?;
+    assert!(output0.status.success());
+
+    for entry in fs::read_dir(esp.path().join("EFI/Linux"))? {
+        let stub = entry?.path();
+        assert!(verify_signature_with_cert(&stub, &keys.join("db.pem"))?);
+
+        // The in-process verifier only supports RSA and says so.
+        let output1 = Command::cargo_bin("lzbt-systemd")?
+            .args(["verify", "--offline", "--public-key"])
+            .arg(keys.join("db.pem"))
+            .arg(&stub)
+            .output()?;
+        assert!(!output1.status.success());
+        let stderr = String::from_utf8(output1.stderr)?;
+        assert!(stderr.contains("cannot be verified offline"), "{stderr}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reject_private_key_of_other_certificate() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let keys = tmpdir.path().join("keys");
+    fs::create_dir(&keys)?;
+    fs::copy("tests/fixtures/uefi-keys/db.pem", keys.join("db.pem"))?;
+    fs::copy("tests/fixtures/uefi-keys-ecdsa/db.key", keys.join("db.key"))?;
+
+    let output0 = common::lanzaboote_install_with_keys(
+        0,
+        esp.path(),
+        [generation_link],
+        &keys,
+        Vec::<&str>::new(),
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(
+        stderr.contains("The private key does not belong to the certificate"),
+        "{stderr}"
+    );
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command as StdCommand;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+/// `EFI_CERT_X509_GUID` in its binary (mixed-endian) representation.
+const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+
+/// Write a db EFI variable with the certificate into `efivars`.
+fn write_db(efivars: &Path, certificate: &Path) -> Result<()> {
+    let der = StdCommand::new("openssl")
+        .args(["x509", "-outform", "DER", "-in"])
+        .arg(certificate)
+        .output()?
+        .stdout;
+
+    // The attributes of the variable, followed by a single EFI_SIGNATURE_LIST.
+    let mut db = 0x27u32.to_le_bytes().to_vec();
+    db.extend(EFI_CERT_X509_GUID);
+    db.extend((28 + 16 + der.len() as u32).to_le_bytes());
+    db.extend(0u32.to_le_bytes());
+    db.extend((16 + der.len() as u32).to_le_bytes());
+    db.extend([0; 16]);
+    db.extend(der);
+
+    fs::write(efivars.join("db-d719b2cb-3d3a-4596-a3bc-dad00e67656f"), db)?;
+    Ok(())
+}
+
+#[test]
+fn require_enrolled_certificate() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivars = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let install = || {
+        common::lanzaboote_install_with_args(
+            0,
+            esp.path(),
+            [&generation_link],
+            [
+                Path::new("--require-enrolled").as_os_str(),
+                Path::new("--efivars").as_os_str(),
+                efivars.path().as_os_str(),
+            ],
+        )
+    };
+
+    // Another certificate is enrolled.
+    let other_keys = tmpdir.path().join("other-keys");
+    let output0 = Command::cargo_bin("lzbt-systemd")?
+        .args(["generate-keys", "--out-dir"])
+        .arg(&other_keys)
+        .output()?;
+    assert!(output0.status.success());
+    write_db(efivars.path(), &other_keys.join("db.pem"))?;
+
+    let output1 = install()?;
+    assert!(!output1.status.success());
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(stderr.contains("is enrolled in the db"), "{stderr}");
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+
+    write_db(efivars.path(), Path::new("tests/fixtures/uefi-keys/db.pem"))?;
+    let output2 = install()?;
+    assert!(output2.status.success());
+    assert!(common::image_path(&esp, 1, &toplevel)?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn report_enrolled_certificates() -> Result<()> {
+    let efivars = tempdir()?;
+    let enrolled = || -> Result<std::process::Output> {
+        Ok(Command::cargo_bin("lzbt-systemd")?
+            .args([
+                "enrolled",
+                "--public-key",
+                "tests/fixtures/uefi-keys/db.pem",
+            ])
+            .arg("--efivars")
+            .arg(efivars.path())
+            .output()?)
+    };
+
+    write_db(
+        efivars.path(),
+        Path::new("tests/fixtures/uefi-keys-ecdsa/db.pem"),
+    )?;
+    let output0 = enrolled()?;
+    assert!(!output0.status.success());
+    let stdout = String::from_utf8(output0.stdout)?;
+    assert!(
+        stdout.starts_with("PROBLEM: Your signing certificate"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("is NOT enrolled"), "{stdout}");
+    assert!(stdout.contains("CN=Database Key ECDSA"), "{stdout}");
+
+    write_db(efivars.path(), Path::new("tests/fixtures/uefi-keys/db.pem"))?;
+    let output1 = enrolled()?;
+    assert!(output1.status.success());
+    let stdout = String::from_utf8(output1.stdout)?;
+    assert!(!stdout.contains("PROBLEM"), "{stdout}");
+
+    Ok(())
+}
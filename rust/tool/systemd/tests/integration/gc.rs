@@ -122,3 +122,191 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn keep_pinned_gc_roots() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), [&generation_link])?;
+    assert!(output0.status.success());
+
+    let pinned_tool = esp_mountpoint.path().join("EFI/nixos/custom/tool.efi");
+    let pinned_stub = esp_mountpoint.path().join("EFI/Linux/nixos-manual.efi");
+    let garbage = esp_mountpoint.path().join("EFI/nixos/custom/garbage.efi");
+    fs::create_dir(esp_mountpoint.path().join("EFI/nixos/custom"))?;
+    for file in [&pinned_tool, &pinned_stub, &garbage] {
+        fs::File::create(file)?;
+    }
+    let gc_roots_file = tmpdir.path().join("gc-roots");
+    fs::write(
+        &gc_roots_file,
+        format!("# Installed manually\n\n{}\n", pinned_stub.display()),
+    )?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        [&generation_link],
+        [
+            "--gc-root".as_ref(),
+            "EFI/nixos/custom/tool.efi".as_ref(),
+            "--gc-roots-file".as_ref(),
+            gc_roots_file.as_os_str(),
+            // Otherwise, files Lanzaboote does not create are never collected.
+            "--take-over".as_ref(),
+        ],
+    )?;
+    assert!(output1.status.success());
+
+    assert!(pinned_tool.exists());
+    assert!(pinned_stub.exists());
+    // Only the exact file is pinned, not the rest of its directory.
+    assert!(!garbage.exists());
+
+    Ok(())
+}
+
+#[test]
+fn reject_gc_root_outside_of_esp() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        [generation_link],
+        ["--gc-root", "EFI/../../outside.efi"],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("is not on the ESP"), "{stderr}");
+
+    Ok(())
+}
+
+#[test]
+fn keep_recently_built_generations_beyond_limit() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    // Generation 2 was built just now, the others at the epoch.
+    filetime::set_file_mtime(&generation_links[1], filetime::FileTime::now())?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--keep-since", "30d"],
+    )?;
+    assert!(output0.status.success());
+    assert_eq!(count_files(&esp_mountpoint.path().join("EFI/Linux"))?, 2);
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Keeping generation 2 beyond the configuration limit"));
+
+    Ok(())
+}
+
+#[test]
+fn keep_recently_modified_files() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // Only the stub of generation 2 was modified recently.
+    let linux = esp_mountpoint.path().join("EFI/Linux");
+    for stub in fs::read_dir(&linux)? {
+        let stub = stub?.path();
+        if !stub.to_string_lossy().contains("nixos-generation-2-") {
+            filetime::set_file_mtime(&stub, filetime::FileTime::zero())?;
+        }
+    }
+
+    let output1 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--keep-modified-since", "1d"],
+    )?;
+    assert!(output1.status.success());
+    let stubs = fs::read_dir(&linux)?
+        .map(|stub| Ok(stub?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(stubs.len(), 2, "{stubs:?}");
+    assert!(stubs
+        .iter()
+        .any(|stub| stub.contains("nixos-generation-2-")));
+    assert!(stubs
+        .iter()
+        .any(|stub| stub.contains("nixos-generation-3-")));
+
+    Ok(())
+}
+
+#[test]
+fn keep_unknown_files_in_nixos_directory() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), [&generation_link])?;
+    assert!(output0.status.success());
+
+    let nixos = esp_mountpoint.path().join("EFI/nixos");
+    let garbage_kernel = nixos.join("kernel-garbage.efi");
+    let unknown_file = nixos.join("memtest.efi");
+    let unknown_directory = nixos.join("custom");
+    fs::create_dir(&unknown_directory)?;
+    for file in [
+        &garbage_kernel,
+        &unknown_file,
+        &unknown_directory.join("kernel-custom.efi"),
+    ] {
+        fs::File::create(file)?;
+    }
+
+    let output1 = common::lanzaboote_install(0, esp_mountpoint.path(), [&generation_link])?;
+    assert!(output1.status.success());
+    assert!(!garbage_kernel.exists());
+    assert!(unknown_file.exists());
+    assert!(unknown_directory.join("kernel-custom.efi").exists());
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(stderr.contains("memtest.efi\" because Lanzaboote does not create it"));
+
+    let output2 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        [&generation_link],
+        ["--take-over"],
+    )?;
+    assert!(output2.status.success());
+    assert!(!unknown_file.exists());
+    assert!(!unknown_directory.exists());
+
+    Ok(())
+}
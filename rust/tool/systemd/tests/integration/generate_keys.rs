@@ -0,0 +1,81 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+#[test]
+fn generate_keys_without_overwriting() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let out_dir = tmpdir.path().join("keys");
+
+    let output0 = Command::cargo_bin("lzbt-systemd")?
+        .args(["generate-keys", "--validity-days", "30", "--out-dir"])
+        .arg(&out_dir)
+        .output()?;
+    assert!(output0.status.success());
+
+    let private_key = out_dir.join("db.key");
+    let certificate = out_dir.join("db.pem");
+    assert_eq!(
+        fs::metadata(&private_key)?.permissions().mode() & 0o777,
+        0o600
+    );
+    assert!(fs::read_to_string(&certificate)?.starts_with("-----BEGIN CERTIFICATE-----"));
+
+    let certificate0 = fs::read(&certificate)?;
+
+    let output1 = Command::cargo_bin("lzbt-systemd")?
+        .args(["generate-keys", "--out-dir"])
+        .arg(&out_dir)
+        .output()?;
+    assert!(!output1.status.success());
+    assert_eq!(fs::read(&certificate)?, certificate0);
+
+    let output2 = Command::cargo_bin("lzbt-systemd")?
+        .args(["generate-keys", "--force", "--out-dir"])
+        .arg(&out_dir)
+        .output()?;
+    assert!(output2.status.success());
+    assert_ne!(fs::read(&certificate)?, certificate0);
+
+    Ok(())
+}
+
+#[test]
+fn keep_keys_if_generating_fails() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let out_dir = tmpdir.path().join("keys");
+
+    // openssl rejects common names longer than 64 characters.
+    let output0 = Command::cargo_bin("lzbt-systemd")?
+        .args([
+            "generate-keys",
+            "--common-name",
+            &"a".repeat(65),
+            "--out-dir",
+        ])
+        .arg(&out_dir)
+        .output()?;
+    assert!(!output0.status.success());
+    assert_eq!(fs::read_dir(&out_dir)?.count(), 0);
+
+    let output1 = Command::cargo_bin("lzbt-systemd")?
+        .args(["generate-keys", "--out-dir"])
+        .arg(&out_dir)
+        .output()?;
+    assert!(output1.status.success());
+    let private_key0 = fs::read(out_dir.join("db.key"))?;
+
+    let output2 = Command::cargo_bin("lzbt-systemd")?
+        .args(["generate-keys", "--force", "--common-name", &"a".repeat(65)])
+        .arg("--out-dir")
+        .arg(&out_dir)
+        .output()?;
+    assert!(!output2.status.success());
+    assert_eq!(fs::read(out_dir.join("db.key"))?, private_key0);
+    assert_eq!(fs::read_dir(&out_dir)?.count(), 2);
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn list_and_extract_stub_sections() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+    let stub = common::image_path(&esp, 1, &toplevel)?;
+
+    let list = Command::cargo_bin("lzbt-systemd")?
+        .arg("inspect")
+        .arg(&stub)
+        .output()?;
+    assert!(list.status.success());
+    let list = String::from_utf8(list.stdout)?;
+    for section in [
+        ".osrel", ".cmdline", ".initrd", ".linux", ".initrdh", ".linuxh",
+    ] {
+        assert!(list.contains(section), "Missing {section} in: {list}");
+    }
+    assert!(list.contains("signed: yes"));
+
+    let osrel = Command::cargo_bin("lzbt-systemd")?
+        .args(["inspect", "--extract", ".osrel"])
+        .arg(&stub)
+        .output()?;
+    assert!(osrel.status.success());
+    assert!(String::from_utf8(osrel.stdout)?.starts_with("ID=lanzaboote\n"));
+
+    Ok(())
+}
+
+#[test]
+fn inspect_stub_as_json() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+    let stub = common::image_path(&esp, 1, &toplevel)?;
+
+    let inspection = Command::cargo_bin("lzbt-systemd")?
+        .args(["inspect", "--format", "json", "--public-key"])
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(&stub)
+        .output()?;
+    assert!(inspection.status.success());
+    let inspection: serde_json::Value = serde_json::from_slice(&inspection.stdout)?;
+
+    assert_eq!(inspection["signed"], true);
+    assert_eq!(inspection["valid"], true);
+    let osrel = inspection["sections"]
+        .as_array()
+        .and_then(|sections| sections.iter().find(|s| s["name"] == ".osrel"))
+        .expect("Missing .osrel section");
+    let osrel_data = lanzaboote_tool::pe::read_section_data(&std::fs::read(&stub)?, ".osrel")
+        .expect("Missing .osrel section")
+        .to_owned();
+    assert_eq!(osrel["sha256"], format!("{:x}", Sha256::digest(osrel_data)));
+
+    Ok(())
+}
+
+#[test]
+fn inspect_embedded_metadata() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--embed-metadata"],
+    )?;
+    assert!(output0.status.success());
+    // The metadata is part of the stub name, so the stub is looked up instead.
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(stubs.len(), 1);
+
+    let inspection = Command::cargo_bin("lzbt-systemd")?
+        .args(["inspect", "--format", "json"])
+        .arg(&stubs[0])
+        .output()?;
+    assert!(inspection.status.success());
+    let inspection: serde_json::Value = serde_json::from_slice(&inspection.stdout)?;
+
+    let metadata = &inspection["metadata"];
+    assert_eq!(metadata["generation"], 1);
+    assert_eq!(
+        metadata["profile"],
+        profiles.path().join("system").to_str().unwrap()
+    );
+    assert!(metadata["specialisation"].is_null());
+    assert_eq!(metadata["toplevel"], toplevel.to_str().unwrap());
+
+    Ok(())
+}
@@ -1,3 +1,8 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
 use anyhow::Result;
 use base32ct::{Base32Unpadded, Encoding};
 use tempfile::tempdir;
@@ -121,3 +126,738 @@ fn content_addressing_works() -> Result<()> {
 
     Ok(())
 }
+
+/// Installing with `--parallel-copy` should produce the same ESP as a serial install.
+#[test]
+fn parallel_copy_installs_all_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_links = (1..=3)
+        .map(|version| setup_generation_link_from_toplevel(&toplevel, profiles.path(), version))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), generation_links, ["--parallel-copy"])?;
+    assert!(output0.status.success());
+
+    for version in 1..=3 {
+        assert!(verify_signature(&common::image_path(
+            &esp, version, &toplevel
+        )?)?);
+    }
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 3);
+    assert_eq!(count_files(&esp.path().join("EFI/nixos"))?, 2);
+
+    Ok(())
+}
+
+#[test]
+fn apply_esp_permissions() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--esp-file-mode", "644", "--esp-dir-mode", "700"],
+    )?;
+    assert!(output0.status.success());
+
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(&esp.path().join("EFI/nixos")), 0o700);
+    assert_eq!(mode(&common::image_path(&esp, 1, &toplevel)?), 0o644);
+
+    Ok(())
+}
+
+#[test]
+fn install_in_requested_order() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_links = (1..=3)
+        .map(|version| setup_generation_link_from_toplevel(&toplevel, profiles.path(), version))
+        .collect::<Result<Vec<_>>>()?;
+    let profile_order = [
+        &generation_links[1],
+        &generation_links[0],
+        &generation_links[2],
+    ];
+
+    let signing_order = |output: std::process::Output| -> Result<Vec<u64>> {
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr)?;
+        let mut versions = vec![1, 2, 3];
+        versions
+            .sort_by_key(|version| stderr.find(&format!("Signing \"nixos-generation-{version}-")));
+        Ok(versions)
+    };
+
+    let esp = tempdir()?;
+    let output = common::lanzaboote_install(0, esp.path(), &generation_links)?;
+    assert_eq!(signing_order(output)?, vec![3, 2, 1]);
+
+    let esp = tempdir()?;
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        &generation_links,
+        ["--install-order", "oldest"],
+    )?;
+    assert_eq!(signing_order(output)?, vec![1, 2, 3]);
+
+    let esp = tempdir()?;
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        profile_order,
+        ["--install-order", "profile"],
+    )?;
+    assert_eq!(signing_order(output)?, vec![2, 1, 3]);
+
+    Ok(())
+}
+
+#[test]
+fn drop_oldest_generations_to_fit_esp_budget() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    // Make every generation use more than 4 MiB on the ESP, so that only two of them fit into
+    // 10 MiB.
+    let (toplevels, generation_links) =
+        common::setup_padded_generations(tmpdir.path(), profiles.path(), 3, 4 * 1024 * 1024)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        &generation_links,
+        ["--esp-budget", "10"],
+    )?;
+    assert!(output0.status.success());
+
+    assert!(!common::image_path(&esp, 1, &toplevels[0])?.exists());
+    assert!(common::image_path(&esp, 2, &toplevels[1])?.exists());
+    assert!(common::image_path(&esp, 3, &toplevels[2])?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn report_missing_kernel() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    // Simulate that the kernel was garbage collected from the Nix store.
+    let kernel = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+    fs::remove_file(&kernel)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Failed to install generation 1"));
+    assert!(stderr.contains(&format!("The kernel {kernel:?} does not exist")));
+
+    Ok(())
+}
+
+#[test]
+fn report_all_missing_inputs_before_signing() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    let kernel = store_path.join("kernel");
+    let initrd = store_path.join("initrd");
+    let initrd_target = tmpdir.path().join("garbage-collected-initrd");
+    fs::remove_file(&kernel)?;
+    fs::remove_file(&initrd)?;
+    std::os::unix::fs::symlink(&initrd_target, &initrd)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link1, generation_link2])?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Failed to install generation 1"));
+    assert!(stderr.contains("Failed to install generation 2"));
+    assert!(stderr.contains(&format!("The kernel {kernel:?} does not exist.")));
+    assert!(stderr.contains(&format!(
+        "The initrd {initrd:?} is a symlink to {initrd_target:?}, which does not exist."
+    )));
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn install_initrd_behind_symlink() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let initrd = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd");
+    let initrd_target = tmpdir.path().join("initrd");
+    fs::rename(&initrd, &initrd_target)?;
+    std::os::unix::fs::symlink(&initrd_target, &initrd)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+    assert!(common::image_path(&esp, 1, &toplevel)?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn install_generation_without_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]
+        .as_object_mut()
+        .expect("The bootspec is an object")
+        .remove("initrd");
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+    assert!(common::image_path(&esp, 1, &toplevel)?.exists());
+    assert_eq!(
+        count_files(&esp.path().join("EFI/nixos"))?,
+        1,
+        "Only the kernel should be installed"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn keep_booted_generation_to_fit_esp_budget() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    // Make every generation use more than 4 MiB on the ESP, so that only two of them fit into
+    // 10 MiB.
+    let (toplevels, generation_links) =
+        common::setup_padded_generations(tmpdir.path(), profiles.path(), 3, 4 * 1024 * 1024)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        &generation_links,
+        [
+            Path::new("--esp-budget").as_os_str(),
+            Path::new("10").as_os_str(),
+            Path::new("--booted-system").as_os_str(),
+            generation_links[0].as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    assert!(common::image_path(&esp, 1, &toplevels[0])?.exists());
+    assert!(!common::image_path(&esp, 2, &toplevels[1])?.exists());
+    assert!(common::image_path(&esp, 3, &toplevels[2])?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn fit_esp_with_enough_space() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_links = (1..=2)
+        .map(|version| setup_generation_link_from_toplevel(&toplevel, profiles.path(), version))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), &generation_links, ["--fit-esp"])?;
+    assert!(output0.status.success());
+
+    for version in 1..=2 {
+        assert!(common::image_path(&esp, version, &toplevel)?.exists());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn refuse_to_install_below_esp_reserve() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    // No ESP has this much free space.
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--esp-reserve", "1000000000"],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("less than the reserved 1000000000 MiB"));
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn cache_hashes_of_kernels_and_initrds() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let hash_cache = tmpdir.path().join("cache/hashes.json");
+
+    let args = ["--hash-cache".as_ref(), hash_cache.as_os_str()];
+    let output0 = common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], args)?;
+    assert!(output0.status.success());
+
+    let cache: serde_json::Value = serde_json::from_slice(&fs::read(&hash_cache)?)?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    for input in ["kernel", "initrd"] {
+        let input = store_path.join(input);
+        assert!(cache.get(input.to_str().unwrap()).is_some(), "{input:?}");
+    }
+
+    // An invalid cache does not stop the installation.
+    fs::write(&hash_cache, "not json")?;
+    let output1 = common::lanzaboote_install_with_args(0, esp.path(), [generation_link], args)?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn report_whether_anything_changed() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let changed = |output: &std::process::Output| -> Result<serde_json::Value> {
+        assert!(output.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        Ok(report["changed"].clone())
+    };
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], ["--json"])?;
+    assert_eq!(changed(&output0)?, true);
+
+    let output1 =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], ["--json"])?;
+    assert_eq!(changed(&output1)?, false);
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(stderr.contains("Nothing changed"), "{stderr}");
+
+    Ok(())
+}
+
+#[test]
+fn record_signatures_in_audit_log() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let audit_log = tmpdir.path().join("audit.log");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--audit-log".as_ref(), audit_log.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    let records = fs::read_to_string(&audit_log)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+    assert!(records.iter().all(|record| record["result"] == "success"));
+    let stub_name = common::image_path(&esp, 1, &toplevel)?
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let stub_record = records
+        .iter()
+        .find(|record| {
+            record["output"]
+                .as_str()
+                .is_some_and(|output| output.ends_with(&stub_name))
+        })
+        .expect("The stub was signed without an audit record");
+    assert_eq!(stub_record["key_source"], "file");
+    assert!(stub_record["output_sha256"].is_string());
+
+    Ok(())
+}
+
+#[test]
+fn collapse_identical_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let other_tmpdir = tempdir()?;
+    let other_toplevel = common::setup_toplevel(other_tmpdir.path())?;
+
+    let generation_links = [
+        setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?,
+        setup_generation_link_from_toplevel(&other_toplevel, profiles.path(), 2)?,
+        setup_generation_link_from_toplevel(&other_toplevel, profiles.path(), 3)?,
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        2,
+        esp.path(),
+        &generation_links,
+        ["--collapse-identical"],
+    )?;
+    assert!(output0.status.success());
+
+    // Generation 2 is skipped because generation 3 is identical. The configuration limit thus
+    // still includes generation 1.
+    assert!(common::image_path(&esp, 1, &toplevel)?.exists());
+    assert!(!common::image_path(&esp, 2, &other_toplevel)?.exists());
+    assert!(common::image_path(&esp, 3, &other_toplevel)?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn handle_booted_generation_beyond_limit() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links = (1..=3)
+        .map(|version| setup_generation_link_from_toplevel(&toplevel, profiles.path(), version))
+        .collect::<Result<Vec<_>>>()?;
+
+    let install = |esp: &Path, policy: &str| {
+        common::lanzaboote_install_with_args(
+            2,
+            esp,
+            &generation_links,
+            [
+                Path::new("--running-generation-policy").as_os_str(),
+                Path::new(policy).as_os_str(),
+                Path::new("--booted-system").as_os_str(),
+                generation_links[0].as_os_str(),
+            ],
+        )
+    };
+
+    let esp = tempdir()?;
+    assert!(install(esp.path(), "keep")?.status.success());
+    assert!(common::image_path(&esp, 1, &toplevel)?.exists());
+    assert!(common::image_path(&esp, 2, &toplevel)?.exists());
+    assert!(common::image_path(&esp, 3, &toplevel)?.exists());
+
+    let esp = tempdir()?;
+    assert!(install(esp.path(), "collect")?.status.success());
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+    assert!(common::image_path(&esp, 3, &toplevel)?.exists());
+
+    let esp = tempdir()?;
+    let output = install(esp.path(), "error")?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("The booted generation 1 is beyond"));
+    assert!(!common::image_path(&esp, 3, &toplevel)?.exists());
+
+    Ok(())
+}
+
+#[test]
+fn compare_with_installed_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [&generation_link1])?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1],
+        ["--compare-with-installed"],
+    )?;
+    assert!(output1.status.success());
+    assert!(output1.stdout.is_empty());
+
+    let output2 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link2],
+        ["--compare-with-installed"],
+    )?;
+    assert_eq!(output2.status.code(), Some(4));
+    let stdout = String::from_utf8(output2.stdout)?;
+    assert!(stdout.contains("install generation 2\n"));
+    // The stub of generation 1 would be collected.
+    let stub1 = common::image_path(&esp, 1, &toplevel)?;
+    assert!(stdout.contains(&format!("remove {stub1:?}\n")));
+
+    // Nothing was written.
+    assert!(stub1.exists());
+    assert!(!common::image_path(&esp, 2, &toplevel)?.exists());
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 1);
+
+    Ok(())
+}
+
+/// Add a specialisation with the given name to a generation that boots the same as the
+/// generation itself.
+fn add_specialisation(generation_link: &Path, name: &str) -> Result<()> {
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    let specialisation = serde_json::json!({
+        "org.nixos.bootspec.v1": bootspec["org.nixos.bootspec.v1"].clone(),
+    });
+    bootspec["org.nixos.specialisation.v1"] = serde_json::json!({ name: specialisation });
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    filetime::set_file_mtime(generation_link, filetime::FileTime::zero())?;
+    Ok(())
+}
+
+#[test]
+fn install_to_esp_with_spaces_in_path() -> Result<()> {
+    let esp = tempfile::Builder::new()
+        .prefix("esp with spaces ")
+        .tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    add_specialisation(&generation_link, "debug (verbose)")?;
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), [generation_link], ["--bls-entries"])?;
+    assert!(output0.status.success());
+
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let stub = esp
+        .path()
+        .join("EFI/nixos")
+        .join(image.file_name().unwrap());
+    assert!(stub.exists());
+    let entries = fs::read_dir(esp.path().join("loader/entries"))?
+        .map(|entry| fs::read_to_string(entry?.path()).map_err(Into::into))
+        .collect::<Result<Vec<String>>>()?;
+    assert_eq!(entries.len(), 2);
+    // The paths in the entries are relative to the ESP and spaces need no escaping.
+    assert!(entries.iter().any(|entry| entry
+        .contains("\nefi /EFI/nixos/nixos-generation-1-specialisation-debug (verbose)-")));
+
+    Ok(())
+}
+
+#[test]
+fn reject_specialisation_names_illegal_on_fat() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    add_specialisation(&generation_link, "debug:verbose")?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("Invalid specialisation name"));
+    assert!(stderr.contains("':'"));
+
+    Ok(())
+}
+
+#[test]
+fn reconcile_broken_files() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let install = |extra_args: &[&str]| -> Result<serde_json::Value> {
+        let mut args = vec!["--json"];
+        args.extend(extra_args);
+        let output = common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], args)?;
+        assert!(output.status.success());
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+    assert_eq!(install(&[])?["changed"], true);
+
+    let stub = common::image_path(&esp, 1, &toplevel)?;
+    let initrd = fs::read_dir(esp.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("initrd"))
+        .expect("No initrd installed");
+    let initrd_hash = hash_file(&initrd);
+    remove_signature(&stub)?;
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&initrd)?
+        .write_all(b"corrupted")?;
+
+    // Without reconciling, installed generations are trusted.
+    assert_eq!(install(&[])?["changed"], false);
+    assert!(!verify_signature(&stub)?);
+
+    assert_eq!(install(&["--reconcile"])?["changed"], true);
+    assert!(verify_signature(&stub)?);
+    assert_eq!(hash_file(&initrd), initrd_hash);
+
+    assert_eq!(install(&["--reconcile"])?["changed"], false);
+    Ok(())
+}
+
+#[test]
+fn refuse_empty_profile() -> Result<()> {
+    let esp = tempdir()?;
+    let profiles = tempdir()?;
+    let installed = esp.path().join("EFI/nixos/kernel.efi");
+    fs::create_dir_all(esp.path().join("EFI/nixos"))?;
+    fs::write(&installed, "kernel")?;
+
+    // The shell passes a glob that matches nothing verbatim.
+    let output0 =
+        common::lanzaboote_install(0, esp.path(), [profiles.path().join("system-*-link")])?;
+    assert_eq!(output0.status.code(), Some(3));
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(
+        stderr.contains(&format!("No generations found under {:?}", profiles.path())),
+        "{stderr}"
+    );
+    // Nothing is garbage collected.
+    assert!(installed.exists());
+    Ok(())
+}
+
+#[test]
+fn select_default_entry() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links = [1, 2]
+        .map(|version| setup_generation_link_from_toplevel(&toplevel, profiles.path(), version))
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links.clone(),
+        ["--default", "1"],
+    )?;
+    assert!(output0.status.success());
+    let stub1 = common::image_path(&esp, 1, &toplevel)?;
+    let loader_config = fs::read_to_string(esp.path().join("loader/loader.conf"))?;
+    let default = format!("default {}\n", stub1.file_name().unwrap().to_str().unwrap());
+    assert!(loader_config.ends_with(&default), "{loader_config}");
+
+    // A generation that is not installed cannot be the default.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        generation_links,
+        ["--default", "3:debug"],
+    )?;
+    assert!(!output1.status.success());
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(
+        stderr.contains("The default entry 3:debug is not among the installed generations: 1, 2."),
+        "{stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn refuse_esp_device_without_fat() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let device = tmpdir.path().join("device");
+    fs::write(&device, vec![0; 1024 * 1024])?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [Path::new("--esp-device"), &device],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(
+        stderr.contains("does not contain a FAT file system"),
+        "{stderr}"
+    );
+    assert_eq!(count_files(esp.path())?, 0);
+    Ok(())
+}
+
+#[test]
+fn sign_installed_generation_again_only_if_forced() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output0.status.success());
+
+    let signed_stub = |output: std::process::Output| -> Result<bool> {
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stderr)?.contains("Signing \"nixos-generation-1-"))
+    };
+    let output1 = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(!signed_stub(output1)?);
+    let output2 =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], ["--force"])?;
+    assert!(signed_stub(output2)?);
+    assert!(common::verify_signature(&common::image_path(
+        &esp, 1, &toplevel
+    )?)?);
+
+    Ok(())
+}
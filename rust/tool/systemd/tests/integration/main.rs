@@ -1,5 +1,28 @@
+mod additional_sections;
+mod bls;
+mod bootctl;
+mod build_uki;
+mod cert_chain;
+mod cmdline_map;
 mod common;
+mod config;
+mod detached_signatures;
+mod diff;
+mod drift;
+mod dump_bootspec;
+mod ecdsa;
+mod enrolled;
 mod gc;
+mod generate_keys;
+mod inspect;
 mod install;
+mod mirror_esp;
 mod os_release;
+mod prune_store_refs;
+mod sbat;
+mod signed_manifest;
+mod sizes;
+mod snapshot;
+mod status;
 mod systemd_boot;
+mod verify;
@@ -0,0 +1,112 @@
+use std::fs;
+use std::process::Output;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common::{self, setup_generation_link_from_toplevel, verify_signature};
+
+#[test]
+fn install_to_mirrored_esps() -> Result<()> {
+    let esp = tempdir()?;
+    let mirror = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--mirror-esp".as_ref(), mirror.path().as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    for esp in [&esp, &mirror] {
+        let image = common::image_path(esp, 1, &toplevel)?;
+        assert!(verify_signature(&image)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn share_hash_cache_between_mirrored_esps() -> Result<()> {
+    let esp = tempdir()?;
+    let mirror = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let cache_dir = tempdir()?;
+    let hash_cache = cache_dir.path().join("hashes.json");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        [
+            "--mirror-esp".as_ref(),
+            mirror.path().as_os_str(),
+            "--hash-cache".as_ref(),
+            hash_cache.as_os_str(),
+        ],
+    )?;
+    assert!(output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(
+        !stderr.contains("Failed to save the hash cache"),
+        "{stderr}"
+    );
+
+    let cache: serde_json::Value = serde_json::from_slice(&fs::read(&hash_cache)?)?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    for input in ["kernel", "initrd"] {
+        let input = store_path.join(input);
+        assert!(cache.get(input.to_str().unwrap()).is_some(), "{input:?}");
+    }
+    // Only the cache itself is left, no temporary files of concurrent saves.
+    assert_eq!(common::count_files(cache_dir.path())?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn succeed_if_one_esp_was_updated() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    // A file instead of a mountpoint, so that the installation to this ESP fails.
+    let broken_esp = tmpdir.path().join("broken-esp");
+    fs::write(&broken_esp, "")?;
+
+    let install = |extra_args: &[&str]| -> Result<Output> {
+        let mut args = vec!["--json", "--mirror-esp", broken_esp.to_str().unwrap()];
+        args.extend(extra_args);
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], args)
+    };
+
+    let output0 = install(&[])?;
+    assert!(output0.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output0.stdout)?;
+    assert_eq!(report["changed"], true);
+    assert_eq!(report["esps"][0]["changed"], true);
+    assert!(report["esps"][0]["error"].is_null());
+    assert!(report["esps"][1]["changed"].is_null());
+    assert!(report["esps"][1]["error"].is_string());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("only installed to 1 of 2 ESPs"), "{stderr}");
+
+    let output1 = install(&["--require-all-esps"])?;
+    assert!(!output1.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    assert_eq!(report["esps"][0]["changed"], false);
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(
+        stderr.contains("Failed to install Lanzaboote to 1 of 2 ESPs"),
+        "{stderr}"
+    );
+    Ok(())
+}
@@ -27,8 +27,8 @@ fn generate_expected_os_release() -> Result<()> {
 
     let expected = expect![[r#"
         ID=lanzaboote
-        PRETTY_NAME=LanzaOS (Generation 1, 1970-01-01)
-        VERSION_ID=Generation 1, 1970-01-01
+        PRETTY_NAME="LanzaOS (Generation 1, 1970-01-01)"
+        VERSION_ID="Generation 1, 1970-01-01"
     "#]];
 
     expected.assert_eq(&String::from_utf8(os_release_section)?);
@@ -36,6 +36,101 @@ fn generate_expected_os_release() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn override_os_release_and_cmdline() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)
+            .expect("Failed to setup generation link");
+
+    let os_release = tmpdir.path().join("os-release");
+    fs::write(&os_release, "ID=custom\nPRETTY_NAME=\"Custom OS\"\n")?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        [
+            "--os-release".as_ref(),
+            os_release.as_os_str(),
+            "--cmdline".as_ref(),
+            "init=/custom/init quiet".as_ref(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    // The overrides are inputs of the stub name.
+    assert!(!common::image_path(&esp_mountpoint, 1, &toplevel)?.exists());
+    let stub = fs::read_dir(esp_mountpoint.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("nixos-generation-1-"))
+        .context("Missing stub of generation 1")?;
+    let stub_data = fs::read(stub)?;
+
+    let os_release_section =
+        pe_section(&stub_data, ".osrel").context("Failed to read .osrel PE section.")?;
+    let expected = expect![[r#"
+        ID=custom
+        PRETTY_NAME="Custom OS"
+    "#]];
+    expected.assert_eq(&String::from_utf8(os_release_section.to_owned())?);
+
+    let cmdline_section =
+        pe_section(&stub_data, ".cmdline").context("Failed to read .cmdline PE section.")?;
+    assert_eq!(cmdline_section, b"init=/custom/init quiet");
+
+    Ok(())
+}
+
+#[test]
+fn embed_sort_key_from_template() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)
+            .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--sort-key-template", "nixos-{sort_key}{specialisation}"],
+    )?;
+    assert!(output0.status.success());
+
+    // The template is an input of the stub name.
+    let stub = fs::read_dir(esp_mountpoint.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("nixos-generation-1-"))
+        .context("Missing stub of generation 1")?;
+    assert_ne!(stub, common::image_path(&esp_mountpoint, 1, &toplevel)?);
+    let stub_data = fs::read(stub)?;
+
+    let os_release_section =
+        pe_section(&stub_data, ".osrel").context("Failed to read .osrel PE section.")?;
+    let expected = expect![[r#"
+        ID=lanzaboote
+        IMAGE_ID=nixos-lanzaboote
+        IMAGE_VERSION=1
+        PRETTY_NAME="LanzaOS (Generation 1, 1970-01-01)"
+        VERSION_ID="Generation 1, 1970-01-01"
+    "#]];
+    expected.assert_eq(&String::from_utf8(os_release_section.to_owned())?);
+
+    Ok(())
+}
+
 fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
     let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
 
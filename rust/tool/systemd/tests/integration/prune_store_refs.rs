@@ -0,0 +1,36 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn print_store_paths_of_dropped_generations_only() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    // The bootspecs are only read, so the store paths do not need to exist.
+    let old_toplevel = tmpdir.path().join("old-toplevel");
+    let toplevel = tmpdir.path().join("toplevel");
+    let generation_links = vec![
+        common::setup_generation_link_from_toplevel(&old_toplevel, profiles.path(), 1)?,
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?,
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 3)?,
+    ];
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["prune-store-refs", "--configuration-limit", "2"])
+        .args(&generation_links)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+
+    let expected = format!(
+        "{}\n{}\n",
+        old_toplevel.display(),
+        old_toplevel
+            .join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1")
+            .display()
+    );
+    assert_eq!(String::from_utf8(output.stdout)?, expected);
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use lanzaboote_tool::pe;
+use tempfile::tempdir;
+
+use crate::common;
+
+const SBAT: &str = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md
+lanzaboote,1,Lanzaboote,lanzaboote,0.4.1,https://github.com/nix-community/lanzaboote
+";
+
+#[test]
+fn embed_sbat_metadata() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let sbat = tmpdir.path().join("sbat.csv");
+    fs::write(&sbat, SBAT)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--sbat".as_ref(), sbat.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    // The stub has a different name because the SBAT metadata is part of its inputs.
+    assert!(!common::image_path(&esp, 1, &toplevel)?.exists());
+    let stub = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("nixos-generation-1-"))
+        .context("Missing stub of generation 1")?;
+    let stub = fs::read(stub)?;
+    let embedded = pe::read_section_data(&stub, ".sbat").context("Missing .sbat")?;
+    assert_eq!(embedded, SBAT.as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn reject_malformed_sbat_metadata() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    // The version entry is missing.
+    let sbat = tmpdir.path().join("sbat.csv");
+    fs::write(&sbat, SBAT.lines().nth(1).unwrap())?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--sbat".as_ref(), sbat.as_os_str()],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("must declare the SBAT version"));
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
+
+#[test]
+fn embed_lanzaboote_sbat_level() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--sbat-level", "2"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.to_string_lossy().contains("nixos-generation-1-"))
+        .context("Missing stub of generation 1")?;
+    let stub = fs::read(stub)?;
+    let embedded = pe::read_section_data(&stub, ".sbat").context("Missing .sbat")?;
+    let embedded = String::from_utf8_lossy(embedded);
+    assert!(embedded.starts_with(SBAT.lines().next().unwrap()));
+    assert!(embedded.contains("\nlanzaboote,2,Lanzaboote,lanzaboote,"));
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common::{self, hash_file, setup_generation_link_from_toplevel, verify_signature};
+
+#[test]
+fn list_signed_and_unsigned_files() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let manifest = esp.path().join("EFI/nixos-signed/manifest.json");
+
+    let install = || {
+        common::lanzaboote_install_with_args(
+            0,
+            esp.path(),
+            [&generation_link],
+            ["--signed-manifest", "EFI/nixos-signed/manifest.json"],
+        )
+    };
+    let output0 = install()?;
+    assert!(output0.status.success());
+
+    let contents: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest)?)?;
+    let paths = |key: &str| -> Vec<String> {
+        contents[key]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry["path"].as_str().map(String::from))
+            .collect()
+    };
+    let signed = paths("signed");
+    let unsigned = paths("unsigned");
+
+    let stub = common::image_path(&esp, 1, &toplevel)?;
+    let stub = format!("/{}", stub.strip_prefix(esp.path())?.display());
+    assert!(signed.contains(&stub), "{signed:?}");
+    assert!(signed.contains(&"/EFI/systemd/systemd-bootx64.efi".to_string()));
+    // The kernel and initrd are verified by the stub instead.
+    assert_eq!(
+        unsigned
+            .iter()
+            .filter(|path| path.starts_with("/EFI/nixos/"))
+            .count(),
+        2
+    );
+
+    for entry in contents["signed"]
+        .as_array()
+        .into_iter()
+        .chain(contents["unsigned"].as_array())
+        .flatten()
+    {
+        let path = esp
+            .path()
+            .join(entry["path"].as_str().unwrap().trim_start_matches('/'));
+        assert_eq!(
+            format!("{:x}", hash_file(&path)),
+            entry["sha256"].as_str().unwrap()
+        );
+    }
+    for path in &signed {
+        assert!(verify_signature(
+            &esp.path().join(path.trim_start_matches('/'))
+        )?);
+    }
+
+    // The manifest itself is unchanged and not garbage collected.
+    let output1 = install()?;
+    assert!(output1.status.success());
+    assert!(manifest.exists());
+    Ok(())
+}
+
+#[test]
+fn reject_signed_manifest_outside_of_esp() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--signed-manifest", "../manifest.json"],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("is not on the ESP"), "{stderr}");
+    Ok(())
+}
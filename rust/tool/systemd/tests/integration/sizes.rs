@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+const MIB: usize = 1024 * 1024;
+
+#[test]
+fn estimate_sizes_with_shared_files() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    // Only the sizes of the files are read, so they do not need to be real PE binaries.
+    let stub = tmpdir.path().join("stub.efi");
+    fs::write(&stub, vec![0; MIB / 2])?;
+    let toplevel = fake_toplevel(&tmpdir.path().join("toplevel"))?;
+    let other_toplevel = fake_toplevel(&tmpdir.path().join("other-toplevel"))?;
+
+    let generation_links = [
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?,
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?,
+        common::setup_generation_link_from_toplevel(&other_toplevel, profiles.path(), 3)?,
+    ];
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .env("LANZABOOTE_STUB", &stub)
+        .args(["sizes", "--configuration-limit", "0"])
+        .args(&generation_links)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    for line in &lines[1..4] {
+        assert!(line.ends_with("2.5 MiB"), "{line}");
+    }
+    // Generations 1 and 2 share the kernel and initrd.
+    assert!(lines[4].starts_with("total"));
+    assert!(lines[4].ends_with("5.5 MiB"), "{}", lines[4]);
+
+    Ok(())
+}
+
+#[test]
+fn skip_entries_of_profile_directory_that_are_not_generations() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let stub = tmpdir.path().join("stub.efi");
+    fs::write(&stub, vec![0; MIB / 2])?;
+    let toplevel = fake_toplevel(&tmpdir.path().join("toplevel"))?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    // The link to the current generation and a backup of a generation link.
+    for name in ["system", "system-1-link.bak"] {
+        std::os::unix::fs::symlink(&generation_link, profiles.path().join(name))?;
+    }
+
+    let mut entries = fs::read_dir(profiles.path())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .env("LANZABOOTE_STUB", &stub)
+        .args(["sizes", "--configuration-limit", "0"])
+        .args(&entries)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    // A header, generation 1 and the total.
+    assert_eq!(stdout.lines().count(), 3, "{stdout}");
+
+    Ok(())
+}
+
+/// Create a toplevel with a 1 MiB kernel and a 1 MiB initrd.
+fn fake_toplevel(toplevel: &Path) -> Result<std::path::PathBuf> {
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    fs::create_dir_all(&store_path)?;
+    fs::write(store_path.join("kernel"), vec![0; MIB])?;
+    fs::write(store_path.join("initrd"), vec![0; MIB])?;
+    Ok(toplevel.to_path_buf())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common::{self, setup_generation_link_from_toplevel};
+
+#[test]
+fn restore_snapshot_after_bad_install() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let snapshots = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [&generation_link1])?;
+    assert!(output0.status.success());
+    let stub1 = common::image_path(&esp, 1, &toplevel)?;
+    let stub1_contents = fs::read(&stub1)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1, &generation_link2],
+        ["--snapshot".as_ref(), snapshots.path().as_os_str()],
+    )?;
+    assert!(output1.status.success());
+    let stub2 = common::image_path(&esp, 2, &toplevel)?;
+    assert!(stub2.exists());
+
+    let ids = fs::read_dir(snapshots.path())?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    let [id] = ids.as_slice() else {
+        panic!("Expected one snapshot, found {ids:?}");
+    };
+    fs::write(&stub1, "broken")?;
+
+    let output2 = Command::cargo_bin("lzbt-systemd")?
+        .arg("restore-snapshot")
+        .arg("--snapshots")
+        .arg(snapshots.path())
+        .arg(id)
+        .arg(esp.path())
+        .output()?;
+    assert!(output2.status.success());
+    assert_eq!(fs::read(&stub1)?, stub1_contents);
+    assert!(!stub2.exists());
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_snapshot() -> Result<()> {
+    let esp = tempdir()?;
+    let snapshots = tempdir()?;
+
+    let output0 = Command::cargo_bin("lzbt-systemd")?
+        .arg("restore-snapshot")
+        .arg("--snapshots")
+        .arg(snapshots.path())
+        .arg("1700000000")
+        .arg(esp.path())
+        .output()?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(
+        stderr.contains("There is no snapshot 1700000000"),
+        "{stderr}"
+    );
+    Ok(())
+}
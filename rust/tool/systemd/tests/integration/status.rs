@@ -0,0 +1,40 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common::{self, setup_generation_link_from_toplevel};
+
+#[test]
+fn report_installed_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+    let stub = common::image_path(&esp, 1, &toplevel)?;
+
+    let output1 = Command::cargo_bin("lzbt-systemd")?
+        .arg("status")
+        .arg("--system")
+        .arg("x86_64-linux")
+        .arg("--public-key")
+        .arg("tests/fixtures/uefi-keys/db.pem")
+        .arg(esp.path())
+        .output()?;
+    assert!(output1.status.success());
+    let state: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    assert_eq!(state["schemaVersion"], 1);
+    let generation = &state["generations"][0];
+    assert_eq!(generation["version"], 1);
+    assert_eq!(generation["specialisation"], serde_json::Value::Null);
+    assert_eq!(
+        generation["path"],
+        format!("/EFI/Linux/{}", stub.file_name().unwrap().to_str().unwrap())
+    );
+    assert_eq!(generation["signature"], "valid");
+    assert_eq!(state["systemdBoot"]["signature"], "valid");
+    Ok(())
+}
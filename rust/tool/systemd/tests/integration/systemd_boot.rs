@@ -112,6 +112,95 @@ fn overwrite_unsigned_systemd_boot_binaries() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn overwrite_systemd_boot_binaries_for_wrong_architecture() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+
+    // Pretend that the fallback was built for another architecture by patching the machine type
+    // in its COFF header.
+    let arch = Architecture::from_nixos_system(SYSTEM)?;
+    let other_arch = match arch {
+        Architecture::X86 => Architecture::AArch64,
+        _ => Architecture::X86,
+    };
+    let mut fallback = fs::read(&systemd_boot_fallback_path)?;
+    let coff_header_offset = u32::from_le_bytes(fallback[0x3c..0x40].try_into()?) as usize + 4;
+    fallback[coff_header_offset..coff_header_offset + 2]
+        .copy_from_slice(&other_arch.pe_machine().to_le_bytes());
+    fs::write(&systemd_boot_fallback_path, &fallback)?;
+
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output1.status.success());
+
+    let fallback = fs::read(&systemd_boot_fallback_path)?;
+    assert_eq!(lanzaboote_tool::pe::read_architecture(&fallback)?, arch);
+    assert!(verify_signature(&systemd_boot_fallback_path)?);
+
+    Ok(())
+}
+
+#[test]
+fn install_efi_fallback_with_custom_filename() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        ["--efi-fallback-filename", "SHIMX64.EFI"],
+    )?;
+    assert!(output0.status.success());
+
+    let custom_fallback_path = esp.path().join("EFI/BOOT/SHIMX64.EFI");
+    assert!(verify_signature(&custom_fallback_path)?);
+    assert!(!systemd_boot_fallback_path(&esp).exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_foreign_efi_fallback() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    // Simulate the fallback of another OS on a shared ESP.
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+    fs::create_dir_all(systemd_boot_fallback_path.parent().unwrap())?;
+    fs::write(&systemd_boot_fallback_path, "foreign fallback")?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        ["--no-efi-fallback"],
+    )?;
+    assert!(output0.status.success());
+
+    assert!(verify_signature(&systemd_boot_path(&esp))?);
+    assert_eq!(
+        fs::read_to_string(&systemd_boot_fallback_path)?,
+        "foreign fallback"
+    );
+
+    Ok(())
+}
+
 fn systemd_boot_path(esp: &tempfile::TempDir) -> PathBuf {
     let arch = Architecture::from_nixos_system(SYSTEM).unwrap();
     esp.path()
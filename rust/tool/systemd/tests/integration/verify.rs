@@ -0,0 +1,226 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn verify_images_as_json() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let kernel = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+
+    let output1 = Command::cargo_bin("lzbt-systemd")?
+        .args([
+            "verify",
+            "--json",
+            "--public-key",
+            "tests/fixtures/uefi-keys/db.pem",
+        ])
+        .arg(&image)
+        .arg(&kernel)
+        .output()?;
+    // The kernel is not signed.
+    assert!(!output1.status.success());
+
+    let verifications: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    assert_eq!(verifications[0]["path"], image.to_str().unwrap());
+    assert_eq!(verifications[0]["signed"], true);
+    assert_eq!(verifications[0]["valid"], true);
+    assert_eq!(verifications[0]["signer"], "CN=Database Key,C=Database Key");
+    assert!(verifications[0]["error"].is_null());
+    assert_eq!(verifications[1]["signed"], false);
+    assert_eq!(verifications[1]["valid"], false);
+    assert_eq!(verifications[1]["error"], "The file is not signed.");
+
+    Ok(())
+}
+
+fn verify_offline(public_key: &str, args: &[&str]) -> Result<std::process::Output> {
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["verify", "--offline", "--public-key", public_key])
+        .args(args)
+        .output()?;
+    Ok(output)
+}
+
+#[test]
+fn verify_offline_embedded_signature() -> Result<()> {
+    let output = verify_offline(
+        "tests/fixtures/uefi-keys/db.pem",
+        &[
+            "--json",
+            "tests/fixtures/authenticode/signed.efi",
+            "tests/fixtures/authenticode/unsigned.efi",
+        ],
+    )?;
+    assert!(!output.status.success());
+
+    let verifications: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(verifications[0]["valid"], true);
+    assert_eq!(verifications[0]["signer"], "CN=Database Key,C=Database Key");
+    assert_eq!(verifications[1]["signed"], false);
+    assert_eq!(verifications[1]["error"], "The file is not signed.");
+
+    Ok(())
+}
+
+#[test]
+fn verify_offline_detached_signature() -> Result<()> {
+    // The unsigned binary is not padded to 8 bytes, which sbsign does before signing.
+    let output = verify_offline(
+        "tests/fixtures/uefi-keys/db.pem",
+        &[
+            "--detached",
+            "tests/fixtures/authenticode/signed.efi.p7s",
+            "tests/fixtures/authenticode/unsigned.efi",
+        ],
+    )?;
+    assert!(output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn verify_offline_rejects_modified_binary() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let path = tmpdir.path().join("modified.efi");
+    let mut binary = std::fs::read("tests/fixtures/authenticode/signed.efi")?;
+    binary[1100] ^= 0xff;
+    std::fs::write(&path, binary)?;
+
+    let output = verify_offline("tests/fixtures/uefi-keys/db.pem", &[path.to_str().unwrap()])?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("The signature was created for a different binary"));
+
+    Ok(())
+}
+
+#[test]
+fn verify_offline_certificate_chain() -> Result<()> {
+    for trusted in ["root", "intermediate", "db"] {
+        let output = verify_offline(
+            &format!("tests/fixtures/uefi-keys-chain/{trusted}.pem"),
+            &["tests/fixtures/authenticode/signed-chain.efi"],
+        )?;
+        assert!(output.status.success(), "not trusted by {trusted}.pem");
+    }
+
+    let output = verify_offline(
+        "tests/fixtures/uefi-keys/db.pem",
+        &["tests/fixtures/authenticode/signed-chain.efi"],
+    )?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("is not issued by the trusted certificate"));
+
+    Ok(())
+}
+
+#[test]
+fn verify_installed_binaries_on_esp() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output0.status.success());
+
+    let verify_esp = || -> Result<std::process::Output> {
+        let output = Command::cargo_bin("lzbt-systemd")?
+            .args(["verify", "--offline", "--json", "--system", common::SYSTEM])
+            .args(["--public-key", "tests/fixtures/uefi-keys/db.pem", "--esp"])
+            .arg(esp.path())
+            .output()?;
+        Ok(output)
+    };
+
+    let output1 = verify_esp()?;
+    assert!(output1.status.success());
+    let verifications: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    let paths = verifications
+        .as_array()
+        .expect("The verifications are an array")
+        .iter()
+        .map(|verification| verification["path"].as_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    assert!(paths.contains(&image.to_str().unwrap()));
+    assert!(paths
+        .iter()
+        .any(|path| path.ends_with("systemd-bootx64.efi")));
+
+    common::remove_signature(&image)?;
+    let output2 = verify_esp()?;
+    assert!(!output2.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_to_verify_empty_esp() -> Result<()> {
+    let esp = tempdir()?;
+
+    let output0 = Command::cargo_bin("lzbt-systemd")?
+        .args(["verify", "--system", common::SYSTEM])
+        .args(["--public-key", "tests/fixtures/uefi-keys/db.pem", "--esp"])
+        .arg(esp.path())
+        .output()?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("There are no binaries installed"));
+
+    Ok(())
+}
+
+#[test]
+fn verify_kernels_booted_by_loader_entries() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--boot-mode", "kernel"],
+    )?;
+    assert!(output0.status.success());
+
+    let output1 = Command::cargo_bin("lzbt-systemd")?
+        .args(["verify", "--offline", "--json", "--system", common::SYSTEM])
+        .args(["--public-key", "tests/fixtures/uefi-keys/db.pem", "--esp"])
+        .arg(esp.path())
+        .output()?;
+    assert!(output1.status.success());
+    let verifications: serde_json::Value = serde_json::from_slice(&output1.stdout)?;
+    let kernel = verifications
+        .as_array()
+        .expect("The verifications are an array")
+        .iter()
+        .find(|verification| {
+            verification["path"]
+                .as_str()
+                .is_some_and(|path| path.contains("/EFI/nixos/kernel-6.1.1-"))
+        })
+        .expect("The signed kernel was not verified");
+    assert_eq!(kernel["signed"], true);
+    assert_eq!(kernel["valid"], true);
+
+    Ok(())
+}
@@ -25,7 +25,7 @@ struct EmbeddedConfiguration {
     /// The kernel as raw bytes.
     kernel: Vec<u8>,
 
-    /// The initrd as raw bytes.
+    /// The initrd as raw bytes. It is empty if there is no initrd.
     initrd: Vec<u8>,
 }
 
@@ -33,7 +33,9 @@ impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
         Ok(Self {
             kernel: extract_bytes(file_data, ".linux")?,
-            initrd: extract_bytes(file_data, ".initrd")?,
+            initrd: pe_section(file_data, ".initrd")
+                .map(Vec::from)
+                .unwrap_or_default(),
             cmdline: extract_string(file_data, ".cmdline")?,
         })
     }
@@ -23,13 +23,11 @@ struct EmbeddedConfiguration {
     /// The cryptographic hash of the kernel.
     kernel_hash: Hash,
 
-    /// The filename of the initrd to be passed to the kernel. See
-    /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
-
-    /// The cryptographic hash of the initrd. This hash is computed
+    /// The filename of the initrd to be passed to the kernel and its
+    /// cryptographic hash, if there is an initrd. See `kernel_filename`
+    /// for how to interpret these filenames. This hash is computed
     /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
+    initrd: Option<(CString16, Hash)>,
 
     /// The kernel command-line.
     cmdline: CString16,
@@ -51,8 +49,16 @@ impl EmbeddedConfiguration {
             kernel_filename: extract_string(file_data, ".linux")?,
             kernel_hash: extract_hash(file_data, ".linuxh")?,
 
-            initrd_filename: extract_string(file_data, ".initrd")?,
-            initrd_hash: extract_hash(file_data, ".initrdh")?,
+            // Generations without an initrd have neither an `.initrd` nor an
+            // `.initrdh` section. Like all sections, their absence is covered
+            // by the signature.
+            initrd: match pe_section(file_data, ".initrd") {
+                Some(_) => Some((
+                    extract_string(file_data, ".initrd")?,
+                    extract_hash(file_data, ".initrdh")?,
+                )),
+                None => None,
+            },
 
             cmdline: extract_string(file_data, ".cmdline")?,
         })
@@ -90,7 +96,7 @@ pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result
     let secure_boot_enabled = get_secure_boot_status();
 
     let kernel_data;
-    let mut initrd_data;
+    let mut initrd_data = Vec::new();
 
     {
         let file_system =
@@ -100,9 +106,11 @@ pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result
         kernel_data = file_system
             .read(&*config.kernel_filename)
             .expect("Failed to read kernel file into memory");
-        initrd_data = file_system
-            .read(&*config.initrd_filename)
-            .expect("Failed to read initrd file into memory");
+        if let Some((initrd_filename, _)) = &config.initrd {
+            initrd_data = file_system
+                .read(&**initrd_filename)
+                .expect("Failed to read initrd file into memory");
+        }
     }
 
     let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
@@ -113,12 +121,9 @@ pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result
         "Kernel",
         secure_boot_enabled,
     )?;
-    check_hash(
-        &initrd_data,
-        config.initrd_hash,
-        "Initrd",
-        secure_boot_enabled,
-    )?;
+    if let Some((_, initrd_hash)) = config.initrd {
+        check_hash(&initrd_data, initrd_hash, "Initrd", secure_boot_enabled)?;
+    }
 
     // Correctness: dynamic initrds are supposed to be validated by caller,
     // i.e. they are system extension images or credentials